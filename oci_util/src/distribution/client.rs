@@ -23,9 +23,12 @@
 // SUCH DAMAGE.
 
 use crate::digest::{Hasher, OciDigest};
+use crate::distribution::token::{TokenCache, TokenKey};
+use crate::layout::{ImageIndex, IndexEntry};
 use crate::models::{
-    AnyOciConfig, Descriptor, ImageManifest, ImageManifestList, ManifestDesc, ManifestVariant,
-    Platform, DOCKER_MANIFEST, DOCKER_MANIFESTS, OCI_ARTIFACT, OCI_IMAGE_INDEX, OCI_MANIFEST,
+    AnyOciConfig, ArtifactManifest, Descriptor, ImageManifest, ImageManifestList, ManifestDesc,
+    ManifestVariant, Platform, TagList, DOCKER_MANIFEST, DOCKER_MANIFESTS, OCI_ARTIFACT,
+    OCI_IMAGE_INDEX, OCI_MANIFEST,
 };
 use futures::future::Either;
 use reqwest::{Client, ClientBuilder, RequestBuilder, Response};
@@ -35,7 +38,10 @@ use std::path::Path;
 use std::time::{Duration, SystemTime};
 use thiserror::Error;
 use tokio::sync::watch::Sender;
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
+
+/// Maximum number of pages `Session::list_tags` follows
+const MAX_TAG_PAGES: usize = 10000;
 
 #[derive(Debug, Error)]
 pub enum ClientError {
@@ -61,6 +67,33 @@ pub enum ClientError {
     MissingCredential,
     #[error("digest mismatch, expected: {0}, got: {1}")]
     DigestMismatched(OciDigest, OciDigest),
+    #[error("unexpected content-range: {0}")]
+    UnexpectedContentRange(String),
+    #[error("refusing to follow link: {0}")]
+    InvalidLink(String),
+    #[error("registry responded with status {0}: {1}")]
+    ErrorResponse(u16, String),
+}
+
+impl ClientError {
+    /// Whether the error is likely to go away if the request is retried, such as a dropped
+    /// connection, a timeout, or a 5xx / 429 response from the registry
+    pub fn is_transient(&self) -> bool {
+        match self {
+            ClientError::ReqwestError(e) => {
+                e.is_connect() || e.is_timeout() || e.is_request() || e.is_body() || e.is_decode()
+            }
+            ClientError::UnsuccessfulResponse(response) => is_transient_status(response.status()),
+            ClientError::UnexpectedContentRange(_) => true,
+            _ => false,
+        }
+    }
+}
+
+fn is_transient_status(status: reqwest::StatusCode) -> bool {
+    status.is_server_error()
+        || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+        || status == reqwest::StatusCode::REQUEST_TIMEOUT
 }
 
 impl From<std::io::Error> for ClientError {
@@ -87,6 +120,27 @@ impl From<reqwest::header::ToStrError> for ClientError {
     }
 }
 
+/// The outcome of deleting a tag from a repository
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TagDeletion {
+    Deleted,
+    NotFound,
+    /// The registry only deletes manifests by digest, which removes all the tags of the manifest
+    Unsupported,
+}
+
+/// Whether the error response `body` of the distribution API carries the error `code`
+fn has_error_code(body: &[u8], code: &str) -> bool {
+    serde_json::from_slice::<serde_json::Value>(body)
+        .ok()
+        .and_then(|body| {
+            body["errors"]
+                .as_array()
+                .map(|errors| errors.iter().any(|error| error["code"] == code))
+        })
+        .unwrap_or(false)
+}
+
 #[derive(Default, Debug)]
 pub struct UploadStat {
     pub uploaded: Option<usize>,
@@ -137,6 +191,133 @@ impl UploadStat {
     }
 }
 
+#[derive(Default, Debug)]
+pub struct DownloadStat {
+    /// Bytes of the blob available locally, including the bytes resumed from a partial file
+    pub downloaded: Option<usize>,
+    /// Size of the blob, if advertised by the registry
+    pub total: Option<usize>,
+    /// Number of bytes that were already present when the download started
+    pub resumed_from: usize,
+    /// Number of times the download has been retried after a transient failure
+    pub retries: usize,
+    pub started_at: Option<SystemTime>,
+    pub completed_at: Option<Either<Duration, Duration>>,
+}
+
+pub enum DownloadProgress {
+    Download(usize, std::time::Duration),
+    Done(std::time::Duration),
+    Pending,
+    Started,
+    Failed(std::time::Duration),
+}
+
+impl DownloadStat {
+    pub fn is_finished(&self) -> bool {
+        self.completed_at.is_some()
+    }
+
+    pub fn progress(&self) -> DownloadProgress {
+        match self.started_at {
+            None => DownloadProgress::Pending,
+            Some(started_at) => match &self.completed_at {
+                Some(Either::Left(duration)) => DownloadProgress::Done(duration.to_owned()),
+                Some(Either::Right(duration)) => DownloadProgress::Failed(duration.to_owned()),
+                None => match self.downloaded {
+                    None => DownloadProgress::Started,
+                    Some(downloaded) => DownloadProgress::Download(
+                        downloaded,
+                        started_at.elapsed().unwrap_or_default(),
+                    ),
+                },
+            },
+        }
+    }
+}
+
+/// How requests failed with transient errors should be retried. The delay before the n-th retry
+/// is `initial_backoff * 2^n`, capped at `max_backoff`, unless the registry asked for a longer
+/// delay with `Retry-After`.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Maximum number of retries after the initial attempt, 0 disables retrying
+    pub max_retries: usize,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 5,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn none() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 0,
+            ..RetryPolicy::default()
+        }
+    }
+
+    fn backoff(&self, retry: usize, error: &ClientError) -> Duration {
+        let exponential = self
+            .initial_backoff
+            .saturating_mul(1u32.checked_shl(retry as u32).unwrap_or(u32::MAX))
+            .min(self.max_backoff);
+        let retry_after = match error {
+            ClientError::UnsuccessfulResponse(response) => response
+                .headers()
+                .get("retry-after")
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.trim().parse::<u64>().ok())
+                .map(|secs| Duration::from_secs(secs).min(self.max_backoff)),
+            _ => None,
+        };
+        retry_after.map_or(exponential, |delay| delay.max(exponential))
+    }
+}
+
+/// Parse the starting offset and the total size of a `Content-Range: bytes start-end/total`
+/// header, total is `None` if the server responded with `*`
+fn parse_content_range(value: &str) -> Option<(usize, Option<usize>)> {
+    let (range, total) = value.trim().strip_prefix("bytes ")?.split_once('/')?;
+    let (start, _end) = range.split_once('-')?;
+    let total = if total == "*" {
+        None
+    } else {
+        Some(total.parse().ok()?)
+    };
+    Some((start.parse().ok()?, total))
+}
+
+/// The tag of the index listing the referrers of `digest` for registries without the referrers
+/// API, for example `sha256-<hex>`
+pub fn referrers_tag(digest: &OciDigest) -> String {
+    digest.as_str().replacen(':', "-", 1)
+}
+
+/// Extract the target of the `rel="next"` link from a `Link` header value, such as
+/// `</v2/name/tags/list?n=100&last=b>; rel="next"`
+pub fn parse_next_link(link: &str) -> Option<String> {
+    link.split(',').find_map(|value| {
+        let (target, params) = value.trim().split_once(';')?;
+        let target = target.trim().strip_prefix('<')?.strip_suffix('>')?;
+        params
+            .split(';')
+            .filter_map(|param| param.trim().split_once('='))
+            .any(|(key, value)| {
+                key.trim().eq_ignore_ascii_case("rel") && value.trim().trim_matches('"') == "next"
+            })
+            .then(|| target.to_string())
+    })
+}
+
 pub fn parse_comma_separated_quoted_kv_str(input: &str) -> Vec<(String, String)> {
     let mut ret = Vec::new();
     let mut input = input;
@@ -156,12 +337,21 @@ pub fn parse_comma_separated_quoted_kv_str(input: &str) -> Vec<(String, String)>
     ret
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone)]
 pub struct BasicAuth {
     pub username: String,
     pub password: String,
 }
 
+impl std::fmt::Debug for BasicAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BasicAuth")
+            .field("username", &self.username)
+            .field("password", &"<redacted>")
+            .finish()
+    }
+}
+
 impl BasicAuth {
     pub fn new(username: String, password: String) -> BasicAuth {
         BasicAuth { username, password }
@@ -174,6 +364,9 @@ pub struct Registry {
     pub base_url: String,
     pub(crate) upload_chunk_size: usize,
     pub basic_auth: Option<BasicAuth>,
+    pub retry_policy: RetryPolicy,
+    /// Bearer tokens shared by the sessions of this registry
+    pub token_cache: TokenCache,
 }
 
 impl Registry {
@@ -187,15 +380,30 @@ impl Registry {
             base_url,
             basic_auth,
             upload_chunk_size: 2 * 1024 * 1024,
+            retry_policy: RetryPolicy::default(),
+            token_cache: TokenCache::new(),
         }
     }
 
+    /// Use `token_cache` instead of a cache private to this registry, such that tokens can be
+    /// reused by other `Registry` pointing to the same registry
+    pub fn with_token_cache(mut self, token_cache: TokenCache) -> Registry {
+        self.token_cache = token_cache;
+        self
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Registry {
+        self.retry_policy = retry_policy;
+        self
+    }
+
     pub fn new_session(&self, repository: String) -> Session {
         Session {
             registry: self.clone(),
             repository,
             use_basic_auth: false,
-            bearer_token: None,
+            bearer: None,
+            fallback: None,
         }
     }
 }
@@ -205,7 +413,47 @@ pub struct Session {
     registry: Registry,
     repository: String,
     use_basic_auth: bool,
-    bearer_token: Option<crate::models::DockerAuthToken>,
+    /// The token this session authenticates with, if the registry asked for bearer tokens
+    bearer: Option<TokenKey>,
+    /// The session blobs are fetched from if this session fails to fetch them
+    fallback: Option<Box<Session>>,
+}
+
+/// Query for the image manifest `reference` of `repository` from each of `mirrors` in order and
+/// then `upstream`, see `Session::query_manifest_traced_with_digest`. A mirror is skipped if it
+/// does not have the manifest or fails to serve it for any reason, such as being unreachable or
+/// refusing the credentials. Returns the session of the registry the manifest is resolved from,
+/// which should be used to fetch the content of the manifest. The session of a mirror falls
+/// back to `upstream` for the blobs the mirror fails to serve.
+pub async fn query_manifest_traced_with_mirrors(
+    mirrors: &[Registry],
+    upstream: &Registry,
+    repository: &str,
+    reference: &str,
+    filter: impl Fn(&ImageManifestList) -> Option<ManifestDesc>,
+) -> Result<(Session, Option<(OciDigest, ImageManifest)>), ClientError> {
+    for mirror in mirrors.iter() {
+        let mut session = mirror.new_session(repository.to_string());
+        match session
+            .query_manifest_traced_with_digest(reference, &filter)
+            .await
+        {
+            Ok(Some(manifest)) => {
+                let session = session.with_fallback(upstream.new_session(repository.to_string()));
+                return Ok((session, Some(manifest)));
+            }
+            Ok(None) => debug!(
+                "{repository}:{reference} not found on mirror {}",
+                mirror.base_url
+            ),
+            Err(err) => info!("skipping mirror {}: {err}", mirror.base_url),
+        }
+    }
+    let mut session = upstream.new_session(repository.to_string());
+    let manifest = session
+        .query_manifest_traced_with_digest(reference, &filter)
+        .await?;
+    Ok((session, manifest))
 }
 
 impl Session {
@@ -213,6 +461,17 @@ impl Session {
         &self.repository
     }
 
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    /// Fetch the blobs this session fails to fetch, because they are missing or for any other
+    /// reason, from `fallback` instead. Used to fall back from a mirror to the upstream registry.
+    pub fn with_fallback(mut self, fallback: Session) -> Session {
+        self.fallback = Some(Box::new(fallback));
+        self
+    }
+
     fn extract_next_location(&self, response: &Response) -> Result<String, ClientError> {
         let headers = response.headers();
         match headers.get("Location") {
@@ -227,45 +486,87 @@ impl Session {
         }
     }
 
-    async fn try_authenticate(&mut self, www_auth: impl AsRef<str>) -> Result<(), ClientError> {
+    /// Request a token for `key` from the token server and store it in the token cache
+    async fn fetch_token(&self, key: &TokenKey) -> Result<String, ClientError> {
+        let mut request = self.registry.client.get(key.token_url());
+        if let Some(basic_auth) = self.registry.basic_auth.clone() {
+            request = request.basic_auth(basic_auth.username, Some(basic_auth.password));
+        }
+        let requested_at = std::time::Instant::now();
+        let response = request.send().await?;
+        if !response.status().is_success() {
+            return Err(ClientError::UnsuccessfulResponse(response));
+        }
+        let auth_token: crate::models::DockerAuthToken = response.json().await?;
+        self.registry
+            .token_cache
+            .insert(key.clone(), &auth_token, requested_at)
+            .ok_or(ClientError::MissingBearerToken)
+    }
+
+    /// Get a token for `key`, from the token cache if there is one not about to expire
+    async fn bearer_token(&self, key: &TokenKey) -> Result<String, ClientError> {
+        match self.registry.token_cache.get(key) {
+            Some(token) => Ok(token),
+            None => self.fetch_token(key).await,
+        }
+    }
+
+    async fn try_authenticate(
+        &mut self,
+        www_auth: impl AsRef<str>,
+        extra_scopes: &[String],
+    ) -> Result<(), ClientError> {
         let fields: std::collections::HashMap<String, String> =
             std::collections::HashMap::from_iter(parse_comma_separated_quoted_kv_str(
                 www_auth.as_ref(),
             ));
         if let Some(realm) = fields.get("Bearer realm") {
-            let scope = fields.get("scope").unwrap();
-            let service = fields.get("service").unwrap();
-            let mut request = self
-                .registry
-                .client
-                .get(format!("{realm}?service={service}&scope={scope}"));
-            if let Some(basic_auth) = self.registry.basic_auth.clone() {
-                request = request.basic_auth(basic_auth.username, Some(basic_auth.password));
-            }
-            let response = request.send().await?;
-            let auth_token: crate::models::DockerAuthToken = response.json().await?;
-            if auth_token.token().is_none() {
-                return Err(ClientError::MissingBearerToken);
-            } else {
-                self.bearer_token = Some(auth_token);
+            let scopes: Vec<String> = fields
+                .get("scope")
+                .map(|scope| scope.split_whitespace().map(|s| s.to_string()).collect())
+                .unwrap_or_default();
+            let key = TokenKey::new(
+                realm.to_string(),
+                fields.get("service").cloned(),
+                scopes.into_iter().chain(extra_scopes.iter().cloned()),
+                self.registry
+                    .basic_auth
+                    .as_ref()
+                    .map(|a| a.username.clone()),
+            );
+            if self.bearer.as_ref() == Some(&key) {
+                // the registry rejected the token we had, do not trust the cache
+                self.registry.token_cache.remove(&key);
             }
-        } else if fields.get("Basic realm").is_some() {
+            self.bearer_token(&key).await?;
+            self.bearer = Some(key);
+        } else if fields.contains_key("Basic realm") {
             self.use_basic_auth = true;
         }
         Ok(())
     }
 
+    /// Make sure the token of this session also grants `scopes`, if the session authenticates
+    /// with bearer tokens
+    async fn extend_scopes(&mut self, scopes: &[String]) -> Result<(), ClientError> {
+        if let Some(key) = &self.bearer {
+            if !key.contains_scopes(scopes) {
+                let key = key.with_scopes(scopes);
+                self.bearer_token(&key).await?;
+                self.bearer = Some(key);
+            }
+        }
+        Ok(())
+    }
+
     pub async fn request(&self, request: RequestBuilder) -> Result<Response, ClientError> {
         let mut req = request;
-        if let Some(bearer) = self.bearer_token.clone() {
-            req = req.bearer_auth(bearer.token().unwrap());
+        if let Some(key) = &self.bearer {
+            req = req.bearer_auth(self.bearer_token(key).await?);
         } else if self.use_basic_auth {
             if let Some(basic_auth) = self.registry.basic_auth.clone() {
-                debug!(
-                    username = basic_auth.username,
-                    password = basic_auth.password,
-                    "using basic auth"
-                );
+                debug!(username = basic_auth.username, "using basic auth");
                 req = req.basic_auth(basic_auth.username, Some(basic_auth.password));
             } else {
                 return Err(ClientError::MissingCredential);
@@ -277,16 +578,29 @@ impl Session {
     pub async fn request_with_try_auth(
         &mut self,
         request: RequestBuilder,
+    ) -> Result<Response, ClientError> {
+        self.request_with_scopes(request, &[]).await
+    }
+
+    /// Send a request, authenticating and retrying once if the registry asked to. If the
+    /// registry uses bearer tokens, the token requested also grants `extra_scopes`, which is
+    /// needed for requests touching more than one repository, such as cross-repository mounts.
+    pub async fn request_with_scopes(
+        &mut self,
+        request: RequestBuilder,
+        extra_scopes: &[String],
     ) -> Result<Response, ClientError> {
         let cloned = match request.try_clone() {
             Some(clone) => clone,
             None => return Err(ClientError::InvalidRequest(request)),
         };
+        self.extend_scopes(extra_scopes).await?;
         let response = self.request(request).await?;
         if response.status().as_u16() == 401 {
             if let Some(www_auth) = response.headers().get("www-authenticate") {
                 debug!("www-authenticate: {www_auth:#?}");
-                self.try_authenticate(www_auth.to_str()?).await?;
+                self.try_authenticate(www_auth.to_str()?, extra_scopes)
+                    .await?;
                 Ok(self.request(cloned).await?)
             } else {
                 Err(ClientError::MissingHeader("www-authenticate".to_string()))
@@ -315,43 +629,36 @@ impl Session {
         }
     }
 
-    pub async fn upload_content(
+    /// Upload the content of `reader` in chunks to the upload session at `next_location`, returns
+    /// the location to complete the upload at and the number of bytes uploaded. Empty content
+    /// takes no chunk at all, such that the upload is completed by the final PUT alone.
+    async fn upload_chunks(
         &mut self,
         progress: Option<Sender<UploadStat>>,
-        media_type: String,
+        mut next_location: String,
+        hasher: &mut Hasher,
         mut reader: impl Read,
-    ) -> Result<Descriptor, ClientError> {
-        let mut hasher = Hasher::sha256();
-        let repository = &self.repository;
-        let base_url = self.registry.base_url.to_string();
-        let init_res = self
-            .request_with_try_auth(
-                self.registry
-                    .client
-                    .post(format!("{base_url}/v2/{repository}/blobs/uploads/")),
-            )
-            .await?;
-
-        if !init_res.status().is_success() {
-            return Err(ClientError::UnsuccessfulResponse(init_res));
-        }
-
+    ) -> Result<(String, usize), ClientError> {
         let mut cursor = 0;
         let mut buffer = vec![0u8; self.registry.upload_chunk_size];
-        let mut next_location = self.extract_next_location(&init_res)?;
+
         if let Some(progress) = progress.as_ref() {
             progress.send_modify(|p| {
                 p.started_at = Some(SystemTime::now());
             });
         }
+
         loop {
             let bytes = reader.read(&mut buffer)?;
+            if bytes == 0 {
+                break;
+            }
             let range = format!("{cursor}-{}", cursor + bytes - 1);
-
             debug!("uploading range: {range}");
 
             let owned_buf = buffer[..bytes].to_vec();
             hasher.update(&owned_buf);
+
             let request = self
                 .registry
                 .client
@@ -372,11 +679,37 @@ impl Session {
                     p.uploaded = Some(cursor);
                 });
             }
-            if bytes < self.registry.upload_chunk_size {
-                break;
-            }
         }
 
+        Ok((next_location, cursor))
+    }
+
+    pub async fn upload_content(
+        &mut self,
+        progress: Option<Sender<UploadStat>>,
+        media_type: String,
+        reader: impl Read,
+    ) -> Result<Descriptor, ClientError> {
+        let mut hasher = Hasher::sha256();
+        let repository = &self.repository;
+        let base_url = self.registry.base_url.to_string();
+        let init_res = self
+            .request_with_try_auth(
+                self.registry
+                    .client
+                    .post(format!("{base_url}/v2/{repository}/blobs/uploads/")),
+            )
+            .await?;
+
+        if !init_res.status().is_success() {
+            return Err(ClientError::UnsuccessfulResponse(init_res));
+        }
+
+        let next_location = self.extract_next_location(&init_res)?;
+        let (next_location, cursor) = self
+            .upload_chunks(progress, next_location, &mut hasher, reader)
+            .await?;
+
         let digest = hasher.finalize();
         let request = self
             .registry
@@ -405,14 +738,17 @@ impl Session {
         media_type: String,
         mount: bool,
         mount_from: Option<String>,
-        mut reader: impl Read,
+        reader: impl Read,
     ) -> Result<Option<Descriptor>, ClientError> {
         let base_url = self.registry.base_url.to_string();
         let repository = &self.repository;
         let mut hasher = Hasher::sha256();
 
+        // mounting from another repository requires pull access to that repository as well
+        let mut scopes = Vec::new();
         let url = if mount {
             if let Some(from) = mount_from {
+                scopes.push(format!("repository:{from}:pull"));
                 format!("{base_url}/v2/{repository}/blobs/uploads/?mount={digest}&from={from}")
             } else {
                 format!("{base_url}/v2/{repository}/blobs/uploads/?mount={digest}")
@@ -422,7 +758,7 @@ impl Session {
         };
 
         let init_res = self
-            .request_with_try_auth(self.registry.client.post(url))
+            .request_with_scopes(self.registry.client.post(url), &scopes)
             .await?;
 
         if !init_res.status().is_success() {
@@ -434,48 +770,10 @@ impl Session {
             return Ok(None);
         }
 
-        let mut cursor = 0;
-        let mut buffer = vec![0u8; self.registry.upload_chunk_size];
-        let mut next_location = self.extract_next_location(&init_res)?;
-
-        if let Some(progress) = progress.as_ref() {
-            progress.send_modify(|p| {
-                p.started_at = Some(SystemTime::now());
-            });
-        }
-
-        loop {
-            let bytes = reader.read(&mut buffer)?;
-            let range = format!("{cursor}-{}", cursor + bytes - 1);
-            debug!("uploading range: {range}");
-
-            let owned_buf = buffer[..bytes].to_vec();
-            hasher.update(&owned_buf);
-
-            let request = self
-                .registry
-                .client
-                .patch(&next_location)
-                .header("Range", &range)
-                .header("content-type", "application/octet-stream")
-                .body(owned_buf);
-            let response = self.request(request).await?;
-
-            if !response.status().is_success() {
-                return Err(ClientError::UnsuccessfulResponse(response));
-            }
-
-            next_location = self.extract_next_location(&response)?;
-            cursor += bytes;
-            if let Some(progress) = progress.as_ref() {
-                progress.send_modify(|p| {
-                    p.uploaded = Some(cursor);
-                });
-            }
-            if bytes < self.registry.upload_chunk_size {
-                break;
-            }
-        }
+        let next_location = self.extract_next_location(&init_res)?;
+        let (next_location, cursor) = self
+            .upload_chunks(progress, next_location, &mut hasher, reader)
+            .await?;
 
         let calc_digest = hasher.finalize();
 
@@ -505,6 +803,19 @@ impl Session {
         &mut self,
         reference: &str,
     ) -> Result<Option<ManifestVariant>, ClientError> {
+        Ok(self
+            .query_manifest_with_digest(reference)
+            .await?
+            .map(|(_, manifest)| manifest))
+    }
+
+    /// Query for a manifest along with the digest of the manifest as served by the registry.
+    /// If `reference` is a digest, the manifest is verified against it, otherwise the sha256
+    /// digest of the manifest is returned.
+    pub async fn query_manifest_with_digest(
+        &mut self,
+        reference: &str,
+    ) -> Result<Option<(OciDigest, ManifestVariant)>, ClientError> {
         let base_url = &self.registry.base_url;
         let repository = &self.repository;
 
@@ -529,25 +840,34 @@ impl Session {
                 Err(ClientError::UnsuccessfulResponse(response))
             }
         } else {
-            match response.headers().get("content-type") {
-                None => Err(ClientError::MissingHeader("content-type".to_string())),
-                Some(content_type) => {
-                    let content_type = content_type.to_str()?;
-                    //                    eprintln!("content-type: {content_type}");
-                    match content_type {
-                        OCI_MANIFEST | DOCKER_MANIFEST => Ok(Some(ManifestVariant::Manifest(
-                            serde_json::from_slice(&response.bytes().await?)?,
-                        ))),
-                        OCI_IMAGE_INDEX | DOCKER_MANIFESTS => Ok(Some(ManifestVariant::List(
-                            serde_json::from_slice(&response.bytes().await?)?,
-                        ))),
-                        OCI_ARTIFACT => Ok(Some(ManifestVariant::Artifact(
-                            serde_json::from_slice(&response.bytes().await?)?,
-                        ))),
-                        tp => Err(ClientError::UnsupportedContentType(tp.to_string())),
+            let content_type = match response.headers().get("content-type") {
+                None => return Err(ClientError::MissingHeader("content-type".to_string())),
+                Some(content_type) => content_type.to_str()?.to_string(),
+            };
+            let bytes = response.bytes().await?;
+            let digest = match reference.parse::<OciDigest>() {
+                Ok(expected) => {
+                    let mut hasher = Hasher::new(expected.algorithm());
+                    hasher.update(&bytes);
+                    let sum = hasher.finalize();
+                    if sum != expected {
+                        return Err(ClientError::DigestMismatched(expected, sum));
                     }
+                    sum
                 }
-            }
+                Err(_) => crate::digest::sha256_once(&bytes),
+            };
+            let manifest = match content_type.as_str() {
+                OCI_MANIFEST | DOCKER_MANIFEST => {
+                    ManifestVariant::Manifest(serde_json::from_slice(&bytes)?)
+                }
+                OCI_IMAGE_INDEX | DOCKER_MANIFESTS => {
+                    ManifestVariant::List(serde_json::from_slice(&bytes)?)
+                }
+                OCI_ARTIFACT => ManifestVariant::Artifact(serde_json::from_slice(&bytes)?),
+                tp => return Err(ClientError::UnsupportedContentType(tp.to_string())),
+            };
+            Ok(Some((digest, manifest)))
         }
     }
 
@@ -559,65 +879,270 @@ impl Session {
         reference: &str,
         filter: impl Fn(&ImageManifestList) -> Option<ManifestDesc>,
     ) -> Result<Option<ImageManifest>, ClientError> {
-        let mut manifest = self.query_manifest(reference).await?;
-        while let Some(ManifestVariant::List(manifests)) = manifest {
+        Ok(self
+            .query_manifest_traced_with_digest(reference, filter)
+            .await?
+            .map(|(_, manifest)| manifest))
+    }
+
+    /// Same as `query_manifest_traced`, but also returns the digest of the image manifest found
+    pub async fn query_manifest_traced_with_digest(
+        &mut self,
+        reference: &str,
+        filter: impl Fn(&ImageManifestList) -> Option<ManifestDesc>,
+    ) -> Result<Option<(OciDigest, ImageManifest)>, ClientError> {
+        let mut manifest = self.query_manifest_with_digest(reference).await?;
+        while let Some((_, ManifestVariant::List(manifests))) = manifest {
             match filter(&manifests) {
                 None => return Ok(None),
                 Some(desc) => {
-                    manifest = self.query_manifest(desc.digest.as_str()).await?;
+                    manifest = self
+                        .query_manifest_with_digest(desc.digest.as_str())
+                        .await?;
                 }
             }
         }
-        Ok(manifest.and_then(|manifest| match manifest {
-            ManifestVariant::Manifest(manifest) => Some(manifest),
+        Ok(manifest.and_then(|(digest, manifest)| match manifest {
+            ManifestVariant::Manifest(manifest) => Some((digest, manifest)),
             _ => None,
         }))
     }
 
-    pub async fn merge_manifest_list(
+    /// Query for the manifests referring to the manifest `digest` as their subject, only those
+    /// of `artifact_type` if given. If the registry does not support the referrers API, the
+    /// referrers are looked up from the index tagged `<algorithm>-<hex>` instead.
+    pub async fn query_referrers(
         &mut self,
-        descriptor: &Descriptor,
-        platform: &Platform,
-        tag: &str,
-    ) -> Result<Descriptor, ClientError> {
-        // see if there an existing manifest with such tag
-        //
-        // if it is a manifest list, check if there's one matching the platform, if so, replace it
-        // and re-upload the list
-        //
-        // if it is a single manifest, and the platform is different, create a manifest list
-        //
-        // if there is none, just register this manifest
-        let manifest_variant = self.query_manifest(tag).await?;
-        let manifest_list = match manifest_variant {
-            Some(ManifestVariant::List(mut manifest_list)) => {
-                let mut manifests = manifest_list
-                    .manifests
-                    .into_iter()
-                    .filter(|m| !m.platform.is_compatible(platform))
-                    .collect::<Vec<_>>();
-                let desc = ManifestDesc {
-                    media_type: descriptor.media_type.to_string(),
-                    size: descriptor.size,
-                    platform: platform.clone(),
-                    digest: descriptor.digest.clone(),
-                    annotations: std::collections::HashMap::new(),
-                    artifact_type: None,
-                };
-                manifests.push(desc);
-                manifest_list.manifests = manifests;
-                manifest_list
+        digest: &OciDigest,
+        artifact_type: Option<&str>,
+    ) -> Result<Vec<IndexEntry>, ClientError> {
+        let base_url = self.registry.base_url.to_string();
+        let repository = self.repository.to_string();
+        let mut request = self
+            .registry
+            .client
+            .get(format!("{base_url}/v2/{repository}/referrers/{digest}"))
+            .header("accept", OCI_IMAGE_INDEX);
+        if let Some(artifact_type) = artifact_type {
+            request = request.query(&[("artifactType", artifact_type)]);
+        }
+        let mut response = self.request_with_try_auth(request).await?;
+
+        if response.status().as_u16() == 404 {
+            let tag = referrers_tag(digest);
+            let request = self
+                .registry
+                .client
+                .get(format!("{base_url}/v2/{repository}/manifests/{tag}"))
+                .header("accept", OCI_IMAGE_INDEX);
+            response = self.request_with_try_auth(request).await?;
+            if response.status().as_u16() == 404 {
+                return Ok(Vec::new());
             }
-            Some(ManifestVariant::Manifest(manifest)) => {
-                let manifest_vec = serde_json::to_vec(&manifest).unwrap();
-                let digest = crate::digest::sha256_once(&manifest_vec);
+        }
 
-                let mut list = vec![ManifestDesc {
-                    media_type: descriptor.media_type.to_string(),
-                    size: descriptor.size,
-                    platform: platform.clone(),
-                    digest: descriptor.digest.clone(),
-                    annotations: std::collections::HashMap::new(),
+        if !response.status().is_success() {
+            return Err(ClientError::UnsuccessfulResponse(response));
+        }
+
+        let index: ImageIndex = serde_json::from_slice(&response.bytes().await?)?;
+        // registries are not required to apply the filter, nor is the tag scheme filtered
+        Ok(index
+            .manifests
+            .into_iter()
+            .filter(|entry| {
+                artifact_type.is_none() || entry.artifact_type.as_deref() == artifact_type
+            })
+            .collect())
+    }
+
+    /// List the tags of the repository, following the `Link` header of each page until the last
+    /// one. `page_size` is the number of tags requested per page, registries may return fewer.
+    /// Returns `None` if the repository does not exist.
+    ///
+    /// Links are resolved against the registry and never followed to another origin, as the
+    /// credentials of the registry are sent along. The listing stops at a link to a page already
+    /// visited, or after `MAX_TAG_PAGES` pages.
+    pub async fn list_tags(
+        &mut self,
+        page_size: Option<usize>,
+    ) -> Result<Option<Vec<String>>, ClientError> {
+        let base_url = self.registry.base_url.to_string();
+        let repository = self.repository.to_string();
+        let base = reqwest::Url::parse(&base_url)
+            .map_err(|err| ClientError::InvalidLink(format!("{base_url}: {err}")))?;
+        let mut url = format!("{base_url}/v2/{repository}/tags/list");
+        if let Some(n) = page_size {
+            url = format!("{url}?n={n}");
+        }
+        let mut visited = std::collections::HashSet::new();
+        let mut tags = Vec::new();
+        loop {
+            visited.insert(url.clone());
+            let request = self.registry.client.get(&url);
+            let response = self.request_with_try_auth(request).await?;
+            if response.status().as_u16() == 404 {
+                return Ok(None);
+            } else if !response.status().is_success() {
+                return Err(ClientError::UnsuccessfulResponse(response));
+            }
+            let next = match response.headers().get("link") {
+                None => None,
+                Some(link) => parse_next_link(link.to_str()?),
+            };
+            let page: TagList = serde_json::from_slice(&response.bytes().await?)?;
+            tags.extend(page.tags.unwrap_or_default());
+            let Some(next) = next else {
+                break;
+            };
+            let next = base
+                .join(&next)
+                .map_err(|err| ClientError::InvalidLink(format!("{next}: {err}")))?;
+            if next.origin() != base.origin() {
+                return Err(ClientError::InvalidLink(format!(
+                    "{next} is not on {base_url}"
+                )));
+            }
+            url = next.to_string();
+            if visited.contains(&url) {
+                warn!("stop listing tags, {url} has been visited");
+                break;
+            }
+            if visited.len() >= MAX_TAG_PAGES {
+                warn!("stop listing tags after {MAX_TAG_PAGES} pages");
+                break;
+            }
+        }
+        Ok(Some(tags))
+    }
+
+    /// Delete the manifest `reference` from the repository. Most registries only accept a
+    /// digest, which also removes every tag pointing to the manifest. Returns `false` if there
+    /// is no such manifest.
+    pub async fn delete_manifest(&mut self, reference: &str) -> Result<bool, ClientError> {
+        let base_url = self.registry.base_url.to_string();
+        let repository = self.repository.to_string();
+        let request = self
+            .registry
+            .client
+            .delete(format!("{base_url}/v2/{repository}/manifests/{reference}"));
+        let response = self.request_with_try_auth(request).await?;
+        if response.status().is_success() {
+            Ok(true)
+        } else if response.status().as_u16() == 404 {
+            Ok(false)
+        } else {
+            Err(ClientError::UnsuccessfulResponse(response))
+        }
+    }
+
+    /// Delete the tag `tag` alone, the manifest and its other tags stay in the repository. The
+    /// distribution spec allows registries to refuse this, see `TagDeletion::Unsupported`.
+    pub async fn delete_tag(&mut self, tag: &str) -> Result<TagDeletion, ClientError> {
+        let base_url = self.registry.base_url.to_string();
+        let repository = self.repository.to_string();
+        let request = self
+            .registry
+            .client
+            .delete(format!("{base_url}/v2/{repository}/manifests/{tag}"));
+        let response = self.request_with_try_auth(request).await?;
+        let status = response.status();
+        if status.is_success() {
+            Ok(TagDeletion::Deleted)
+        } else if status.as_u16() == 404 {
+            Ok(TagDeletion::NotFound)
+        } else if status.as_u16() == 400 || status.as_u16() == 405 {
+            Ok(TagDeletion::Unsupported)
+        } else if status.is_client_error() {
+            let body = response.bytes().await?;
+            if has_error_code(&body, "UNSUPPORTED") {
+                Ok(TagDeletion::Unsupported)
+            } else {
+                Err(ClientError::ErrorResponse(
+                    status.as_u16(),
+                    String::from_utf8_lossy(&body).to_string(),
+                ))
+            }
+        } else {
+            Err(ClientError::UnsuccessfulResponse(response))
+        }
+    }
+
+    /// The tags of the repository pointing to the manifest `digest`
+    pub async fn list_tags_of(&mut self, digest: &OciDigest) -> Result<Vec<String>, ClientError> {
+        let mut tags = Vec::new();
+        for tag in self.list_tags(None).await?.unwrap_or_default() {
+            if let Some((tag_digest, _)) = self.query_manifest_with_digest(&tag).await? {
+                if tag_digest == *digest {
+                    tags.push(tag);
+                }
+            }
+        }
+        Ok(tags)
+    }
+
+    /// Delete the blob `digest` from the repository. Returns `false` if there is no such blob.
+    pub async fn delete_blob(&mut self, digest: &OciDigest) -> Result<bool, ClientError> {
+        let base_url = self.registry.base_url.to_string();
+        let repository = self.repository.to_string();
+        let request = self
+            .registry
+            .client
+            .delete(format!("{base_url}/v2/{repository}/blobs/{digest}"));
+        let response = self.request_with_try_auth(request).await?;
+        if response.status().is_success() {
+            Ok(true)
+        } else if response.status().as_u16() == 404 {
+            Ok(false)
+        } else {
+            Err(ClientError::UnsuccessfulResponse(response))
+        }
+    }
+
+    pub async fn merge_manifest_list(
+        &mut self,
+        descriptor: &Descriptor,
+        platform: &Platform,
+        tag: &str,
+    ) -> Result<Descriptor, ClientError> {
+        // see if there an existing manifest with such tag
+        //
+        // if it is a manifest list, check if there's one matching the platform, if so, replace it
+        // and re-upload the list
+        //
+        // if it is a single manifest, and the platform is different, create a manifest list
+        //
+        // if there is none, just register this manifest
+        let manifest_variant = self.query_manifest(tag).await?;
+        let manifest_list = match manifest_variant {
+            Some(ManifestVariant::List(mut manifest_list)) => {
+                let mut manifests = manifest_list
+                    .manifests
+                    .into_iter()
+                    .filter(|m| !m.platform.is_compatible(platform))
+                    .collect::<Vec<_>>();
+                let desc = ManifestDesc {
+                    media_type: descriptor.media_type.to_string(),
+                    size: descriptor.size,
+                    platform: platform.clone(),
+                    digest: descriptor.digest.clone(),
+                    annotations: std::collections::HashMap::new(),
+                    artifact_type: None,
+                };
+                manifests.push(desc);
+                manifest_list.manifests = manifests;
+                manifest_list
+            }
+            Some(ManifestVariant::Manifest(manifest)) => {
+                let manifest_vec = serde_json::to_vec(&manifest).unwrap();
+                let digest = crate::digest::sha256_once(&manifest_vec);
+
+                let mut list = vec![ManifestDesc {
+                    media_type: descriptor.media_type.to_string(),
+                    size: descriptor.size,
+                    platform: platform.clone(),
+                    digest: descriptor.digest.clone(),
+                    annotations: std::collections::HashMap::new(),
                     artifact_type: None,
                 }];
 
@@ -736,6 +1261,122 @@ impl Session {
         }
     }
 
+    /// Attach an artifact made of `blobs`, which should be uploaded already, to the manifest
+    /// `subject`, making it one of the referrers of `subject`. If the registry does not process
+    /// the subject of the artifact, the artifact is added to the index tagged by the referrers
+    /// tag scheme instead.
+    pub async fn attach_artifact(
+        &mut self,
+        subject: &Descriptor,
+        artifact_type: &str,
+        blobs: Vec<Descriptor>,
+        annotations: std::collections::HashMap<String, String>,
+    ) -> Result<Descriptor, ClientError> {
+        let repository = self.repository.to_string();
+        let base_url = self.registry.base_url.to_string();
+        let artifact = ArtifactManifest {
+            media_type: OCI_ARTIFACT.to_string(),
+            artifact_type: artifact_type.to_string(),
+            blobs,
+            subject: subject.clone(),
+            annotations,
+        };
+        let bytes = serde_json::to_vec(&artifact).unwrap();
+        let descriptor = Descriptor {
+            media_type: OCI_ARTIFACT.to_string(),
+            size: bytes.len(),
+            digest: crate::digest::sha256_once(&bytes),
+        };
+        let request = self
+            .registry
+            .client
+            .put(format!(
+                "{base_url}/v2/{repository}/manifests/{}",
+                descriptor.digest
+            ))
+            .header("content-type", OCI_ARTIFACT)
+            .body(bytes);
+        let response = self.request_with_try_auth(request).await?;
+        if !response.status().is_success() {
+            return Err(ClientError::UnsuccessfulResponse(response));
+        }
+        if response.headers().contains_key("oci-subject") {
+            return Ok(descriptor);
+        }
+
+        let tag = referrers_tag(&subject.digest);
+        let request = self
+            .registry
+            .client
+            .get(format!("{base_url}/v2/{repository}/manifests/{tag}"))
+            .header("accept", OCI_IMAGE_INDEX);
+        let response = self.request_with_try_auth(request).await?;
+        let mut index = if response.status().as_u16() == 404 {
+            ImageIndex::default()
+        } else if response.status().is_success() {
+            serde_json::from_slice(&response.bytes().await?)?
+        } else {
+            return Err(ClientError::UnsuccessfulResponse(response));
+        };
+        if !index
+            .manifests
+            .iter()
+            .any(|entry| entry.digest == descriptor.digest)
+        {
+            index.manifests.push(IndexEntry {
+                media_type: descriptor.media_type.clone(),
+                size: descriptor.size,
+                digest: descriptor.digest.clone(),
+                platform: None,
+                artifact_type: Some(artifact.artifact_type),
+                annotations: artifact.annotations,
+            });
+        }
+        let request = self
+            .registry
+            .client
+            .put(format!("{base_url}/v2/{repository}/manifests/{tag}"))
+            .header("content-type", OCI_IMAGE_INDEX)
+            .body(serde_json::to_vec(&index).unwrap());
+        let response = self.request_with_try_auth(request).await?;
+        if !response.status().is_success() {
+            return Err(ClientError::UnsuccessfulResponse(response));
+        }
+        Ok(descriptor)
+    }
+
+    /// Send a request with authentication, retrying it as long as it fails with a transient
+    /// error and the retry policy of the registry allows
+    pub async fn request_with_retry(
+        &mut self,
+        request: RequestBuilder,
+    ) -> Result<Response, ClientError> {
+        let mut retries = 0;
+        loop {
+            let attempt = match request.try_clone() {
+                Some(clone) => clone,
+                None => return Err(ClientError::InvalidRequest(request)),
+            };
+            let result = match self.request_with_try_auth(attempt).await {
+                Ok(response) if is_transient_status(response.status()) => {
+                    Err(ClientError::UnsuccessfulResponse(response))
+                }
+                result => result,
+            };
+            match result {
+                Err(error)
+                    if error.is_transient() && retries < self.registry.retry_policy.max_retries =>
+                {
+                    let delay = self.registry.retry_policy.backoff(retries, &error);
+                    debug!("request failed with transient error: {error}, retrying in {delay:?}");
+                    tokio::time::sleep(delay).await;
+                    retries += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
     /// Download blob from the repository to path at `path`
     ///
     /// # Arguments
@@ -749,35 +1390,246 @@ impl Session {
         path: impl AsRef<Path>,
         replace_on_exists: bool,
     ) -> Result<(), ClientError> {
-        let repository = &self.repository;
+        let path = path.as_ref();
+        if path.exists() {
+            if replace_on_exists {
+                std::fs::remove_file(path)?;
+            } else {
+                return Err(ClientError::IoError(std::io::Error::new(
+                    std::io::ErrorKind::AlreadyExists,
+                    format!("{} already exists", path.display()),
+                )));
+            }
+        }
+        self.download_blob_resumable(None, digest, path).await
+    }
+
+    /// Download blob from the repository to path at `path`, resuming from the content of the
+    /// file if it already exists, for example left behind by an interrupted download.
+    ///
+    /// Connection failures and transient errors are retried according to the retry policy of
+    /// the registry, each retry continues from the bytes already written using a `Range`
+    /// request. If the registry does not support range requests, the download restarts from
+    /// the beginning.
+    ///
+    /// The file is removed if the downloaded content does not match `digest`, such that the
+    /// next attempt starts from scratch.
+    pub async fn download_blob_resumable(
+        &mut self,
+        progress: Option<Sender<DownloadStat>>,
+        digest: &OciDigest,
+        path: impl AsRef<Path>,
+    ) -> Result<(), ClientError> {
+        let path = path.as_ref();
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)?;
+
         let mut hasher = Hasher::new(digest.algorithm());
+        let mut offset = 0;
+        let mut buffer = vec![0u8; 64 * 1024];
+        loop {
+            let bytes = file.read(&mut buffer)?;
+            if bytes == 0 {
+                break;
+            }
+            hasher.update(&buffer[..bytes]);
+            offset += bytes;
+        }
+
+        if let Some(progress) = progress.as_ref() {
+            progress.send_modify(|p| {
+                p.started_at = Some(SystemTime::now());
+                p.resumed_from = offset;
+                p.downloaded = Some(offset);
+            });
+        }
+
+        let started_at = std::time::Instant::now();
+        let mut result = self
+            .download_blob_into(
+                progress.as_ref(),
+                digest,
+                &mut file,
+                &mut hasher,
+                &mut offset,
+            )
+            .await;
+        if let (Err(err), Some(fallback)) = (&result, self.fallback.as_mut()) {
+            // the content is the same wherever it comes from, continue from what is written
+            info!(
+                "cannot download {digest} from {}: {err}, trying {}",
+                self.registry.base_url, fallback.registry.base_url
+            );
+            result = fallback
+                .download_blob_into(
+                    progress.as_ref(),
+                    digest,
+                    &mut file,
+                    &mut hasher,
+                    &mut offset,
+                )
+                .await;
+        }
+        let result = match result {
+            Ok(()) => {
+                let sum = hasher.finalize();
+                if &sum != digest {
+                    _ = std::fs::remove_file(path);
+                    Err(ClientError::DigestMismatched(digest.clone(), sum))
+                } else {
+                    Ok(())
+                }
+            }
+            Err(error) => Err(error),
+        };
+
+        if let Some(progress) = progress.as_ref() {
+            let elapsed = started_at.elapsed();
+            progress.send_modify(|p| {
+                p.completed_at = Some(if result.is_ok() {
+                    Either::Left(elapsed)
+                } else {
+                    Either::Right(elapsed)
+                });
+            });
+        }
+
+        result
+    }
+
+    async fn download_blob_into(
+        &mut self,
+        progress: Option<&Sender<DownloadStat>>,
+        digest: &OciDigest,
+        file: &mut std::fs::File,
+        hasher: &mut Hasher,
+        offset: &mut usize,
+    ) -> Result<(), ClientError> {
+        let mut retries = 0;
+        loop {
+            let result = self
+                .download_blob_attempt(progress, digest, file, hasher, offset)
+                .await;
+            match result {
+                Err(error)
+                    if error.is_transient() && retries < self.registry.retry_policy.max_retries =>
+                {
+                    let delay = self.registry.retry_policy.backoff(retries, &error);
+                    debug!(
+                        "download of {digest} interrupted at {offset}: {error}, retrying in {delay:?}"
+                    );
+                    tokio::time::sleep(delay).await;
+                    retries += 1;
+                    if let Some(progress) = progress {
+                        progress.send_modify(|p| p.retries = retries);
+                    }
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn download_blob_attempt(
+        &mut self,
+        progress: Option<&Sender<DownloadStat>>,
+        digest: &OciDigest,
+        file: &mut std::fs::File,
+        hasher: &mut Hasher,
+        offset: &mut usize,
+    ) -> Result<(), ClientError> {
+        let repository = &self.repository;
         let base_url = &self.registry.base_url;
-        let request = self
+        let mut request = self
             .registry
             .client
             .get(format!("{base_url}/v2/{repository}/blobs/{digest}"));
+        if *offset > 0 {
+            request = request.header("range", format!("bytes={offset}-"));
+        }
         let mut response = self.request_with_try_auth(request).await?;
 
-        let mut file = std::fs::OpenOptions::new()
-            .create_new(replace_on_exists)
-            .write(true)
-            .open(path)?;
+        let total = match response.status().as_u16() {
+            206 => {
+                let content_range = response
+                    .headers()
+                    .get("content-range")
+                    .ok_or_else(|| ClientError::MissingHeader("content-range".to_string()))?
+                    .to_str()?
+                    .to_string();
+                match parse_content_range(&content_range) {
+                    Some((start, total)) if start == *offset => total,
+                    _ => {
+                        // we cannot make use of the partial content, start over
+                        file.set_len(0)?;
+                        *hasher = Hasher::new(digest.algorithm());
+                        *offset = 0;
+                        return Err(ClientError::UnexpectedContentRange(content_range));
+                    }
+                }
+            }
+            // the range starts at or beyond the end of the blob, which means we already have
+            // the whole blob locally, let the digest check decide if it is intact
+            416 if *offset > 0 => return Ok(()),
+            200 => {
+                if *offset > 0 {
+                    debug!("registry ignored range request, restarting download of {digest}");
+                    file.set_len(0)?;
+                    *hasher = Hasher::new(digest.algorithm());
+                    *offset = 0;
+                }
+                response.content_length().map(|len| len as usize)
+            }
+            _ => return Err(ClientError::UnsuccessfulResponse(response)),
+        };
+
+        if let Some(progress) = progress {
+            progress.send_modify(|p| {
+                p.total = total;
+                p.downloaded = Some(*offset);
+            });
+        }
 
         while let Some(bytes) = response.chunk().await? {
             hasher.update(&bytes);
             file.write_all(&bytes)?;
+            *offset += bytes.len();
+            if let Some(progress) = progress {
+                progress.send_modify(|p| p.downloaded = Some(*offset));
+            }
         }
-        let sum = hasher.finalize();
-        if &sum != digest {
-            Err(ClientError::DigestMismatched(digest.clone(), sum))
-        } else {
-            Ok(())
-        }
+
+        Ok(())
     }
 
+    /// Fetch the blob `digest` and deserialize it from JSON, returns `None` if there is no such
+    /// blob
     pub async fn fetch_blob_as<T: DeserializeOwned>(
         &mut self,
         digest: &OciDigest,
+    ) -> Result<Option<T>, ClientError> {
+        let result = self.fetch_blob_as_direct(digest).await;
+        match (&result, self.fallback.as_mut()) {
+            (Ok(Some(_)), _) | (_, None) => result,
+            (_, Some(fallback)) => {
+                match &result {
+                    Ok(None) => info!("{digest} not found on {}", self.registry.base_url),
+                    Err(err) => info!(
+                        "cannot fetch {digest} from {}: {err}",
+                        self.registry.base_url
+                    ),
+                    Ok(Some(_)) => (),
+                }
+                fallback.fetch_blob_as_direct(digest).await
+            }
+        }
+    }
+
+    async fn fetch_blob_as_direct<T: DeserializeOwned>(
+        &mut self,
+        digest: &OciDigest,
     ) -> Result<Option<T>, ClientError> {
         let repository = &self.repository;
         let mut hasher = Hasher::new(digest.algorithm());
@@ -786,7 +1638,7 @@ impl Session {
             .registry
             .client
             .get(format!("{base_url}/v2/{repository}/blobs/{digest}"));
-        let mut response = self.request_with_try_auth(request).await?;
+        let mut response = self.request_with_retry(request).await?;
         if !response.status().is_success() {
             if response.status().as_u16() == 404 {
                 Ok(None)
@@ -809,19 +1661,35 @@ impl Session {
     }
 
     pub async fn fetch_blob(&mut self, digest: &OciDigest) -> Result<Response, ClientError> {
+        self.fetch_blob_from(digest, 0).await
+    }
+
+    /// Fetch a blob starting from byte `offset`, for callers streaming the blob themselves to
+    /// resume after an interruption. The response is `206 Partial Content` if the registry
+    /// honors the range, or `200 OK` with the whole blob if it does not.
+    pub async fn fetch_blob_from(
+        &mut self,
+        digest: &OciDigest,
+        offset: usize,
+    ) -> Result<Response, ClientError> {
         let repository = &self.repository;
         let base_url = &self.registry.base_url;
-        let request = self
+        let mut request = self
             .registry
             .client
             .get(format!("{base_url}/v2/{repository}/blobs/{digest}"));
-        self.request_with_try_auth(request).await
+        if offset > 0 {
+            request = request.header("range", format!("bytes={offset}-"));
+        }
+        self.request_with_retry(request).await
     }
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
+    use std::collections::HashMap;
+    use std::sync::Arc;
 
     #[test]
     fn test_parse_comma_separated_quoted_string_mulitple_items() {
@@ -859,4 +1727,1025 @@ mod tests {
         let vec = parse_comma_separated_quoted_kv_str(input);
         assert_eq!(vec, vec![]);
     }
+
+    pub(crate) mod stand_in {
+        use std::collections::{HashMap, VecDeque};
+        use std::sync::{Arc, Mutex};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::TcpListener;
+
+        pub struct Request {
+            pub method: String,
+            pub target: String,
+            pub headers: Vec<(String, String)>,
+            pub body: Vec<u8>,
+        }
+
+        impl Request {
+            pub fn header(&self, name: &str) -> Option<&str> {
+                self.headers
+                    .iter()
+                    .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                    .map(|(_, value)| value.as_str())
+            }
+        }
+
+        pub struct Reply {
+            /// Status code and reason, for example `200 OK`
+            pub status: String,
+            pub headers: Vec<(String, String)>,
+            pub body: Vec<u8>,
+            /// Advertise the full body but close the connection after this many bytes
+            pub truncate: Option<usize>,
+        }
+
+        impl Reply {
+            pub fn new(status: &str, body: impl Into<Vec<u8>>) -> Reply {
+                Reply {
+                    status: status.to_string(),
+                    headers: Vec::new(),
+                    body: body.into(),
+                    truncate: None,
+                }
+            }
+
+            pub fn header(mut self, key: &str, value: impl Into<String>) -> Reply {
+                self.headers.push((key.to_string(), value.into()));
+                self
+            }
+        }
+
+        /// Serve HTTP/1.1 requests with `handler` in the background, one request per connection,
+        /// returns the base url of the server
+        pub async fn serve_fn(handler: impl Fn(&Request) -> Reply + Send + 'static) -> String {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let base_url = format!("http://{}", listener.local_addr().unwrap());
+            tokio::spawn(async move {
+                loop {
+                    let (mut stream, _) = listener.accept().await.unwrap();
+                    let mut request = Vec::new();
+                    let mut buf = [0u8; 1024];
+                    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                        let n = stream.read(&mut buf).await.unwrap();
+                        if n == 0 {
+                            break;
+                        }
+                        request.extend_from_slice(&buf[..n]);
+                    }
+                    let head_len = request
+                        .windows(4)
+                        .position(|w| w == b"\r\n\r\n")
+                        .map(|pos| pos + 4)
+                        .unwrap_or(request.len());
+                    let mut body = request.split_off(head_len);
+                    let head = String::from_utf8_lossy(&request).to_string();
+                    let mut lines = head.lines();
+                    let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+                    let mut request = Request {
+                        method: request_line.next().unwrap_or_default().to_string(),
+                        target: request_line.next().unwrap_or_default().to_string(),
+                        headers: lines
+                            .filter_map(|line| {
+                                let (key, value) = line.split_once(':')?;
+                                Some((key.trim().to_string(), value.trim().to_string()))
+                            })
+                            .collect(),
+                        body: Vec::new(),
+                    };
+                    let content_length = request
+                        .header("content-length")
+                        .and_then(|len| len.parse::<usize>().ok())
+                        .unwrap_or_default();
+                    while body.len() < content_length {
+                        let n = stream.read(&mut buf).await.unwrap();
+                        if n == 0 {
+                            break;
+                        }
+                        body.extend_from_slice(&buf[..n]);
+                    }
+                    request.body = body;
+
+                    let reply = handler(&request);
+                    let mut head = format!("HTTP/1.1 {}\r\n", reply.status);
+                    for (key, value) in reply.headers.iter() {
+                        head.push_str(&format!("{key}: {value}\r\n"));
+                    }
+                    head.push_str(&format!(
+                        "content-length: {}\r\nconnection: close\r\n\r\n",
+                        reply.body.len()
+                    ));
+                    _ = stream.write_all(head.as_bytes()).await;
+                    let body = match reply.truncate {
+                        Some(n) => &reply.body[..n],
+                        None => &reply.body[..],
+                    };
+                    _ = stream.write_all(body).await;
+                    _ = stream.shutdown().await;
+                }
+            });
+            base_url
+        }
+
+        pub enum Fault {
+            /// Respond with the status code and no body
+            Status(u16),
+            /// Advertise the full body but close the connection after this many bytes
+            Truncate(usize),
+            /// Respond with the whole blob regardless of the range requested
+            IgnoreRange,
+        }
+
+        /// A stand-in registry serving a single blob, with faults injected into the responses
+        /// in order, one per request
+        #[derive(Clone)]
+        pub struct StandIn {
+            pub base_url: String,
+            /// The `Range` header of each request received
+            pub ranges: Arc<Mutex<Vec<Option<String>>>>,
+        }
+
+        impl StandIn {
+            pub async fn serve(blob: Vec<u8>, faults: Vec<Fault>) -> StandIn {
+                let ranges = Arc::new(Mutex::new(Vec::new()));
+                let faults = Mutex::new(VecDeque::from(faults));
+                let seen = ranges.clone();
+                let base_url = serve_fn(move |request| {
+                    let range = request.header("range").map(|s| s.to_string());
+                    seen.lock().unwrap().push(range.clone());
+                    let fault = faults.lock().unwrap().pop_front();
+
+                    if let Some(Fault::Status(code)) = fault {
+                        return Reply::new(&format!("{code} Fault"), Vec::new());
+                    }
+
+                    let start = match (&fault, &range) {
+                        (Some(Fault::IgnoreRange), _) | (_, None) => None,
+                        (_, Some(range)) => range
+                            .strip_prefix("bytes=")
+                            .and_then(|r| r.strip_suffix('-'))
+                            .and_then(|r| r.parse::<usize>().ok()),
+                    };
+
+                    let mut reply = match start {
+                        None => Reply::new("200 OK", blob.clone()),
+                        Some(start) if start >= blob.len() => {
+                            Reply::new("416 Range Not Satisfiable", Vec::new())
+                                .header("content-range", format!("bytes */{}", blob.len()))
+                        }
+                        Some(start) => Reply::new("206 Partial Content", &blob[start..]).header(
+                            "content-range",
+                            format!("bytes {start}-{}/{}", blob.len() - 1, blob.len()),
+                        ),
+                    };
+                    if let Some(Fault::Truncate(n)) = fault {
+                        reply.truncate = Some(n);
+                    }
+                    reply
+                })
+                .await;
+                StandIn { base_url, ranges }
+            }
+        }
+
+        /// Content type and content of manifests by reference
+        type Manifests = HashMap<String, (String, Vec<u8>)>;
+
+        /// A stand-in registry keeping the blobs and manifests pushed to it in memory
+        #[derive(Clone, Default)]
+        pub struct MemoryRegistry {
+            /// Whether the registry supports the referrers API
+            pub referrers_api: bool,
+            /// Whether the registry deletes tags, instead of manifests by digest only
+            pub tag_deletion: bool,
+            pub blobs: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+            /// Content type and content of the manifests by tag and by digest
+            pub manifests: Arc<Mutex<Manifests>>,
+            /// Content of the uploads in progress, by upload id
+            pub uploads: Arc<Mutex<Vec<Vec<u8>>>>,
+        }
+
+        impl MemoryRegistry {
+            pub async fn serve(&self) -> String {
+                let this = self.clone();
+                serve_fn(move |request| {
+                    let (path, query) = request
+                        .target
+                        .split_once('?')
+                        .unwrap_or((request.target.as_str(), ""));
+                    let path = path.strip_prefix("/v2/").unwrap_or_default();
+                    let Some((repository, kind, reference)) = [
+                        "/blobs/uploads/",
+                        "/blobs/",
+                        "/manifests/",
+                        "/referrers/",
+                        "/tags/",
+                    ]
+                    .iter()
+                    .find_map(|kind| {
+                        let (repository, reference) = path.split_once(kind)?;
+                        Some((repository, kind.trim_matches('/'), reference))
+                    }) else {
+                        return Reply::new("404 Not Found", Vec::new());
+                    };
+
+                    match (request.method.as_str(), kind) {
+                        ("POST", "blobs/uploads") => {
+                            let mut uploads = this.uploads.lock().unwrap();
+                            uploads.push(Vec::new());
+                            let location = format!(
+                                "/v2/{repository}/blobs/uploads/{}?state=0",
+                                uploads.len() - 1
+                            );
+                            Reply::new("202 Accepted", Vec::new()).header("location", location)
+                        }
+                        ("PATCH", "blobs/uploads") => {
+                            let id = reference.parse::<usize>().unwrap();
+                            this.uploads.lock().unwrap()[id].extend_from_slice(&request.body);
+                            Reply::new("202 Accepted", Vec::new()).header(
+                                "location",
+                                format!("/v2/{repository}/blobs/uploads/{id}?state=1"),
+                            )
+                        }
+                        ("PUT", "blobs/uploads") => {
+                            let id = reference.parse::<usize>().unwrap();
+                            let digest = query
+                                .split('&')
+                                .find_map(|kv| kv.strip_prefix("digest="))
+                                .unwrap();
+                            let blob = std::mem::take(&mut this.uploads.lock().unwrap()[id]);
+                            this.blobs.lock().unwrap().insert(digest.to_string(), blob);
+                            Reply::new("201 Created", Vec::new())
+                        }
+                        ("GET" | "HEAD", "blobs") => {
+                            match this.blobs.lock().unwrap().get(reference) {
+                                Some(blob) => Reply::new("200 OK", blob.clone()),
+                                None => Reply::new("404 Not Found", Vec::new()),
+                            }
+                        }
+                        ("DELETE", "blobs") => match this.blobs.lock().unwrap().remove(reference) {
+                            Some(_) => Reply::new("202 Accepted", Vec::new()),
+                            None => Reply::new("404 Not Found", Vec::new()),
+                        },
+                        ("PUT", "manifests") => {
+                            let content_type = request.header("content-type").unwrap_or_default();
+                            let digest = crate::digest::sha256_once(&request.body).to_string();
+                            let mut manifests = this.manifests.lock().unwrap();
+                            let entry = (content_type.to_string(), request.body.clone());
+                            manifests.insert(reference.to_string(), entry.clone());
+                            manifests.insert(digest.clone(), entry);
+                            let subject =
+                                serde_json::from_slice::<serde_json::Value>(&request.body)
+                                    .ok()
+                                    .and_then(|manifest| {
+                                        manifest["subject"]["digest"]
+                                            .as_str()
+                                            .map(|s| s.to_string())
+                                    });
+                            let reply = Reply::new("201 Created", Vec::new())
+                                .header("docker-content-digest", digest);
+                            match subject {
+                                Some(subject) if this.referrers_api => {
+                                    reply.header("oci-subject", subject)
+                                }
+                                _ => reply,
+                            }
+                        }
+                        ("GET", "manifests") => match this.manifests.lock().unwrap().get(reference)
+                        {
+                            Some((content_type, manifest)) => {
+                                Reply::new("200 OK", manifest.clone())
+                                    .header("content-type", content_type.clone())
+                            }
+                            None => Reply::new("404 Not Found", Vec::new()),
+                        },
+                        ("DELETE", "manifests") if reference.starts_with("sha256:") => {
+                            // deleting by digest also removes the tags of the manifest
+                            let mut manifests = this.manifests.lock().unwrap();
+                            match manifests.get(reference).cloned() {
+                                Some(manifest) => {
+                                    manifests.retain(|_, value| *value != manifest);
+                                    Reply::new("202 Accepted", Vec::new())
+                                }
+                                None => Reply::new("404 Not Found", Vec::new()),
+                            }
+                        }
+                        ("DELETE", "manifests") if this.tag_deletion => {
+                            match this.manifests.lock().unwrap().remove(reference) {
+                                Some(_) => Reply::new("202 Accepted", Vec::new()),
+                                None => Reply::new("404 Not Found", Vec::new()),
+                            }
+                        }
+                        ("DELETE", "manifests") => Reply::new("405 Method Not Allowed", Vec::new()),
+                        ("GET", "tags") => {
+                            let param = |key: &str| {
+                                query.split('&').find_map(|kv| {
+                                    kv.strip_prefix(key)?.strip_prefix('=').map(String::from)
+                                })
+                            };
+                            let n = param("n").and_then(|n| n.parse::<usize>().ok());
+                            let last = param("last").unwrap_or_default();
+                            let mut tags = this
+                                .manifests
+                                .lock()
+                                .unwrap()
+                                .keys()
+                                .filter(|key| !key.starts_with("sha256:"))
+                                .filter(|key| key.as_str() > last.as_str())
+                                .cloned()
+                                .collect::<Vec<_>>();
+                            tags.sort();
+                            let mut reply_tags = tags.clone();
+                            let mut link = None;
+                            if let Some(n) = n.filter(|n| *n < tags.len()) {
+                                reply_tags.truncate(n);
+                                link = Some(format!(
+                                    "</v2/{repository}/tags/list?n={n}&last={}>; rel=\"next\"",
+                                    reply_tags[n - 1]
+                                ));
+                            }
+                            let body = serde_json::json!({
+                                "name": repository,
+                                "tags": reply_tags,
+                            });
+                            let reply = Reply::new("200 OK", body.to_string());
+                            match link {
+                                Some(link) => reply.header("link", link),
+                                None => reply,
+                            }
+                        }
+                        ("GET", "referrers") if this.referrers_api => {
+                            let manifests = this
+                                .manifests
+                                .lock()
+                                .unwrap()
+                                .iter()
+                                .filter(|(key, _)| key.starts_with("sha256:"))
+                                .filter_map(|(digest, (content_type, manifest))| {
+                                    let value: serde_json::Value =
+                                        serde_json::from_slice(manifest).ok()?;
+                                    (value["subject"]["digest"].as_str()? == reference).then(|| {
+                                        serde_json::json!({
+                                            "mediaType": content_type,
+                                            "size": manifest.len(),
+                                            "digest": digest,
+                                            "artifactType": value["artifactType"],
+                                        })
+                                    })
+                                })
+                                .collect::<Vec<_>>();
+                            let index =
+                                serde_json::json!({ "schemaVersion": 2, "manifests": manifests });
+                            Reply::new("200 OK", serde_json::to_vec(&index).unwrap())
+                                .header("content-type", super::OCI_IMAGE_INDEX)
+                        }
+                        _ => Reply::new("404 Not Found", Vec::new()),
+                    }
+                })
+                .await
+            }
+        }
+    }
+
+    fn test_blob() -> (Vec<u8>, OciDigest) {
+        let blob = (0..256 * 1024).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let digest = crate::digest::sha256_once(&blob);
+        (blob, digest)
+    }
+
+    fn test_session(stand_in: &stand_in::StandIn, max_retries: usize) -> Session {
+        Registry::new(stand_in.base_url.clone(), None)
+            .with_retry_policy(RetryPolicy {
+                max_retries,
+                initial_backoff: Duration::from_millis(1),
+                max_backoff: Duration::from_millis(10),
+            })
+            .new_session("test/blob".to_string())
+    }
+
+    #[test]
+    fn test_parse_content_range() {
+        assert_eq!(
+            parse_content_range("bytes 100-199/200"),
+            Some((100, Some(200)))
+        );
+        assert_eq!(parse_content_range("bytes 0-9/*"), Some((0, None)));
+        assert_eq!(parse_content_range("bytes */200"), None);
+        assert_eq!(parse_content_range("items 0-9/10"), None);
+    }
+
+    #[tokio::test]
+    async fn test_download_blob_resume_after_dropped_connection() {
+        use stand_in::Fault;
+        let (blob, digest) = test_blob();
+        let stand_in = stand_in::StandIn::serve(
+            blob.clone(),
+            vec![
+                Fault::Truncate(1000),
+                Fault::Status(503),
+                Fault::Truncate(5000),
+            ],
+        )
+        .await;
+        let mut session = test_session(&stand_in, 5);
+        let dir = std::env::temp_dir().join(format!("oci-util-dl-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("resume-after-drop");
+        _ = std::fs::remove_file(&path);
+
+        let (tx, rx) = tokio::sync::watch::channel(DownloadStat::default());
+        session
+            .download_blob_resumable(Some(tx), &digest, &path)
+            .await
+            .unwrap();
+
+        assert_eq!(std::fs::read(&path).unwrap(), blob);
+        assert_eq!(
+            *stand_in.ranges.lock().unwrap(),
+            vec![
+                None,
+                Some("bytes=1000-".to_string()),
+                Some("bytes=1000-".to_string()),
+                Some("bytes=6000-".to_string()),
+            ]
+        );
+        let stat = rx.borrow();
+        assert_eq!(stat.downloaded, Some(blob.len()));
+        assert_eq!(stat.total, Some(blob.len()));
+        assert_eq!(stat.retries, 3);
+        assert!(matches!(stat.progress(), DownloadProgress::Done(_)));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_download_blob_resume_from_partial_file() {
+        use stand_in::Fault;
+        let (blob, digest) = test_blob();
+        let dir = std::env::temp_dir().join(format!("oci-util-dl-partial-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // the registry honors the range request
+        let stand_in = stand_in::StandIn::serve(blob.clone(), Vec::new()).await;
+        let path = dir.join("partial");
+        std::fs::write(&path, &blob[..100_000]).unwrap();
+        let (tx, rx) = tokio::sync::watch::channel(DownloadStat::default());
+        test_session(&stand_in, 0)
+            .download_blob_resumable(Some(tx), &digest, &path)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), blob);
+        assert_eq!(
+            *stand_in.ranges.lock().unwrap(),
+            vec![Some("bytes=100000-".to_string())]
+        );
+        assert_eq!(rx.borrow().resumed_from, 100_000);
+
+        // the file is already complete
+        let stand_in = stand_in::StandIn::serve(blob.clone(), Vec::new()).await;
+        test_session(&stand_in, 0)
+            .download_blob_resumable(None, &digest, &path)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), blob);
+
+        // the registry does not support range requests
+        let stand_in = stand_in::StandIn::serve(blob.clone(), vec![Fault::IgnoreRange]).await;
+        std::fs::write(&path, &blob[..100_000]).unwrap();
+        test_session(&stand_in, 0)
+            .download_blob_resumable(None, &digest, &path)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), blob);
+
+        // the partial file is corrupted, it should be removed so the next attempt starts over
+        let stand_in = stand_in::StandIn::serve(blob.clone(), Vec::new()).await;
+        std::fs::write(&path, vec![0xffu8; 100_000]).unwrap();
+        let result = test_session(&stand_in, 0)
+            .download_blob_resumable(None, &digest, &path)
+            .await;
+        assert!(matches!(result, Err(ClientError::DigestMismatched(_, _))));
+        assert!(!path.exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_download_blob_gives_up_after_max_retries() {
+        use stand_in::Fault;
+        let (blob, digest) = test_blob();
+        let stand_in = stand_in::StandIn::serve(
+            blob.clone(),
+            vec![Fault::Status(503), Fault::Status(502), Fault::Status(500)],
+        )
+        .await;
+        let dir = std::env::temp_dir().join(format!("oci-util-dl-retry-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("blob");
+
+        let result = test_session(&stand_in, 2)
+            .download_blob(&digest, &path, true)
+            .await;
+        assert!(matches!(result, Err(ClientError::UnsuccessfulResponse(_))));
+        assert_eq!(stand_in.ranges.lock().unwrap().len(), 3);
+
+        // fetch_blob retries the same way and succeeds once the registry recovers
+        let stand_in =
+            stand_in::StandIn::serve(blob.clone(), vec![Fault::Status(503), Fault::Status(429)])
+                .await;
+        let response = test_session(&stand_in, 2)
+            .fetch_blob(&digest)
+            .await
+            .unwrap();
+        assert_eq!(response.status().as_u16(), 200);
+        assert_eq!(response.bytes().await.unwrap().to_vec(), blob);
+
+        // client errors are not retried
+        let stand_in = stand_in::StandIn::serve(blob, vec![Fault::Status(404)]).await;
+        let result = test_session(&stand_in, 2)
+            .download_blob(&digest, &path, true)
+            .await;
+        assert!(matches!(result, Err(ClientError::UnsuccessfulResponse(_))));
+        assert_eq!(stand_in.ranges.lock().unwrap().len(), 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// A stand-in registry protected by a token server, tokens expire after `expires_in` seconds
+    #[derive(Clone, Default)]
+    struct TokenStandIn {
+        /// scopes granted by each token issued
+        issued: Arc<std::sync::Mutex<HashMap<String, Vec<String>>>>,
+        /// target of each token request, and whether it carried credentials
+        token_requests: Arc<std::sync::Mutex<Vec<(String, bool)>>>,
+        challenges: Arc<std::sync::Mutex<usize>>,
+    }
+
+    impl TokenStandIn {
+        async fn serve(&self, expires_in: usize) -> String {
+            let this = self.clone();
+            stand_in::serve_fn(move |request| {
+                let host = request.header("host").unwrap_or_default().to_string();
+                if let Some(query) = request.target.strip_prefix("/token?") {
+                    this.token_requests.lock().unwrap().push((
+                        request.target.clone(),
+                        request.header("authorization").is_some(),
+                    ));
+                    let scopes = query
+                        .split('&')
+                        .filter_map(|kv| kv.strip_prefix("scope="))
+                        .map(|s| s.to_string())
+                        .collect::<Vec<_>>();
+                    let mut issued = this.issued.lock().unwrap();
+                    let token = format!("token-{}", issued.len());
+                    issued.insert(token.clone(), scopes);
+                    let body = format!("{{\"token\":\"{token}\",\"expires_in\":{expires_in}}}");
+                    return stand_in::Reply::new("200 OK", body)
+                        .header("content-type", "application/json");
+                }
+
+                let path = request.target.split('?').next().unwrap_or_default();
+                let repository = path
+                    .strip_prefix("/v2/")
+                    .and_then(|p| p.split_once("/blobs/"))
+                    .map(|(repository, _)| repository.to_string())
+                    .unwrap_or_default();
+                let action = if request.method == "POST" { "push" } else { "pull" };
+                let mut required = vec![(repository.clone(), action)];
+                if let Some((_, from)) = request.target.split_once("&from=") {
+                    required.push((from.to_string(), "pull"));
+                }
+
+                let granted = request
+                    .header("authorization")
+                    .and_then(|value| value.strip_prefix("Bearer "))
+                    .and_then(|token| this.issued.lock().unwrap().get(token).cloned())
+                    .unwrap_or_default();
+                let authorized = required.iter().all(|(repository, action)| {
+                    granted.iter().any(|scope| {
+                        scope
+                            .strip_prefix(&format!("repository:{repository}:"))
+                            .is_some_and(|actions| actions.split(',').any(|a| a == *action))
+                    })
+                });
+
+                if !authorized {
+                    *this.challenges.lock().unwrap() += 1;
+                    let actions = if action == "push" { "pull,push" } else { "pull" };
+                    return stand_in::Reply::new("401 Unauthorized", Vec::new()).header(
+                        "www-authenticate",
+                        format!(
+                            "Bearer realm=\"http://{host}/token\",service=\"stand-in\",scope=\"repository:{repository}:{actions}\""
+                        ),
+                    );
+                }
+
+                if request.method == "POST" {
+                    stand_in::Reply::new("201 Created", Vec::new())
+                } else {
+                    stand_in::Reply::new("200 OK", Vec::new())
+                }
+            })
+            .await
+        }
+
+        fn token_requests(&self) -> Vec<(String, bool)> {
+            self.token_requests.lock().unwrap().clone()
+        }
+
+        fn challenges(&self) -> usize {
+            *self.challenges.lock().unwrap()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bearer_token_shared_and_refreshed() {
+        let stand_in = TokenStandIn::default();
+        let base_url = stand_in.serve(1).await;
+        let registry = Registry::new(base_url, None);
+        let (_, digest) = test_blob();
+
+        let mut session = registry.new_session("a/b".to_string());
+        assert!(session.exists_digest(&digest).await.unwrap());
+        assert_eq!(stand_in.challenges(), 1);
+        assert_eq!(stand_in.token_requests().len(), 1);
+
+        // another session on the same repository picks up the cached token once it learns
+        // about the token server
+        let mut other = registry.new_session("a/b".to_string());
+        assert!(other.exists_digest(&digest).await.unwrap());
+        assert!(session.exists_digest(&digest).await.unwrap());
+        assert_eq!(stand_in.challenges(), 2);
+        assert_eq!(stand_in.token_requests().len(), 1);
+
+        // the token is refreshed before it expires, without being rejected by the registry
+        tokio::time::sleep(Duration::from_millis(900)).await;
+        assert!(session.exists_digest(&digest).await.unwrap());
+        assert_eq!(stand_in.challenges(), 2);
+        assert_eq!(stand_in.token_requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_anonymous_multiple_scopes_for_mount() {
+        let stand_in = TokenStandIn::default();
+        let base_url = stand_in.serve(300).await;
+        let registry = Registry::new(base_url, None);
+        let (blob, digest) = test_blob();
+
+        let mut session = registry.new_session("target/repo".to_string());
+        let mounted = session
+            .upload_content_known_digest(
+                None,
+                &digest,
+                "application/octet-stream".to_string(),
+                true,
+                Some("source/repo".to_string()),
+                &blob[..],
+            )
+            .await
+            .unwrap();
+        assert!(mounted.is_none());
+
+        let requests = stand_in.token_requests();
+        let (target, authenticated) = requests.last().unwrap();
+        assert!(!authenticated);
+        assert!(target.contains("scope=repository:source/repo:pull"));
+        assert!(target.contains("scope=repository:target/repo:pull,push"));
+
+        // a session already holding a token widens its scopes before mounting
+        let mut session = registry.new_session("other/repo".to_string());
+        assert!(session.exists_digest(&digest).await.unwrap());
+        let challenges = stand_in.challenges();
+        let mounted = session
+            .upload_content_known_digest(
+                None,
+                &digest,
+                "application/octet-stream".to_string(),
+                true,
+                Some("source/repo".to_string()),
+                &blob[..],
+            )
+            .await
+            .unwrap();
+        assert!(mounted.is_none());
+        let (target, _) = stand_in.token_requests().last().unwrap().clone();
+        assert!(target.contains("scope=repository:source/repo:pull"));
+        assert!(target.contains("scope=repository:other/repo:pull"));
+        // the registry still asks for push access to the target repository
+        assert_eq!(stand_in.challenges(), challenges + 1);
+    }
+
+    #[tokio::test]
+    async fn test_list_tags_foreign_link() {
+        let contacted = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let flag = contacted.clone();
+        let foreign_url = stand_in::serve_fn(move |_| {
+            flag.store(true, std::sync::atomic::Ordering::SeqCst);
+            stand_in::Reply::new("200 OK", r#"{"name":"test/image","tags":["b"]}"#)
+        })
+        .await;
+        let link = format!(r#"<{foreign_url}/v2/test/image/tags/list?last=a>; rel="next""#);
+        let base_url = stand_in::serve_fn(move |_| {
+            stand_in::Reply::new("200 OK", r#"{"name":"test/image","tags":["a"]}"#)
+                .header("link", link.clone())
+        })
+        .await;
+        let mut session = Registry::new(base_url, None).new_session("test/image".to_string());
+
+        let result = session.list_tags(None).await;
+        assert!(matches!(result, Err(ClientError::InvalidLink(_))));
+        assert!(!contacted.load(std::sync::atomic::Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_list_tags_link_loop() {
+        let requests = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = requests.clone();
+        // every page links to the second page, relative to the current one
+        let base_url = stand_in::serve_fn(move |_| {
+            counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            stand_in::Reply::new("200 OK", r#"{"name":"test/image","tags":["a"]}"#)
+                .header("link", r#"<list?n=1&last=a>; rel="next""#)
+        })
+        .await;
+        let mut session = Registry::new(base_url, None).new_session("test/image".to_string());
+
+        let tags = session.list_tags(Some(1)).await.unwrap().unwrap();
+        assert_eq!(tags, vec!["a", "a"]);
+        assert_eq!(requests.load(std::sync::atomic::Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_query_manifest_with_digest() {
+        let manifest = serde_json::to_vec(&ImageManifest {
+            schema_version: 2,
+            media_type: OCI_MANIFEST.to_string(),
+            config: Descriptor {
+                media_type: "application/vnd.oci.image.config.v1+json".to_string(),
+                size: 2,
+                digest: crate::digest::sha256_once(b"{}"),
+            },
+            layers: Vec::new(),
+        })
+        .unwrap();
+        let digest = crate::digest::sha256_once(&manifest);
+        let served = manifest.clone();
+        let base_url = stand_in::serve_fn(move |_| {
+            stand_in::Reply::new("200 OK", served.clone()).header("content-type", OCI_MANIFEST)
+        })
+        .await;
+        let mut session = Registry::new(base_url, None).new_session("test/image".to_string());
+
+        let (by_tag, _) = session
+            .query_manifest_with_digest("latest")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(by_tag, digest);
+        let (by_digest, _) = session
+            .query_manifest_with_digest(digest.as_str())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(by_digest, digest);
+
+        // the registry served a manifest other than the one requested
+        let other = crate::digest::sha256_once(b"other");
+        let result = session.query_manifest_with_digest(other.as_str()).await;
+        assert!(
+            matches!(result, Err(ClientError::DigestMismatched(expected, _)) if expected == other)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_query_manifest_with_mirrors() {
+        let manifest = serde_json::to_vec(&ImageManifest {
+            schema_version: 2,
+            media_type: OCI_MANIFEST.to_string(),
+            config: Descriptor {
+                media_type: "application/vnd.oci.image.config.v1+json".to_string(),
+                size: 2,
+                digest: crate::digest::sha256_once(b"{}"),
+            },
+            layers: Vec::new(),
+        })
+        .unwrap();
+        let digest = crate::digest::sha256_once(&manifest);
+
+        // a mirror that cannot be reached
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let unreachable = Registry::new(format!("http://{}", listener.local_addr().unwrap()), None);
+        drop(listener);
+
+        let mirror = stand_in::MemoryRegistry::default();
+        let mirror_url = mirror.serve().await;
+        let upstream = stand_in::MemoryRegistry::default();
+        let upstream_url = upstream.serve().await;
+        upstream.manifests.lock().unwrap().insert(
+            "latest".to_string(),
+            (OCI_MANIFEST.to_string(), manifest.clone()),
+        );
+        let resolve = |mirrors: Vec<Registry>| {
+            let upstream = Registry::new(upstream_url.clone(), None);
+            async move {
+                query_manifest_traced_with_mirrors(
+                    &mirrors,
+                    &upstream,
+                    "test/image",
+                    "latest",
+                    |_| None,
+                )
+                .await
+            }
+        };
+        let mirrors = || vec![unreachable.clone(), Registry::new(mirror_url.clone(), None)];
+
+        // falls back to upstream if the mirrors do not have the manifest
+        let (session, found) = resolve(mirrors()).await.unwrap();
+        assert_eq!(session.registry().base_url, upstream_url);
+        assert_eq!(found.unwrap().0, digest);
+
+        // the first mirror having the manifest is used
+        mirror.manifests.lock().unwrap().insert(
+            "latest".to_string(),
+            (OCI_MANIFEST.to_string(), manifest.clone()),
+        );
+        let (session, found) = resolve(mirrors()).await.unwrap();
+        assert_eq!(session.registry().base_url, mirror_url);
+        assert_eq!(found.unwrap().0, digest);
+
+        // the blobs missing on the mirror are fetched from upstream
+        let config = crate::digest::sha256_once(b"{}");
+        upstream
+            .blobs
+            .lock()
+            .unwrap()
+            .insert(config.to_string(), b"{}".to_vec());
+        let mut session = session;
+        let fetched: Option<serde_json::Value> = session.fetch_blob_as(&config).await.unwrap();
+        assert_eq!(fetched, Some(serde_json::json!({})));
+        let path = std::env::temp_dir().join(format!("oci-util-mirror-{}", std::process::id()));
+        _ = std::fs::remove_file(&path);
+        session
+            .download_blob_resumable(None, &config, &path)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"{}");
+        _ = std::fs::remove_file(&path);
+
+        // a mirror refusing the request is skipped as well
+        let refusing =
+            stand_in::serve_fn(|_| stand_in::Reply::new("403 Forbidden", Vec::new())).await;
+        let (session, found) = resolve(vec![Registry::new(refusing, None)]).await.unwrap();
+        assert_eq!(session.registry().base_url, upstream_url);
+        assert_eq!(found.unwrap().0, digest);
+
+        // nowhere to be found
+        let (session, found) = query_manifest_traced_with_mirrors(
+            &mirrors(),
+            &Registry::new(upstream_url.clone(), None),
+            "test/image",
+            "other",
+            |_| None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(session.registry().base_url, upstream_url);
+        assert!(found.is_none());
+    }
+
+    #[test]
+    fn test_parse_next_link() {
+        assert_eq!(
+            parse_next_link(r#"</v2/name/tags/list?n=2&last=b>; rel="next""#),
+            Some("/v2/name/tags/list?n=2&last=b".to_string())
+        );
+        assert_eq!(
+            parse_next_link(
+                r#"<https://example.com/prev>; rel="prev", <https://example.com/next>; rel=next"#
+            ),
+            Some("https://example.com/next".to_string())
+        );
+        assert_eq!(parse_next_link(r#"</v2/name/tags/list>; rel="prev""#), None);
+        assert_eq!(parse_next_link(""), None);
+    }
+
+    #[tokio::test]
+    async fn test_delete_tag() {
+        let registry = stand_in::MemoryRegistry {
+            tag_deletion: true,
+            ..Default::default()
+        };
+        let manifest = |config: &[u8]| {
+            serde_json::to_vec(&ImageManifest {
+                schema_version: 2,
+                media_type: OCI_MANIFEST.to_string(),
+                config: Descriptor {
+                    media_type: "application/vnd.oci.image.config.v1+json".to_string(),
+                    size: config.len(),
+                    digest: crate::digest::sha256_once(config),
+                },
+                layers: Vec::new(),
+            })
+            .unwrap()
+        };
+        let shared = manifest(b"{}");
+        let other = manifest(b"{ }");
+        let digest = crate::digest::sha256_once(&shared);
+        {
+            let mut manifests = registry.manifests.lock().unwrap();
+            for (reference, content) in [("a", &shared), ("b", &shared), ("c", &other)] {
+                manifests.insert(
+                    reference.to_string(),
+                    (OCI_MANIFEST.to_string(), content.clone()),
+                );
+            }
+            manifests.insert(digest.to_string(), (OCI_MANIFEST.to_string(), shared));
+        }
+        let base_url = registry.serve().await;
+        let mut session = Registry::new(base_url, None).new_session("test/image".to_string());
+
+        assert_eq!(session.list_tags_of(&digest).await.unwrap(), vec!["a", "b"]);
+        assert_eq!(session.delete_tag("a").await.unwrap(), TagDeletion::Deleted);
+        assert_eq!(
+            session.delete_tag("a").await.unwrap(),
+            TagDeletion::NotFound
+        );
+        // the manifest and its other tag are left alone
+        assert_eq!(session.list_tags_of(&digest).await.unwrap(), vec!["b"]);
+        assert!(session
+            .query_manifest(digest.as_str())
+            .await
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn test_has_error_code() {
+        let body = br#"{"errors":[{"code":"DENIED"},{"code":"UNSUPPORTED","message":"x"}]}"#;
+        assert!(has_error_code(body, "UNSUPPORTED"));
+        assert!(!has_error_code(body, "NAME_UNKNOWN"));
+        assert!(!has_error_code(b"not json", "UNSUPPORTED"));
+    }
+
+    #[tokio::test]
+    async fn test_upload_content_empty_and_chunk_aligned() {
+        let registry = stand_in::MemoryRegistry::default();
+        let base_url = registry.serve().await;
+        let mut client = Registry::new(base_url, None);
+        client.upload_chunk_size = 4;
+        let mut session = client.new_session("test/image".to_string());
+
+        for content in [&b""[..], b"abcd", b"abcdefgh", b"abcdef"] {
+            let descriptor = session
+                .upload_content(None, "text/plain".to_string(), content)
+                .await
+                .unwrap();
+            assert_eq!(descriptor.size, content.len());
+            assert_eq!(descriptor.digest, crate::digest::sha256_once(content));
+            let blobs = registry.blobs.lock().unwrap();
+            assert_eq!(blobs.get(descriptor.digest.as_str()).unwrap(), content);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_list_tags_and_delete() {
+        let registry = stand_in::MemoryRegistry::default();
+        let manifest = |tag: &str| (OCI_MANIFEST.to_string(), tag.as_bytes().to_vec());
+        {
+            let mut manifests = registry.manifests.lock().unwrap();
+            for tag in ["a", "b", "c", "d", "e"] {
+                manifests.insert(tag.to_string(), manifest(tag));
+            }
+            let digest = crate::digest::sha256_once(b"c");
+            manifests.insert(digest.to_string(), manifest("c"));
+        }
+        let blob_digest = crate::digest::sha256_once(b"blob");
+        registry
+            .blobs
+            .lock()
+            .unwrap()
+            .insert(blob_digest.to_string(), b"blob".to_vec());
+        let base_url = registry.serve().await;
+        let mut session = Registry::new(base_url, None).new_session("test/image".to_string());
+
+        // two tags per page takes three requests
+        let tags = session.list_tags(Some(2)).await.unwrap().unwrap();
+        assert_eq!(tags, vec!["a", "b", "c", "d", "e"]);
+        assert_eq!(session.list_tags(None).await.unwrap().unwrap().len(), 5);
+
+        // deleting by tag is refused, deleting by digest drops the tags pointing to it
+        assert!(session.delete_manifest("c").await.is_err());
+        assert_eq!(
+            session.delete_tag("c").await.unwrap(),
+            TagDeletion::Unsupported
+        );
+        let digest = crate::digest::sha256_once(b"c");
+        assert!(session.delete_manifest(digest.as_str()).await.unwrap());
+        assert!(!session.delete_manifest(digest.as_str()).await.unwrap());
+        let tags = session.list_tags(Some(2)).await.unwrap().unwrap();
+        assert_eq!(tags, vec!["a", "b", "d", "e"]);
+
+        assert!(session.delete_blob(&blob_digest).await.unwrap());
+        assert!(!session.delete_blob(&blob_digest).await.unwrap());
+        assert!(registry.blobs.lock().unwrap().is_empty());
+    }
 }
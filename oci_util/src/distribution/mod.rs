@@ -22,3 +22,5 @@
 // OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
 // SUCH DAMAGE.
 pub mod client;
+pub mod signature;
+pub mod token;
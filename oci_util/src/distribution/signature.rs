@@ -0,0 +1,469 @@
+// Copyright (c) 2023 Yan Ka, Chiu.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions
+// are met:
+// 1. Redistributions of source code must retain the above copyright
+//    notice, this list of conditions, and the following disclaimer,
+//    without modification, immediately at the beginning of the file.
+// 2. The name of the author may not be used to endorse or promote products
+//    derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE AUTHOR AND CONTRIBUTORS ``AS IS'' AND
+// ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE AUTHOR OR CONTRIBUTORS BE LIABLE FOR
+// ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+// OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+// HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+// LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
+// OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
+// SUCH DAMAGE.
+
+//! Signatures of manifests, attached to the signed manifest as artifacts referring to it.
+//!
+//! A signature artifact is an artifact manifest of type `SIGNATURE_ARTIFACT_TYPE` with the
+//! signed manifest as its subject, each blob of the artifact is a JSON `SignaturePayload`
+//! carrying a signature over the digest string of the signed manifest, for example
+//! `sha256:e3b0c442...`.
+
+use crate::digest::OciDigest;
+use crate::distribution::client::{ClientError, Session};
+use crate::models::{Descriptor, ManifestVariant};
+
+use base64::{engine::general_purpose, Engine as _};
+use ed25519_dalek::pkcs8::{DecodePrivateKey, DecodePublicKey};
+use p256::ecdsa::signature::{Signer, Verifier};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::warn;
+
+pub const SIGNATURE_ARTIFACT_TYPE: &str = "application/vnd.xc.signature.v1";
+pub const SIGNATURE_MEDIA_TYPE: &str = "application/vnd.xc.signature.v1+json";
+
+#[derive(Error, Debug)]
+pub enum SignatureError {
+    #[error("not a PEM encoded ed25519 or ecdsa p-256 public key")]
+    InvalidPublicKey,
+    #[error("not a PEM encoded PKCS#8 ed25519 or ecdsa p-256 private key")]
+    InvalidPrivateKey,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SignatureAlgorithm {
+    #[serde(rename = "ed25519")]
+    Ed25519,
+    /// ECDSA over P-256 with SHA-256, the signature is either DER encoded or the fixed 64 bytes
+    #[serde(rename = "ecdsa-p256-sha256")]
+    EcdsaP256Sha256,
+}
+
+/// The content of a signature blob
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SignaturePayload {
+    pub algorithm: SignatureAlgorithm,
+    /// The digest of the signed manifest, which is also the signed message
+    pub digest: OciDigest,
+    /// Base64 encoded signature
+    pub signature: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PublicKey {
+    Ed25519(ed25519_dalek::VerifyingKey),
+    EcdsaP256(p256::ecdsa::VerifyingKey),
+}
+
+impl PublicKey {
+    /// Parse a PEM encoded `SubjectPublicKeyInfo`, such as the output of `openssl pkey -pubout`
+    pub fn from_pem(pem: &str) -> Result<PublicKey, SignatureError> {
+        if let Ok(key) = ed25519_dalek::VerifyingKey::from_public_key_pem(pem) {
+            return Ok(PublicKey::Ed25519(key));
+        }
+        p256::ecdsa::VerifyingKey::from_public_key_pem(pem)
+            .map(PublicKey::EcdsaP256)
+            .map_err(|_| SignatureError::InvalidPublicKey)
+    }
+
+    /// Check if `payload` is a valid signature of `payload.digest` made by this key
+    pub fn verify(&self, payload: &SignaturePayload) -> bool {
+        let Ok(signature) = general_purpose::STANDARD.decode(&payload.signature) else {
+            return false;
+        };
+        let message = payload.digest.as_str().as_bytes();
+        match (self, payload.algorithm) {
+            (PublicKey::Ed25519(key), SignatureAlgorithm::Ed25519) => {
+                ed25519_dalek::Signature::from_slice(&signature)
+                    .is_ok_and(|signature| key.verify_strict(message, &signature).is_ok())
+            }
+            (PublicKey::EcdsaP256(key), SignatureAlgorithm::EcdsaP256Sha256) => {
+                let signature = if signature.len() == 64 {
+                    p256::ecdsa::Signature::from_slice(&signature)
+                } else {
+                    p256::ecdsa::Signature::from_der(&signature)
+                };
+                signature.is_ok_and(|signature| key.verify(message, &signature).is_ok())
+            }
+            _ => false,
+        }
+    }
+}
+
+pub enum PrivateKey {
+    Ed25519(ed25519_dalek::SigningKey),
+    EcdsaP256(p256::ecdsa::SigningKey),
+}
+
+impl std::fmt::Debug for PrivateKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("PrivateKey")
+            .field(&self.public_key())
+            .finish()
+    }
+}
+
+impl PrivateKey {
+    /// Parse a PEM encoded PKCS#8 private key, such as one generated by `openssl genpkey`
+    pub fn from_pem(pem: &str) -> Result<PrivateKey, SignatureError> {
+        if let Ok(key) = ed25519_dalek::SigningKey::from_pkcs8_pem(pem) {
+            return Ok(PrivateKey::Ed25519(key));
+        }
+        p256::ecdsa::SigningKey::from_pkcs8_pem(pem)
+            .map(PrivateKey::EcdsaP256)
+            .map_err(|_| SignatureError::InvalidPrivateKey)
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        match self {
+            PrivateKey::Ed25519(key) => PublicKey::Ed25519(key.verifying_key()),
+            PrivateKey::EcdsaP256(key) => PublicKey::EcdsaP256(*key.verifying_key()),
+        }
+    }
+
+    /// Sign the manifest `digest`
+    pub fn sign(&self, digest: &OciDigest) -> SignaturePayload {
+        let message = digest.as_str().as_bytes();
+        let (algorithm, signature) = match self {
+            PrivateKey::Ed25519(key) => (
+                SignatureAlgorithm::Ed25519,
+                key.sign(message).to_bytes().to_vec(),
+            ),
+            PrivateKey::EcdsaP256(key) => {
+                let signature: p256::ecdsa::Signature = key.sign(message);
+                (
+                    SignatureAlgorithm::EcdsaP256Sha256,
+                    signature.to_der().as_bytes().to_vec(),
+                )
+            }
+        };
+        SignaturePayload {
+            algorithm,
+            digest: digest.clone(),
+            signature: general_purpose::STANDARD.encode(signature),
+        }
+    }
+}
+
+/// Sign the manifest `subject` with `key` and attach the signature to it, returns the
+/// descriptor of the signature artifact
+pub async fn push_signature(
+    session: &mut Session,
+    subject: &Descriptor,
+    key: &PrivateKey,
+) -> Result<Descriptor, ClientError> {
+    let payload = serde_json::to_vec(&key.sign(&subject.digest)).unwrap();
+    let blob = session
+        .upload_content(None, SIGNATURE_MEDIA_TYPE.to_string(), payload.as_slice())
+        .await?;
+    session
+        .attach_artifact(
+            subject,
+            SIGNATURE_ARTIFACT_TYPE,
+            vec![blob],
+            std::collections::HashMap::new(),
+        )
+        .await
+}
+
+/// Look up the signature artifacts attached to the manifest `digest` in the repository of
+/// `session`, returns the digest of the first artifact carrying a signature made by any of
+/// `keys`, or `None` if there is no such artifact.
+///
+/// Referrers that cannot be fetched or decoded are skipped, as anyone who can push to the
+/// repository can attach artifacts to the manifest.
+pub async fn find_verified_signature(
+    session: &mut Session,
+    digest: &OciDigest,
+    keys: &[PublicKey],
+) -> Result<Option<OciDigest>, ClientError> {
+    let referrers = session
+        .query_referrers(digest, Some(SIGNATURE_ARTIFACT_TYPE))
+        .await?;
+    for referrer in referrers {
+        let artifact = match session.query_manifest(referrer.digest.as_str()).await {
+            Ok(Some(ManifestVariant::Artifact(artifact))) => artifact,
+            Ok(_) => continue,
+            Err(err) => {
+                warn!("cannot fetch signature {}: {err:?}", referrer.digest);
+                continue;
+            }
+        };
+        if &artifact.subject.digest != digest || artifact.artifact_type != SIGNATURE_ARTIFACT_TYPE {
+            continue;
+        }
+        for blob in artifact.blobs.iter() {
+            if blob.media_type != SIGNATURE_MEDIA_TYPE {
+                continue;
+            }
+            let payload = match session
+                .fetch_blob_as::<SignaturePayload>(&blob.digest)
+                .await
+            {
+                Ok(Some(payload)) => payload,
+                Ok(None) => continue,
+                Err(err) => {
+                    warn!("cannot fetch signature blob {}: {err:?}", blob.digest);
+                    continue;
+                }
+            };
+            if &payload.digest == digest && keys.iter().any(|key| key.verify(&payload)) {
+                return Ok(Some(referrer.digest));
+            }
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::digest::sha256_once;
+    use crate::distribution::client::tests::stand_in::{serve_fn, MemoryRegistry, Reply};
+    use crate::distribution::client::{referrers_tag, Registry};
+    use crate::layout::{ImageIndex, IndexEntry};
+    use crate::models::{ArtifactManifest, OCI_ARTIFACT, OCI_IMAGE_INDEX, OCI_MANIFEST};
+    use ed25519_dalek::pkcs8::{EncodePrivateKey, EncodePublicKey};
+    use p256::pkcs8::LineEnding;
+    use std::collections::HashMap;
+
+    fn ed25519_key() -> ed25519_dalek::SigningKey {
+        ed25519_dalek::SigningKey::from_bytes(&[7; 32])
+    }
+
+    fn p256_key() -> p256::ecdsa::SigningKey {
+        p256::ecdsa::SigningKey::from_slice(&[7; 32]).unwrap()
+    }
+
+    fn ed25519_payload(digest: &OciDigest) -> SignaturePayload {
+        PrivateKey::Ed25519(ed25519_key()).sign(digest)
+    }
+
+    #[test]
+    fn test_verify_signature() {
+        let digest = sha256_once(b"manifest");
+        let other = sha256_once(b"other manifest");
+
+        let ed25519_pem = ed25519_key()
+            .verifying_key()
+            .to_public_key_pem(LineEnding::LF)
+            .unwrap();
+        let ed25519 = PublicKey::from_pem(&ed25519_pem).unwrap();
+        let p256_pem = p256_key()
+            .verifying_key()
+            .to_public_key_pem(LineEnding::LF)
+            .unwrap();
+        let p256 = PublicKey::from_pem(&p256_pem).unwrap();
+        assert!(matches!(ed25519, PublicKey::Ed25519(_)));
+        assert!(matches!(p256, PublicKey::EcdsaP256(_)));
+        assert!(PublicKey::from_pem("not a key").is_err());
+
+        let payload = ed25519_payload(&digest);
+        assert!(ed25519.verify(&payload));
+        assert!(!p256.verify(&payload));
+        // the signature does not cover another manifest
+        assert!(!ed25519.verify(&SignaturePayload {
+            digest: other.clone(),
+            ..payload
+        }));
+
+        let signature: p256::ecdsa::Signature = p256_key().sign(digest.as_str().as_bytes());
+        for encoded in [signature.to_der().as_bytes().to_vec(), signature.to_vec()] {
+            let payload = SignaturePayload {
+                algorithm: SignatureAlgorithm::EcdsaP256Sha256,
+                digest: digest.clone(),
+                signature: general_purpose::STANDARD.encode(encoded),
+            };
+            assert!(p256.verify(&payload));
+            assert!(!ed25519.verify(&payload));
+            assert!(!p256.verify(&SignaturePayload {
+                digest: other.clone(),
+                ..payload
+            }));
+        }
+    }
+
+    /// A stand-in registry serving `manifests` and `blobs` by digest and the referrers of
+    /// `subject`, through the referrers API, or if `referrers_api` is false, the tag scheme
+    async fn serve_signed(
+        subject: &OciDigest,
+        payload: &SignaturePayload,
+        referrers_api: bool,
+    ) -> String {
+        let blob = serde_json::to_vec(payload).unwrap();
+        let blob_digest = sha256_once(&blob);
+        let artifact = serde_json::to_vec(&ArtifactManifest {
+            media_type: OCI_ARTIFACT.to_string(),
+            artifact_type: SIGNATURE_ARTIFACT_TYPE.to_string(),
+            blobs: vec![Descriptor {
+                media_type: SIGNATURE_MEDIA_TYPE.to_string(),
+                size: blob.len(),
+                digest: blob_digest.clone(),
+            }],
+            subject: Descriptor {
+                media_type: OCI_MANIFEST.to_string(),
+                size: 0,
+                digest: subject.clone(),
+            },
+            annotations: HashMap::new(),
+        })
+        .unwrap();
+        let artifact_digest = sha256_once(&artifact);
+        let index = serde_json::to_vec(&ImageIndex {
+            manifests: vec![
+                IndexEntry {
+                    media_type: OCI_ARTIFACT.to_string(),
+                    size: artifact.len(),
+                    digest: artifact_digest.clone(),
+                    platform: None,
+                    artifact_type: Some(SIGNATURE_ARTIFACT_TYPE.to_string()),
+                    annotations: HashMap::new(),
+                },
+                // not a signature, should be ignored
+                IndexEntry {
+                    media_type: OCI_ARTIFACT.to_string(),
+                    size: 0,
+                    digest: sha256_once(b"sbom"),
+                    platform: None,
+                    artifact_type: Some("application/spdx+json".to_string()),
+                    annotations: HashMap::new(),
+                },
+            ],
+            ..ImageIndex::default()
+        })
+        .unwrap();
+
+        let referrers_path = if referrers_api {
+            format!("/v2/test/image/referrers/{subject}")
+        } else {
+            format!(
+                "/v2/test/image/manifests/{}",
+                subject.as_str().replace(':', "-")
+            )
+        };
+        let routes = HashMap::from([
+            (referrers_path, (OCI_IMAGE_INDEX, index)),
+            (
+                format!("/v2/test/image/manifests/{artifact_digest}"),
+                (OCI_ARTIFACT, artifact),
+            ),
+            (
+                format!("/v2/test/image/blobs/{blob_digest}"),
+                ("application/octet-stream", blob),
+            ),
+        ]);
+        serve_fn(move |request| {
+            let path = request.target.split('?').next().unwrap_or_default();
+            match routes.get(path) {
+                Some((content_type, body)) => {
+                    Reply::new("200 OK", body.clone()).header("content-type", *content_type)
+                }
+                None => Reply::new("404 Not Found", Vec::new()),
+            }
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_find_verified_signature() {
+        let digest = sha256_once(b"manifest");
+        let payload = ed25519_payload(&digest);
+        let key = PublicKey::Ed25519(ed25519_key().verifying_key());
+        let other_key = PublicKey::EcdsaP256(*p256_key().verifying_key());
+
+        for referrers_api in [true, false] {
+            let base_url = serve_signed(&digest, &payload, referrers_api).await;
+            let mut session = Registry::new(base_url, None).new_session("test/image".to_string());
+
+            let found =
+                find_verified_signature(&mut session, &digest, std::slice::from_ref(&other_key))
+                    .await
+                    .unwrap();
+            assert_eq!(found, None);
+
+            let found =
+                find_verified_signature(&mut session, &digest, &[other_key.clone(), key.clone()])
+                    .await
+                    .unwrap();
+            assert!(found.is_some());
+
+            // the signature is not attached to other manifests
+            let found = find_verified_signature(
+                &mut session,
+                &sha256_once(b"other"),
+                std::slice::from_ref(&key),
+            )
+            .await
+            .unwrap();
+            assert_eq!(found, None);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_push_signature() {
+        let pem = p256_key().to_pkcs8_pem(LineEnding::LF).unwrap().to_string();
+        let key = PrivateKey::from_pem(&pem).unwrap();
+        assert!(matches!(key, PrivateKey::EcdsaP256(_)));
+        assert!(PrivateKey::from_pem("not a key").is_err());
+
+        let subject = Descriptor {
+            media_type: OCI_MANIFEST.to_string(),
+            size: 8,
+            digest: sha256_once(b"manifest"),
+        };
+
+        for referrers_api in [true, false] {
+            let registry = MemoryRegistry {
+                referrers_api,
+                ..MemoryRegistry::default()
+            };
+            let base_url = registry.serve().await;
+            let mut session = Registry::new(base_url, None).new_session("test/image".to_string());
+
+            let signature = push_signature(&mut session, &subject, &key).await.unwrap();
+            // signing again attaches another signature without dropping the first one
+            push_signature(&mut session, &subject, &key).await.unwrap();
+            let referrers = session
+                .query_referrers(&subject.digest, Some(SIGNATURE_ARTIFACT_TYPE))
+                .await
+                .unwrap();
+            assert!(referrers
+                .iter()
+                .any(|entry| entry.digest == signature.digest));
+            assert_eq!(
+                registry
+                    .manifests
+                    .lock()
+                    .unwrap()
+                    .contains_key(&referrers_tag(&subject.digest)),
+                !referrers_api
+            );
+
+            let found = find_verified_signature(&mut session, &subject.digest, &[key.public_key()])
+                .await
+                .unwrap();
+            assert!(found.is_some());
+        }
+    }
+}
@@ -0,0 +1,371 @@
+// Copyright (c) 2023 Yan Ka, Chiu.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions
+// are met:
+// 1. Redistributions of source code must retain the above copyright
+//    notice, this list of conditions, and the following disclaimer,
+//    without modification, immediately at the beginning of the file.
+// 2. The name of the author may not be used to endorse or promote products
+//    derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE AUTHOR AND CONTRIBUTORS ``AS IS'' AND
+// ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE AUTHOR OR CONTRIBUTORS BE LIABLE FOR
+// ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+// OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+// HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+// LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
+// OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
+// SUCH DAMAGE.
+
+//! Cache of bearer tokens issued by the token servers of registries.
+//!
+//! Tokens are keyed by the realm and service of the token server, the scopes they grant, and the
+//! identity requesting them, such that sessions on the same registry can share tokens as long
+//! as they need the same access.
+
+use crate::models::DockerAuthToken;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Lifetime of a token if the token server does not specify `expires_in`, as defined by the
+/// token authentication specification
+const DEFAULT_TOKEN_LIFETIME: Duration = Duration::from_secs(60);
+
+/// A token is considered stale and refreshed once it has less than this portion of its
+/// lifetime left, capped by `MAX_REFRESH_MARGIN`
+const REFRESH_MARGIN_DIVISOR: u32 = 5;
+const MAX_REFRESH_MARGIN: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct TokenKey {
+    pub realm: String,
+    pub service: Option<String>,
+    /// Sorted and deduplicated
+    pub scopes: Vec<String>,
+    /// The user authenticating with the token server, `None` for anonymous access
+    pub username: Option<String>,
+}
+
+impl TokenKey {
+    pub fn new(
+        realm: String,
+        service: Option<String>,
+        scopes: impl IntoIterator<Item = String>,
+        username: Option<String>,
+    ) -> TokenKey {
+        let mut scopes = scopes.into_iter().collect::<Vec<_>>();
+        scopes.sort();
+        scopes.dedup();
+        TokenKey {
+            realm,
+            service,
+            scopes,
+            username,
+        }
+    }
+
+    /// A key for the same token server and identity, granting `scopes` on top of the scopes of
+    /// this key
+    pub fn with_scopes(&self, scopes: &[String]) -> TokenKey {
+        TokenKey::new(
+            self.realm.clone(),
+            self.service.clone(),
+            self.scopes.iter().chain(scopes.iter()).cloned(),
+            self.username.clone(),
+        )
+    }
+
+    pub fn contains_scopes(&self, scopes: &[String]) -> bool {
+        scopes.iter().all(|scope| self.scopes.contains(scope))
+    }
+
+    /// The url to request a token for this key from the token server
+    pub fn token_url(&self) -> String {
+        let mut params = Vec::new();
+        if let Some(service) = &self.service {
+            params.push(format!("service={service}"));
+        }
+        for scope in self.scopes.iter() {
+            params.push(format!("scope={scope}"));
+        }
+        if params.is_empty() {
+            self.realm.clone()
+        } else {
+            format!("{}?{}", self.realm, params.join("&"))
+        }
+    }
+}
+
+#[derive(Clone)]
+struct CachedToken {
+    token: String,
+    expires_at: Instant,
+    refresh_at: Instant,
+}
+
+impl std::fmt::Debug for CachedToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CachedToken")
+            .field("token", &"<redacted>")
+            .field("expires_at", &self.expires_at)
+            .field("refresh_at", &self.refresh_at)
+            .finish()
+    }
+}
+
+/// Registry-wide bearer token cache, cloning the cache gives a handle to the same cache
+#[derive(Clone, Debug, Default)]
+pub struct TokenCache(Arc<Mutex<HashMap<TokenKey, CachedToken>>>);
+
+impl TokenCache {
+    pub fn new() -> TokenCache {
+        TokenCache::default()
+    }
+
+    /// Get a token for `key` that is not yet due for refresh
+    pub fn get(&self, key: &TokenKey) -> Option<String> {
+        let now = Instant::now();
+        let mut tokens = self.0.lock().unwrap();
+        tokens.retain(|_, cached| cached.expires_at > now);
+        tokens
+            .get(key)
+            .filter(|cached| cached.refresh_at > now)
+            .map(|cached| cached.token.clone())
+    }
+
+    /// Cache a token issued by the token server in response to a request sent at
+    /// `requested_at`, returns the token string if the response contains one
+    pub fn insert(
+        &self,
+        key: TokenKey,
+        token: &DockerAuthToken,
+        requested_at: Instant,
+    ) -> Option<String> {
+        let value = token.token()?;
+        let lifetime = token_lifetime(token, SystemTime::now());
+        let margin = (lifetime / REFRESH_MARGIN_DIVISOR).min(MAX_REFRESH_MARGIN);
+        let cached = CachedToken {
+            token: value.clone(),
+            expires_at: requested_at + lifetime,
+            refresh_at: requested_at + lifetime - margin,
+        };
+        self.0.lock().unwrap().insert(key, cached);
+        Some(value)
+    }
+
+    pub fn remove(&self, key: &TokenKey) {
+        self.0.lock().unwrap().remove(key);
+    }
+
+    pub fn clear(&self) {
+        self.0.lock().unwrap().clear();
+    }
+}
+
+/// The remaining lifetime of a freshly issued token. If the token server tells when the token
+/// was issued, the time the token spent in transit (or the clock running ahead of the token
+/// server) is deducted.
+fn token_lifetime(token: &DockerAuthToken, now: SystemTime) -> Duration {
+    let lifetime = token
+        .expires_in
+        .map(|secs| Duration::from_secs(secs as u64))
+        .unwrap_or(DEFAULT_TOKEN_LIFETIME);
+    let age = token
+        .issued_at
+        .as_deref()
+        .and_then(parse_rfc3339)
+        .and_then(|issued_at| now.duration_since(issued_at).ok())
+        .unwrap_or_default();
+    lifetime.saturating_sub(age)
+}
+
+/// Parse a RFC 3339 timestamp such as `2009-11-10T23:00:00Z` or
+/// `2023-05-01T12:30:45.123456789-07:00`
+fn parse_rfc3339(input: &str) -> Option<SystemTime> {
+    fn number<T: std::str::FromStr>(input: &str, range: std::ops::Range<usize>) -> Option<T> {
+        let digits = input.get(range)?;
+        if digits.bytes().all(|b| b.is_ascii_digit()) {
+            digits.parse().ok()
+        } else {
+            None
+        }
+    }
+
+    let input = input.trim();
+    let bytes = input.as_bytes();
+    if bytes.len() < 20
+        || bytes[4] != b'-'
+        || bytes[7] != b'-'
+        || !matches!(bytes[10], b'T' | b't' | b' ')
+        || bytes[13] != b':'
+        || bytes[16] != b':'
+    {
+        return None;
+    }
+
+    let year: i64 = number(input, 0..4)?;
+    let month: i64 = number(input, 5..7)?;
+    let day: i64 = number(input, 8..10)?;
+    let hour: i64 = number(input, 11..13)?;
+    let minute: i64 = number(input, 14..16)?;
+    let second: i64 = number(input, 17..19)?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 {
+        return None;
+    }
+
+    let mut rest = &input[19..];
+    let mut nanos = 0u32;
+    if let Some(fraction) = rest.strip_prefix('.') {
+        let len = fraction.bytes().take_while(|b| b.is_ascii_digit()).count();
+        if len == 0 {
+            return None;
+        }
+        let digits = &fraction[..len.min(9)];
+        nanos = digits.parse::<u32>().ok()? * 10u32.pow(9 - digits.len() as u32);
+        rest = &fraction[len..];
+    }
+
+    let offset = match rest {
+        "Z" | "z" => 0,
+        _ => {
+            let sign = match rest.as_bytes().first()? {
+                b'+' => 1,
+                b'-' => -1,
+                _ => return None,
+            };
+            if rest.len() != 6 || rest.as_bytes()[3] != b':' {
+                return None;
+            }
+            let hours: i64 = number(rest, 1..3)?;
+            let minutes: i64 = number(rest, 4..6)?;
+            sign * (hours * 3600 + minutes * 60)
+        }
+    };
+
+    // days since the unix epoch of the civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let (y, m) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * m + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+
+    let secs = days * 86400 + hour * 3600 + minute * 60 + second - offset;
+    let secs = u64::try_from(secs).ok()?;
+    Some(UNIX_EPOCH + Duration::new(secs, nanos))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(value: &str, expires_in: Option<usize>, issued_at: Option<&str>) -> DockerAuthToken {
+        DockerAuthToken {
+            token: Some(value.to_string()),
+            access_token: None,
+            expires_in,
+            issued_at: issued_at.map(|s| s.to_string()),
+        }
+    }
+
+    #[test]
+    fn test_parse_rfc3339() {
+        assert_eq!(parse_rfc3339("1970-01-01T00:00:00Z"), Some(UNIX_EPOCH));
+        assert_eq!(
+            parse_rfc3339("2009-11-10T23:00:00Z"),
+            Some(UNIX_EPOCH + Duration::from_secs(1257894000))
+        );
+        assert_eq!(
+            parse_rfc3339("2009-11-10T16:00:00.5-07:00"),
+            Some(UNIX_EPOCH + Duration::new(1257894000, 500_000_000))
+        );
+        assert_eq!(
+            parse_rfc3339("2024-02-29T12:00:00+01:30"),
+            Some(UNIX_EPOCH + Duration::from_secs(1709202600))
+        );
+        assert_eq!(parse_rfc3339("2009-11-10"), None);
+        assert_eq!(parse_rfc3339("2009-13-10T23:00:00Z"), None);
+        assert_eq!(parse_rfc3339("2009-11-10T23:00:00"), None);
+    }
+
+    #[test]
+    fn test_token_lifetime() {
+        let now = UNIX_EPOCH + Duration::from_secs(1257894010);
+        assert_eq!(
+            token_lifetime(&token("t", None, None), now),
+            DEFAULT_TOKEN_LIFETIME
+        );
+        assert_eq!(
+            token_lifetime(&token("t", Some(300), Some("2009-11-10T23:00:00Z")), now),
+            Duration::from_secs(290)
+        );
+        // issued in the future according to our clock
+        assert_eq!(
+            token_lifetime(&token("t", Some(300), Some("2009-11-10T23:10:00Z")), now),
+            Duration::from_secs(300)
+        );
+    }
+
+    #[test]
+    fn test_token_key() {
+        let key = TokenKey::new(
+            "https://auth.example.com/token".to_string(),
+            Some("registry.example.com".to_string()),
+            vec![
+                "repository:a/b:pull".to_string(),
+                "repository:a/b:pull".to_string(),
+            ],
+            None,
+        );
+        assert_eq!(
+            key.token_url(),
+            "https://auth.example.com/token?service=registry.example.com&scope=repository:a/b:pull"
+        );
+        let extra = vec!["repository:c/d:pull".to_string()];
+        assert!(!key.contains_scopes(&extra));
+        let wider = key.with_scopes(&extra);
+        assert!(wider.contains_scopes(&extra));
+        assert_eq!(
+            wider.token_url(),
+            "https://auth.example.com/token?service=registry.example.com&scope=repository:a/b:pull&scope=repository:c/d:pull"
+        );
+    }
+
+    #[test]
+    fn test_token_cache_expiry() {
+        let cache = TokenCache::new();
+        let key = TokenKey::new("realm".to_string(), None, Vec::new(), None);
+        let other = TokenKey::new("realm".to_string(), None, Vec::new(), Some("u".to_string()));
+
+        cache.insert(
+            key.clone(),
+            &token("fresh", Some(300), None),
+            Instant::now(),
+        );
+        assert_eq!(cache.get(&key), Some("fresh".to_string()));
+        assert_eq!(cache.get(&other), None);
+
+        // within the refresh margin, the token is not handed out anymore
+        let requested_at = Instant::now() - Duration::from_secs(9);
+        cache.insert(key.clone(), &token("stale", Some(10), None), requested_at);
+        assert_eq!(cache.get(&key), None);
+
+        cache.insert(
+            key.clone(),
+            &token("fresh", Some(300), None),
+            Instant::now(),
+        );
+        cache.remove(&key);
+        assert_eq!(cache.get(&key), None);
+    }
+}
@@ -0,0 +1,422 @@
+// Copyright (c) 2023 Yan Ka, Chiu.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions
+// are met:
+// 1. Redistributions of source code must retain the above copyright
+//    notice, this list of conditions, and the following disclaimer,
+//    without modification, immediately at the beginning of the file.
+// 2. The name of the author may not be used to endorse or promote products
+//    derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE AUTHOR AND CONTRIBUTORS ``AS IS'' AND
+// ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE AUTHOR OR CONTRIBUTORS BE LIABLE FOR
+// ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+// OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+// HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+// LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
+// OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
+// SUCH DAMAGE.
+
+//! OCI image layout, a directory of blobs addressed by their digests with `index.json` as the
+//! entry point, see <https://github.com/opencontainers/image-spec/blob/main/image-layout.md>
+
+use crate::digest::{Hasher, OciDigest};
+use crate::models::{Descriptor, Platform, OCI_IMAGE_INDEX};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Read};
+use std::path::{Path, PathBuf};
+
+pub const OCI_LAYOUT_FILE: &str = "oci-layout";
+pub const OCI_LAYOUT_VERSION: &str = "1.0.0";
+pub const OCI_INDEX_FILE: &str = "index.json";
+
+/// The tag of a manifest in the layout
+pub const ANNOTATION_REF_NAME: &str = "org.opencontainers.image.ref.name";
+/// The full image reference of a manifest in the layout, as written by containerd and docker
+pub const ANNOTATION_IMAGE_NAME: &str = "io.containerd.image.name";
+
+/// Open `path` for read, failing if it is not a regular file such as a symlink or a device
+fn open_regular_file(path: &Path) -> Result<std::fs::File, Error> {
+    if !std::fs::symlink_metadata(path)?.is_file() {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("{path:?} is not a regular file"),
+        ));
+    }
+    std::fs::File::open(path)
+}
+
+fn read_regular_file(path: &Path) -> Result<Vec<u8>, Error> {
+    let mut content = Vec::new();
+    open_regular_file(path)?.read_to_end(&mut content)?;
+    Ok(content)
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OciLayoutFile {
+    image_layout_version: String,
+}
+
+#[derive(PartialEq, Eq, Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct IndexEntry {
+    pub media_type: String,
+    pub size: usize,
+    pub digest: OciDigest,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub platform: Option<Platform>,
+    /// The type of the artifact, for entries listed by the referrers API
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub artifact_type: Option<String>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub annotations: HashMap<String, String>,
+}
+
+impl IndexEntry {
+    pub fn descriptor(&self) -> Descriptor {
+        Descriptor {
+            media_type: self.media_type.clone(),
+            size: self.size,
+            digest: self.digest.clone(),
+        }
+    }
+
+    /// The image reference of this entry, either the full reference or only the tag
+    pub fn image_name(&self) -> Option<&str> {
+        self.annotations
+            .get(ANNOTATION_IMAGE_NAME)
+            .or_else(|| self.annotations.get(ANNOTATION_REF_NAME))
+            .map(|name| name.as_str())
+    }
+}
+
+#[derive(PartialEq, Eq, Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageIndex {
+    pub schema_version: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub media_type: Option<String>,
+    pub manifests: Vec<IndexEntry>,
+}
+
+impl Default for ImageIndex {
+    fn default() -> ImageIndex {
+        ImageIndex {
+            schema_version: 2,
+            media_type: Some(OCI_IMAGE_INDEX.to_string()),
+            manifests: Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ImageLayout {
+    root: PathBuf,
+}
+
+impl ImageLayout {
+    /// Create an image layout at `root`, or open it if it already is one
+    pub fn create(root: impl AsRef<Path>) -> Result<ImageLayout, Error> {
+        let root = root.as_ref().to_path_buf();
+        std::fs::create_dir_all(root.join("blobs").join("sha256"))?;
+        if !root.join(OCI_LAYOUT_FILE).exists() {
+            let layout = OciLayoutFile {
+                image_layout_version: OCI_LAYOUT_VERSION.to_string(),
+            };
+            std::fs::write(root.join(OCI_LAYOUT_FILE), serde_json::to_vec(&layout)?)?;
+        }
+        let layout = ImageLayout { root };
+        if !layout.root.join(OCI_INDEX_FILE).exists() {
+            layout.write_index(&ImageIndex::default())?;
+        }
+        ImageLayout::open(&layout.root)
+    }
+
+    /// Open an existing image layout at `root`
+    pub fn open(root: impl AsRef<Path>) -> Result<ImageLayout, Error> {
+        let root = root.as_ref().to_path_buf();
+        let content = read_regular_file(&root.join(OCI_LAYOUT_FILE))?;
+        let layout: OciLayoutFile = serde_json::from_slice(&content)?;
+        if !layout.image_layout_version.starts_with("1.") {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "unsupported image layout version: {}",
+                    layout.image_layout_version
+                ),
+            ));
+        }
+        Ok(ImageLayout { root })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The path of the blob `digest` in this layout, fails if the digest cannot safely be used
+    /// as a path, as the digests in a layout may come from untrusted sources
+    pub fn blob_path(&self, digest: &OciDigest) -> Result<PathBuf, Error> {
+        let invalid = || Error::new(ErrorKind::InvalidData, format!("invalid digest: {digest}"));
+        let (algorithm, encoded) = digest.as_str().split_once(':').ok_or_else(invalid)?;
+        if !matches!(algorithm, "sha256" | "sha512")
+            || encoded.is_empty()
+            || !encoded.bytes().all(|c| c.is_ascii_hexdigit())
+        {
+            return Err(invalid());
+        }
+        Ok(self.root.join("blobs").join(algorithm).join(encoded))
+    }
+
+    pub fn has_blob(&self, digest: &OciDigest) -> bool {
+        self.blob_path(digest).is_ok_and(|path| path.exists())
+    }
+
+    pub fn index(&self) -> Result<ImageIndex, Error> {
+        let content = read_regular_file(&self.root.join(OCI_INDEX_FILE))?;
+        Ok(serde_json::from_slice(&content)?)
+    }
+
+    pub fn write_index(&self, index: &ImageIndex) -> Result<(), Error> {
+        let temp = self.root.join(format!("{OCI_INDEX_FILE}.tmp"));
+        std::fs::write(&temp, serde_json::to_vec_pretty(index)?)?;
+        std::fs::rename(temp, self.root.join(OCI_INDEX_FILE))
+    }
+
+    /// Add `content` as a sha256 addressed blob
+    pub fn add_blob(&self, content: &[u8]) -> Result<OciDigest, Error> {
+        let digest = crate::digest::sha256_once(content);
+        let path = self.blob_path(&digest)?;
+        if !path.exists() {
+            let temp = path.with_extension("tmp");
+            std::fs::write(&temp, content)?;
+            std::fs::rename(temp, path)?;
+        }
+        Ok(digest)
+    }
+
+    /// Copy the file at `source` as the blob `digest`. The caller is responsible for `digest`
+    /// matching the content.
+    pub fn add_blob_file(&self, digest: &OciDigest, source: impl AsRef<Path>) -> Result<(), Error> {
+        let path = self.blob_path(digest)?;
+        if path.exists() {
+            return Ok(());
+        }
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let temp = path.with_extension("tmp");
+        std::fs::copy(source.as_ref(), &temp)?;
+        std::fs::rename(temp, path)
+    }
+
+    /// Read the blob `digest`, verifying its content matches the digest
+    pub fn read_blob(&self, digest: &OciDigest) -> Result<Vec<u8>, Error> {
+        let content = read_regular_file(&self.blob_path(digest)?)?;
+        let mut hasher = Hasher::from_digest_str(digest.as_str())
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "unsupported digest"))?;
+        hasher.update(&content);
+        if hasher.finalize() != *digest {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("content of blob {digest} does not match its digest"),
+            ));
+        }
+        Ok(content)
+    }
+
+    /// Verify the content of the blob `digest` matches the digest without loading it in memory
+    pub fn verify_blob(&self, digest: &OciDigest) -> Result<(), Error> {
+        let mut file = open_regular_file(&self.blob_path(digest)?)?;
+        let mut hasher = Hasher::from_digest_str(digest.as_str())
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "unsupported digest"))?;
+        let mut buf = vec![0u8; 1024 * 1024];
+        loop {
+            let len = file.read(&mut buf)?;
+            if len == 0 {
+                break;
+            }
+            hasher.update(&buf[..len]);
+        }
+        if hasher.finalize() != *digest {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("content of blob {digest} does not match its digest"),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Add `entry` to the index, replacing the entry with the same image name if any
+    pub fn add_index_entry(&self, entry: IndexEntry) -> Result<(), Error> {
+        let mut index = self.index()?;
+        if let Some(name) = entry.image_name() {
+            index
+                .manifests
+                .retain(|existing| existing.image_name() != Some(name));
+        }
+        index.manifests.push(entry);
+        self.write_index(&index)
+    }
+
+    /// Copy the blobs and the index entries of `source` into this layout, entries with the same
+    /// image name as one in `source` are replaced
+    pub fn merge(&self, source: &ImageLayout) -> Result<(), Error> {
+        for algorithm in ["sha256", "sha512"] {
+            let dir = source.root.join("blobs").join(algorithm);
+            if !dir.is_dir() {
+                continue;
+            }
+            for dirent in std::fs::read_dir(dir)? {
+                let dirent = dirent?;
+                let digest = OciDigest::new_unchecked(&format!(
+                    "{algorithm}:{}",
+                    dirent.file_name().to_string_lossy()
+                ));
+                // skip anything that is not a blob, such as an interrupted write
+                if source.blob_path(&digest).is_err() || !dirent.file_type()?.is_file() {
+                    continue;
+                }
+                self.add_blob_file(&digest, dirent.path())?;
+            }
+        }
+        for entry in source.index()?.manifests.into_iter() {
+            self.add_index_entry(entry)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::OCI_MANIFEST;
+
+    fn temp_layout(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("oci-layout-{name}-{}", std::process::id()));
+        _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn entry(digest: OciDigest, size: usize, name: &str) -> IndexEntry {
+        IndexEntry {
+            media_type: OCI_MANIFEST.to_string(),
+            size,
+            digest,
+            platform: None,
+            artifact_type: None,
+            annotations: HashMap::from([(ANNOTATION_IMAGE_NAME.to_string(), name.to_string())]),
+        }
+    }
+
+    #[test]
+    fn test_layout_round_trip() {
+        let root = temp_layout("round-trip");
+        let layout = ImageLayout::create(&root).unwrap();
+        assert!(layout.index().unwrap().manifests.is_empty());
+
+        let digest = layout.add_blob(b"manifest").unwrap();
+        assert!(root
+            .join("blobs/sha256")
+            .join(&digest.as_str()[7..])
+            .exists());
+        assert_eq!(layout.read_blob(&digest).unwrap(), b"manifest");
+
+        let source = root.join("layer");
+        std::fs::write(&source, b"layer").unwrap();
+        let layer_digest = crate::digest::sha256_once(b"layer");
+        layout.add_blob_file(&layer_digest, &source).unwrap();
+        layout.verify_blob(&layer_digest).unwrap();
+
+        layout
+            .add_index_entry(entry(digest.clone(), 8, "example.com/app:1"))
+            .unwrap();
+        layout
+            .add_index_entry(entry(layer_digest.clone(), 5, "example.com/app:2"))
+            .unwrap();
+        // retagging replaces the previous entry of the same name
+        layout
+            .add_index_entry(entry(layer_digest, 5, "example.com/app:1"))
+            .unwrap();
+
+        let reopened = ImageLayout::open(&root).unwrap();
+        let index = reopened.index().unwrap();
+        assert_eq!(index.manifests.len(), 2);
+        assert_eq!(index.manifests[0].image_name(), Some("example.com/app:2"));
+        assert_eq!(index.manifests[1].image_name(), Some("example.com/app:1"));
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_layout_merge() {
+        let root = temp_layout("merge");
+        let layout = ImageLayout::create(&root).unwrap();
+        let kept = layout.add_blob(b"kept").unwrap();
+        layout
+            .add_index_entry(entry(kept.clone(), 4, "example.com/app:1"))
+            .unwrap();
+        layout
+            .add_index_entry(entry(kept.clone(), 4, "example.com/app:2"))
+            .unwrap();
+
+        let other_root = temp_layout("merge-source");
+        let other = ImageLayout::create(&other_root).unwrap();
+        let added = other.add_blob(b"added").unwrap();
+        other
+            .add_index_entry(entry(added.clone(), 5, "example.com/app:2"))
+            .unwrap();
+        std::fs::write(other_root.join("blobs/sha256/partial.tmp"), b"partial").unwrap();
+
+        layout.merge(&other).unwrap();
+        assert_eq!(layout.read_blob(&added).unwrap(), b"added");
+        assert_eq!(layout.read_blob(&kept).unwrap(), b"kept");
+        assert!(!root.join("blobs/sha256/partial.tmp").exists());
+
+        let index = layout.index().unwrap();
+        assert_eq!(index.manifests.len(), 2);
+        assert_eq!(index.manifests[0].image_name(), Some("example.com/app:1"));
+        assert_eq!(index.manifests[0].digest, kept);
+        assert_eq!(index.manifests[1].image_name(), Some("example.com/app:2"));
+        assert_eq!(index.manifests[1].digest, added);
+
+        std::fs::remove_dir_all(&root).unwrap();
+        std::fs::remove_dir_all(&other_root).unwrap();
+    }
+
+    #[test]
+    fn test_layout_rejects_bad_blobs() {
+        let root = temp_layout("bad-blobs");
+        let layout = ImageLayout::create(&root).unwrap();
+
+        for digest in ["sha256:../../index.json", "md5:abcd", "sha256:", "sha256"] {
+            assert!(layout.blob_path(&OciDigest::new_unchecked(digest)).is_err());
+        }
+
+        let digest = layout.add_blob(b"content").unwrap();
+        std::fs::write(layout.blob_path(&digest).unwrap(), b"tampered").unwrap();
+        assert!(layout.read_blob(&digest).is_err());
+        assert!(layout.verify_blob(&digest).is_err());
+
+        let other = root.join("other");
+        std::fs::write(&other, b"content").unwrap();
+        std::fs::remove_file(layout.blob_path(&digest).unwrap()).unwrap();
+        std::os::unix::fs::symlink(&other, layout.blob_path(&digest).unwrap()).unwrap();
+        assert!(layout.read_blob(&digest).is_err());
+
+        std::fs::write(
+            root.join(OCI_LAYOUT_FILE),
+            r#"{"imageLayoutVersion":"2.0.0"}"#,
+        )
+        .unwrap();
+        assert!(ImageLayout::open(&root).is_err());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
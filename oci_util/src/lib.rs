@@ -24,6 +24,7 @@
 pub mod digest;
 pub mod distribution;
 pub mod image_reference;
+pub mod layout;
 pub mod layer;
 pub mod models;
 pub mod util;
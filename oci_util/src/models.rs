@@ -57,6 +57,7 @@ pub struct ArtifactManifest {
     pub artifact_type: String,
     pub blobs: Vec<Descriptor>,
     pub subject: Descriptor,
+    #[serde(default)]
     pub annotations: HashMap<String, String>,
 }
 
@@ -111,6 +112,15 @@ pub struct ImageManifestList {
     pub manifests: Vec<ManifestDesc>,
 }
 
+/// A page of the tags of a repository, as returned by `/v2/<name>/tags/list`
+#[derive(PartialEq, Eq, Serialize, Deserialize, Clone, Debug)]
+pub struct TagList {
+    pub name: String,
+    /// Some registries return null instead of an empty list
+    #[serde(default)]
+    pub tags: Option<Vec<String>>,
+}
+
 #[derive(PartialEq, Eq, Serialize, Deserialize, Clone, Debug)]
 pub struct DockerAuthToken {
     // Either token or access_token must exist
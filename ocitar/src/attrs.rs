@@ -0,0 +1,535 @@
+// Copyright (c) 2023 Yan Ka, Chiu.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions
+// are met:
+// 1. Redistributions of source code must retain the above copyright
+//    notice, this list of conditions, and the following disclaimer,
+//    without modification, immediately at the beginning of the file.
+// 2. The name of the author may not be used to endorse or promote products
+//    derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE AUTHOR AND CONTRIBUTORS ``AS IS'' AND
+// ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE AUTHOR OR CONTRIBUTORS BE LIABLE FOR
+// ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+// OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+// HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+// LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
+// OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
+// SUCH DAMAGE.
+//! Extended attributes, NFSv4 ACLs and file flags, and their representation as PAX records
+//!
+//! The records follow the ones used by libarchive and star: extended attributes are stored as
+//! `SCHILY.xattr.{namespace}.{name}`, NFSv4 ACLs as `SCHILY.acl.ace` and file flags, in the form
+//! chflags(1) accepts, as `SCHILY.fflags`
+use crate::tar::Extension;
+use crate::util::cstring;
+use std::path::Path;
+
+pub const XATTR_PREFIX: &str = "SCHILY.xattr.";
+pub const ACL_ACE_KEY: &str = "SCHILY.acl.ace";
+pub const FFLAGS_KEY: &str = "SCHILY.fflags";
+
+/// The metadata of a file not covered by the ustar header
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ExtendedMetadata {
+    /// extended attributes, with names in the form of "{namespace}.{name}"
+    pub xattrs: Vec<(String, Vec<u8>)>,
+    /// non-trivial NFSv4 ACL, entries in the text form separated by commas
+    pub acl: Option<String>,
+    /// file flags in the form chflags(1) accepts, such as "schg,nodump"
+    pub fflags: Option<String>,
+}
+
+impl ExtendedMetadata {
+    pub fn is_empty(&self) -> bool {
+        self.xattrs.is_empty() && self.acl.is_none() && self.fflags.is_none()
+    }
+
+    /// Read the extended metadata of the file at `path`, without following symlinks
+    pub fn read(path: impl AsRef<Path>) -> std::io::Result<ExtendedMetadata> {
+        let path = path.as_ref();
+        Ok(ExtendedMetadata {
+            xattrs: os::read_xattrs(path)?,
+            acl: os::read_acl(path)?,
+            fflags: os::read_fflags(path)?,
+        })
+    }
+
+    /// Collect the extended metadata from the records applying to an entry
+    pub fn from_extensions(extensions: &[Extension]) -> ExtendedMetadata {
+        let mut metadata = ExtendedMetadata::default();
+        for extension in extensions {
+            if let Some(name) = extension.key.strip_prefix(XATTR_PREFIX) {
+                metadata.xattrs.retain(|(key, _)| key != name);
+                metadata
+                    .xattrs
+                    .push((name.to_string(), extension.value.clone()));
+            } else if extension.key == ACL_ACE_KEY {
+                metadata.acl = extension.value_str().map(|acl| acl.to_string());
+            } else if extension.key == FFLAGS_KEY {
+                metadata.fflags = extension.value_str().map(|flags| flags.to_string());
+            }
+        }
+        metadata
+    }
+
+    pub fn to_extensions(&self) -> Vec<Extension> {
+        let mut extensions = Vec::new();
+        for (name, value) in self.xattrs.iter() {
+            extensions.push(Extension::new_binary(
+                format!("{XATTR_PREFIX}{name}"),
+                value.clone(),
+            ));
+        }
+        if let Some(acl) = &self.acl {
+            extensions.push(Extension::new(ACL_ACE_KEY.to_string(), acl.to_string()));
+        }
+        if let Some(fflags) = &self.fflags {
+            extensions.push(Extension::new(FFLAGS_KEY.to_string(), fflags.to_string()));
+        }
+        extensions
+    }
+
+    /// Apply the extended attributes and the ACL to the file at `path`. The file flags are not
+    /// applied, as flags such as `schg` prevent further modifications to the file, see
+    /// `set_fflags`
+    pub fn apply_xattrs_and_acl(&self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        let path = path.as_ref();
+        for (name, value) in self.xattrs.iter() {
+            os::write_xattr(path, name, value)?;
+        }
+        if let Some(acl) = &self.acl {
+            os::write_acl(path, acl)?;
+        }
+        Ok(())
+    }
+}
+
+/// Set the file flags of the file at `path` without following symlinks
+pub fn set_fflags(path: impl AsRef<Path>, fflags: &str) -> std::io::Result<()> {
+    os::write_fflags(path.as_ref(), fflags)
+}
+
+#[cfg(target_os = "freebsd")]
+mod os {
+    use super::cstring;
+    use std::ffi::{c_char, c_int, c_ulong, c_void, CStr, CString};
+    use std::path::Path;
+
+    const EXTATTR_NAMESPACE_USER: c_int = 1;
+    const EXTATTR_NAMESPACE_SYSTEM: c_int = 2;
+    const ACL_TYPE_NFS4: c_int = 4;
+    const ACL_TEXT_APPEND_ID: c_int = 4;
+
+    type Acl = *mut c_void;
+
+    extern "C" {
+        fn extattr_list_link(
+            path: *const c_char,
+            attrnamespace: c_int,
+            data: *mut c_void,
+            nbytes: usize,
+        ) -> isize;
+        fn extattr_get_link(
+            path: *const c_char,
+            attrnamespace: c_int,
+            attrname: *const c_char,
+            data: *mut c_void,
+            nbytes: usize,
+        ) -> isize;
+        fn extattr_set_link(
+            path: *const c_char,
+            attrnamespace: c_int,
+            attrname: *const c_char,
+            data: *const c_void,
+            nbytes: usize,
+        ) -> isize;
+        fn acl_get_link_np(path: *const c_char, tpe: c_int) -> Acl;
+        fn acl_set_link_np(path: *const c_char, tpe: c_int, acl: Acl) -> c_int;
+        fn acl_is_trivial_np(acl: Acl, trivial: *mut c_int) -> c_int;
+        fn acl_to_text_np(acl: Acl, len: *mut isize, flags: c_int) -> *mut c_char;
+        fn acl_from_text(text: *const c_char) -> Acl;
+        fn acl_free(obj: *mut c_void) -> c_int;
+        fn fflagstostr(flags: c_ulong) -> *mut c_char;
+        fn strtofflags(stringp: *mut *mut c_char, setp: *mut c_ulong, clrp: *mut c_ulong) -> c_int;
+        fn lchflags(path: *const c_char, flags: c_ulong) -> c_int;
+    }
+
+    fn namespaces() -> [(c_int, &'static str); 2] {
+        [
+            (EXTATTR_NAMESPACE_USER, "user"),
+            (EXTATTR_NAMESPACE_SYSTEM, "system"),
+        ]
+    }
+
+    pub(super) fn read_xattrs(path: &Path) -> std::io::Result<Vec<(String, Vec<u8>)>> {
+        let cpath = cstring(path)?;
+        let mut xattrs = Vec::new();
+        for (namespace, prefix) in namespaces() {
+            let size =
+                unsafe { extattr_list_link(cpath.as_ptr(), namespace, std::ptr::null_mut(), 0) };
+            // the system namespace is only accessible by root
+            if size <= 0 {
+                continue;
+            }
+            let mut names = vec![0u8; size as usize];
+            let size = unsafe {
+                extattr_list_link(
+                    cpath.as_ptr(),
+                    namespace,
+                    names.as_mut_ptr() as *mut c_void,
+                    names.len(),
+                )
+            };
+            if size < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            names.truncate(size as usize);
+
+            // the names are each prefixed by a single byte length and not nul terminated
+            let mut rem = names.as_slice();
+            while let Some((length, rest)) = rem.split_first() {
+                let (name, rest) = rest.split_at((*length as usize).min(rest.len()));
+                rem = rest;
+                let cname = CString::new(name)?;
+                let size = unsafe {
+                    extattr_get_link(
+                        cpath.as_ptr(),
+                        namespace,
+                        cname.as_ptr(),
+                        std::ptr::null_mut(),
+                        0,
+                    )
+                };
+                if size < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                let mut value = vec![0u8; size as usize];
+                let size = unsafe {
+                    extattr_get_link(
+                        cpath.as_ptr(),
+                        namespace,
+                        cname.as_ptr(),
+                        value.as_mut_ptr() as *mut c_void,
+                        value.len(),
+                    )
+                };
+                if size < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                value.truncate(size as usize);
+                xattrs.push((format!("{prefix}.{}", String::from_utf8_lossy(name)), value));
+            }
+        }
+        Ok(xattrs)
+    }
+
+    pub(super) fn write_xattr(path: &Path, name: &str, value: &[u8]) -> std::io::Result<()> {
+        let Some((namespace, name)) = name.split_once('.').and_then(|(prefix, name)| {
+            namespaces()
+                .into_iter()
+                .find(|(_, p)| *p == prefix)
+                .map(|(namespace, _)| (namespace, name))
+        }) else {
+            log::warn!("skipping extended attribute {name} of unsupported namespace");
+            return Ok(());
+        };
+        let cpath = cstring(path)?;
+        let cname = CString::new(name)?;
+        let written = unsafe {
+            extattr_set_link(
+                cpath.as_ptr(),
+                namespace,
+                cname.as_ptr(),
+                value.as_ptr() as *const c_void,
+                value.len(),
+            )
+        };
+        if written < 0 {
+            Err(std::io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    pub(super) fn read_acl(path: &Path) -> std::io::Result<Option<String>> {
+        let cpath = cstring(path)?;
+        // filesystems without NFSv4 ACL support fail with EINVAL
+        let acl = unsafe { acl_get_link_np(cpath.as_ptr(), ACL_TYPE_NFS4) };
+        if acl.is_null() {
+            return Ok(None);
+        }
+        let mut trivial = 0;
+        let text = unsafe {
+            if acl_is_trivial_np(acl, &mut trivial) != 0 || trivial != 0 {
+                acl_free(acl);
+                return Ok(None);
+            }
+            let text = acl_to_text_np(acl, std::ptr::null_mut(), ACL_TEXT_APPEND_ID);
+            acl_free(acl);
+            if text.is_null() {
+                return Err(std::io::Error::last_os_error());
+            }
+            let string = CStr::from_ptr(text).to_string_lossy().to_string();
+            acl_free(text as *mut c_void);
+            string
+        };
+        Ok(Some(
+            text.split_whitespace()
+                .filter(|entry| !entry.is_empty())
+                .collect::<Vec<_>>()
+                .join(","),
+        ))
+    }
+
+    pub(super) fn write_acl(path: &Path, acl: &str) -> std::io::Result<()> {
+        let cpath = cstring(path)?;
+        let text = CString::new(acl.replace(',', "\n"))?;
+        unsafe {
+            let acl = acl_from_text(text.as_ptr());
+            if acl.is_null() {
+                return Err(std::io::Error::last_os_error());
+            }
+            let result = acl_set_link_np(cpath.as_ptr(), ACL_TYPE_NFS4, acl);
+            acl_free(acl);
+            if result != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    pub(super) fn read_fflags(path: &Path) -> std::io::Result<Option<String>> {
+        let cpath = cstring(path)?;
+        let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+        if unsafe { libc::lstat(cpath.as_ptr(), &mut stat) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        if stat.st_flags == 0 {
+            return Ok(None);
+        }
+        unsafe {
+            let text = fflagstostr(stat.st_flags as c_ulong);
+            if text.is_null() {
+                return Err(std::io::Error::last_os_error());
+            }
+            let flags = CStr::from_ptr(text).to_string_lossy().to_string();
+            libc::free(text as *mut c_void);
+            Ok(Some(flags))
+        }
+    }
+
+    pub(super) fn write_fflags(path: &Path, fflags: &str) -> std::io::Result<()> {
+        let cpath = cstring(path)?;
+        let text = CString::new(fflags)?.into_raw();
+        let mut set: c_ulong = 0;
+        let mut clear: c_ulong = 0;
+        let mut stringp = text;
+        let parsed = unsafe { strtofflags(&mut stringp, &mut set, &mut clear) };
+        drop(unsafe { CString::from_raw(text) });
+        if parsed != 0 {
+            return crate::util::err!("invalid file flags");
+        }
+        if unsafe { lchflags(cpath.as_ptr(), set) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod os {
+    use super::cstring;
+    use std::ffi::{c_void, CString};
+    use std::path::Path;
+
+    /// Only the user namespace is recorded, the others are specific to the host, such as
+    /// security labels, or are covered by the other records
+    const USER_NAMESPACE: &str = "user.";
+
+    pub(super) fn read_xattrs(path: &Path) -> std::io::Result<Vec<(String, Vec<u8>)>> {
+        let cpath = cstring(path)?;
+        let size = unsafe { libc::llistxattr(cpath.as_ptr(), std::ptr::null_mut(), 0) };
+        if size < 0 {
+            let error = std::io::Error::last_os_error();
+            return match error.raw_os_error() {
+                Some(libc::ENOTSUP) => Ok(Vec::new()),
+                _ => Err(error),
+            };
+        }
+        let mut names = vec![0u8; size as usize];
+        let size =
+            unsafe { libc::llistxattr(cpath.as_ptr(), names.as_mut_ptr() as *mut _, names.len()) };
+        if size < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        names.truncate(size as usize);
+
+        let mut xattrs = Vec::new();
+        for name in names.split(|c| *c == 0) {
+            if !name.starts_with(USER_NAMESPACE.as_bytes()) {
+                continue;
+            }
+            let cname = CString::new(name)?;
+            let size =
+                unsafe { libc::lgetxattr(cpath.as_ptr(), cname.as_ptr(), std::ptr::null_mut(), 0) };
+            if size < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            let mut value = vec![0u8; size as usize];
+            let size = unsafe {
+                libc::lgetxattr(
+                    cpath.as_ptr(),
+                    cname.as_ptr(),
+                    value.as_mut_ptr() as *mut c_void,
+                    value.len(),
+                )
+            };
+            if size < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            value.truncate(size as usize);
+            xattrs.push((String::from_utf8_lossy(name).to_string(), value));
+        }
+        Ok(xattrs)
+    }
+
+    pub(super) fn write_xattr(path: &Path, name: &str, value: &[u8]) -> std::io::Result<()> {
+        let cpath = cstring(path)?;
+        let cname = CString::new(name)?;
+        let result = unsafe {
+            libc::lsetxattr(
+                cpath.as_ptr(),
+                cname.as_ptr(),
+                value.as_ptr() as *const c_void,
+                value.len(),
+                0,
+            )
+        };
+        if result != 0 {
+            Err(std::io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    pub(super) fn read_acl(_path: &Path) -> std::io::Result<Option<String>> {
+        Ok(None)
+    }
+
+    pub(super) fn write_acl(path: &Path, _acl: &str) -> std::io::Result<()> {
+        log::warn!("NFSv4 ACL is not supported on this platform, skipping {path:?}");
+        Ok(())
+    }
+
+    pub(super) fn read_fflags(_path: &Path) -> std::io::Result<Option<String>> {
+        Ok(None)
+    }
+
+    pub(super) fn write_fflags(path: &Path, _fflags: &str) -> std::io::Result<()> {
+        log::warn!("file flags are not supported on this platform, skipping {path:?}");
+        Ok(())
+    }
+}
+
+#[cfg(not(any(target_os = "freebsd", target_os = "linux")))]
+mod os {
+    use std::path::Path;
+
+    pub(super) fn read_xattrs(_path: &Path) -> std::io::Result<Vec<(String, Vec<u8>)>> {
+        Ok(Vec::new())
+    }
+
+    pub(super) fn write_xattr(path: &Path, _name: &str, _value: &[u8]) -> std::io::Result<()> {
+        log::warn!("extended attributes are not supported on this platform, skipping {path:?}");
+        Ok(())
+    }
+
+    pub(super) fn read_acl(_path: &Path) -> std::io::Result<Option<String>> {
+        Ok(None)
+    }
+
+    pub(super) fn write_acl(path: &Path, _acl: &str) -> std::io::Result<()> {
+        log::warn!("NFSv4 ACL is not supported on this platform, skipping {path:?}");
+        Ok(())
+    }
+
+    pub(super) fn read_fflags(_path: &Path) -> std::io::Result<Option<String>> {
+        Ok(None)
+    }
+
+    pub(super) fn write_fflags(path: &Path, _fflags: &str) -> std::io::Result<()> {
+        log::warn!("file flags are not supported on this platform, skipping {path:?}");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extensions_round_trip() -> std::io::Result<()> {
+        let metadata = ExtendedMetadata {
+            xattrs: vec![("user.binary".to_string(), vec![0xff, 0, 0x10, b'\n'])],
+            acl: Some("owner@:rwxp--aARWcCos:-------:allow".to_string()),
+            fflags: Some("schg".to_string()),
+        };
+        let extensions = metadata.to_extensions();
+        let encoded = extensions
+            .iter()
+            .flat_map(|extension| extension.encoded())
+            .collect::<Vec<_>>();
+        let decoded = Extension::parse(&encoded)?;
+        assert_eq!(decoded, extensions);
+        assert_eq!(ExtendedMetadata::from_extensions(&decoded), metadata);
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_xattrs_in_layer() -> std::io::Result<()> {
+        use crate::compression::CompressionType;
+        use crate::layer::{create_layer, extract_layer_confined, CreateOptions};
+
+        crate::util::with_stage_dir("xattr", |stage| {
+            let source = stage.join("source");
+            let target = stage.join("target");
+            std::fs::create_dir_all(source.join("dir"))?;
+            std::fs::write(source.join("dir/file"), "content")?;
+            let value = vec![0xff, 0, 0x10, b'\n'];
+            if let Err(error) = os::write_xattr(&source.join("dir/file"), "user.binary", &value) {
+                eprintln!("filesystem does not support extended attributes: {error}");
+                return Ok(());
+            }
+
+            let mut archive = Vec::new();
+            create_layer(
+                &CreateOptions {
+                    without_ext: true,
+                    ..CreateOptions::default()
+                },
+                source.to_str(),
+                &["dir".to_string()],
+                &[],
+                &mut archive,
+            )?;
+
+            std::fs::create_dir_all(&target)?;
+            extract_layer_confined(archive.as_slice(), CompressionType::Auto, &target)
+                .map_err(|error| std::io::Error::other(error.to_string()))?;
+
+            assert_eq!(std::fs::read_to_string(target.join("dir/file"))?, "content");
+            assert_eq!(
+                ExtendedMetadata::read(target.join("dir/file"))?.xattrs,
+                vec![("user.binary".to_string(), value)]
+            );
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,141 @@
+// Copyright (c) 2023 Yan Ka, Chiu.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions
+// are met:
+// 1. Redistributions of source code must retain the above copyright
+//    notice, this list of conditions, and the following disclaimer,
+//    without modification, immediately at the beginning of the file.
+// 2. The name of the author may not be used to endorse or promote products
+//    derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE AUTHOR AND CONTRIBUTORS ``AS IS'' AND
+// ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE AUTHOR OR CONTRIBUTORS BE LIABLE FOR
+// ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+// OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+// HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+// LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
+// OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
+// SUCH DAMAGE.
+use crate::seekable::SeekableEncoder;
+use crate::util::PrebufferedSource;
+use std::io::{Read, Write};
+use zstd::{Decoder as ZstdDecoder, Encoder as ZstdEncoder};
+
+pub const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+pub const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum CompressionType {
+    #[default]
+    Auto,
+    None,
+    Zstd,
+    Gzip,
+}
+
+impl std::str::FromStr for CompressionType {
+    type Err = std::io::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Self::Auto),
+            "none" => Ok(Self::None),
+            "zstd" => Ok(Self::Zstd),
+            "gzip" => Ok(Self::Gzip),
+            _ => Err(std::io::Error::other("unknown value")),
+        }
+    }
+}
+
+/// Wrap `input` with a decoder of the compression type. If `hint` is `Auto`, the type of
+/// compression is guessed by the first 4 bytes of the stream
+pub fn prepare_compressed_stream_reader<'a>(
+    mut input: Box<dyn Read + 'a>,
+    hint: CompressionType,
+) -> Result<Box<dyn Read + 'a>, std::io::Error> {
+    match hint {
+        CompressionType::Auto => {
+            let mut check_magic = [0u8; 4];
+            input.read_exact(&mut check_magic)?;
+            if check_magic == ZSTD_MAGIC {
+                Ok(Box::new(ZstdDecoder::new(PrebufferedSource::new(
+                    &check_magic,
+                    input,
+                ))?))
+            } else if check_magic[0..2] == GZIP_MAGIC {
+                Ok(Box::new(flate2::read::GzDecoder::new(
+                    PrebufferedSource::new(&check_magic, input),
+                )))
+            } else {
+                Ok(Box::new(PrebufferedSource::new(&check_magic, input)))
+            }
+        }
+        CompressionType::None => Ok(input),
+        CompressionType::Zstd => Ok(Box::new(ZstdDecoder::new(input)?)),
+        CompressionType::Gzip => Ok(Box::new(flate2::read::GzDecoder::new(input))),
+    }
+}
+
+/// Parameters of zstd compression
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ZstdOptions {
+    pub level: i32,
+    /// Number of worker threads, 0 compresses in the calling thread
+    pub workers: u32,
+    /// If set, write the seekable format with frames of this many bytes of uncompressed data,
+    /// see `crate::seekable`
+    pub seekable_frame_size: Option<usize>,
+}
+
+impl Default for ZstdOptions {
+    fn default() -> ZstdOptions {
+        ZstdOptions {
+            level: 3,
+            workers: 0,
+            seekable_frame_size: None,
+        }
+    }
+}
+
+/// Wrap `output` with an encoder of the compression type, `Auto` is treated as no compression.
+/// The encoder finishes the compressed stream when dropped
+pub fn prepare_compressed_stream_writer<'a, W: Write + 'a>(
+    output: W,
+    compression: CompressionType,
+) -> Result<Box<dyn Write + 'a>, std::io::Error> {
+    prepare_compressed_stream_writer_with(output, compression, &ZstdOptions::default())
+}
+
+/// Same as `prepare_compressed_stream_writer`, with the parameters of zstd compression
+pub fn prepare_compressed_stream_writer_with<'a, W: Write + 'a>(
+    output: W,
+    compression: CompressionType,
+    zstd: &ZstdOptions,
+) -> Result<Box<dyn Write + 'a>, std::io::Error> {
+    match compression {
+        CompressionType::Zstd => match zstd.seekable_frame_size {
+            Some(frame_size) => Ok(Box::new(SeekableEncoder::new(
+                output,
+                zstd.level,
+                zstd.workers as usize,
+                frame_size,
+            )?)),
+            None => {
+                let mut encoder = ZstdEncoder::new(output, zstd.level)?;
+                if zstd.workers > 0 {
+                    encoder.multithread(zstd.workers)?;
+                }
+                Ok(Box::new(encoder.auto_finish()))
+            }
+        },
+        CompressionType::Gzip => Ok(Box::new(flate2::write::GzEncoder::new(
+            output,
+            flate2::Compression::default(),
+        ))),
+        _ => Ok(Box::new(output)),
+    }
+}
@@ -0,0 +1,672 @@
+// Copyright (c) 2023 Yan Ka, Chiu.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions
+// are met:
+// 1. Redistributions of source code must retain the above copyright
+//    notice, this list of conditions, and the following disclaimer,
+//    without modification, immediately at the beginning of the file.
+// 2. The name of the author may not be used to endorse or promote products
+//    derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE AUTHOR AND CONTRIBUTORS ``AS IS'' AND
+// ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE AUTHOR OR CONTRIBUTORS BE LIABLE FOR
+// ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+// OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+// HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+// LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
+// OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
+// SUCH DAMAGE.
+//! Extraction of layers with every entry confined in the target directory
+use crate::attrs::set_fflags;
+use crate::idmap::OwnerMap;
+use crate::sparse::ContentSegments;
+use crate::tar::{TarEntry, TarEntryHandle, WhiteoutExtension};
+use crate::util::cstring;
+use std::collections::VecDeque;
+use std::ffi::OsString;
+use std::path::{Component, Path, PathBuf};
+use thiserror::Error;
+
+/// Maximum number of symlinks to follow when resolving a path
+const MAX_REDIRECT: usize = 256;
+
+/// An entry that attempts to reach outside of the target directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Violation {
+    /// The entry resolves to a location outside of the root
+    PathEscapesRoot(PathBuf),
+    /// Resolving the entry exceeded the maximum number of symlink redirections
+    TooManySymlinks(PathBuf),
+    /// The symlink entry points to a location outside of the root
+    SymlinkEscapesRoot { path: PathBuf, target: PathBuf },
+    /// The hardlink entry links to a file outside of the root
+    HardlinkEscapesRoot { path: PathBuf, target: PathBuf },
+}
+
+impl std::fmt::Display for Violation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::PathEscapesRoot(path) => write!(f, "{path:?} escapes the root"),
+            Self::TooManySymlinks(path) => write!(f, "{path:?} has too many levels of symlinks"),
+            Self::SymlinkEscapesRoot { path, target } => {
+                write!(
+                    f,
+                    "symlink {path:?} points to {target:?} outside of the root"
+                )
+            }
+            Self::HardlinkEscapesRoot { path, target } => {
+                write!(
+                    f,
+                    "hardlink {path:?} links to {target:?} outside of the root"
+                )
+            }
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ExtractError {
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("{} entries violated root confinement: {}", .0.len(), .0.iter().map(|v| v.to_string()).collect::<Vec<_>>().join(", "))]
+    Violations(Vec<Violation>),
+}
+
+enum PathComp {
+    RootDir,
+    CurDir,
+    ParentDir,
+    Normal(OsString),
+}
+
+impl<'a> From<Component<'a>> for PathComp {
+    fn from(c: Component<'a>) -> PathComp {
+        match c {
+            Component::RootDir => Self::RootDir,
+            Component::ParentDir => Self::ParentDir,
+            Component::Normal(osstr) => Self::Normal(osstr.to_os_string()),
+            Component::CurDir | Component::Prefix(_) => Self::CurDir,
+        }
+    }
+}
+
+enum Resolved {
+    Path(PathBuf),
+    Violation(Violation),
+}
+
+/// Resolve `path` as if `root` is the root directory. Symlinks in the intermediate components
+/// are followed within `root`, and the last component is followed only if `follow_last` is set.
+/// Both absolute and relative paths are treated as relative to `root`.
+fn resolve_in_root(root: &Path, path: &Path, follow_last: bool) -> std::io::Result<Resolved> {
+    let mut current = PathBuf::new();
+    let mut real_path = root.to_path_buf();
+    let mut redirected = 0;
+
+    let mut components: VecDeque<_> = path.components().map(PathComp::from).collect();
+
+    while let Some(head) = components.pop_front() {
+        if redirected >= MAX_REDIRECT {
+            return Ok(Resolved::Violation(Violation::TooManySymlinks(
+                path.to_path_buf(),
+            )));
+        }
+        match head {
+            PathComp::RootDir => {
+                current = PathBuf::new();
+                real_path = root.to_path_buf();
+            }
+            PathComp::CurDir => continue,
+            PathComp::ParentDir => {
+                if !current.pop() {
+                    return Ok(Resolved::Violation(Violation::PathEscapesRoot(
+                        path.to_path_buf(),
+                    )));
+                }
+                real_path.pop();
+            }
+            PathComp::Normal(ent) => {
+                let try_path = real_path.join(&ent);
+                let is_last = components.is_empty();
+                if try_path.is_symlink() && (follow_last || !is_last) {
+                    redirected += 1;
+                    let link = try_path.read_link()?;
+                    for component in link.components().rev() {
+                        components.push_front(PathComp::from(component));
+                    }
+                } else {
+                    real_path.push(&ent);
+                    current.push(&ent);
+                }
+            }
+        }
+    }
+    Ok(Resolved::Path(real_path))
+}
+
+/// Check if a symlink at `path` (relative to root) pointing to a relative `target` reaches
+/// outside of the root. Absolute targets are interpreted relative to the root and never escape.
+fn symlink_escapes(path: &Path, target: &Path) -> bool {
+    if target.is_absolute() {
+        return false;
+    }
+    let mut depth = 0usize;
+    let parent = path.parent().unwrap_or_else(|| Path::new(""));
+    for component in parent.components().chain(target.components()) {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::ParentDir => {
+                if depth == 0 {
+                    return true;
+                }
+                depth -= 1;
+            }
+            Component::RootDir => depth = 0,
+            _ => (),
+        }
+    }
+    false
+}
+
+fn set_times(path: &Path, mtime: i64, mtime_nsec: u32) -> std::io::Result<()> {
+    let cpath = cstring(path)?;
+    let time = libc::timespec {
+        tv_sec: mtime as libc::time_t,
+        tv_nsec: mtime_nsec as _,
+    };
+    let times = [time, time];
+    let ret = unsafe {
+        libc::utimensat(
+            libc::AT_FDCWD,
+            cpath.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    };
+    if ret != 0 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Apply the mode and modification time of a deferred directory through a file descriptor, such
+/// that a symlink taking the place of the directory is never followed
+fn apply_directory(dir: &DeferredDirectory) -> std::io::Result<()> {
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+    use std::os::unix::io::AsRawFd;
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_DIRECTORY | libc::O_NOFOLLOW)
+        .open(&dir.path)?;
+    file.set_permissions(std::fs::Permissions::from_mode(dir.mode))?;
+    let time = libc::timespec {
+        tv_sec: dir.mtime as libc::time_t,
+        tv_nsec: dir.mtime_nsec as _,
+    };
+    let times = [time, time];
+    if unsafe { libc::futimens(file.as_raw_fd(), times.as_ptr()) } != 0 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Remove whatever at `path` without following symlinks, directories are removed recursively
+fn remove_entry(path: &Path) -> std::io::Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(error) => Err(error),
+        Ok(meta) if meta.is_dir() => std::fs::remove_dir_all(path),
+        Ok(_) => std::fs::remove_file(path),
+    }
+}
+
+/// The regular file being written
+struct CurrentFile {
+    file: std::fs::File,
+    path: PathBuf,
+    entry: TarEntry,
+    content: ContentSegments,
+}
+
+struct DeferredDirectory {
+    path: PathBuf,
+    mode: u32,
+    mtime: i64,
+    mtime_nsec: u32,
+}
+
+/// A `TarEntryHandle` extracting the entries by itself instead of passing them to tar(1), where
+/// every entry, link target and whiteout is resolved within `root`
+pub struct ConfinedExtractTar {
+    root: PathBuf,
+    violations: Vec<Violation>,
+    current: Option<CurrentFile>,
+    /// the metadata of directories are applied after all entries are extracted, such that
+    /// read-only directories and modification times are not affected by the entries within them
+    directories: Vec<DeferredDirectory>,
+    /// file flags such as `schg` prevent further modification to the files, they are applied
+    /// after everything else
+    fflags: Vec<(PathBuf, String)>,
+    set_owner: bool,
+    owner_map: OwnerMap,
+}
+
+impl ConfinedExtractTar {
+    pub fn new(root: impl AsRef<Path>) -> ConfinedExtractTar {
+        ConfinedExtractTar {
+            root: root.as_ref().to_path_buf(),
+            violations: Vec::new(),
+            current: None,
+            directories: Vec::new(),
+            fflags: Vec::new(),
+            set_owner: unsafe { libc::geteuid() } == 0,
+            owner_map: OwnerMap::default(),
+        }
+    }
+
+    /// Map the ownership of the entries with `owner_map`
+    pub fn with_owner_map(mut self, owner_map: OwnerMap) -> ConfinedExtractTar {
+        self.owner_map = owner_map;
+        self
+    }
+
+    /// Apply the deferred directory metadata, and report the violations found during extraction
+    pub fn finish(mut self) -> Result<(), ExtractError> {
+        // apply the deepest directories first
+        self.directories
+            .sort_by_key(|dir| std::cmp::Reverse(dir.path.components().count()));
+        for dir in self.directories.iter() {
+            apply_directory(dir)?;
+        }
+        for (path, fflags) in self.fflags.iter() {
+            if let Err(error) = set_fflags(path, fflags) {
+                log::warn!("cannot set file flags {fflags} on {path:?}: {error}");
+            }
+        }
+
+        if self.violations.is_empty() {
+            Ok(())
+        } else {
+            Err(ExtractError::Violations(self.violations))
+        }
+    }
+
+    pub fn violations(&self) -> &[Violation] {
+        &self.violations
+    }
+
+    fn resolve(&mut self, path: &Path, follow_last: bool) -> std::io::Result<Option<PathBuf>> {
+        match resolve_in_root(&self.root, path, follow_last)? {
+            Resolved::Path(path) => Ok(Some(path)),
+            Resolved::Violation(violation) => {
+                log::warn!("{violation}");
+                self.violations.push(violation);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Drop the deferred metadata of `path` and everything under it, as they are about to be
+    /// replaced by later entries. Otherwise the metadata could be applied through a symlink
+    /// taking the place of a directory
+    fn forget_deferred(&mut self, path: &Path) {
+        self.directories.retain(|dir| !dir.path.starts_with(path));
+        self.fflags
+            .retain(|(flagged, _)| !flagged.starts_with(path));
+    }
+
+    fn remove_whiteout(&mut self, path: &Path) -> std::io::Result<()> {
+        if let Some(real_path) = self.resolve(path, false)? {
+            if real_path != self.root {
+                self.forget_deferred(&real_path);
+                remove_entry(&real_path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply the extended attributes and ACL of the entry, and defer the file flags. Failing to
+    /// apply them, for example due to the lack of privilege or filesystem support, is not fatal
+    fn apply_extended_metadata(&mut self, path: &Path, entry: &TarEntry) {
+        let metadata = entry.extended_metadata();
+        if let Err(error) = metadata.apply_xattrs_and_acl(path) {
+            log::warn!("cannot apply extended attributes or ACL on {path:?}: {error}");
+        }
+        if let Some(fflags) = metadata.fflags {
+            self.fflags.push((path.to_path_buf(), fflags));
+        }
+    }
+
+    fn apply_metadata(
+        &mut self,
+        path: &Path,
+        entry: &TarEntry,
+        is_symlink: bool,
+    ) -> std::io::Result<()> {
+        if self.set_owner {
+            std::os::unix::fs::lchown(path, Some(entry.uid), Some(entry.gid))?;
+        }
+        if !is_symlink {
+            std::fs::set_permissions(
+                path,
+                std::os::unix::fs::PermissionsExt::from_mode(entry.mode & 0o7777),
+            )?;
+            self.apply_extended_metadata(path, entry);
+        }
+        set_times(path, entry.mtime, entry.mtime_nsec)
+    }
+
+    fn finish_current(&mut self) -> std::io::Result<()> {
+        if let Some(current) = self.current.take() {
+            // the holes at the end of a sparse file are not covered by any segment
+            current.file.set_len(current.content.real_size)?;
+            drop(current.file);
+            self.apply_metadata(&current.path, &current.entry, false)?;
+        }
+        Ok(())
+    }
+}
+
+impl TarEntryHandle for ConfinedExtractTar {
+    fn on_whiteout_extension(&mut self, extension: WhiteoutExtension) -> std::io::Result<()> {
+        for whiteout in extension.whiteouts {
+            self.remove_whiteout(Path::new(&whiteout))?;
+        }
+        Ok(())
+    }
+
+    fn on_oci_whiteout_entry(&mut self, path: PathBuf) -> std::io::Result<()> {
+        self.remove_whiteout(&path)
+    }
+
+    fn on_oci_whiteout_opq(&mut self, path: PathBuf) -> std::io::Result<()> {
+        if let Some(real_path) = self.resolve(&path, true)? {
+            if let Ok(dirents) = std::fs::read_dir(real_path) {
+                for dirent in dirents {
+                    let path = dirent?.path();
+                    self.forget_deferred(&path);
+                    remove_entry(&path)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn on_normal_entry(&mut self, _buf: &[u8; 512], entry: &TarEntry) -> std::io::Result<()> {
+        self.finish_current()?;
+
+        let mapped;
+        let entry = if self.owner_map.is_identity() {
+            entry
+        } else {
+            mapped = self.owner_map.map_entry(entry)?;
+            &mapped
+        };
+
+        let Some(real_path) = self.resolve(&entry.path, false)? else {
+            return Ok(());
+        };
+
+        let tpe = entry.header.entry_type();
+
+        if real_path == self.root {
+            if tpe == b'5' {
+                self.directories.push(DeferredDirectory {
+                    path: real_path,
+                    mode: entry.mode & 0o7777,
+                    mtime: entry.mtime,
+                    mtime_nsec: entry.mtime_nsec,
+                });
+            }
+            return Ok(());
+        }
+
+        if let Some(parent) = real_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        if tpe != b'5' {
+            self.forget_deferred(&real_path);
+        }
+
+        match tpe {
+            b'5' => {
+                match std::fs::symlink_metadata(&real_path) {
+                    Ok(meta) if meta.is_dir() => (),
+                    Ok(_) => {
+                        std::fs::remove_file(&real_path)?;
+                        std::fs::create_dir(&real_path)?;
+                    }
+                    Err(_) => std::fs::create_dir(&real_path)?,
+                }
+                if self.set_owner {
+                    std::os::unix::fs::lchown(&real_path, Some(entry.uid), Some(entry.gid))?;
+                }
+                self.apply_extended_metadata(&real_path, entry);
+                self.directories.push(DeferredDirectory {
+                    path: real_path,
+                    mode: entry.mode & 0o7777,
+                    mtime: entry.mtime,
+                    mtime_nsec: entry.mtime_nsec,
+                });
+            }
+            b'2' => {
+                let Some(target) = entry.link_path.clone() else {
+                    return crate::util::err!("symlink entry without target");
+                };
+                if symlink_escapes(&entry.path, &target) {
+                    let violation = Violation::SymlinkEscapesRoot {
+                        path: entry.path.clone(),
+                        target,
+                    };
+                    log::warn!("{violation}");
+                    self.violations.push(violation);
+                    return Ok(());
+                }
+                remove_entry(&real_path)?;
+                std::os::unix::fs::symlink(&target, &real_path)?;
+                self.apply_metadata(&real_path, entry, true)?;
+            }
+            b'1' => {
+                let Some(target) = entry.link_path.clone() else {
+                    return crate::util::err!("hardlink entry without target");
+                };
+                let real_target = match resolve_in_root(&self.root, &target, false)? {
+                    Resolved::Path(path) => path,
+                    Resolved::Violation(_) => {
+                        let violation = Violation::HardlinkEscapesRoot {
+                            path: entry.path.clone(),
+                            target,
+                        };
+                        log::warn!("{violation}");
+                        self.violations.push(violation);
+                        return Ok(());
+                    }
+                };
+                if real_target != real_path {
+                    remove_entry(&real_path)?;
+                    std::fs::hard_link(&real_target, &real_path)?;
+                }
+            }
+            b'3' | b'4' | b'6' => {
+                remove_entry(&real_path)?;
+                let cpath = cstring(&real_path)?;
+                let file_type = match tpe {
+                    b'3' => libc::S_IFCHR,
+                    b'4' => libc::S_IFBLK,
+                    _ => libc::S_IFIFO,
+                };
+                let (major, minor) = entry.header.device_numbers()?;
+                let dev = libc::makedev(major as _, minor as _);
+                let mode = file_type | (entry.mode & 0o7777) as libc::mode_t;
+                if unsafe { libc::mknod(cpath.as_ptr(), mode, dev) } != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                self.apply_metadata(&real_path, entry, false)?;
+            }
+            b'0' | b'\0' | b'7' | b'S' => {
+                remove_entry(&real_path)?;
+                let file = std::fs::OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .open(&real_path)?;
+                self.current = Some(CurrentFile {
+                    file,
+                    path: real_path,
+                    entry: entry.clone(),
+                    content: ContentSegments::new(entry),
+                });
+                if entry.size == 0 {
+                    self.finish_current()?;
+                }
+            }
+            tpe => {
+                log::warn!(
+                    "skipping entry {:?} of unsupported type {}",
+                    entry.path,
+                    tpe as char
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    fn on_normal_entry_block(&mut self, buf: &[u8; 512], _entry: &TarEntry) -> std::io::Result<()> {
+        if let Some(current) = self.current.as_mut() {
+            use std::os::unix::fs::FileExt;
+            let file = &current.file;
+            current
+                .content
+                .read_block(buf, |offset, data| file.write_all_at(data, offset))?;
+            if current.content.is_done() {
+                self.finish_current()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tar::{summarize_entries, write_entry_header, write_oci_whiteouts, RawTarHeader};
+    use crate::util::with_stage_dir;
+
+    fn write_entry(archive: &mut Vec<u8>, path: &str, tpe: u8, link: &str, content: &[u8]) {
+        let mut header = RawTarHeader::empty_ustar();
+        header.set_mode(0o644);
+        header.set_uid_gid();
+        header.set_lastmod(0);
+        header.set_entry_type(tpe);
+        header.set_link_path(link);
+        write_entry_header(archive, header, path, content.len() as u64).unwrap();
+        for chunk in content.chunks(512) {
+            let mut block = [0u8; 512];
+            block[..chunk.len()].copy_from_slice(chunk);
+            archive.extend_from_slice(&block);
+        }
+    }
+
+    fn write_directory(archive: &mut Vec<u8>, path: &str, mode: u32) {
+        let mut header = RawTarHeader::empty_ustar();
+        header.set_mode(mode);
+        header.set_uid_gid();
+        header.set_lastmod(0);
+        header.set_entry_type(b'5');
+        write_entry_header(archive, header, path, 0).unwrap();
+    }
+
+    #[test]
+    fn test_directory_replaced_by_symlink() -> std::io::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+        with_stage_dir("confined-replaced-dir", |stage| {
+            let root = stage.join("root");
+            let victim = stage.join("victim");
+            std::fs::create_dir_all(&root)?;
+            std::fs::create_dir_all(victim.join("b"))?;
+            std::fs::set_permissions(&victim, std::fs::Permissions::from_mode(0o700))?;
+            std::fs::set_permissions(victim.join("b"), std::fs::Permissions::from_mode(0o700))?;
+            let victim = std::fs::canonicalize(&victim)?;
+
+            let mut archive = Vec::new();
+            write_directory(&mut archive, "a/", 0o777);
+            write_directory(&mut archive, "a/b/", 0o777);
+            write_entry(&mut archive, "a", b'2', victim.to_str().unwrap(), b"");
+            archive.extend_from_slice(&[0u8; 1024]);
+
+            let mut extractor = ConfinedExtractTar::new(&root);
+            summarize_entries(archive.as_slice(), &mut extractor)?;
+            extractor.finish().unwrap();
+
+            assert!(root.join("a").is_symlink());
+            let mode = |path: &Path| {
+                std::fs::metadata(path).map(|meta| meta.permissions().mode() & 0o7777)
+            };
+            assert_eq!(mode(&victim)?, 0o700);
+            assert_eq!(mode(&victim.join("b"))?, 0o700);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_confined_extraction() -> std::io::Result<()> {
+        with_stage_dir("confined-escape", |stage| {
+            let root = stage.join("root");
+            std::fs::create_dir_all(&root)?;
+            std::fs::write(stage.join("outside"), "secret")?;
+
+            let mut archive = Vec::new();
+            write_entry(&mut archive, "../outside", b'0', "", b"pwned");
+            write_entry(&mut archive, "/abs.txt", b'0', "", b"absolute");
+            write_entry(&mut archive, "escape", b'2', "../../outside", b"");
+            write_entry(&mut archive, "etc", b'2', "/real_etc", b"");
+            write_entry(&mut archive, "etc/passwd", b'0', "", b"root");
+            write_entry(&mut archive, "hl", b'1', "../outside", b"");
+            write_entry(&mut archive, "up", b'2', "/..", b"");
+            write_entry(&mut archive, "up/evil", b'0', "", b"pwned");
+            write_oci_whiteouts("../outside".to_string(), None, &mut archive)?;
+            archive.extend_from_slice(&[0u8; 1024]);
+
+            let mut extractor = ConfinedExtractTar::new(&root);
+            summarize_entries(archive.as_slice(), &mut extractor)?;
+            let Err(ExtractError::Violations(violations)) = extractor.finish() else {
+                panic!("expected violations");
+            };
+
+            assert_eq!(
+                violations,
+                vec![
+                    Violation::PathEscapesRoot(PathBuf::from("../outside")),
+                    Violation::SymlinkEscapesRoot {
+                        path: PathBuf::from("escape"),
+                        target: PathBuf::from("../../outside")
+                    },
+                    Violation::HardlinkEscapesRoot {
+                        path: PathBuf::from("hl"),
+                        target: PathBuf::from("../outside")
+                    },
+                    Violation::PathEscapesRoot(PathBuf::from("up/evil")),
+                    Violation::PathEscapesRoot(PathBuf::from("../outside")),
+                ]
+            );
+
+            assert_eq!(std::fs::read_to_string(stage.join("outside"))?, "secret");
+            assert_eq!(std::fs::read_to_string(root.join("abs.txt"))?, "absolute");
+            assert_eq!(
+                std::fs::read_to_string(root.join("real_etc/passwd"))?,
+                "root"
+            );
+            assert!(!root.join("escape").exists());
+            assert!(!root.join("hl").exists());
+            Ok(())
+        })
+    }
+}
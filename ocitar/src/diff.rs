@@ -0,0 +1,392 @@
+// Copyright (c) 2023 Yan Ka, Chiu.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions
+// are met:
+// 1. Redistributions of source code must retain the above copyright
+//    notice, this list of conditions, and the following disclaimer,
+//    without modification, immediately at the beginning of the file.
+// 2. The name of the author may not be used to endorse or promote products
+//    derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE AUTHOR AND CONTRIBUTORS ``AS IS'' AND
+// ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE AUTHOR OR CONTRIBUTORS BE LIABLE FOR
+// ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+// OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+// HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+// LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
+// OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
+// SUCH DAMAGE.
+//! Compare the trees two stacks of layers build
+use crate::attrs::ExtendedMetadata;
+use crate::compression::CompressionType;
+use crate::layer::visit_layer;
+use crate::sparse::ContentSegments;
+use crate::tar::{EntryRecord, EntryType, TarEntry, TarEntryHandle, WhiteoutExtension};
+use crate::tree::LayerTree;
+use crate::util::{hex, normalize_entry_path};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+/// An entry of the tree built by a stack of layers
+#[derive(Debug, Clone)]
+struct Node {
+    record: EntryRecord,
+    metadata: ExtendedMetadata,
+    /// sha256 digest of the content if the entry is a regular file
+    content: Option<[u8; 32]>,
+}
+
+/// Hash `until - position` zeros, the holes of a sparse file
+fn hash_zeros(hasher: &mut Sha256, position: &mut u64, until: u64) {
+    let zeros = [0u8; 4096];
+    while *position < until {
+        let n = (until - *position).min(zeros.len() as u64);
+        hasher.update(&zeros[..n as usize]);
+        *position += n;
+    }
+}
+
+/// Hash the content of a regular file as it would be extracted, such that sparse entries are
+/// the same as the regular entries of the same content
+struct ContentHasher {
+    hasher: Sha256,
+    content: ContentSegments,
+    /// the offset in the file hashed so far
+    position: u64,
+}
+
+impl ContentHasher {
+    fn new(entry: &TarEntry) -> ContentHasher {
+        ContentHasher {
+            hasher: Sha256::new(),
+            content: ContentSegments::new(entry),
+            position: 0,
+        }
+    }
+
+    fn update(&mut self, buf: &[u8; 512]) -> std::io::Result<()> {
+        let (hasher, position) = (&mut self.hasher, &mut self.position);
+        self.content.read_block(buf, |offset, data| {
+            hash_zeros(hasher, position, offset);
+            hasher.update(data);
+            *position += data.len() as u64;
+            Ok(())
+        })
+    }
+
+    fn finalize(mut self) -> [u8; 32] {
+        hash_zeros(&mut self.hasher, &mut self.position, self.content.real_size);
+        self.hasher.finalize().into()
+    }
+}
+
+/// The whiteouts and entries of a layer, in the order they appear in the archive
+#[derive(Default)]
+struct LayerChanges {
+    whiteouts: Vec<PathBuf>,
+    opaques: Vec<PathBuf>,
+    entries: Vec<(PathBuf, Node)>,
+    /// the digest of the content of the last entry
+    hashing: Option<ContentHasher>,
+}
+
+impl LayerChanges {
+    fn finish_entry(&mut self) {
+        if let Some(hasher) = self.hashing.take() {
+            if let Some((_, node)) = self.entries.last_mut() {
+                node.content = Some(hasher.finalize());
+            }
+        }
+    }
+}
+
+impl TarEntryHandle for LayerChanges {
+    fn on_whiteout_extension(&mut self, extension: WhiteoutExtension) -> std::io::Result<()> {
+        for whiteout in extension.whiteouts.iter() {
+            self.whiteouts
+                .push(normalize_entry_path(Path::new(whiteout)));
+        }
+        Ok(())
+    }
+
+    fn on_oci_whiteout_entry(&mut self, path: PathBuf) -> std::io::Result<()> {
+        self.whiteouts.push(normalize_entry_path(&path));
+        Ok(())
+    }
+
+    fn on_oci_whiteout_opq(&mut self, path: PathBuf) -> std::io::Result<()> {
+        self.opaques.push(normalize_entry_path(&path));
+        Ok(())
+    }
+
+    fn on_normal_entry(&mut self, _buf: &[u8; 512], entry: &TarEntry) -> std::io::Result<()> {
+        self.finish_entry();
+        let record = EntryRecord::from_entry(entry);
+        if record.entry_type == EntryType::Regular {
+            self.hashing = Some(ContentHasher::new(entry));
+        }
+        let node = Node {
+            record,
+            metadata: entry.extended_metadata(),
+            content: None,
+        };
+        self.entries.push((normalize_entry_path(&entry.path), node));
+        Ok(())
+    }
+
+    fn on_normal_entry_block(&mut self, buf: &[u8; 512], _entry: &TarEntry) -> std::io::Result<()> {
+        if let Some(hasher) = self.hashing.as_mut() {
+            hasher.update(buf)?;
+        }
+        Ok(())
+    }
+}
+
+/// Build the tree of applying `layers`, from the bottom to the top, in sequence
+fn build_tree<P: AsRef<Path>>(layers: &[P]) -> std::io::Result<BTreeMap<PathBuf, Node>> {
+    let mut tree = LayerTree::default();
+    for layer in layers {
+        let mut changes = LayerChanges::default();
+        visit_layer(File::open(layer)?, CompressionType::Auto, &mut changes)?;
+        changes.finish_entry();
+
+        tree.apply_whiteouts(&changes.whiteouts, &changes.opaques);
+        for (path, node) in changes.entries {
+            let is_dir = node.record.entry_type == EntryType::Directory;
+            tree.apply_entry(path, is_dir, node);
+        }
+    }
+    Ok(tree.into_entries())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Modified,
+}
+
+/// A difference in an attribute of an entry
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Delta {
+    /// name of the attribute, such as "mode" or "content"
+    pub field: String,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+/// A path added, removed or modified between two trees
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Change {
+    /// path of the entry, "." for the root directory
+    pub path: String,
+    pub kind: ChangeKind,
+    /// type of the entry, of the old one if it is removed
+    #[serde(rename = "type")]
+    pub entry_type: EntryType,
+    /// the attributes differ, only present on modified entries
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deltas: Vec<Delta>,
+}
+
+/// The attributes of a node compared, as displayed in the deltas
+fn attributes(node: &Node) -> Vec<(&'static str, Option<String>)> {
+    let record = &node.record;
+    let xattrs = node
+        .metadata
+        .xattrs
+        .iter()
+        .map(|(name, value)| format!("{name}={}", hex(value)))
+        .collect::<Vec<_>>();
+    vec![
+        ("type", Some(record.entry_type.as_str().to_string())),
+        (
+            "mode",
+            record.mode.map(|mode| format!("{:o}", mode & 0o7777)),
+        ),
+        ("uid", record.uid.map(|uid| uid.to_string())),
+        ("gid", record.gid.map(|gid| gid.to_string())),
+        ("size", record.size.map(|size| size.to_string())),
+        ("mtime", record.mtime.map(|mtime| mtime.to_string())),
+        ("link_path", record.link_path.clone()),
+        (
+            "content",
+            node.content.map(|digest| format!("sha256:{}", hex(digest))),
+        ),
+        ("xattrs", (!xattrs.is_empty()).then(|| xattrs.join(","))),
+        ("acl", node.metadata.acl.clone()),
+        ("fflags", node.metadata.fflags.clone()),
+    ]
+}
+
+fn display_path(path: &Path) -> String {
+    if path.as_os_str().is_empty() {
+        ".".to_string()
+    } else {
+        path.to_string_lossy().to_string()
+    }
+}
+
+/// Compare the trees built by two stacks of layers, each from the bottom to the top, in any
+/// supported compression. The changes are sorted by path, entries under an added or removed
+/// directory are reported individually
+pub fn diff_layers<P: AsRef<Path>, Q: AsRef<Path>>(
+    old: &[P],
+    new: &[Q],
+) -> Result<Vec<Change>, std::io::Error> {
+    let old = build_tree(old)?;
+    let new = build_tree(new)?;
+    let mut changes = Vec::new();
+
+    for (path, node) in old.iter() {
+        if !new.contains_key(path) {
+            changes.push(Change {
+                path: display_path(path),
+                kind: ChangeKind::Removed,
+                entry_type: node.record.entry_type,
+                deltas: Vec::new(),
+            });
+        }
+    }
+
+    for (path, node) in new.iter() {
+        let Some(old_node) = old.get(path) else {
+            changes.push(Change {
+                path: display_path(path),
+                kind: ChangeKind::Added,
+                entry_type: node.record.entry_type,
+                deltas: Vec::new(),
+            });
+            continue;
+        };
+
+        let deltas = attributes(old_node)
+            .into_iter()
+            .zip(attributes(node))
+            .filter(|((_, old), (_, new))| old != new)
+            .map(|((field, old), (_, new))| Delta {
+                field: field.to_string(),
+                old,
+                new,
+            })
+            .collect::<Vec<_>>();
+
+        if !deltas.is_empty() {
+            changes.push(Change {
+                path: display_path(path),
+                kind: ChangeKind::Modified,
+                entry_type: node.record.entry_type,
+                deltas,
+            });
+        }
+    }
+
+    changes.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tar::{write_entry_header, write_oci_whiteouts, RawTarHeader};
+
+    fn write_entry(archive: &mut Vec<u8>, path: &str, tpe: u8, mode: u32, content: &[u8]) {
+        let mut header = RawTarHeader::empty_ustar();
+        header.set_mode(mode);
+        header.set_owner(0, 0, b"", b"");
+        header.set_lastmod(0);
+        header.set_entry_type(tpe);
+        write_entry_header(archive, header, path, content.len() as u64).unwrap();
+        for chunk in content.chunks(512) {
+            let mut block = [0u8; 512];
+            block[..chunk.len()].copy_from_slice(chunk);
+            archive.extend_from_slice(&block);
+        }
+    }
+
+    #[test]
+    fn test_diff_layers() -> std::io::Result<()> {
+        let mut bottom = Vec::new();
+        write_entry(&mut bottom, "a/", b'5', 0o755, b"");
+        write_entry(&mut bottom, "a/1", b'0', 0o644, b"one");
+        write_entry(&mut bottom, "a/2", b'0', 0o644, b"two");
+        write_entry(&mut bottom, "b/", b'5', 0o755, b"");
+        write_entry(&mut bottom, "b/x", b'0', 0o644, b"x");
+        write_entry(&mut bottom, "c", b'0', 0o644, b"c");
+        bottom.extend_from_slice(&[0u8; 1024]);
+
+        let mut top = Vec::new();
+        write_entry(&mut top, "a/2", b'0', 0o644, b"TWO");
+        // whiteouts apply to the layers below only, wherever they are in the archive
+        write_oci_whiteouts("a/1".to_string(), None, &mut top)?;
+        write_entry(&mut top, "b/y", b'0', 0o644, b"y");
+        write_oci_whiteouts("b/.wh..opq".to_string(), None, &mut top)?;
+        write_entry(&mut top, "c", b'0', 0o755, b"c");
+        write_entry(&mut top, "d", b'0', 0o644, b"d");
+        top.extend_from_slice(&[0u8; 1024]);
+
+        crate::util::with_stage_dir("diff", |stage| {
+            let paths = [stage.join("bottom.tar"), stage.join("top.tar")];
+            std::fs::write(&paths[0], &bottom)?;
+            std::fs::write(&paths[1], &top)?;
+
+            let changes = diff_layers(&paths[..1], &paths)?;
+            let summary = changes
+                .iter()
+                .map(|change| (change.path.as_str(), change.kind))
+                .collect::<Vec<_>>();
+            assert_eq!(
+                summary,
+                vec![
+                    ("a/1", ChangeKind::Removed),
+                    ("a/2", ChangeKind::Modified),
+                    ("b/x", ChangeKind::Removed),
+                    ("b/y", ChangeKind::Added),
+                    ("c", ChangeKind::Modified),
+                    ("d", ChangeKind::Added),
+                ]
+            );
+
+            let fields = |path: &str| {
+                changes
+                    .iter()
+                    .find(|change| change.path == path)
+                    .unwrap()
+                    .deltas
+                    .iter()
+                    .map(|delta| delta.field.clone())
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(fields("a/2"), vec!["content"]);
+            assert_eq!(fields("c"), vec!["mode"]);
+            let mode = &changes[4].deltas[0];
+            assert_eq!(mode.old.as_deref(), Some("644"));
+            assert_eq!(mode.new.as_deref(), Some("755"));
+
+            // comparing the single layers, the top layer alone does not contain what the bottom
+            // layer does
+            let changes = diff_layers(&paths[..1], &paths[1..])?;
+            assert!(changes
+                .iter()
+                .any(|change| change.path == "a" && change.kind == ChangeKind::Removed));
+            assert!(diff_layers(&paths, &paths)?.is_empty());
+
+            // sparse entries are compared by the content once extracted
+            let changes = diff_layers(
+                &["test-materials/sparse-gnu.tar"],
+                &["test-materials/sparse-pax.tar"],
+            )?;
+            assert!(changes.is_empty());
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,212 @@
+// Copyright (c) 2023 Yan Ka, Chiu.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions
+// are met:
+// 1. Redistributions of source code must retain the above copyright
+//    notice, this list of conditions, and the following disclaimer,
+//    without modification, immediately at the beginning of the file.
+// 2. The name of the author may not be used to endorse or promote products
+//    derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE AUTHOR AND CONTRIBUTORS ``AS IS'' AND
+// ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE AUTHOR OR CONTRIBUTORS BE LIABLE FOR
+// ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+// OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+// HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+// LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
+// OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
+// SUCH DAMAGE.
+//! Map the ownership of the entries while extracting, for example to shift the ids of an image
+//! into a range reserved for unprivileged use
+use crate::tar::{Extension, TarEntry};
+use std::str::FromStr;
+
+/// The largest id the ustar header can hold with the 6 octal digits this program writes, larger
+/// ids are carried by PAX records
+const MAX_HEADER_ID: u32 = 0o777777;
+
+/// `count` consecutive ids starting from `inside` in the archive, mapped to the ids starting from
+/// `outside` on the host
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdRange {
+    pub inside: u32,
+    pub outside: u32,
+    pub count: u32,
+}
+
+impl IdRange {
+    fn map(&self, id: u32) -> Option<u32> {
+        let offset = id.checked_sub(self.inside)?;
+        (offset < self.count)
+            .then(|| self.outside.checked_add(offset))
+            .flatten()
+    }
+}
+
+impl FromStr for IdRange {
+    type Err = String;
+
+    /// Parse a range in the form of "{inside}:{outside}:{count}", such as "0:100000:65536"
+    fn from_str(s: &str) -> Result<IdRange, String> {
+        let fields = s.split(':').collect::<Vec<_>>();
+        let [inside, outside, count] = fields.as_slice() else {
+            return Err(format!("expected inside:outside:count, got {s}"));
+        };
+        let parse = |value: &str| {
+            value
+                .parse::<u32>()
+                .map_err(|_| format!("invalid id {value} in {s}"))
+        };
+        let range = IdRange {
+            inside: parse(inside)?,
+            outside: parse(outside)?,
+            count: parse(count)?,
+        };
+        if range.count == 0 {
+            return Err(format!("empty range {s}"));
+        }
+        if range.outside.checked_add(range.count - 1).is_none() {
+            return Err(format!("range {s} overflows"));
+        }
+        Ok(range)
+    }
+}
+
+/// How the uids and gids of the entries are mapped on extraction. If there is no range for uids
+/// (or gids), they are extracted as is, otherwise ids not covered by any range are rejected
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct OwnerMap {
+    pub uids: Vec<IdRange>,
+    pub gids: Vec<IdRange>,
+}
+
+fn map_id(ranges: &[IdRange], id: u32, kind: &str) -> std::io::Result<u32> {
+    if ranges.is_empty() {
+        return Ok(id);
+    }
+    match ranges.iter().find_map(|range| range.map(id)) {
+        Some(id) => Ok(id),
+        None => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("{kind} {id} is not mapped"),
+        )),
+    }
+}
+
+impl OwnerMap {
+    /// If the ownership of the entries are extracted as is
+    pub fn is_identity(&self) -> bool {
+        self.uids.is_empty() && self.gids.is_empty()
+    }
+
+    pub fn map_uid(&self, uid: u32) -> std::io::Result<u32> {
+        map_id(&self.uids, uid, "uid")
+    }
+
+    pub fn map_gid(&self, gid: u32) -> std::io::Result<u32> {
+        map_id(&self.gids, gid, "gid")
+    }
+
+    /// Map the ownership of an entry, in both the header and the PAX records. The user and group
+    /// names are removed as they no longer correspond to the ids, such that tar(1) uses the ids
+    pub fn map_entry(&self, entry: &TarEntry) -> std::io::Result<TarEntry> {
+        let uid = self.map_uid(entry.uid)?;
+        let gid = self.map_gid(entry.gid)?;
+
+        let mut mapped = entry.clone();
+        mapped.uid = uid;
+        mapped.gid = gid;
+        mapped.user_name.clear();
+        mapped.group_name.clear();
+        mapped
+            .extensions
+            .retain(|ext| !matches!(ext.key.as_str(), "uid" | "gid" | "uname" | "gname"));
+
+        let header_uid = if uid > MAX_HEADER_ID {
+            mapped
+                .extensions
+                .push(Extension::new("uid".to_string(), uid.to_string()));
+            0
+        } else {
+            uid
+        };
+        let header_gid = if gid > MAX_HEADER_ID {
+            mapped
+                .extensions
+                .push(Extension::new("gid".to_string(), gid.to_string()));
+            0
+        } else {
+            gid
+        };
+        mapped.header.set_owner(header_uid, header_gid, b"", b"");
+        mapped.header.set_checksum();
+        Ok(mapped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_owner_map() {
+        let map = OwnerMap {
+            uids: vec!["0:100000:65536".parse().unwrap()],
+            gids: vec![
+                "0:200000:10".parse().unwrap(),
+                "1000:1000:1".parse().unwrap(),
+            ],
+        };
+        assert_eq!(map.map_uid(0).unwrap(), 100000);
+        assert_eq!(map.map_uid(65535).unwrap(), 165535);
+        assert!(map.map_uid(65536).is_err());
+        assert_eq!(map.map_gid(9).unwrap(), 200009);
+        assert_eq!(map.map_gid(1000).unwrap(), 1000);
+        assert!(map.map_gid(10).is_err());
+        assert_eq!(OwnerMap::default().map_uid(12345).unwrap(), 12345);
+
+        assert!("0:100000".parse::<IdRange>().is_err());
+        assert!("0:100000:0".parse::<IdRange>().is_err());
+        assert!("0:4294967295:2".parse::<IdRange>().is_err());
+    }
+
+    #[test]
+    fn test_map_entry() -> std::io::Result<()> {
+        let mut header = crate::tar::RawTarHeader::empty_ustar();
+        header.set_owner(1, 2, b"user", b"group");
+        header.set_checksum();
+        let extensions = vec![
+            Extension::new("uid".to_string(), "7".to_string()),
+            Extension::new("uname".to_string(), "pax-user".to_string()),
+        ];
+        let entry = TarEntry::new(header, extensions, Vec::new())?;
+        assert_eq!(entry.uid, 7);
+
+        let map = OwnerMap {
+            uids: vec!["0:100000:65536".parse().unwrap()],
+            gids: vec!["0:3000000:65536".parse().unwrap()],
+        };
+        let mapped = map.map_entry(&entry)?;
+        assert_eq!((mapped.uid, mapped.gid), (100007, 3000002));
+        assert!(mapped.header.is_valid_tar_header());
+        assert_eq!(mapped.header.uid()?, 100007);
+        assert_eq!(mapped.header.user_name()?, "");
+        // the gid does not fit in the header
+        assert_eq!(mapped.header.gid()?, 0);
+        assert_eq!(
+            mapped.extensions,
+            vec![Extension::new("gid".to_string(), "3000002".to_string())]
+        );
+
+        // the entry parsed from the mapped header and records has the mapped ownership
+        let reparsed = TarEntry::new(mapped.header.clone(), mapped.extensions.clone(), Vec::new())?;
+        assert_eq!((reparsed.uid, reparsed.gid), (100007, 3000002));
+        assert_eq!(reparsed.user_name, "");
+        Ok(())
+    }
+}
@@ -0,0 +1,1285 @@
+// Copyright (c) 2023 Yan Ka, Chiu.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions
+// are met:
+// 1. Redistributions of source code must retain the above copyright
+//    notice, this list of conditions, and the following disclaimer,
+//    without modification, immediately at the beginning of the file.
+// 2. The name of the author may not be used to endorse or promote products
+//    derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE AUTHOR AND CONTRIBUTORS ``AS IS'' AND
+// ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE AUTHOR OR CONTRIBUTORS BE LIABLE FOR
+// ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+// OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+// HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+// LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
+// OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
+// SUCH DAMAGE.
+//! Create, list and extract OCI layer archives
+use crate::compression::{
+    prepare_compressed_stream_reader, prepare_compressed_stream_writer_with, CompressionType,
+    ZstdOptions,
+};
+use crate::confined::{ConfinedExtractTar, ExtractError};
+use crate::idmap::OwnerMap;
+use crate::tar::{self, EntryRecord, RecordEntries, Reproducible, Summary, TarEntryHandle};
+use crate::util::{err, hex, DigestReader, DigestReaderHandle, DigestSink, DigestWriter};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+#[derive(Debug, Default, Clone)]
+pub struct CreateOptions {
+    /// Do not create OCI whiteout files. Use this program's custom tar extension only
+    pub without_oci: bool,
+    /// Do not include this program's custom tar extension to the archive
+    pub without_ext: bool,
+    /// Types of compression to be use, `Auto` creates an uncompressed archive
+    pub compression: CompressionType,
+    /// Parameters of zstd compression, used only if `compression` is `Zstd`
+    pub zstd: ZstdOptions,
+    /// Create a reproducible archive, identical trees produce identical archives
+    pub reproducible: Option<Reproducible>,
+    /// Do not descend into the directories in `paths`, every entry to include has to be listed
+    pub no_recursion: bool,
+}
+
+/// Difference between two trees, paths are relative to `root`
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct TreeDiff {
+    /// the location of the newer tree, such as the mountpoint of the newer dataset
+    pub root: String,
+    pub adding: Vec<String>,
+    pub removing: Vec<String>,
+}
+
+fn zfs_dataset_get_mountpoint(dataset: &str) -> Result<Option<String>, std::io::Error> {
+    let dataset = match dataset.split_once(if dataset.contains('#') { '#' } else { '@' }) {
+        None => dataset.to_string(),
+        Some((origin, _)) => origin.to_string(),
+    };
+    let out = Command::new("zfs")
+        .arg("get")
+        .arg("-Ho")
+        .arg("value")
+        .arg("mountpoint")
+        .arg(dataset)
+        .output()?
+        .stdout;
+    let mountpoint = std::str::from_utf8(&out).unwrap().trim().to_string();
+    Ok(if mountpoint == "-" {
+        None
+    } else {
+        Some(mountpoint)
+    })
+}
+
+/// Get the difference between two ZFS snapshots / datasets, in the order zfs-diff(8) accepts
+pub fn zfs_diff(from: &str, to: &str) -> Result<TreeDiff, std::io::Error> {
+    let mut adding = Vec::new();
+    let mut removing = Vec::new();
+
+    let Some(mountpoint) = zfs_dataset_get_mountpoint(to)? else {
+        return err!("dataset has no mountpoint");
+    };
+    let root = format!("{mountpoint}/");
+
+    let out = Command::new("zfs")
+        .arg("diff")
+        .arg("-H")
+        .arg(from)
+        .arg(to)
+        .output()?;
+
+    if !out.status.success() {
+        let mut err_msg = String::new();
+
+        // ZFS error output tends to create line breaking error messages, in order
+        // to print it out without ruining the formatting, we need to re-construct
+        // the actual error message
+        for line in std::str::from_utf8(&out.stderr).unwrap().lines() {
+            if err_msg.ends_with(|c: char| c.is_alphanumeric())
+                && line.starts_with(|c: char| c.is_alphanumeric())
+            {
+                err_msg.push(' ');
+                err_msg.push_str(line);
+            } else {
+                err_msg.push_str(line);
+            }
+        }
+
+        return Err(std::io::Error::other(err_msg));
+    }
+
+    let stdout = std::str::from_utf8(&out.stdout).unwrap();
+
+    for line in stdout.lines() {
+        log::debug!("zfs diff: {line}");
+        let mut columns = line.split('\t');
+        let flag = columns.next().expect("Expect flag");
+        let path = columns
+            .next()
+            .expect("Expect path")
+            .to_string()
+            .replacen(&root, "", 1);
+        match flag {
+            "-" => removing.push(path),
+            "+" => adding.push(path),
+            "M" => adding.push(path),
+            "R" => {
+                let new = columns.next().expect("expect new path").to_string();
+                removing.push(path.to_string());
+                adding.push(new.replacen(&path, "", 1))
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    Ok(TreeDiff {
+        root,
+        adding,
+        removing,
+    })
+}
+
+/// Collect the entries under `root` into `entries`, keyed by their path relative to `root`
+fn walk_tree(
+    root: &Path,
+    relative: &Path,
+    entries: &mut BTreeMap<PathBuf, std::fs::Metadata>,
+) -> std::io::Result<()> {
+    for dirent in std::fs::read_dir(root.join(relative))? {
+        let path = relative.join(dirent?.file_name());
+        let metadata = std::fs::symlink_metadata(root.join(&path))?;
+        if metadata.is_dir() {
+            walk_tree(root, &path, entries)?;
+        }
+        entries.insert(path, metadata);
+    }
+    Ok(())
+}
+
+fn sha256_file(path: &Path) -> std::io::Result<[u8; 32]> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut std::fs::File::open(path)?, &mut hasher)?;
+    Ok(hasher.finalize().into())
+}
+
+/// If the entry at `path` is different in the two trees, comparing the type, mode, owner, size,
+/// modification time, the target of symlinks and the content of regular files
+fn entry_changed(
+    old_root: &Path,
+    new_root: &Path,
+    path: &Path,
+    old: &std::fs::Metadata,
+    new: &std::fs::Metadata,
+) -> std::io::Result<bool> {
+    if old.mode() != new.mode()
+        || old.uid() != new.uid()
+        || old.gid() != new.gid()
+        || old.size() != new.size()
+        || old.mtime() != new.mtime()
+        || old.mtime_nsec() != new.mtime_nsec()
+        || old.rdev() != new.rdev()
+    {
+        return Ok(true);
+    }
+
+    let file_type = new.file_type();
+    if file_type.is_symlink() {
+        Ok(std::fs::read_link(old_root.join(path))? != std::fs::read_link(new_root.join(path))?)
+    } else if file_type.is_file() {
+        Ok(sha256_file(&old_root.join(path))? != sha256_file(&new_root.join(path))?)
+    } else {
+        Ok(false)
+    }
+}
+
+/// Get the difference between two directory trees. Every added or changed entry is listed
+/// individually in `adding`, which should be archived without recursion. Entries removed, or
+/// replaced by an entry of another type, are listed in `removing`, without their children
+pub fn dir_diff(old: impl AsRef<Path>, new: impl AsRef<Path>) -> Result<TreeDiff, std::io::Error> {
+    let old = old.as_ref();
+    let new = new.as_ref();
+    let Some(root) = new.to_str() else {
+        return err!("path is not valid UTF-8");
+    };
+
+    let mut old_entries = BTreeMap::new();
+    let mut new_entries = BTreeMap::new();
+    walk_tree(old, Path::new(""), &mut old_entries)?;
+    walk_tree(new, Path::new(""), &mut new_entries)?;
+
+    let to_string = |path: &Path| match path.to_str() {
+        Some(path) => Ok(path.to_string()),
+        None => err!("path is not valid UTF-8"),
+    };
+
+    let mut adding = Vec::new();
+    let mut removing = Vec::new();
+
+    for (path, metadata) in new_entries.iter() {
+        match old_entries.get(path) {
+            None => adding.push(to_string(path)?),
+            Some(old_metadata) if old_metadata.file_type() != metadata.file_type() => {
+                removing.push(to_string(path)?);
+                adding.push(to_string(path)?);
+            }
+            Some(old_metadata) => {
+                if entry_changed(old, new, path, old_metadata, metadata)? {
+                    adding.push(to_string(path)?);
+                }
+            }
+        }
+    }
+
+    let mut removed = BTreeSet::new();
+    for path in old_entries.keys() {
+        let exists = new_entries.contains_key(path);
+        // the children of a removed or replaced entry are gone with it
+        if path
+            .ancestors()
+            .skip(1)
+            .any(|parent| removed.contains(parent))
+        {
+            continue;
+        }
+        if exists && old_entries[path].file_type() == new_entries[path].file_type() {
+            continue;
+        }
+        // entries replaced by another type are already in `removing`
+        if !exists {
+            removing.push(to_string(path)?);
+        }
+        removed.insert(path.clone());
+    }
+    removing.sort();
+
+    Ok(TreeDiff {
+        root: root.to_string(),
+        adding,
+        removing,
+    })
+}
+
+/// Expand the directories in `paths`, relative to `chdir`, into a sorted list of every path
+/// under them
+fn expand_paths_sorted(chdir: Option<&str>, paths: &[String]) -> std::io::Result<Vec<PathBuf>> {
+    let base = Path::new(chdir.unwrap_or("."));
+    let mut expanded = BTreeSet::new();
+    let mut pending: Vec<PathBuf> = paths.iter().map(PathBuf::from).collect();
+
+    while let Some(path) = pending.pop() {
+        if std::fs::symlink_metadata(base.join(&path))?.is_dir() {
+            for dirent in std::fs::read_dir(base.join(&path))? {
+                pending.push(path.join(dirent?.file_name()));
+            }
+        }
+        expanded.insert(path);
+    }
+
+    Ok(expanded.into_iter().collect())
+}
+
+/// Create a layer archive containing `paths` and the deletion of `whiteouts`, `paths` are
+/// relative to `chdir` if set
+pub fn create_layer<W: Write>(
+    options: &CreateOptions,
+    chdir: Option<&str>,
+    paths: &[String],
+    whiteouts: &[String],
+    output: W,
+) -> Result<Summary, std::io::Error> {
+    let sha256 = std::rc::Rc::new(std::cell::RefCell::new(Sha256::new()));
+    let handle = DigestSink::<W>::new(output, sha256.clone());
+    let mut output =
+        prepare_compressed_stream_writer_with(handle, options.compression, &options.zstd)?;
+
+    let mut tar = Command::new("tar");
+    if let Some(chdir) = chdir {
+        tar.arg("-C").arg(chdir);
+    }
+
+    if options.no_recursion || options.reproducible.is_some() {
+        tar.arg("--no-recursion");
+    }
+
+    // in reproducible mode, walk the directories ourselves such that the order of the entries
+    // does not depend on the order of the directory entries on disk
+    let paths_input = match options.reproducible {
+        Some(_) if options.no_recursion => {
+            let mut paths = paths.to_vec();
+            paths.sort();
+            paths.join("\n").into_bytes()
+        }
+        Some(_) => {
+            let mut input = Vec::new();
+            for path in expand_paths_sorted(chdir, paths)? {
+                input.extend_from_slice(path.as_os_str().as_bytes());
+                input.push(b'\n');
+            }
+            input
+        }
+        None => paths.join("\n").into_bytes(),
+    };
+
+    // the detection of hardlinks and holes is left to tar(1). Both bsdtar(1) and GNU tar store
+    // the files sharing the same inode as hardlinks to the first of them. bsdtar(1) stores the
+    // holes of sparse files as sparse entries by itself, and only honors `-S` on extraction,
+    // while GNU tar needs `-S` to do so
+    let mut child = tar
+        .arg("-S")
+        .arg("-cf-")
+        .arg("-T-")
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::null())
+        .spawn()?;
+
+    let mut child_stdin = child.stdin.take().unwrap();
+
+    std::thread::spawn(move || {
+        _ = child_stdin.write_all(&paths_input);
+        drop(child_stdin);
+    });
+
+    let tar_stdout = child.stdout.as_mut().unwrap();
+
+    let diff_id = tar::tap_create_tar(
+        options.without_oci,
+        options.without_ext,
+        options.reproducible.as_ref(),
+        Some(Path::new(chdir.unwrap_or("."))),
+        whiteouts,
+        tar_stdout,
+        &mut output,
+    )?;
+
+    match child.wait()?.code() {
+        Some(code) if code != 0 => {
+            return err!("tar returns non-zero exit code");
+        }
+        _ => (),
+    }
+
+    drop(output);
+    let archive_digest: [u8; 32] = sha256.borrow().clone().finalize().into();
+
+    Ok(Summary {
+        diff_id,
+        archive_digest,
+        files: paths.to_vec(),
+        whiteouts: whiteouts.to_vec(),
+    })
+}
+
+/// Create a layer archive containing the difference between two ZFS snapshots / datasets
+pub fn create_layer_from_zfs_diff<W: Write>(
+    options: &CreateOptions,
+    from: &str,
+    to: &str,
+    output: W,
+) -> Result<Summary, std::io::Error> {
+    let diff = zfs_diff(from, to)?;
+    create_layer(
+        options,
+        Some(&diff.root),
+        &diff.adding,
+        &diff.removing,
+        output,
+    )
+}
+
+/// Create a layer archive containing the difference between two directory trees, see `dir_diff`
+pub fn create_layer_from_dir_diff<W: Write>(
+    options: &CreateOptions,
+    old: impl AsRef<Path>,
+    new: impl AsRef<Path>,
+    output: W,
+) -> Result<Summary, std::io::Error> {
+    let diff = dir_diff(old, new)?;
+    let options = CreateOptions {
+        no_recursion: true,
+        ..options.clone()
+    };
+    create_layer(
+        &options,
+        Some(&diff.root),
+        &diff.adding,
+        &diff.removing,
+        output,
+    )
+}
+
+/// Walk through the entries of a (possibly compressed) layer archive with `handle`
+pub fn visit_layer<R: Read, H: TarEntryHandle>(
+    input: R,
+    compression: CompressionType,
+    handle: &mut H,
+) -> Result<Summary, std::io::Error> {
+    let digest_input = std::rc::Rc::new(std::cell::RefCell::new(DigestReader::<R>::new(input)));
+    let reader = prepare_compressed_stream_reader(
+        Box::new(DigestReaderHandle(digest_input.clone())),
+        compression,
+    )?;
+    let mut summary = tar::summarize_entries(reader, handle)?;
+    // the decoder may stop before the end of the input, such as after the first gzip member
+    std::io::copy(
+        &mut DigestReaderHandle(digest_input.clone()),
+        &mut std::io::sink(),
+    )?;
+    summary.archive_digest = digest_input.borrow().consume();
+    Ok(summary)
+}
+
+/// List the entries and whiteouts of a layer archive
+pub fn list_layer<R: Read>(
+    input: R,
+    compression: CompressionType,
+) -> Result<Summary, std::io::Error> {
+    visit_layer(input, compression, &mut ())
+}
+
+/// List the entries and whiteouts of a layer archive, passing an `EntryRecord` of each of them to
+/// `on_record` in the order they appear in the archive
+pub fn list_layer_records<R: Read, F: FnMut(EntryRecord) -> std::io::Result<()>>(
+    input: R,
+    compression: CompressionType,
+    on_record: F,
+) -> Result<Summary, std::io::Error> {
+    visit_layer(input, compression, &mut RecordEntries { on_record })
+}
+
+/// Reject any non-zero bytes after the end-of-archive records
+struct StrictTrailer;
+
+impl TarEntryHandle for StrictTrailer {
+    fn on_tailing_record(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        if buf.iter().any(|byte| *byte != 0) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "unexpected data after the end of archive",
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn digest_mismatch(what: &str, expected: [u8; 32], actual: [u8; 32]) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!(
+            "{what} mismatch: expected sha256:{}, got sha256:{}",
+            hex(expected),
+            hex(actual)
+        ),
+    )
+}
+
+/// Walk through a layer archive and fail if any header is malformed, or if the digests of the
+/// archive do not match the expected ones
+pub fn verify_layer<R: Read>(
+    input: R,
+    compression: CompressionType,
+    expected_diff_id: Option<[u8; 32]>,
+    expected_digest: Option<[u8; 32]>,
+) -> Result<Summary, std::io::Error> {
+    let summary = visit_layer(input, compression, &mut StrictTrailer)?;
+
+    if let Some(expected) = expected_diff_id {
+        if expected != summary.diff_id {
+            return Err(digest_mismatch("diff_id", expected, summary.diff_id));
+        }
+    }
+
+    if let Some(expected) = expected_digest {
+        if expected != summary.archive_digest {
+            return Err(digest_mismatch("digest", expected, summary.archive_digest));
+        }
+    }
+
+    Ok(summary)
+}
+
+/// Digests of a layer converted by `recompress_layer`
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Recompressed {
+    /// sha256 digest of the uncompressed tar stream
+    pub diff_id: [u8; 32],
+    /// sha256 digest of the input archive
+    pub input_digest: [u8; 32],
+    /// sha256 digest of the converted archive
+    pub archive_digest: [u8; 32],
+}
+
+/// Convert a layer archive from one compression to another in a single pass. If
+/// `expected_diff_id` is set, fail if the uncompressed stream does not hash to it, in which case
+/// the content written to `output` should be discarded
+pub fn recompress_layer<R: Read, W: Write>(
+    input: R,
+    from: CompressionType,
+    to: CompressionType,
+    zstd: &ZstdOptions,
+    expected_diff_id: Option<[u8; 32]>,
+    output: W,
+) -> Result<Recompressed, std::io::Error> {
+    let digest_input = std::rc::Rc::new(std::cell::RefCell::new(DigestReader::<R>::new(input)));
+    let mut reader =
+        prepare_compressed_stream_reader(Box::new(DigestReaderHandle(digest_input.clone())), from)?;
+
+    let sha256 = std::rc::Rc::new(std::cell::RefCell::new(Sha256::new()));
+    let handle = DigestSink::<W>::new(output, sha256.clone());
+    let mut output = prepare_compressed_stream_writer_with(handle, to, zstd)?;
+    let mut writer = DigestWriter::<&mut Box<dyn Write>>::new(&mut output);
+
+    std::io::copy(&mut reader, &mut writer)?;
+    let diff_id = writer.consume();
+    drop(reader);
+    // the decoder may stop before the end of the input, such as after the first gzip member
+    std::io::copy(
+        &mut DigestReaderHandle(digest_input.clone()),
+        &mut std::io::sink(),
+    )?;
+    output.flush()?;
+    drop(output);
+
+    if let Some(expected) = expected_diff_id {
+        if expected != diff_id {
+            return Err(digest_mismatch("diff_id", expected, diff_id));
+        }
+    }
+
+    let input_digest = digest_input.borrow().consume();
+    let archive_digest: [u8; 32] = sha256.borrow().clone().finalize().into();
+
+    Ok(Recompressed {
+        diff_id,
+        input_digest,
+        archive_digest,
+    })
+}
+
+/// Extract a layer archive to `root`, including the deletions
+pub fn extract_layer<R: Read>(
+    input: R,
+    compression: CompressionType,
+    root: impl AsRef<Path>,
+) -> Result<Summary, std::io::Error> {
+    extract_layer_mapped(input, compression, root, &OwnerMap::default())
+}
+
+/// Extract a layer archive to `root` like `extract_layer`, with the ownership of the entries
+/// mapped by `owner_map`
+pub fn extract_layer_mapped<R: Read>(
+    input: R,
+    compression: CompressionType,
+    root: impl AsRef<Path>,
+    owner_map: &OwnerMap,
+) -> Result<Summary, std::io::Error> {
+    let root = root.as_ref();
+    let digest_input = std::rc::Rc::new(std::cell::RefCell::new(DigestReader::<R>::new(input)));
+    let reader = prepare_compressed_stream_reader(
+        Box::new(DigestReaderHandle(digest_input.clone())),
+        compression,
+    )?;
+
+    let mut tar = Command::new("tar");
+    if !owner_map.is_identity() {
+        tar.arg("--numeric-owner");
+    }
+    let mut child = tar
+        .arg("-xf-")
+        .arg("-C")
+        .arg(root)
+        .stdin(std::process::Stdio::piped())
+        .spawn()?;
+
+    let tar_stdin = child.stdin.as_mut().unwrap();
+
+    let mut summary = tar::tap_extract_tar(reader, tar_stdin, root, owner_map)?;
+    summary.archive_digest = digest_input.borrow().consume();
+
+    match child.wait()?.code() {
+        Some(ec) if ec != 0 => {
+            err!("tar return non-zero exit code")
+        }
+        _ => Ok(summary),
+    }
+}
+
+/// Extract a layer archive to `root` without tar(1). Every entry, link target and whiteout is
+/// resolved within `root`; entries escaping `root` are skipped and reported as violations after
+/// the rest of the archive is extracted
+pub fn extract_layer_confined<R: Read>(
+    input: R,
+    compression: CompressionType,
+    root: impl AsRef<Path>,
+) -> Result<Summary, ExtractError> {
+    extract_layer_confined_mapped(input, compression, root, &OwnerMap::default())
+}
+
+/// Extract a layer archive to `root` like `extract_layer_confined`, with the ownership of the
+/// entries mapped by `owner_map`
+pub fn extract_layer_confined_mapped<R: Read>(
+    input: R,
+    compression: CompressionType,
+    root: impl AsRef<Path>,
+    owner_map: &OwnerMap,
+) -> Result<Summary, ExtractError> {
+    let mut extractor = ConfinedExtractTar::new(root).with_owner_map(owner_map.clone());
+    let summary = visit_layer(input, compression, &mut extractor)?;
+    extractor.finish()?;
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tar::EntryType;
+
+    const GNU_LONG_DIR: &str = "gnu/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb";
+
+    #[test]
+    fn test_list_gnu_long_names() -> std::io::Result<()> {
+        let file = std::fs::File::open("test-materials/gnu-longname.tar")?;
+        let summary = list_layer(file, CompressionType::Auto)?;
+        assert_eq!(
+            summary.whiteouts,
+            vec![format!("{GNU_LONG_DIR}/{}", "c".repeat(50))]
+        );
+        assert_eq!(
+            summary.files,
+            vec![
+                "gnu/".to_string(),
+                "gnu/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa/".to_string(),
+                format!("{GNU_LONG_DIR}/"),
+                format!("{GNU_LONG_DIR}/file.txt"),
+                "gnu/link".to_string(),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_list_layer_records() -> std::io::Result<()> {
+        let file = std::fs::File::open("test-materials/gnu-longname.tar")?;
+        let mut records = Vec::new();
+        list_layer_records(file, CompressionType::Auto, |record| {
+            records.push(record);
+            Ok(())
+        })?;
+
+        let types = records
+            .iter()
+            .map(|record| record.entry_type)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            types,
+            vec![
+                EntryType::Directory,
+                EntryType::Directory,
+                EntryType::Directory,
+                EntryType::Whiteout,
+                EntryType::Regular,
+                EntryType::Symlink,
+            ]
+        );
+
+        assert!(records[3].whiteout);
+        assert_eq!(records[3].mode, None);
+        assert_eq!(records[4].size, Some(16));
+        assert_eq!(records[4].mtime, Some(1672531200));
+        assert_eq!(records[4].mode, Some(0o644));
+        assert_eq!(records[5].path, "gnu/link");
+        assert_eq!(
+            records[5].link_path,
+            Some(format!("../{GNU_LONG_DIR}/file.txt"))
+        );
+
+        let json = serde_json::to_value(&records[5]).unwrap();
+        assert_eq!(json["type"], "symlink");
+        assert!(json.get("size").is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_create_list_extract_round_trip() -> std::io::Result<()> {
+        crate::util::with_stage_dir("round-trip", |stage| {
+            let source = stage.join("source");
+            let target = stage.join("target");
+            std::fs::create_dir_all(source.join("dir/nested"))?;
+            std::fs::write(source.join("dir/file"), "file\n")?;
+            std::fs::write(source.join("dir/nested/other"), "other\n")?;
+            std::os::unix::fs::symlink("file", source.join("dir/link"))?;
+
+            for compression in [
+                CompressionType::None,
+                CompressionType::Gzip,
+                CompressionType::Zstd,
+            ] {
+                let options = CreateOptions {
+                    compression,
+                    ..CreateOptions::default()
+                };
+                let mut archive = Vec::new();
+                let created = create_layer(
+                    &options,
+                    source.to_str(),
+                    &["dir".to_string()],
+                    &["dir/removed".to_string()],
+                    &mut archive,
+                )?;
+                assert_eq!(created.archive_digest, {
+                    let digest: [u8; 32] = Sha256::digest(&archive).into();
+                    digest
+                });
+
+                let listed = list_layer(archive.as_slice(), CompressionType::Auto)?;
+                assert_eq!(listed.diff_id, created.diff_id);
+                assert_eq!(listed.archive_digest, created.archive_digest);
+                assert_eq!(listed.whiteouts, vec!["dir/removed".to_string()]);
+                let mut files = listed.files.clone();
+                files.sort();
+                assert_eq!(
+                    files,
+                    vec![
+                        "dir/",
+                        "dir/file",
+                        "dir/link",
+                        "dir/nested/",
+                        "dir/nested/other"
+                    ]
+                );
+
+                _ = std::fs::remove_dir_all(&target);
+                std::fs::create_dir_all(target.join("dir"))?;
+                std::fs::write(target.join("dir/removed"), "should be removed")?;
+                let extracted = extract_layer(archive.as_slice(), CompressionType::Auto, &target)?;
+                assert_eq!(extracted.diff_id, created.diff_id);
+                assert!(!target.join("dir/removed").exists());
+                assert_eq!(std::fs::read_to_string(target.join("dir/file"))?, "file\n");
+                assert_eq!(
+                    std::fs::read_to_string(target.join("dir/nested/other"))?,
+                    "other\n"
+                );
+                assert_eq!(
+                    std::fs::read_link(target.join("dir/link"))?,
+                    PathBuf::from("file")
+                );
+            }
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_visit_layer() -> std::io::Result<()> {
+        #[derive(Default)]
+        struct Visited {
+            paths: Vec<PathBuf>,
+            whiteouts: Vec<PathBuf>,
+            content: Vec<u8>,
+        }
+
+        impl TarEntryHandle for Visited {
+            fn on_oci_whiteout_entry(&mut self, path: PathBuf) -> std::io::Result<()> {
+                self.whiteouts.push(path);
+                Ok(())
+            }
+
+            fn on_normal_entry(
+                &mut self,
+                _buf: &[u8; 512],
+                entry: &tar::TarEntry,
+            ) -> std::io::Result<()> {
+                self.paths.push(entry.path.clone());
+                Ok(())
+            }
+
+            fn on_normal_entry_block(
+                &mut self,
+                buf: &[u8; 512],
+                entry: &tar::TarEntry,
+            ) -> std::io::Result<()> {
+                if entry.path.ends_with("file.txt") {
+                    self.content.extend_from_slice(buf);
+                }
+                Ok(())
+            }
+        }
+
+        let archive = std::fs::read("test-materials/gnu-longname.tar")?;
+        let mut visited = Visited::default();
+        let summary = visit_layer(archive.as_slice(), CompressionType::Auto, &mut visited)?;
+        let listed = list_layer(archive.as_slice(), CompressionType::Auto)?;
+        assert_eq!(summary.diff_id, listed.diff_id);
+        assert_eq!(summary.files, listed.files);
+        assert_eq!(
+            visited.whiteouts,
+            vec![PathBuf::from(format!("{GNU_LONG_DIR}/{}", "c".repeat(50)))]
+        );
+        assert_eq!(visited.paths.len(), summary.files.len());
+        assert_eq!(visited.paths[4], PathBuf::from("gnu/link"));
+        assert_eq!(&visited.content[..16], b"hello long name\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_layer() -> std::io::Result<()> {
+        let archive = std::fs::read("test-materials/base.tar.zst")?;
+        let summary = list_layer(archive.as_slice(), CompressionType::Auto)?;
+
+        verify_layer(
+            archive.as_slice(),
+            CompressionType::Auto,
+            Some(summary.diff_id),
+            Some(summary.archive_digest),
+        )?;
+
+        let error = verify_layer(
+            archive.as_slice(),
+            CompressionType::Auto,
+            Some(summary.archive_digest),
+            None,
+        )
+        .unwrap_err();
+        assert!(error.to_string().starts_with("diff_id mismatch"));
+
+        let error = verify_layer(
+            archive.as_slice(),
+            CompressionType::Auto,
+            None,
+            Some(summary.diff_id),
+        )
+        .unwrap_err();
+        assert!(error.to_string().starts_with("digest mismatch"));
+
+        // corrupt the name of the first entry without updating the checksum
+        let mut tar = std::fs::read("test-materials/gnu-longname.tar")?;
+        tar[0] ^= 1;
+        let error = verify_layer(tar.as_slice(), CompressionType::None, None, None).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("checksum mismatch"));
+
+        // trailing garbage after the end of archive
+        let mut tar = std::fs::read("test-materials/gnu-longname.tar")?;
+        tar.extend_from_slice(b"garbage");
+        assert!(verify_layer(tar.as_slice(), CompressionType::None, None, None).is_err());
+
+        // truncated archive
+        let tar = std::fs::read("test-materials/gnu-longname.tar")?;
+        assert!(verify_layer(&tar[..1000], CompressionType::None, None, None).is_err());
+        Ok(())
+    }
+
+    const SPARSE_SIZE: u64 = 7 << 20;
+
+    /// Write a file with 6 data segments of 1MiB apart and a hole at the end, same as the one in
+    /// test-materials/sparse-*.tar
+    fn write_sparse_file(path: &Path) -> std::io::Result<()> {
+        use std::os::unix::fs::FileExt;
+        let file = std::fs::File::create(path)?;
+        for i in 0..6 {
+            file.write_all_at(format!("chunk{i}").as_bytes(), i << 20)?;
+        }
+        file.set_len(SPARSE_SIZE)
+    }
+
+    /// Check the files of test-materials/sparse-*.tar are extracted at `root`
+    fn check_sparse_tree(root: &Path) -> std::io::Result<()> {
+        use std::os::unix::fs::FileExt;
+        let holes = std::fs::File::open(root.join("sparse/holes"))?;
+        assert_eq!(holes.metadata()?.len(), SPARSE_SIZE);
+        let mut buf = [0u8; 16];
+        for i in 0..6u64 {
+            holes.read_exact_at(&mut buf, i << 20)?;
+            assert_eq!(&buf[..6], format!("chunk{i}").as_bytes());
+            assert!(buf[6..].iter().all(|byte| *byte == 0));
+        }
+        holes.read_exact_at(&mut buf, SPARSE_SIZE - 16)?;
+        assert_eq!(buf, [0u8; 16]);
+
+        let data = std::fs::metadata(root.join("sparse/data"))?;
+        let link = std::fs::metadata(root.join("sparse/link"))?;
+        assert_eq!(data.ino(), link.ino());
+        assert_eq!(std::fs::read_to_string(root.join("sparse/data"))?, "data\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_extract_sparse() -> std::io::Result<()> {
+        for format in ["pax", "gnu"] {
+            crate::util::with_stage_dir(&format!("sparse-{format}"), |root| {
+                let archive = format!("test-materials/sparse-{format}.tar");
+                let summary = list_layer(std::fs::File::open(&archive)?, CompressionType::Auto)?;
+                assert_eq!(
+                    summary.files,
+                    vec!["sparse/", "sparse/link", "sparse/data", "sparse/holes"]
+                );
+
+                let mut records = Vec::new();
+                list_layer_records(std::fs::File::open(&archive)?, CompressionType::Auto, |r| {
+                    records.push(r);
+                    Ok(())
+                })?;
+                assert_eq!(records[2].entry_type, EntryType::HardLink);
+                assert_eq!(records[3].size, Some(SPARSE_SIZE));
+
+                extract_layer_confined(std::fs::File::open(&archive)?, CompressionType::Auto, root)
+                    .map_err(|error| std::io::Error::other(error.to_string()))?;
+                check_sparse_tree(root)
+            })?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_create_sparse_and_hardlinks() -> std::io::Result<()> {
+        crate::util::with_stage_dir("sparse-hardlinks", |stage| {
+            let source = stage.join("source");
+            let target = stage.join("target");
+            std::fs::create_dir_all(source.join("sparse"))?;
+            std::fs::create_dir_all(&target)?;
+            write_sparse_file(&source.join("sparse/holes"))?;
+            std::fs::write(source.join("sparse/data"), "data\n")?;
+            std::fs::hard_link(source.join("sparse/data"), source.join("sparse/link"))?;
+
+            let mut archive = Vec::new();
+            create_layer(
+                &CreateOptions::default(),
+                source.to_str(),
+                &["sparse".to_string()],
+                &[],
+                &mut archive,
+            )?;
+            assert!((archive.len() as u64) < SPARSE_SIZE / 4);
+
+            let mut records = Vec::new();
+            list_layer_records(archive.as_slice(), CompressionType::Auto, |record| {
+                records.push(record);
+                Ok(())
+            })?;
+            let links = records
+                .iter()
+                .filter(|record| record.entry_type == EntryType::HardLink)
+                .count();
+            assert_eq!(links, 1);
+
+            extract_layer_confined(archive.as_slice(), CompressionType::Auto, &target)
+                .map_err(|error| std::io::Error::other(error.to_string()))?;
+            check_sparse_tree(&target)
+        })
+    }
+
+    #[test]
+    fn test_extract_mapped() -> std::io::Result<()> {
+        use std::os::unix::fs::MetadataExt;
+        let owner_map = OwnerMap {
+            uids: vec!["0:100000:65536".parse().unwrap()],
+            gids: vec!["0:200000:65536".parse().unwrap()],
+        };
+        let is_root = unsafe { libc::geteuid() } == 0;
+
+        for confined in [false, true] {
+            crate::util::with_stage_dir(&format!("mapped-{confined}"), |root| {
+                let file = std::fs::File::open("test-materials/gnu-longname.tar")?;
+                if confined {
+                    extract_layer_confined_mapped(file, CompressionType::Auto, root, &owner_map)
+                        .map_err(|error| std::io::Error::other(error.to_string()))?;
+                } else {
+                    extract_layer_mapped(file, CompressionType::Auto, root, &owner_map)?;
+                }
+
+                let file = std::fs::metadata(root.join(GNU_LONG_DIR).join("file.txt"))?;
+                let link = std::fs::symlink_metadata(root.join("gnu/link"))?;
+                if is_root {
+                    assert_eq!((file.uid(), file.gid()), (100000, 200000));
+                    assert_eq!((link.uid(), link.gid()), (100000, 200000));
+                }
+                Ok(())
+            })?;
+        }
+
+        // entries not covered by the map are rejected
+        let owner_map = OwnerMap {
+            uids: vec!["1:100000:65536".parse().unwrap()],
+            gids: Vec::new(),
+        };
+        crate::util::with_stage_dir("mapped-rejected", |root| {
+            let file = std::fs::File::open("test-materials/gnu-longname.tar")?;
+            let result =
+                extract_layer_confined_mapped(file, CompressionType::Auto, root, &owner_map);
+            assert!(result.is_err());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_extract_gnu_long_names() -> std::io::Result<()> {
+        crate::util::with_stage_dir("gnu-longname", |root| {
+            let long_dir = root.join(GNU_LONG_DIR);
+            std::fs::create_dir_all(&long_dir)?;
+            std::fs::write(long_dir.join("c".repeat(50)), "should be removed")?;
+
+            let file = std::fs::File::open("test-materials/gnu-longname.tar")?;
+            extract_layer(file, CompressionType::Auto, root)?;
+
+            assert!(!long_dir.join("c".repeat(50)).exists());
+            assert_eq!(
+                std::fs::read_to_string(long_dir.join("file.txt"))?,
+                "hello long name\n"
+            );
+            let link = std::fs::read_link(root.join("gnu/link"))?;
+            assert_eq!(
+                link,
+                std::path::PathBuf::from(format!("../{GNU_LONG_DIR}/file.txt"))
+            );
+            assert_eq!(
+                std::fs::read_to_string(root.join("gnu/link"))?,
+                "hello long name\n"
+            );
+            // no entries should be extracted at the truncated paths
+            assert!(!root.join("././@LongLink").exists());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_create_reproducible() -> std::io::Result<()> {
+        let long_name = "l".repeat(120);
+        let create_tree = |root: &Path, names: &[&str], mtime: u64| -> std::io::Result<()> {
+            std::fs::create_dir_all(root.join("dir"))?;
+            for name in names {
+                std::fs::write(root.join("dir").join(name), name)?;
+            }
+            let mtime = std::time::UNIX_EPOCH + std::time::Duration::from_secs(mtime);
+            for name in names.iter().chain(&[""]) {
+                std::fs::File::open(root.join("dir").join(name))?.set_modified(mtime)?;
+            }
+            Ok(())
+        };
+
+        crate::util::with_stage_dir("reproducible", |stage| {
+            let root_a = stage.join("a");
+            let root_b = stage.join("b");
+            create_tree(&root_a, &["b", "a", &long_name, "c"], 1_700_000_000)?;
+            create_tree(&root_b, &["c", &long_name, "a", "b"], 1_600_000_000)?;
+
+            let options = CreateOptions {
+                without_ext: true,
+                compression: CompressionType::Zstd,
+                reproducible: Some(Reproducible {
+                    source_date_epoch: 1_000_000,
+                }),
+                ..CreateOptions::default()
+            };
+            let paths = ["dir".to_string()];
+
+            let mut archive_a = Vec::new();
+            let summary_a = create_layer(
+                &options,
+                root_a.to_str(),
+                &paths,
+                &["x".to_string(), "y/z".to_string()],
+                &mut archive_a,
+            )?;
+            let mut archive_b = Vec::new();
+            let summary_b = create_layer(
+                &options,
+                root_b.to_str(),
+                &paths,
+                &["y/z".to_string(), "x".to_string()],
+                &mut archive_b,
+            )?;
+
+            assert_eq!(summary_a.diff_id, summary_b.diff_id);
+            assert_eq!(summary_a.archive_digest, summary_b.archive_digest);
+            assert_eq!(archive_a, archive_b);
+
+            let listed = list_layer(archive_a.as_slice(), CompressionType::Auto)?;
+            assert_eq!(listed.whiteouts, vec!["x".to_string(), "y/z".to_string()]);
+            assert_eq!(
+                listed
+                    .files
+                    .iter()
+                    .map(|file| file.trim_end_matches('/'))
+                    .collect::<Vec<_>>(),
+                vec![
+                    "dir",
+                    "dir/a",
+                    "dir/b",
+                    "dir/c",
+                    &format!("dir/{long_name}"),
+                ]
+            );
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_dir_diff() -> std::io::Result<()> {
+        let create_tree = |root: &Path, files: &[(&str, &str)]| -> std::io::Result<()> {
+            std::fs::create_dir_all(root)?;
+            for (path, content) in files {
+                let path = root.join(path);
+                std::fs::create_dir_all(path.parent().unwrap())?;
+                std::fs::write(path, content)?;
+            }
+            let mtime = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_600_000_000);
+            let mut entries = BTreeMap::new();
+            walk_tree(root, Path::new(""), &mut entries)?;
+            for path in entries.keys() {
+                std::fs::File::open(root.join(path))?.set_modified(mtime)?;
+            }
+            Ok(())
+        };
+
+        crate::util::with_stage_dir("dirdiff", |stage| {
+            let old = stage.join("old");
+            let new = stage.join("new");
+            create_tree(
+                &old,
+                &[
+                    ("dir/keep", "keep"),
+                    ("dir/changed", "aaaa"),
+                    ("dir/removed", "removed"),
+                    ("gone/x", "x"),
+                    ("swap", "file"),
+                ],
+            )?;
+            create_tree(
+                &new,
+                &[
+                    ("dir/keep", "keep"),
+                    ("dir/changed", "bbbb"),
+                    ("added/z", "z"),
+                    ("swap/y", "y"),
+                ],
+            )?;
+
+            let diff = dir_diff(&old, &new)?;
+            assert_eq!(diff.root, new.to_str().unwrap());
+            assert_eq!(
+                diff.adding,
+                vec!["added", "added/z", "dir/changed", "swap", "swap/y"]
+            );
+            assert_eq!(diff.removing, vec!["dir/removed", "gone", "swap"]);
+
+            let mut archive = Vec::new();
+            let options = CreateOptions {
+                without_ext: true,
+                ..CreateOptions::default()
+            };
+            create_layer_from_dir_diff(&options, &old, &new, &mut archive)?;
+
+            let summary = list_layer(archive.as_slice(), CompressionType::Auto)?;
+            assert_eq!(summary.whiteouts, diff.removing);
+            assert_eq!(
+                summary
+                    .files
+                    .iter()
+                    .map(|file| file.trim_end_matches('/'))
+                    .collect::<Vec<_>>(),
+                diff.adding
+            );
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_create_zstd_multithread_seekable() -> std::io::Result<()> {
+        for seekable_frame_size in [None, Some(4096)] {
+            let options = CreateOptions {
+                without_ext: true,
+                compression: CompressionType::Zstd,
+                zstd: ZstdOptions {
+                    level: 5,
+                    workers: 4,
+                    seekable_frame_size,
+                },
+                ..CreateOptions::default()
+            };
+            let mut archive = Vec::new();
+            let created = create_layer(
+                &options,
+                Some("test-materials"),
+                &["stage-before.tar".to_string()],
+                &[],
+                &mut archive,
+            )?;
+            let listed = list_layer(archive.as_slice(), CompressionType::Auto)?;
+            assert_eq!(listed.diff_id, created.diff_id);
+            assert_eq!(listed.files, vec!["stage-before.tar"]);
+
+            if seekable_frame_size.is_some() {
+                let mut reader = std::io::Cursor::new(&archive);
+                let table = crate::seekable::SeekTable::read_from(&mut reader)?;
+                assert!(table.entries.len() > 1);
+                // the first block of the archive is the header of the only entry
+                let header = table.read_range(&mut reader, 0, 512)?;
+                assert!(header.starts_with(b"stage-before.tar"));
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_recompress_layer() -> std::io::Result<()> {
+        let plain = std::fs::read("test-materials/stage-expected.tar")?;
+        let diff_id: [u8; 32] = Sha256::digest(&plain).into();
+
+        let mut gzip = Vec::new();
+        let to_gzip = recompress_layer(
+            plain.as_slice(),
+            CompressionType::Auto,
+            CompressionType::Gzip,
+            &ZstdOptions::default(),
+            Some(diff_id),
+            &mut gzip,
+        )?;
+        assert_eq!(to_gzip.diff_id, diff_id);
+        assert_eq!(to_gzip.input_digest, diff_id);
+        assert_eq!(
+            to_gzip.archive_digest,
+            <[u8; 32]>::from(Sha256::digest(&gzip))
+        );
+
+        let mut zstd = Vec::new();
+        let to_zstd = recompress_layer(
+            gzip.as_slice(),
+            CompressionType::Auto,
+            CompressionType::Zstd,
+            &ZstdOptions::default(),
+            Some(diff_id),
+            &mut zstd,
+        )?;
+        assert_eq!(to_zstd.input_digest, to_gzip.archive_digest);
+        assert_eq!(
+            list_layer(zstd.as_slice(), CompressionType::Zstd)?.diff_id,
+            diff_id
+        );
+
+        let mismatch = recompress_layer(
+            zstd.as_slice(),
+            CompressionType::Auto,
+            CompressionType::None,
+            &ZstdOptions::default(),
+            Some([0u8; 32]),
+            std::io::sink(),
+        );
+        assert_eq!(
+            mismatch.unwrap_err().kind(),
+            std::io::ErrorKind::InvalidData
+        );
+        Ok(())
+    }
+}
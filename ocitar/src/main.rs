@@ -21,20 +21,20 @@
 // LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
 // OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
 // SUCH DAMAGE.
-mod tar;
-mod util;
-
-use crate::util::*;
 use clap::{Parser, Subcommand};
-use sha2::{Digest, Sha256};
+use ocitar::compression::{CompressionType, ZstdOptions};
+use ocitar::confined::ExtractError;
+use ocitar::diff::{diff_layers, ChangeKind};
+use ocitar::idmap::{IdRange, OwnerMap};
+use ocitar::layer::{
+    create_layer, dir_diff, extract_layer_confined_mapped, extract_layer_mapped, list_layer,
+    list_layer_records, recompress_layer, verify_layer, zfs_diff, CreateOptions,
+};
+use ocitar::squash::squash_layers;
+use ocitar::tar::Reproducible;
+use ocitar::util::{hex, parse_sha256_digest};
 use std::fs::File;
 use std::io::{Read, Write};
-use std::process::Command;
-use util::hex;
-use zstd::{Decoder as ZstdDecoder, Encoder as ZstdEncoder};
-
-const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
-const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about)]
@@ -55,28 +55,41 @@ enum Commands {
     List(ListArgs),
     #[clap(short_flag = 'x')]
     Extract(ExtractArgs),
+    /// Merge layers into a single layer equivalent to applying them in sequence
+    Squash(SquashArgs),
+    /// Convert a layer to another compression, verifying the uncompressed stream
+    Recompress(RecompressArgs),
+    /// Check the headers of a layer and its digests without extracting it
+    Verify(VerifyArgs),
+    /// Show the paths added, removed and modified between two layers, or two stacks of layers
+    Diff(DiffArgs),
 }
 
-#[derive(Debug)]
-enum CompressionType {
-    Auto,
-    None,
-    Zstd,
-    Gzip,
+#[derive(clap::Args, Debug)]
+pub struct ZstdArgs {
+    /// zstd compression level
+    #[clap(long = "zstd-level", default_value_t = 3)]
+    level: i32,
+
+    /// Number of threads compressing with zstd, 0 compresses in the main thread
+    #[clap(long = "zstd-workers", default_value_t = 0)]
+    workers: u32,
+
+    /// Write the zstd seekable format, where frames can be decompressed independently
+    #[clap(long = "zstd-seekable", action)]
+    seekable: bool,
+
+    /// Bytes of uncompressed data in each frame of the zstd seekable format
+    #[clap(long = "zstd-frame-size", default_value_t = 4 << 20)]
+    frame_size: usize,
 }
 
-impl std::str::FromStr for CompressionType {
-    type Err = std::io::Error;
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "auto" => Ok(Self::Auto),
-            "none" => Ok(Self::None),
-            "zstd" => Ok(Self::Zstd),
-            "gzip" => Ok(Self::Gzip),
-            _ => Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "unknown value",
-            )),
+impl ZstdArgs {
+    fn options(&self) -> ZstdOptions {
+        ZstdOptions {
+            level: self.level,
+            workers: self.workers,
+            seekable_frame_size: self.seekable.then_some(self.frame_size),
         }
     }
 }
@@ -92,7 +105,8 @@ pub struct CreateArgs {
     remove: Vec<String>,
 
     /// paths to include in the layer archive, when "--zfs-diff" is set, these define
-    /// the 2 ZFS snapshot / dataset to be diff, in the order zfs-diff(8) accepts
+    /// the 2 ZFS snapshot / dataset to be diff, in the order zfs-diff(8) accepts. When
+    /// "--dir-diff" is set, these are the older and the newer directory
     #[clap(multiple = true)]
     paths: Vec<String>,
 
@@ -100,11 +114,19 @@ pub struct CreateArgs {
     #[clap(long, default_value = "auto")]
     compression: CompressionType,
 
+    #[clap(flatten)]
+    zstd: ZstdArgs,
+
     /// If this flag is set, the utility create an archive with the difference between the two zfs
     /// datasets
     #[clap(long = "zfs-diff", action)]
     zfs_diff: bool,
 
+    /// If this flag is set, the utility create an archive with the difference between the two
+    /// directories, without requiring ZFS
+    #[clap(long = "dir-diff", action, conflicts_with = "zfs-diff")]
+    dir_diff: bool,
+
     /// Do not create OCI whiteout files. Use this program's custom tar extension only
     #[clap(long = "no-oci")]
     without_oci: bool,
@@ -116,6 +138,16 @@ pub struct CreateArgs {
     /// write digest to stderr instead of stdin
     #[clap(long = "write-to-stderr", action)]
     write_to_stderr: bool,
+
+    /// Create a reproducible archive: entries are sorted by path, modification times are clamped
+    /// to the source date epoch, and user and group names are removed
+    #[clap(long, action)]
+    reproducible: bool,
+
+    /// The timestamp modification times are clamped to in reproducible mode, defaults to the
+    /// SOURCE_DATE_EPOCH environment variable, or 0 if unset
+    #[clap(long = "source-date-epoch")]
+    source_date_epoch: Option<u64>,
 }
 
 #[derive(Parser, Debug)]
@@ -128,6 +160,46 @@ pub struct ListArgs {
     /// default, the type of compression will be guessed by the first 4 bytes of the file
     #[clap(long, default_value = "auto")]
     compression: CompressionType,
+
+    /// print one JSON object per line for each entry and whiteout, with its path, type, mode,
+    /// owner, size, modification time and link target
+    #[clap(long, action)]
+    json: bool,
+}
+
+#[derive(Parser, Debug)]
+pub struct VerifyArgs {
+    /// path to the archive file, or '-' for stdin
+    #[clap(short = 'f', long)]
+    file: String,
+
+    /// specify the compression type the archive is compressed in, if set to auto, which is the
+    /// default, the type of compression will be guessed by the first 4 bytes of the file
+    #[clap(long, default_value = "auto")]
+    compression: CompressionType,
+
+    /// the expected digest of the uncompressed stream, in the form of "sha256:{hex}"
+    #[clap(long = "diff-id")]
+    diff_id: Option<String>,
+
+    /// the expected digest of the archive as stored, in the form of "sha256:{hex}"
+    #[clap(long)]
+    digest: Option<String>,
+}
+
+#[derive(Parser, Debug)]
+pub struct DiffArgs {
+    /// print one JSON object per line for each change instead
+    #[clap(long, action)]
+    json: bool,
+
+    /// the old layer followed by the new layer. To compare stacks of layers, list the old stack
+    /// from the bottom to the top, followed by "--" and the new stack
+    #[clap(multiple = true, required = true)]
+    old: Vec<String>,
+
+    #[clap(last = true)]
+    new: Vec<String>,
 }
 
 #[derive(Parser, Debug)]
@@ -145,44 +217,92 @@ pub struct ExtractArgs {
     #[clap(short)]
     /// path to the archive file or '-' for stdin
     file: String,
+
+    /// Extract the entries without tar(1), resolving every entry within the target directory.
+    /// Entries escaping the target directory are skipped and reported
+    #[clap(long, action)]
+    confined: bool,
+
+    /// map the uids of the entries, in the form of "{inside}:{outside}:{count}", for example
+    /// "0:100000:65536" maps uid 0-65535 to 100000-165535. Can be repeated, and uids not covered
+    /// by any range are rejected
+    #[clap(long = "map-uid", multiple_occurrences = true)]
+    map_uid: Vec<IdRange>,
+
+    /// map the gids of the entries, in the same form as "--map-uid"
+    #[clap(long = "map-gid", multiple_occurrences = true)]
+    map_gid: Vec<IdRange>,
 }
 
-fn prepare_compressed_stream_reader(
-    mut input: Box<dyn Read>,
-    hint: CompressionType,
-) -> Result<Box<dyn Read>, std::io::Error> {
-    match hint {
-        CompressionType::Auto => {
-            let mut check_magic = [0u8; 4];
-            input.read_exact(&mut check_magic)?;
-            if check_magic == ZSTD_MAGIC {
-                Ok(Box::new(ZstdDecoder::new(PrebufferedSource::new(
-                    &check_magic,
-                    input,
-                ))?))
-            } else if check_magic[0..2] == GZIP_MAGIC {
-                Ok(Box::new(flate2::read::GzDecoder::new(
-                    PrebufferedSource::new(&check_magic, input),
-                )))
-            } else {
-                Ok(Box::new(PrebufferedSource::new(&check_magic, input)))
-            }
-        }
-        CompressionType::None => Ok(input),
-        CompressionType::Zstd => Ok(Box::new(ZstdDecoder::new(input)?)),
-        CompressionType::Gzip => Ok(Box::new(flate2::read::GzDecoder::new(input))),
-    }
+#[derive(Parser, Debug)]
+pub struct SquashArgs {
+    /// path to the output file, or '-' for stdout
+    #[clap(short = 'f', long)]
+    file: String,
+
+    /// Types of compression to be use for the output, available compressions are zstd and gzip
+    #[clap(long, default_value = "auto")]
+    compression: CompressionType,
+
+    #[clap(flatten)]
+    zstd: ZstdArgs,
+
+    /// write digest to stderr instead of stdin
+    #[clap(long = "write-to-stderr", action)]
+    write_to_stderr: bool,
+
+    /// paths to the layers to squash, from the bottom to the top, in any supported compression
+    #[clap(multiple = true, required = true)]
+    layers: Vec<String>,
+}
+
+#[derive(Parser, Debug)]
+pub struct RecompressArgs {
+    /// path to the input archive, or '-' for stdin
+    #[clap(short = 'f', long)]
+    file: String,
+
+    /// path to the output file, or '-' for stdout
+    #[clap(short = 'o', long)]
+    output: String,
+
+    /// compression of the input archive, guessed by the first 4 bytes if set to auto
+    #[clap(long, default_value = "auto")]
+    from: CompressionType,
+
+    /// compression of the output archive, available compressions are none, zstd and gzip
+    #[clap(long)]
+    to: CompressionType,
+
+    #[clap(flatten)]
+    zstd: ZstdArgs,
+
+    /// fail if the uncompressed stream does not hash to this digest, in the form of
+    /// "sha256:{hex}"
+    #[clap(long = "diff-id")]
+    diff_id: Option<String>,
+
+    /// write digest to stderr instead of stdin
+    #[clap(long = "write-to-stderr", action)]
+    write_to_stderr: bool,
 }
 
 pub fn do_list(args: ListArgs) -> Result<(), std::io::Error> {
-    let mut input: Box<dyn Read> = match args.file.as_str() {
+    let input: Box<dyn Read> = match args.file.as_str() {
         "-" => Box::new(std::io::stdin()),
         path => Box::new(File::open(path)?),
     };
 
-    input = prepare_compressed_stream_reader(input, args.compression)?;
+    if args.json {
+        let mut stdout = std::io::stdout().lock();
+        list_layer_records(input, args.compression, |record| {
+            serde_json::to_writer(&mut stdout, &record)?;
+            stdout.write_all(b"\n")
+        })?;
+        return Ok(());
+    }
 
-    let summary = tar::list_tar(&mut input)?;
+    let summary = list_layer(input, args.compression)?;
 
     for whiteout in summary.whiteouts.iter() {
         println!("-\t{whiteout}");
@@ -195,25 +315,70 @@ pub fn do_list(args: ListArgs) -> Result<(), std::io::Error> {
     Ok(())
 }
 
-fn zfs_dataset_get_mountpoint(dataset: &String) -> Result<Option<String>, std::io::Error> {
-    let dataset = match dataset.split_once(if dataset.contains('#') { '#' } else { '@' }) {
-        None => dataset.to_string(),
-        Some((origin, _)) => origin.to_string(),
+pub fn do_diff(mut args: DiffArgs) -> Result<(), std::io::Error> {
+    if args.new.is_empty() {
+        if args.old.len() != 2 {
+            return Err(std::io::Error::other(
+                "expected two layers, or two stacks of layers separated by --",
+            ));
+        }
+        args.new = args.old.split_off(1);
+    }
+
+    let changes = diff_layers(&args.old, &args.new)?;
+    let mut stdout = std::io::stdout().lock();
+
+    for change in changes.iter() {
+        if args.json {
+            serde_json::to_writer(&mut stdout, change)?;
+            writeln!(stdout)?;
+            continue;
+        }
+
+        let kind = match change.kind {
+            ChangeKind::Added => "+",
+            ChangeKind::Removed => "-",
+            ChangeKind::Modified => "M",
+        };
+        write!(stdout, "{kind}\t{}", change.path)?;
+        for delta in change.deltas.iter() {
+            write!(
+                stdout,
+                "\t{}: {} -> {}",
+                delta.field,
+                delta.old.as_deref().unwrap_or("-"),
+                delta.new.as_deref().unwrap_or("-")
+            )?;
+        }
+        writeln!(stdout)?;
+    }
+    Ok(())
+}
+
+fn parse_digest_arg(digest: Option<&str>, name: &str) -> Result<Option<[u8; 32]>, std::io::Error> {
+    match digest {
+        None => Ok(None),
+        Some(digest) => match parse_sha256_digest(digest) {
+            Some(digest) => Ok(Some(digest)),
+            None => Err(std::io::Error::other(format!("invalid {name}"))),
+        },
+    }
+}
+
+pub fn do_verify(args: VerifyArgs) -> Result<(), std::io::Error> {
+    let expected_diff_id = parse_digest_arg(args.diff_id.as_deref(), "diff_id")?;
+    let expected_digest = parse_digest_arg(args.digest.as_deref(), "digest")?;
+
+    let input: Box<dyn Read> = match args.file.as_str() {
+        "-" => Box::new(std::io::stdin()),
+        path => Box::new(File::open(path)?),
     };
-    let out = std::process::Command::new("zfs")
-        .arg("get")
-        .arg("-Ho")
-        .arg("value")
-        .arg("mountpoint")
-        .arg(dataset)
-        .output()?
-        .stdout;
-    let mountpoint = std::str::from_utf8(&out).unwrap().trim().to_string();
-    Ok(if mountpoint == "-" {
-        None
-    } else {
-        Some(mountpoint)
-    })
+
+    let summary = verify_layer(input, args.compression, expected_diff_id, expected_digest)?;
+
+    println!("sha256:{}", hex(summary.diff_id));
+    println!("sha256:{}", hex(summary.archive_digest));
+    Ok(())
 }
 
 pub fn do_create(args: CreateArgs) -> Result<(), std::io::Error> {
@@ -222,201 +387,152 @@ pub fn do_create(args: CreateArgs) -> Result<(), std::io::Error> {
         path => Box::new(File::create(path)?),
     };
 
-    let sha256 = std::rc::Rc::new(std::cell::RefCell::new(Sha256::new()));
-    let handle = DigestSink::<Box<dyn Write>>::new(output, sha256.clone());
-
-    let mut output: Box<dyn Write> = match args.compression {
-        CompressionType::Zstd => Box::new(ZstdEncoder::new(handle, 3)?.auto_finish()),
-        CompressionType::Gzip => Box::new(flate2::write::GzEncoder::new(
-            handle,
-            flate2::Compression::default(),
-        )),
-        _ => Box::new(handle),
+    let options = CreateOptions {
+        without_oci: args.without_oci,
+        without_ext: !args.with_ext,
+        compression: args.compression,
+        zstd: args.zstd.options(),
+        no_recursion: args.dir_diff,
+        reproducible: args.reproducible.then(|| Reproducible {
+            source_date_epoch: args
+                .source_date_epoch
+                .or_else(|| {
+                    std::env::var("SOURCE_DATE_EPOCH")
+                        .ok()
+                        .and_then(|epoch| epoch.trim().parse().ok())
+                })
+                .unwrap_or(0),
+        }),
     };
 
-    if args.zfs_diff {
+    let summary = if args.zfs_diff || args.dir_diff {
         if args.paths.len() != 2 {
-            panic!("zfs diff accepts two and only two arguments")
+            panic!("zfs diff and dir diff accept two and only two arguments")
         }
 
-        let mut adding = Vec::new();
-        let mut removing = Vec::new();
-
-        let root = format!("{}/", zfs_dataset_get_mountpoint(&args.paths[1])?.unwrap());
-
-        let out = std::process::Command::new("zfs")
-            .arg("diff")
-            .arg("-H")
-            .arg(&args.paths[0])
-            .arg(&args.paths[1])
-            .output()?;
-
-        if !out.status.success() {
-            let mut err_msg = String::new();
-
-            // ZFS error output tends to create line breaking error messages, in order
-            // to print it out without ruining the formatting, we need to re-construct
-            // the actual error message
-            for line in std::str::from_utf8(&out.stderr).unwrap().lines() {
-                if err_msg.ends_with(|c: char| c.is_alphanumeric())
-                    && line.starts_with(|c: char| c.is_alphanumeric())
-                {
-                    err_msg.push(' ');
-                    err_msg.push_str(line);
-                } else {
-                    err_msg.push_str(line);
-                }
-            }
-
-            return Err(std::io::Error::new(std::io::ErrorKind::Other, err_msg));
-        }
+        let diff = if args.zfs_diff {
+            zfs_diff(&args.paths[0], &args.paths[1])?
+        } else {
+            dir_diff(&args.paths[0], &args.paths[1])?
+        };
 
-        let stdout = std::str::from_utf8(&out.stdout).unwrap();
-
-        for line in stdout.lines() {
-            if !args.write_to_stderr {
-                eprintln!("{line}");
+        if !args.write_to_stderr {
+            for path in diff.removing.iter() {
+                eprintln!("-\t{path}");
             }
-            let mut columns = line.split('\t'); //.collect::<Vec<_>>();
-            let flag = columns.next().expect("Expect flag");
-            let path = columns
-                .next()
-                .expect("Expect path")
-                .to_string()
-                .replacen(&root, "", 1);
-            match flag {
-                "-" => removing.push(path),
-                "+" => adding.push(path),
-                "M" => adding.push(path),
-                "R" => {
-                    let new = columns.next().expect("expect new path").to_string();
-                    removing.push(path.to_string());
-                    adding.push(new.replacen(&path, "", 1))
-                }
-                _ => unreachable!(),
+            for path in diff.adding.iter() {
+                eprintln!("+\t{path}");
             }
         }
 
-        create_tar(
-            args.without_oci,
-            !args.with_ext,
-            &["-C".to_string(), root],
-            &adding,
-            &removing,
-            &mut output,
-            args.write_to_stderr,
-        )?;
+        create_layer(
+            &options,
+            Some(&diff.root),
+            &diff.adding,
+            &diff.removing,
+            output,
+        )?
     } else {
-        create_tar(
-            args.without_oci,
-            !args.with_ext,
-            &[],
-            &args.paths,
-            &args.remove,
-            &mut output,
-            args.write_to_stderr,
-        )?;
-    }
-
-    drop(output);
-    let digest: [u8; 32] = sha256.borrow().clone().finalize().into();
+        create_layer(&options, None, &args.paths, &args.remove, output)?
+    };
 
     if !args.write_to_stderr {
-        println!("sha256:{}", hex(digest));
+        println!("sha256:{}", hex(summary.diff_id));
+        println!("sha256:{}", hex(summary.archive_digest));
     } else {
-        eprintln!("sha256:{}", hex(digest));
+        eprintln!("sha256:{}", hex(summary.diff_id));
+        eprintln!("sha256:{}", hex(summary.archive_digest));
     }
     Ok(())
 }
 
-pub fn do_extract(args: ExtractArgs) -> Result<(), std::io::Error> {
-    let mut input: Box<dyn Read> = match args.file.as_str() {
-        "-" => Box::new(std::io::stdin()),
-        path => Box::new(File::open(path)?),
+pub fn do_squash(args: SquashArgs) -> Result<(), std::io::Error> {
+    let output: Box<dyn Write> = match args.file.as_str() {
+        "-" => Box::new(std::io::stdout()),
+        path => Box::new(File::create(path)?),
     };
 
-    let digest_input = std::rc::Rc::new(std::cell::RefCell::new(
-        DigestReader::<Box<dyn Read>>::new(input),
-    ));
-
-    let handle = DigestReaderHandle(digest_input.clone());
+    let summary = squash_layers(&args.layers, args.compression, &args.zstd.options(), output)?;
 
-    input = prepare_compressed_stream_reader(Box::new(handle), args.compression)?;
-
-    if let Some(dir) = args.chdir {
-        std::env::set_current_dir(dir)?;
-    }
-    extract(&mut input)?;
-    let digest = digest_input.borrow().consume();
-    if args.print_input_digest {
-        println!("sha256:{}", hex(digest));
+    if !args.write_to_stderr {
+        println!("sha256:{}", hex(summary.diff_id));
+        println!("sha256:{}", hex(summary.archive_digest));
+    } else {
+        eprintln!("sha256:{}", hex(summary.diff_id));
+        eprintln!("sha256:{}", hex(summary.archive_digest));
     }
     Ok(())
 }
 
-fn extract<R: Read>(reader: &mut R) -> Result<(), std::io::Error> {
-    let mut child = Command::new("tar")
-        .arg("-xf-")
-        .stdin(std::process::Stdio::piped())
-        .spawn()?;
+pub fn do_recompress(args: RecompressArgs) -> Result<(), std::io::Error> {
+    let expected_diff_id = parse_digest_arg(args.diff_id.as_deref(), "diff_id")?;
 
-    let tar_stdin = child.stdin.as_mut().unwrap();
-
-    let digest = tar::tap_extract_tar(reader, tar_stdin)?;
+    let input: Box<dyn Read> = match args.file.as_str() {
+        "-" => Box::new(std::io::stdin()),
+        path => Box::new(File::open(path)?),
+    };
 
-    println!("sha256:{}", hex(digest));
+    let output: Box<dyn Write> = match args.output.as_str() {
+        "-" => Box::new(std::io::stdout()),
+        path => Box::new(File::create(path)?),
+    };
 
-    match child.wait()?.code() {
-        Some(ec) if ec != 0 => {
-            err!("tar return non-zero exit code")
+    let result = recompress_layer(
+        input,
+        args.from,
+        args.to,
+        &args.zstd.options(),
+        expected_diff_id,
+        output,
+    );
+
+    let recompressed = match result {
+        Ok(recompressed) => recompressed,
+        Err(error) => {
+            if args.output != "-" {
+                _ = std::fs::remove_file(&args.output);
+            }
+            return Err(error);
         }
-        _ => Ok(()),
+    };
+
+    if !args.write_to_stderr {
+        println!("sha256:{}", hex(recompressed.diff_id));
+        println!("sha256:{}", hex(recompressed.archive_digest));
+    } else {
+        eprintln!("sha256:{}", hex(recompressed.diff_id));
+        eprintln!("sha256:{}", hex(recompressed.archive_digest));
     }
+    Ok(())
 }
 
-fn create_tar<W: Write>(
-    without_oci: bool,
-    without_ext: bool,
-    tar_options: &[String],
-    paths: &[String],
-    whiteouts: &[String],
-    output: &mut W,
-    write_to_stderr: bool,
-) -> Result<(), std::io::Error> {
-    let paths_input = paths.join("\n");
-
-    let mut child = Command::new("tar")
-        .args(tar_options)
-        .arg("-cf-")
-        .arg("-T-") //.args(paths)
-        .stdin(std::process::Stdio::piped())
-        .stdout(std::process::Stdio::piped())
-        .stderr(std::process::Stdio::null())
-        .spawn()?;
-
-    let mut child_stdin = child.stdin.take().unwrap();
-
-    std::thread::spawn(move || {
-        _ = child_stdin.write_all(paths_input.as_bytes());
-        drop(child_stdin);
-    });
-
-    let tar_stdout = child.stdout.as_mut().unwrap();
-
-    let digest = tar::tap_create_tar(without_oci, without_ext, whiteouts, tar_stdout, output)?;
+pub fn do_extract(args: ExtractArgs) -> Result<(), std::io::Error> {
+    let input: Box<dyn Read> = match args.file.as_str() {
+        "-" => Box::new(std::io::stdin()),
+        path => Box::new(File::open(path)?),
+    };
 
-    if !write_to_stderr {
-        println!("sha256:{}", hex(digest));
+    let owner_map = OwnerMap {
+        uids: args.map_uid,
+        gids: args.map_gid,
+    };
+    let root = args.chdir.unwrap_or_else(|| ".".to_string());
+    let summary = if args.confined {
+        extract_layer_confined_mapped(input, args.compression, root, &owner_map).map_err(
+            |error| match error {
+                ExtractError::Io(error) => error,
+                error => std::io::Error::other(error.to_string()),
+            },
+        )?
     } else {
-        eprintln!("sha256:{}", hex(digest));
-    }
+        extract_layer_mapped(input, args.compression, root, &owner_map)?
+    };
 
-    match child.wait()?.code() {
-        Some(code) if code != 0 => {
-            err!("tar returns non-zero exit code")
-        }
-        _ => Ok(()),
+    println!("sha256:{}", hex(summary.diff_id));
+    if args.print_input_digest {
+        println!("sha256:{}", hex(summary.archive_digest));
     }
+    Ok(())
 }
 
 fn main() -> Result<(), std::io::Error> {
@@ -435,6 +551,7 @@ fn main() -> Result<(), std::io::Error> {
     // setup logging facility
     stderrlog::new()
         .module(module_path!())
+        .module("ocitar")
         .verbosity(args.verbosity)
         .init()
         .unwrap();
@@ -445,6 +562,10 @@ fn main() -> Result<(), std::io::Error> {
         Commands::Create(c) => do_create(c)?,
         Commands::List(c) => do_list(c)?,
         Commands::Extract(c) => do_extract(c)?,
+        Commands::Squash(c) => do_squash(c)?,
+        Commands::Recompress(c) => do_recompress(c)?,
+        Commands::Verify(c) => do_verify(c)?,
+        Commands::Diff(c) => do_diff(c)?,
     };
     Ok(())
 }
@@ -515,6 +636,9 @@ mod tests {
                 file: "test-materials/base.tar.zst".to_string(),
                 compression: CompressionType::Auto,
                 print_input_digest: false,
+                confined: false,
+                map_uid: Vec::new(),
+                map_gid: Vec::new(),
             };
             do_extract(extract_arg).unwrap();
         });
@@ -529,6 +653,26 @@ mod tests {
                 file: "test-materials/base.tar".to_string(),
                 compression: CompressionType::Auto,
                 print_input_digest: false,
+                confined: false,
+                map_uid: Vec::new(),
+                map_gid: Vec::new(),
+            };
+            do_extract(extract_arg).unwrap();
+        });
+    }
+
+    #[test]
+    #[serial]
+    fn test_extract_confined() {
+        test_extraction("confined", |dir| {
+            let extract_arg = ExtractArgs {
+                chdir: Some(dir.to_string()),
+                file: "test-materials/base.tar.zst".to_string(),
+                compression: CompressionType::Auto,
+                print_input_digest: false,
+                confined: true,
+                map_uid: Vec::new(),
+                map_gid: Vec::new(),
             };
             do_extract(extract_arg).unwrap();
         });
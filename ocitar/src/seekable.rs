@@ -0,0 +1,326 @@
+// Copyright (c) 2023 Yan Ka, Chiu.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions
+// are met:
+// 1. Redistributions of source code must retain the above copyright
+//    notice, this list of conditions, and the following disclaimer,
+//    without modification, immediately at the beginning of the file.
+// 2. The name of the author may not be used to endorse or promote products
+//    derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE AUTHOR AND CONTRIBUTORS ``AS IS'' AND
+// ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE AUTHOR OR CONTRIBUTORS BE LIABLE FOR
+// ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+// OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+// HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+// LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
+// OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
+// SUCH DAMAGE.
+//! The zstd seekable format: the data is compressed into independent frames, followed by a seek
+//! table in a skippable frame mapping the frames to their offsets. The result is still a valid
+//! zstd stream, while readers aware of the format can decompress any range of the data without
+//! decompressing the frames before it.
+//!
+//! See https://github.com/facebook/zstd/blob/dev/contrib/seekable_format/zstd_seekable_compression_format.md
+use crate::util::err;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+pub const SKIPPABLE_FRAME_MAGIC: u32 = 0x184d2a5e;
+pub const SEEKABLE_MAGIC: u32 = 0x8f92eab1;
+
+/// The largest frame size accepted, the sizes in the seek table are 32 bits
+pub const MAX_FRAME_SIZE: usize = 1 << 30;
+
+/// An encoder writing the zstd seekable format. Up to `workers` frames are compressed in
+/// parallel. The seek table is written by `finish`, or when the encoder is dropped
+pub struct SeekableEncoder<W: Write> {
+    writer: Option<W>,
+    level: i32,
+    workers: usize,
+    frame_size: usize,
+    /// uncompressed data of the current frame
+    buffer: Vec<u8>,
+    /// frames waiting to be compressed
+    pending: Vec<Vec<u8>>,
+    /// compressed and decompressed size of the frames written
+    frames: Vec<(u32, u32)>,
+}
+
+impl<W: Write> SeekableEncoder<W> {
+    pub fn new(
+        writer: W,
+        level: i32,
+        workers: usize,
+        frame_size: usize,
+    ) -> std::io::Result<SeekableEncoder<W>> {
+        if frame_size == 0 || frame_size > MAX_FRAME_SIZE {
+            return err!("invalid seekable frame size");
+        }
+        Ok(SeekableEncoder {
+            writer: Some(writer),
+            level,
+            workers: workers.max(1),
+            frame_size,
+            buffer: Vec::with_capacity(frame_size),
+            pending: Vec::new(),
+            frames: Vec::new(),
+        })
+    }
+
+    fn compress_pending(&mut self) -> std::io::Result<()> {
+        let level = self.level;
+        let pending = std::mem::take(&mut self.pending);
+        let compressed = if pending.len() == 1 {
+            vec![zstd::bulk::compress(&pending[0], level)]
+        } else {
+            std::thread::scope(|scope| {
+                let handles = pending
+                    .iter()
+                    .map(|frame| scope.spawn(move || zstd::bulk::compress(frame, level)))
+                    .collect::<Vec<_>>();
+                handles
+                    .into_iter()
+                    .map(|handle| handle.join().expect("zstd worker panicked"))
+                    .collect::<Vec<_>>()
+            })
+        };
+
+        let writer = self.writer.as_mut().unwrap();
+        for (frame, compressed) in pending.iter().zip(compressed) {
+            let compressed = compressed?;
+            writer.write_all(&compressed)?;
+            self.frames
+                .push((compressed.len() as u32, frame.len() as u32));
+        }
+        Ok(())
+    }
+
+    fn write_seek_table(&mut self) -> std::io::Result<()> {
+        if !self.buffer.is_empty() {
+            self.pending.push(std::mem::take(&mut self.buffer));
+        }
+        if !self.pending.is_empty() {
+            self.compress_pending()?;
+        }
+
+        let mut table = Vec::with_capacity(self.frames.len() * 8 + 9);
+        for (compressed, decompressed) in self.frames.iter() {
+            table.extend_from_slice(&compressed.to_le_bytes());
+            table.extend_from_slice(&decompressed.to_le_bytes());
+        }
+        table.extend_from_slice(&(self.frames.len() as u32).to_le_bytes());
+        // seek table descriptor, without checksums
+        table.push(0);
+        table.extend_from_slice(&SEEKABLE_MAGIC.to_le_bytes());
+
+        let writer = self.writer.as_mut().unwrap();
+        writer.write_all(&SKIPPABLE_FRAME_MAGIC.to_le_bytes())?;
+        writer.write_all(&(table.len() as u32).to_le_bytes())?;
+        writer.write_all(&table)?;
+        writer.flush()
+    }
+
+    /// Compress the remaining data, write the seek table and return the underlying writer
+    pub fn finish(mut self) -> std::io::Result<W> {
+        self.write_seek_table()?;
+        Ok(self.writer.take().unwrap())
+    }
+}
+
+impl<W: Write> Write for SeekableEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let length = buf.len().min(self.frame_size - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..length]);
+        if self.buffer.len() == self.frame_size {
+            let frame = std::mem::replace(&mut self.buffer, Vec::with_capacity(self.frame_size));
+            self.pending.push(frame);
+            if self.pending.len() == self.workers {
+                self.compress_pending()?;
+            }
+        }
+        Ok(length)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.as_mut().unwrap().flush()
+    }
+}
+
+impl<W: Write> Drop for SeekableEncoder<W> {
+    fn drop(&mut self) {
+        if self.writer.is_some() {
+            if let Err(error) = self.write_seek_table() {
+                log::error!("cannot finish seekable zstd stream: {error}");
+            }
+        }
+    }
+}
+
+/// The location of a frame in a seekable zstd stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SeekTableEntry {
+    pub compressed_offset: u64,
+    pub compressed_size: u32,
+    pub decompressed_offset: u64,
+    pub decompressed_size: u32,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SeekTable {
+    pub entries: Vec<SeekTableEntry>,
+}
+
+impl SeekTable {
+    /// Read the seek table at the end of a seekable zstd stream
+    pub fn read_from<R: Read + Seek>(reader: &mut R) -> std::io::Result<SeekTable> {
+        let mut footer = [0u8; 9];
+        reader.seek(SeekFrom::End(-9))?;
+        reader.read_exact(&mut footer)?;
+
+        let count = u32::from_le_bytes(footer[..4].try_into().unwrap()) as u64;
+        let descriptor = footer[4];
+        if u32::from_le_bytes(footer[5..].try_into().unwrap()) != SEEKABLE_MAGIC {
+            return err!("not a seekable zstd stream");
+        }
+        if descriptor & 0x7c != 0 {
+            return err!("reserved bits of seek table descriptor are set");
+        }
+        let entry_size = if descriptor & 0x80 != 0 { 12 } else { 8 };
+        let table_size = count * entry_size + 9;
+
+        let mut frame_header = [0u8; 8];
+        reader.seek(SeekFrom::End(-(table_size as i64 + 8)))?;
+        reader.read_exact(&mut frame_header)?;
+        if u32::from_le_bytes(frame_header[..4].try_into().unwrap()) != SKIPPABLE_FRAME_MAGIC
+            || u32::from_le_bytes(frame_header[4..].try_into().unwrap()) as u64 != table_size
+        {
+            return err!("malformed seek table frame");
+        }
+
+        let mut table = vec![0u8; (count * entry_size) as usize];
+        reader.read_exact(&mut table)?;
+
+        let mut entries = Vec::with_capacity(count as usize);
+        let mut compressed_offset = 0u64;
+        let mut decompressed_offset = 0u64;
+        for entry in table.chunks(entry_size as usize) {
+            let compressed_size = u32::from_le_bytes(entry[..4].try_into().unwrap());
+            let decompressed_size = u32::from_le_bytes(entry[4..8].try_into().unwrap());
+            entries.push(SeekTableEntry {
+                compressed_offset,
+                compressed_size,
+                decompressed_offset,
+                decompressed_size,
+            });
+            compressed_offset += compressed_size as u64;
+            decompressed_offset += decompressed_size as u64;
+        }
+
+        Ok(SeekTable { entries })
+    }
+
+    pub fn decompressed_size(&self) -> u64 {
+        self.entries
+            .last()
+            .map(|entry| entry.decompressed_offset + entry.decompressed_size as u64)
+            .unwrap_or(0)
+    }
+
+    /// Index of the frame containing the decompressed `offset`
+    pub fn frame_index_at(&self, offset: u64) -> Option<usize> {
+        if offset >= self.decompressed_size() {
+            return None;
+        }
+        Some(
+            self.entries
+                .partition_point(|entry| entry.decompressed_offset <= offset)
+                - 1,
+        )
+    }
+
+    /// Decompress a single frame
+    pub fn read_frame<R: Read + Seek>(
+        &self,
+        reader: &mut R,
+        index: usize,
+    ) -> std::io::Result<Vec<u8>> {
+        let Some(entry) = self.entries.get(index) else {
+            return err!("frame index out of range");
+        };
+        let mut compressed = vec![0u8; entry.compressed_size as usize];
+        reader.seek(SeekFrom::Start(entry.compressed_offset))?;
+        reader.read_exact(&mut compressed)?;
+        let frame = zstd::bulk::decompress(&compressed, entry.decompressed_size as usize)?;
+        if frame.len() != entry.decompressed_size as usize {
+            return err!("frame size does not match the seek table");
+        }
+        Ok(frame)
+    }
+
+    /// Decompress `length` bytes of data starting at the decompressed `offset`, only the frames
+    /// covering the range are decompressed
+    pub fn read_range<R: Read + Seek>(
+        &self,
+        reader: &mut R,
+        offset: u64,
+        length: usize,
+    ) -> std::io::Result<Vec<u8>> {
+        if offset + length as u64 > self.decompressed_size() {
+            return err!("range exceeds the decompressed size");
+        }
+        let mut data = Vec::with_capacity(length);
+        let mut position = offset;
+        while data.len() < length {
+            let index = self.frame_index_at(position).unwrap();
+            let frame = self.read_frame(reader, index)?;
+            let start = (position - self.entries[index].decompressed_offset) as usize;
+            let end = frame.len().min(start + length - data.len());
+            data.extend_from_slice(&frame[start..end]);
+            position += (end - start) as u64;
+        }
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seekable_round_trip() -> std::io::Result<()> {
+        let data = (0..100_000u32)
+            .flat_map(|i| (i % 251).to_le_bytes())
+            .collect::<Vec<u8>>();
+
+        for workers in [1, 4] {
+            let mut encoder = SeekableEncoder::new(Vec::new(), 3, workers, 32 * 1024)?;
+            encoder.write_all(&data)?;
+            let compressed = encoder.finish()?;
+
+            // the seekable format is a valid zstd stream
+            let mut decoded = Vec::new();
+            zstd::Decoder::new(compressed.as_slice())?.read_to_end(&mut decoded)?;
+            assert_eq!(decoded, data);
+
+            let mut reader = std::io::Cursor::new(compressed);
+            let table = SeekTable::read_from(&mut reader)?;
+            assert_eq!(table.entries.len(), data.len().div_ceil(32 * 1024));
+            assert_eq!(table.decompressed_size(), data.len() as u64);
+            assert_eq!(table.frame_index_at(32 * 1024), Some(1));
+            assert_eq!(
+                table.read_range(&mut reader, 30_000, 70_000)?,
+                &data[30_000..100_000]
+            );
+            assert_eq!(
+                table.read_frame(&mut reader, 2)?,
+                &data[64 * 1024..96 * 1024]
+            );
+        }
+        Ok(())
+    }
+}
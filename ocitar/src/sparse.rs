@@ -0,0 +1,336 @@
+// Copyright (c) 2023 Yan Ka, Chiu.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions
+// are met:
+// 1. Redistributions of source code must retain the above copyright
+//    notice, this list of conditions, and the following disclaimer,
+//    without modification, immediately at the beginning of the file.
+// 2. The name of the author may not be used to endorse or promote products
+//    derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE AUTHOR AND CONTRIBUTORS ``AS IS'' AND
+// ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE AUTHOR OR CONTRIBUTORS BE LIABLE FOR
+// ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+// OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+// HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+// LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
+// OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
+// SUCH DAMAGE.
+//! GNU sparse entries, where only the data segments of a file are stored along with a map of
+//! their offsets, and the holes in between are recreated on extraction. Both the old GNU format
+//! (type flag 'S', with the map in the header and the extended sparse headers following it) and
+//! the PAX formats 0.0, 0.1 and 1.0 (`GNU.sparse.*` records, format 1.0 storing the map at the
+//! start of the entry content) are understood.
+use crate::tar::{parse_numeric, Extension, TarEntry};
+use crate::util::err;
+use std::collections::VecDeque;
+use std::io::Read;
+
+/// The layout of a sparse entry
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SparseMap {
+    /// Size of the file once extracted
+    pub real_size: u64,
+    /// The (offset, length) of the data segments, in the order they are stored in the archive
+    pub segments: Vec<(u64, u64)>,
+    /// Number of bytes preceding the data of the segments in the blocks following the header,
+    /// occupied by the extended sparse headers or the format 1.0 map
+    pub map_size: u64,
+}
+
+/// Walk the content blocks of a regular entry as the data segments of the file extracted from
+/// it. The sparse map at the start of the content is skipped, and the whole file is a single
+/// segment unless the entry is sparse
+pub(crate) struct ContentSegments {
+    /// bytes of the entry content yet to be read
+    remaining: u64,
+    /// bytes of the sparse map at the start of the content yet to be skipped
+    skip: u64,
+    /// the (offset, length) of the data segments yet to be read
+    segments: VecDeque<(u64, u64)>,
+    /// size of the file once extracted, the holes at the end are not covered by any segment
+    pub real_size: u64,
+}
+
+impl ContentSegments {
+    pub fn new(entry: &TarEntry) -> ContentSegments {
+        let (skip, segments, real_size) = match &entry.sparse {
+            Some(sparse) => (
+                sparse.map_size,
+                sparse.segments.iter().copied().collect(),
+                sparse.real_size,
+            ),
+            None => (0, [(0, entry.size)].into(), entry.size),
+        };
+        ContentSegments {
+            remaining: entry.size,
+            skip,
+            segments,
+            real_size,
+        }
+    }
+
+    /// If all the content blocks of the entry are read
+    pub fn is_done(&self) -> bool {
+        self.remaining == 0
+    }
+
+    /// Read the next content block, `data` is called with the offset in the file and the data
+    /// at the offset, in the order of the segments
+    pub fn read_block(
+        &mut self,
+        block: &[u8; 512],
+        mut data: impl FnMut(u64, &[u8]) -> std::io::Result<()>,
+    ) -> std::io::Result<()> {
+        let length = self.remaining.min(512);
+        self.remaining -= length;
+        let skip = self.skip.min(length);
+        self.skip -= skip;
+        let mut rest = &block[skip as usize..length as usize];
+        while !rest.is_empty() {
+            let Some((offset, length)) = self.segments.front_mut() else {
+                return err!("sparse entry content exceeds its map");
+            };
+            let n = (*length).min(rest.len() as u64) as usize;
+            data(*offset, &rest[..n])?;
+            *offset += n as u64;
+            *length -= n as u64;
+            if *length == 0 {
+                self.segments.pop_front();
+            }
+            rest = &rest[n..];
+        }
+        Ok(())
+    }
+}
+
+fn parse_decimal(value: &str) -> std::io::Result<u64> {
+    match value.trim().parse::<u64>() {
+        Ok(value) => Ok(value),
+        Err(_) => err!("malformed sparse map"),
+    }
+}
+
+/// Parse the old GNU sparse descriptors, each of them is a 12 bytes offset followed by a 12 bytes
+/// length, the first zero descriptor terminates the list
+fn parse_descriptors(buf: &[u8], segments: &mut Vec<(u64, u64)>) -> std::io::Result<bool> {
+    for descriptor in buf.chunks_exact(24) {
+        if descriptor[0] == 0 {
+            return Ok(false);
+        }
+        let offset = parse_numeric(&descriptor[..12])?;
+        let length = parse_numeric(&descriptor[12..])?;
+        segments.push((offset, length));
+    }
+    Ok(true)
+}
+
+/// Find the sparse map of an entry, if it is a sparse entry. The map may follow the header, in
+/// which case the blocks read from `reader` are appended to `blocks`, and `map_size` is the
+/// number of bytes read
+pub(crate) fn read_sparse_map<R: Read>(
+    header: &[u8; 512],
+    extensions: &[Extension],
+    reader: &mut R,
+    blocks: &mut Vec<u8>,
+) -> std::io::Result<Option<SparseMap>> {
+    let find = |key: &str| {
+        extensions
+            .iter()
+            .rev()
+            .find(|ext| ext.key == key)
+            .and_then(|ext| ext.value_str())
+    };
+
+    if find("GNU.sparse.major") == Some("1") {
+        let Some(real_size) = find("GNU.sparse.realsize") else {
+            return err!("sparse entry without real size");
+        };
+        let real_size = parse_decimal(real_size)?;
+        let content_length = parse_numeric(&header[124..136])?;
+        // the map is a decimal count of segments, followed by the offset and length of each
+        // segment, one number per line, padded to the block size
+        let mut numbers = Vec::new();
+        // the count of numbers in the map, known once the first number is read
+        let mut expected = None;
+        let mut line = Vec::new();
+        let mut buf = [0u8; 512];
+        loop {
+            if blocks.len() as u64 >= content_length {
+                return err!("malformed sparse map");
+            }
+            reader.read_exact(&mut buf)?;
+            blocks.extend_from_slice(&buf);
+            for byte in buf {
+                if byte != b'\n' {
+                    line.push(byte);
+                    continue;
+                }
+                let Ok(value) = std::str::from_utf8(&line) else {
+                    return err!("malformed sparse map");
+                };
+                numbers.push(parse_decimal(value)?);
+                line.clear();
+                if numbers.len() == 1 {
+                    let Some(count) = numbers[0].checked_mul(2).and_then(|n| n.checked_add(1))
+                    else {
+                        return err!("malformed sparse map");
+                    };
+                    expected = Some(count);
+                }
+                if expected == Some(numbers.len() as u64) {
+                    break;
+                }
+            }
+            if expected == Some(numbers.len() as u64) {
+                break;
+            }
+        }
+        let segments = numbers[1..]
+            .chunks_exact(2)
+            .map(|pair| (pair[0], pair[1]))
+            .collect();
+        return Ok(Some(SparseMap {
+            real_size,
+            segments,
+            map_size: blocks.len() as u64,
+        }));
+    }
+
+    if let Some(map) = find("GNU.sparse.map") {
+        let Some(real_size) = find("GNU.sparse.size") else {
+            return err!("sparse entry without real size");
+        };
+        let numbers = map
+            .split(',')
+            .filter(|value| !value.is_empty())
+            .map(parse_decimal)
+            .collect::<std::io::Result<Vec<_>>>()?;
+        if numbers.len() % 2 != 0 {
+            return err!("malformed sparse map");
+        }
+        return Ok(Some(SparseMap {
+            real_size: parse_decimal(real_size)?,
+            segments: numbers
+                .chunks_exact(2)
+                .map(|pair| (pair[0], pair[1]))
+                .collect(),
+            map_size: 0,
+        }));
+    }
+
+    if find("GNU.sparse.offset").is_some() {
+        let Some(real_size) = find("GNU.sparse.size") else {
+            return err!("sparse entry without real size");
+        };
+        // format 0.0 repeats the offset and numbytes records for each segment
+        let mut segments = Vec::new();
+        let mut offset = None;
+        for extension in extensions.iter() {
+            let Some(value) = extension.value_str() else {
+                continue;
+            };
+            match extension.key.as_str() {
+                "GNU.sparse.offset" => offset = Some(parse_decimal(value)?),
+                "GNU.sparse.numbytes" => match offset.take() {
+                    Some(offset) => segments.push((offset, parse_decimal(value)?)),
+                    None => return err!("malformed sparse map"),
+                },
+                _ => (),
+            }
+        }
+        return Ok(Some(SparseMap {
+            real_size: parse_decimal(real_size)?,
+            segments,
+            map_size: 0,
+        }));
+    }
+
+    if header[156] == b'S' {
+        let mut segments = Vec::new();
+        parse_descriptors(&header[386..482], &mut segments)?;
+        let real_size = parse_numeric(&header[483..495])?;
+        let mut extended = header[482] != 0;
+        let mut buf = [0u8; 512];
+        while extended {
+            reader.read_exact(&mut buf)?;
+            blocks.extend_from_slice(&buf);
+            parse_descriptors(&buf[..504], &mut segments)?;
+            extended = buf[504] != 0;
+        }
+        return Ok(Some(SparseMap {
+            real_size,
+            segments,
+            map_size: blocks.len() as u64,
+        }));
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pax_1_0_map() -> std::io::Result<()> {
+        let extensions = vec![
+            Extension::new("GNU.sparse.major".to_string(), "1".to_string()),
+            Extension::new("GNU.sparse.minor".to_string(), "0".to_string()),
+            Extension::new("GNU.sparse.realsize".to_string(), "10485762".to_string()),
+        ];
+        let mut content = b"2\n0\n512\n10485760\n2\n".to_vec();
+        content.resize(1024, 0);
+        let mut header = [0u8; 512];
+        header[124..136].copy_from_slice(b"00000002002\0");
+        let mut blocks = Vec::new();
+        let map = read_sparse_map(&header, &extensions, &mut content.as_slice(), &mut blocks)?;
+        assert_eq!(
+            map,
+            Some(SparseMap {
+                real_size: 10485762,
+                segments: vec![(0, 512), (10485760, 2)],
+                map_size: 512,
+            })
+        );
+        assert_eq!(blocks.len(), 512);
+        Ok(())
+    }
+
+    #[test]
+    fn test_pax_1_0_map_overflow() {
+        let extensions = vec![
+            Extension::new("GNU.sparse.major".to_string(), "1".to_string()),
+            Extension::new("GNU.sparse.realsize".to_string(), "1".to_string()),
+        ];
+        let mut content = format!("{}\n", u64::MAX).into_bytes();
+        content.resize(1024, 0);
+        let mut header = [0u8; 512];
+        header[124..136].copy_from_slice(b"00000002000\0");
+        let result = read_sparse_map(&header, &extensions, &mut content.as_slice(), &mut vec![]);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_pax_0_1_map() -> std::io::Result<()> {
+        let extensions = vec![
+            Extension::new("GNU.sparse.size".to_string(), "4096".to_string()),
+            Extension::new("GNU.sparse.map".to_string(), "0,10,4000,96".to_string()),
+        ];
+        let map = read_sparse_map(&[0u8; 512], &extensions, &mut std::io::empty(), &mut vec![])?;
+        assert_eq!(
+            map,
+            Some(SparseMap {
+                real_size: 4096,
+                segments: vec![(0, 10), (4000, 96)],
+                map_size: 0,
+            })
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,487 @@
+// Copyright (c) 2023 Yan Ka, Chiu.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions
+// are met:
+// 1. Redistributions of source code must retain the above copyright
+//    notice, this list of conditions, and the following disclaimer,
+//    without modification, immediately at the beginning of the file.
+// 2. The name of the author may not be used to endorse or promote products
+//    derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE AUTHOR AND CONTRIBUTORS ``AS IS'' AND
+// ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE AUTHOR OR CONTRIBUTORS BE LIABLE FOR
+// ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+// OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+// HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+// LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
+// OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
+// SUCH DAMAGE.
+//! Merge a stack of layers into a single layer
+use crate::compression::{prepare_compressed_stream_writer_with, CompressionType, ZstdOptions};
+use crate::layer::visit_layer;
+use crate::tar::{
+    write_entry_extensions, write_oci_whiteouts, Extension, RawTarHeader, Summary, TarEntry,
+    TarEntryHandle, WhiteoutExtension, EMPTY_TAR_HEADER,
+};
+use crate::tree::LayerTree;
+use crate::util::{normalize_entry_path, DigestSink, DigestWriter};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// An entry of a layer, identified by the index of the layer and the index of the entry within
+type EntryId = (usize, usize);
+
+/// The entries and whiteouts of a layer, in the order they appear in the archive
+#[derive(Default)]
+struct LayerEntries {
+    /// path of the entry and if the entry is a directory
+    entries: Vec<(PathBuf, bool)>,
+    /// the target of the hardlink entries, by the index of the entry
+    hardlinks: HashMap<usize, PathBuf>,
+    whiteouts: Vec<PathBuf>,
+    opaques: Vec<PathBuf>,
+}
+
+impl TarEntryHandle for LayerEntries {
+    fn on_whiteout_extension(&mut self, extension: WhiteoutExtension) -> std::io::Result<()> {
+        for whiteout in extension.whiteouts.iter() {
+            self.whiteouts
+                .push(normalize_entry_path(Path::new(whiteout)));
+        }
+        Ok(())
+    }
+
+    fn on_oci_whiteout_entry(&mut self, path: PathBuf) -> std::io::Result<()> {
+        self.whiteouts.push(normalize_entry_path(&path));
+        Ok(())
+    }
+
+    fn on_oci_whiteout_opq(&mut self, path: PathBuf) -> std::io::Result<()> {
+        self.opaques.push(normalize_entry_path(&path));
+        Ok(())
+    }
+
+    fn on_normal_entry(&mut self, _buf: &[u8; 512], entry: &TarEntry) -> std::io::Result<()> {
+        if entry.header.entry_type() == b'1' {
+            if let Some(target) = entry.link_path.as_ref() {
+                self.hardlinks
+                    .insert(self.entries.len(), normalize_entry_path(target));
+            }
+        }
+        self.entries.push((
+            normalize_entry_path(&entry.path),
+            entry.header.entry_type() == b'5',
+        ));
+        Ok(())
+    }
+}
+
+/// What the layers above the one being resolved have done to the tree
+#[derive(Default)]
+struct Upper {
+    /// paths with a non-directory entry, hiding everything under them
+    non_dirs: HashSet<PathBuf>,
+    whiteouts: HashSet<PathBuf>,
+    opaques: HashSet<PathBuf>,
+}
+
+impl Upper {
+    /// If anything at `path` and under it from the lower layers is removed or replaced
+    fn covers(&self, path: &Path) -> bool {
+        self.whiteouts.contains(path)
+            || path.ancestors().skip(1).any(|parent| {
+                self.whiteouts.contains(parent)
+                    || self.non_dirs.contains(parent)
+                    || self.opaques.contains(parent)
+            })
+    }
+}
+
+/// Which entries and whiteouts of each layer survive in the squashed layer
+struct Resolution {
+    /// for each layer, if the n-th entry is visible
+    visible: Vec<Vec<bool>>,
+    whiteouts: BTreeSet<PathBuf>,
+    opaques: BTreeSet<PathBuf>,
+    /// the visible hardlinks whose target is hidden or replaced in the squashed layer, and the
+    /// entry carrying the content they link to. They are written as copies of that entry
+    detached: HashMap<EntryId, EntryId>,
+}
+
+fn resolve(layers: &[LayerEntries]) -> Resolution {
+    // the entries surviving in the tree are visible, the entry each hardlink links to is the
+    // one at the target path when the hardlink is applied
+    let mut tree = LayerTree::default();
+    let mut references = HashMap::new();
+    for (layer_index, layer) in layers.iter().enumerate() {
+        tree.apply_whiteouts(&layer.whiteouts, &layer.opaques);
+        for (index, (path, is_dir)) in layer.entries.iter().enumerate() {
+            if let Some(target) = layer.hardlinks.get(&index) {
+                if let Some(referenced) = tree.get(target) {
+                    references.insert((layer_index, index), *referenced);
+                }
+            }
+            tree.apply_entry(path.clone(), *is_dir, (layer_index, index));
+        }
+    }
+
+    let mut visible: Vec<Vec<bool>> = layers
+        .iter()
+        .map(|layer| vec![false; layer.entries.len()])
+        .collect();
+    for (layer, index) in tree.into_entries().into_values() {
+        visible[layer][index] = true;
+    }
+
+    // the whiteouts survive unless the layers above remove or replace the same paths
+    let mut upper = Upper::default();
+    let mut whiteouts = BTreeSet::new();
+    let mut opaques = BTreeSet::new();
+    for layer in layers.iter().rev() {
+        for path in layer.whiteouts.iter() {
+            if !upper.covers(path) {
+                whiteouts.insert(path.clone());
+            }
+        }
+        for path in layer.opaques.iter() {
+            if !upper.covers(path)
+                && !upper.non_dirs.contains(path)
+                && !upper.opaques.contains(path)
+            {
+                opaques.insert(path.clone());
+            }
+        }
+
+        for (path, is_dir) in layer.entries.iter() {
+            if !is_dir {
+                upper.non_dirs.insert(path.clone());
+            }
+        }
+        upper.whiteouts.extend(layer.whiteouts.iter().cloned());
+        upper.opaques.extend(layer.opaques.iter().cloned());
+    }
+
+    // a hardlink only survives if the entry it links to does, otherwise it would link to
+    // nothing, or to whatever replaced the target
+    let mut detached = HashMap::new();
+    for (&(layer, index), &referenced) in references.iter() {
+        if !visible[layer][index] || visible[referenced.0][referenced.1] {
+            continue;
+        }
+        // the content is carried by the first entry of the chain that is not a hardlink
+        let mut source = referenced;
+        while let Some(&next) = references.get(&source) {
+            source = next;
+        }
+        if !layers[source.0].hardlinks.contains_key(&source.1) {
+            detached.insert((layer, index), source);
+        }
+    }
+
+    Resolution {
+        visible,
+        whiteouts,
+        opaques,
+        detached,
+    }
+}
+
+/// Write `entry` and its content `blocks` at `path` instead
+fn write_entry_at<W: Write>(
+    writer: &mut W,
+    entry: &TarEntry,
+    blocks: &[u8],
+    path: &Path,
+) -> std::io::Result<()> {
+    let path = path.to_string_lossy();
+    let mut entry = entry.clone();
+    entry.extensions.retain(|extension| extension.key != "path");
+    if !entry.header.try_set_path(&path) {
+        entry
+            .extensions
+            .push(Extension::new("path".to_string(), path.to_string()));
+    }
+    entry.header.set_checksum();
+    write_entry_extensions(writer, &entry)?;
+    writer.write_all(&unsafe { std::mem::transmute::<RawTarHeader, [u8; 512]>(entry.header) })?;
+    writer.write_all(blocks)
+}
+
+/// Copy the visible entries of a layer
+struct CopyVisible<'a, W> {
+    writer: &'a mut W,
+    layer: usize,
+    visible: &'a [bool],
+    detached: &'a HashMap<EntryId, EntryId>,
+    /// the entries and their content needed by the detached hardlinks, kept in memory until
+    /// the hardlinks are written. Only the targets of broken hardlinks are kept
+    sources: &'a mut HashMap<EntryId, Option<(TarEntry, Vec<u8>)>>,
+    index: usize,
+    copying: bool,
+    capturing: Option<EntryId>,
+}
+
+impl<'a, W: Write> TarEntryHandle for CopyVisible<'a, W> {
+    fn on_normal_entry(&mut self, buf: &[u8; 512], entry: &TarEntry) -> std::io::Result<()> {
+        let id = (self.layer, self.index);
+        self.copying = self.visible[self.index];
+        self.index += 1;
+
+        self.capturing = None;
+        if let Some(source) = self.sources.get_mut(&id) {
+            *source = Some((entry.clone(), Vec::new()));
+            self.capturing = Some(id);
+        }
+
+        if self.copying {
+            if let Some(source) = self.detached.get(&id) {
+                let Some(Some((source, blocks))) = self.sources.get(source) else {
+                    return crate::util::err!("content of hardlink target not found");
+                };
+                write_entry_at(self.writer, source, blocks, &entry.path)?;
+                // a hardlink has no content of its own
+                self.copying = false;
+            } else {
+                write_entry_extensions(self.writer, entry)?;
+                self.writer.write_all(buf)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn on_normal_entry_block(&mut self, buf: &[u8; 512], _entry: &TarEntry) -> std::io::Result<()> {
+        if self.copying {
+            self.writer.write_all(buf)?;
+        }
+        if let Some(id) = self.capturing {
+            if let Some(Some((_, blocks))) = self.sources.get_mut(&id) {
+                blocks.extend_from_slice(buf);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Squash `layers`, from the bottom to the top, into a single layer equivalent to applying them
+/// in sequence. The layers can be in any supported compression, the squashed layer is compressed
+/// with `compression`, and `zstd` if it is `Zstd`. Whiteouts removing entries from below the
+/// stack are kept as OCI whiteouts
+pub fn squash_layers<P: AsRef<Path>, W: Write>(
+    layers: &[P],
+    compression: CompressionType,
+    zstd: &ZstdOptions,
+    output: W,
+) -> Result<Summary, std::io::Error> {
+    let mut entries = Vec::with_capacity(layers.len());
+    for layer in layers {
+        let mut layer_entries = LayerEntries::default();
+        visit_layer(
+            File::open(layer)?,
+            CompressionType::Auto,
+            &mut layer_entries,
+        )?;
+        entries.push(layer_entries);
+    }
+
+    let resolution = resolve(&entries);
+
+    let sha256 = std::rc::Rc::new(std::cell::RefCell::new(Sha256::new()));
+    let handle = DigestSink::<W>::new(output, sha256.clone());
+    let mut output = prepare_compressed_stream_writer_with(handle, compression, zstd)?;
+    let mut writer = DigestWriter::<&mut Box<dyn Write>>::new(&mut output);
+
+    let mut summary = Summary::default();
+
+    for whiteout in resolution.whiteouts.iter() {
+        write_oci_whiteouts(whiteout.to_string_lossy().to_string(), None, &mut writer)?;
+        summary
+            .whiteouts
+            .push(whiteout.to_string_lossy().to_string());
+    }
+
+    for opaque in resolution.opaques.iter() {
+        write_oci_whiteouts(
+            opaque.join(".wh..opq").to_string_lossy().to_string(),
+            None,
+            &mut writer,
+        )?;
+        let dir = if opaque.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            opaque.as_path()
+        };
+        summary
+            .whiteouts
+            .push(format!("{}/*", dir.to_string_lossy()));
+    }
+
+    let mut sources = resolution
+        .detached
+        .values()
+        .map(|source| (*source, None))
+        .collect();
+
+    for (layer_index, (layer, visible)) in layers.iter().zip(resolution.visible.iter()).enumerate()
+    {
+        let mut handle = CopyVisible {
+            writer: &mut writer,
+            layer: layer_index,
+            visible,
+            detached: &resolution.detached,
+            sources: &mut sources,
+            index: 0,
+            copying: false,
+            capturing: None,
+        };
+        let layer_summary = visit_layer(File::open(layer)?, CompressionType::Auto, &mut handle)?;
+        summary.files.extend(
+            layer_summary
+                .files
+                .into_iter()
+                .zip(visible.iter())
+                .filter_map(|(file, visible)| visible.then_some(file)),
+        );
+    }
+
+    writer.write_all(&EMPTY_TAR_HEADER)?;
+    writer.write_all(&EMPTY_TAR_HEADER)?;
+    summary.diff_id = writer.consume();
+
+    drop(output);
+    summary.archive_digest = sha256.borrow().clone().finalize().into();
+
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layer::list_layer;
+    use crate::tar::{write_entry_header, RawTarHeader};
+
+    fn write_entry(archive: &mut Vec<u8>, path: &str, tpe: u8, content: &[u8]) {
+        write_entry_with_link(archive, path, tpe, "", content)
+    }
+
+    fn write_entry_with_link(
+        archive: &mut Vec<u8>,
+        path: &str,
+        tpe: u8,
+        link: &str,
+        content: &[u8],
+    ) {
+        let mut header = RawTarHeader::empty_ustar();
+        header.set_mode(0o644);
+        header.set_uid_gid();
+        header.set_lastmod(0);
+        header.set_entry_type(tpe);
+        header.set_link_path(link);
+        write_entry_header(archive, header, path, content.len() as u64).unwrap();
+        for chunk in content.chunks(512) {
+            let mut block = [0u8; 512];
+            block[..chunk.len()].copy_from_slice(chunk);
+            archive.extend_from_slice(&block);
+        }
+    }
+
+    #[test]
+    fn test_squash_layers() -> std::io::Result<()> {
+        let mut bottom = Vec::new();
+        write_entry(&mut bottom, "a/", b'5', b"");
+        write_entry(&mut bottom, "a/1", b'0', b"one");
+        write_entry(&mut bottom, "a/2", b'0', b"two");
+        write_entry(&mut bottom, "b/", b'5', b"");
+        write_entry(&mut bottom, "b/x", b'0', b"x");
+        write_entry(&mut bottom, "c", b'0', b"c");
+        // the target of the first hardlink is replaced by the top layer
+        write_entry(&mut bottom, "h", b'0', b"h");
+        write_entry_with_link(&mut bottom, "g", b'1', "h", b"");
+        write_entry(&mut bottom, "k", b'0', b"k");
+        write_entry_with_link(&mut bottom, "l", b'1', "k", b"");
+        bottom.extend_from_slice(&[0u8; 1024]);
+
+        let mut middle = Vec::new();
+        write_oci_whiteouts("a/1".to_string(), None, &mut middle)?;
+        write_oci_whiteouts("b/.wh..opq".to_string(), None, &mut middle)?;
+        write_entry(&mut middle, "b/y", b'0', b"y");
+        write_entry(&mut middle, "d", b'0', b"d");
+        write_entry(&mut middle, "e/", b'5', b"");
+        write_entry(&mut middle, "e/f", b'0', b"f");
+        middle.extend_from_slice(&[0u8; 1024]);
+
+        let mut top = Vec::new();
+        write_oci_whiteouts("e".to_string(), None, &mut top)?;
+        write_oci_whiteouts("base".to_string(), None, &mut top)?;
+        write_entry(&mut top, "a/2", b'0', b"TWO");
+        write_entry(&mut top, "c/", b'5', b"");
+        write_entry(&mut top, "c/z", b'0', b"z");
+        write_entry(&mut top, "h", b'0', b"H");
+        top.extend_from_slice(&[0u8; 1024]);
+
+        crate::util::with_stage_dir("squash", |stage| {
+            let paths = [
+                stage.join("bottom.tar"),
+                stage.join("middle.tar.zst"),
+                stage.join("top.tar"),
+            ];
+            std::fs::write(&paths[0], &bottom)?;
+            {
+                let file = File::create(&paths[1])?;
+                let mut writer = crate::compression::prepare_compressed_stream_writer(
+                    file,
+                    CompressionType::Zstd,
+                )?;
+                writer.write_all(&middle)?;
+            }
+            std::fs::write(&paths[2], &top)?;
+
+            let mut squashed = Vec::new();
+            let summary = squash_layers(
+                &paths,
+                CompressionType::Auto,
+                &ZstdOptions::default(),
+                &mut squashed,
+            )?;
+            let expected_diff_id: [u8; 32] = Sha256::digest(&squashed).into();
+            assert_eq!(summary.diff_id, expected_diff_id);
+            assert_eq!(summary.archive_digest, expected_diff_id);
+
+            let files = vec![
+                "a/", "b/", "g", "k", "l", "b/y", "d", "a/2", "c/", "c/z", "h",
+            ];
+            let whiteouts = vec!["a/1", "base", "e", "b/*"];
+            assert_eq!(summary.files, files);
+            assert_eq!(summary.whiteouts, whiteouts);
+
+            let listed = list_layer(squashed.as_slice(), CompressionType::Auto)?;
+            assert_eq!(listed.files, files);
+            assert_eq!(listed.whiteouts, whiteouts);
+            assert_eq!(listed.diff_id, summary.diff_id);
+
+            let root = stage.join("extracted");
+            std::fs::create_dir_all(&root)?;
+            let mut extractor = crate::confined::ConfinedExtractTar::new(&root);
+            crate::tar::summarize_entries(squashed.as_slice(), &mut extractor)?;
+            extractor.finish().unwrap();
+            let read = |path: &str| std::fs::read_to_string(root.join(path));
+            let nlink = |path: &str| {
+                std::fs::metadata(root.join(path))
+                    .map(|meta| std::os::unix::fs::MetadataExt::nlink(&meta))
+            };
+            assert_eq!(read("g")?, "h");
+            assert_eq!(read("h")?, "H");
+            assert_eq!(nlink("g")?, 1);
+            assert_eq!(read("l")?, "k");
+            assert_eq!(nlink("l")?, 2);
+            Ok(())
+        })
+    }
+}
@@ -21,17 +21,25 @@
 // LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
 // OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
 // SUCH DAMAGE.
+use crate::attrs::ExtendedMetadata;
+use crate::idmap::OwnerMap;
+use crate::sparse::{read_sparse_map, SparseMap};
 use crate::util::{err, str_from_nul_bytes_buf, DigestReader, DigestWriter};
 use serde::{Deserialize, Serialize};
 use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
 
 const WHITEOUT_VERSION: u32 = 1;
-const EMPTY_TAR_HEADER: [u8; 512] = [0u8; 512];
+pub(crate) const EMPTY_TAR_HEADER: [u8; 512] = [0u8; 512];
 
-#[derive(Debug, Serialize, Deserialize)]
-struct WhiteoutExtension {
-    version: u32,
-    whiteouts: Vec<String>,
+/// The largest size representable by the 11 octal digits of the ustar size field
+const MAX_OCTAL_SIZE: u64 = 0o77777777777;
+
+/// This program's custom tar extension, listing all the paths to whiteout in one record
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WhiteoutExtension {
+    pub version: u32,
+    pub whiteouts: Vec<String>,
 }
 
 impl WhiteoutExtension {
@@ -46,7 +54,7 @@ impl WhiteoutExtension {
 /// ustar header
 #[repr(C, packed)]
 #[derive(Debug, Clone)]
-struct RawTarHeader {
+pub struct RawTarHeader {
     name: [u8; 100],
     mode: [u8; 8],
     uid: [u8; 8],
@@ -95,38 +103,74 @@ impl RawTarHeader {
 
     /// Size of the content the header represents
     pub fn content_length(&self) -> std::io::Result<u128> {
-        // If the size field starts with 0xff, the size is encoded in base256
-        if self.size[0] == 0xff {
-            let mut buf = [0u8; 16];
-            buf[5..].copy_from_slice(&self.size[1..]);
-            Ok(u128::from_be_bytes(buf))
-        } else {
-            let mut value = 0u128;
+        parse_numeric(&self.size).map(|size| size as u128)
+    }
 
-            // loop thru the buffer but skip the Nul terminator
-            for i in 0..self.size.len() - 1 {
-                let byte = self.size[self.size.len() - 2 - i];
+    pub fn mode(&self) -> std::io::Result<u32> {
+        parse_numeric(&self.mode).map(|mode| mode as u32)
+    }
 
-                if !(b'0'..=b'7').contains(&byte) {
-                    return err!("Invalid octal digit");
-                }
+    pub fn uid(&self) -> std::io::Result<u32> {
+        parse_numeric(&self.uid).map(|uid| uid as u32)
+    }
 
-                let digit = byte - b'0';
-                value += (digit as u128) << (3 * i);
-            }
+    pub fn gid(&self) -> std::io::Result<u32> {
+        parse_numeric(&self.gid).map(|gid| gid as u32)
+    }
+
+    /// Last modified time in seconds since epoch, negative if before the epoch
+    pub fn lastmod(&self) -> std::io::Result<i64> {
+        parse_numeric_signed(&self.lastmod)
+    }
+
+    /// The target of the entry if the entry is a symlink or hardlink
+    pub fn link_path(&self) -> std::io::Result<Option<std::path::PathBuf>> {
+        let link = str_from_nul_bytes_buf(&self.link)?;
+        Ok((!link.is_empty()).then(|| std::path::PathBuf::from(link)))
+    }
+
+    /// The major and minor device numbers if the entry is a character or block device
+    pub fn device_numbers(&self) -> std::io::Result<(u32, u32)> {
+        Ok((
+            parse_numeric(&self.devmj_n)? as u32,
+            parse_numeric(&self.devmi_n)? as u32,
+        ))
+    }
+
+    /// The user name of the owner, empty if the header does not carry one
+    pub fn user_name(&self) -> std::io::Result<String> {
+        if self.ustar[..5] == *b"ustar" {
+            str_from_nul_bytes_buf(&self.usr_name).map(|name| name.to_string())
+        } else {
+            Ok(String::new())
+        }
+    }
 
-            Ok(value)
+    /// The group name of the owner, empty if the header does not carry one
+    pub fn group_name(&self) -> std::io::Result<String> {
+        if self.ustar[..5] == *b"ustar" {
+            str_from_nul_bytes_buf(&self.grp_name).map(|name| name.to_string())
+        } else {
+            Ok(String::new())
         }
     }
+
     pub fn set_mode(&mut self, mode: u32) {
         self.mode
             .copy_from_slice(format!("{:0>6o} \0", mode).as_bytes());
     }
 
-    /// Set the content length of the file
-    pub fn set_size(&mut self, size: usize) {
-        self.size
-            .copy_from_slice(format!("{size:0>11o} ").as_bytes());
+    /// Set the content length of the file, sizes too large for the octal representation are
+    /// encoded in base-256
+    pub fn set_size(&mut self, size: u64) {
+        if size > MAX_OCTAL_SIZE {
+            self.size[0] = 0x80;
+            self.size[1..4].fill(0);
+            self.size[4..].copy_from_slice(&size.to_be_bytes());
+        } else {
+            self.size
+                .copy_from_slice(format!("{size:0>11o} ").as_bytes());
+        }
     }
 
     pub fn set_path(&mut self, prefix: Option<String>, name: String) {
@@ -154,6 +198,39 @@ impl RawTarHeader {
         }
     }
 
+    /// Set the path if it fits in the ustar name and prefix fields, returns false if it does not
+    pub fn try_set_path(&mut self, path: &str) -> bool {
+        self.name.fill(0);
+        self.prefix.fill(0);
+
+        if path.len() <= 100 {
+            self.name[..path.len()].copy_from_slice(path.as_bytes());
+            return true;
+        }
+
+        // find a slash to split the path into prefix (up to 155 bytes) and name (up to 100 bytes)
+        for (i, _) in path.match_indices('/') {
+            let (prefix, name) = (&path[..i], &path[i + 1..]);
+            if prefix.len() <= 155 && !name.is_empty() && name.len() <= 100 {
+                self.prefix[..prefix.len()].copy_from_slice(prefix.as_bytes());
+                self.name[..name.len()].copy_from_slice(name.as_bytes());
+                return true;
+            }
+        }
+
+        false
+    }
+
+    /// Set the link target if it fits in the ustar header, returns false if it does not
+    pub fn set_link_path(&mut self, link: &str) -> bool {
+        if link.len() > 100 {
+            return false;
+        }
+        self.link.fill(0);
+        self.link[..link.len()].copy_from_slice(link.as_bytes());
+        true
+    }
+
     /// set the uid, gid, username and group name by the euid and guid of the current process
     pub fn set_uid_gid(&mut self) {
         unsafe {
@@ -163,17 +240,37 @@ impl RawTarHeader {
             let user = libc::getpwuid(uid);
 
             let user_name = std::ffi::CStr::from_ptr((*user).pw_name).to_bytes();
-            let name_len = user_name.len().min(31);
-            let ouid = format!("{:0>6o}\0 ", uid);
-            self.uid = ouid.as_bytes().try_into().unwrap();
-            self.usr_name[..name_len].copy_from_slice(&user_name[..name_len]);
-
             let group_name = std::ffi::CStr::from_ptr((*group).gr_name).to_bytes();
-            let gname_len = group_name.len().min(31);
-            let ogid = format!("{:0>6o}\0 ", gid);
-            self.gid = ogid.as_bytes().try_into().unwrap();
-            self.grp_name[..gname_len].copy_from_slice(&group_name[..gname_len]);
+            self.set_owner(uid, gid, user_name, group_name);
+        }
+    }
+
+    /// set the uid, gid, username and group name, names longer than 31 bytes are truncated
+    pub fn set_owner(&mut self, uid: u32, gid: u32, user_name: &[u8], group_name: &[u8]) {
+        let name_len = user_name.len().min(31);
+        let ouid = format!("{:0>6o}\0 ", uid);
+        self.uid = ouid.as_bytes().try_into().unwrap();
+        self.usr_name.fill(0);
+        self.usr_name[..name_len].copy_from_slice(&user_name[..name_len]);
+
+        let gname_len = group_name.len().min(31);
+        let ogid = format!("{:0>6o}\0 ", gid);
+        self.gid = ogid.as_bytes().try_into().unwrap();
+        self.grp_name.fill(0);
+        self.grp_name[..gname_len].copy_from_slice(&group_name[..gname_len]);
+    }
+
+    /// Rewrite the fields depending on the host and the time the archive is created, such that
+    /// the header is suitable for a reproducible archive. The modification time is clamped to
+    /// `source_date_epoch` and the user and group names are cleared, the numeric ids are kept
+    pub fn normalize(&mut self, reproducible: &Reproducible) -> std::io::Result<()> {
+        if self.lastmod()? > reproducible.source_date_epoch as i64 {
+            self.set_lastmod(reproducible.source_date_epoch);
         }
+        self.usr_name.fill(0);
+        self.grp_name.fill(0);
+        self.set_checksum();
+        Ok(())
     }
 
     /// Calculate and set the checksum of this header
@@ -221,6 +318,15 @@ impl RawTarHeader {
         string.as_bytes().try_into().unwrap()
     }
 
+    /// The type flag of the entry, for example `b'0'` for regular files and `b'5'` for directories
+    pub fn entry_type(&self) -> u8 {
+        self.tpe[0]
+    }
+
+    pub fn set_entry_type(&mut self, tpe: u8) {
+        self.tpe[0] = tpe;
+    }
+
     pub fn is_valid_tar_header(&self) -> bool {
         self.checksum() == self.cksum
     }
@@ -229,9 +335,15 @@ impl RawTarHeader {
         self.is_valid_tar_header() && self.ustar == *b"ustar\0"
     }
 
-    #[allow(dead_code)]
+    /// If the header is a PAX extended header, per-file ('x') or global ('g')
     pub fn is_extension(&self) -> bool {
-        self.is_ustar_header() && (self.tpe[0] == b'x' || self.tpe[0] == b'g')
+        self.is_valid_tar_header() && (self.tpe[0] == b'x' || self.tpe[0] == b'g')
+    }
+
+    /// If the header is a GNU long name ('L') or long link ('K') entry, the content of which is
+    /// the path or link target of the next entry
+    pub fn is_gnu_long_entry(&self) -> bool {
+        self.is_valid_tar_header() && (self.tpe[0] == b'L' || self.tpe[0] == b'K')
     }
 
     pub fn is_whiteout_extension(&self) -> bool {
@@ -242,61 +354,457 @@ impl RawTarHeader {
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+/// Parse a numeric field of a tar header that cannot be negative, see `parse_numeric_signed`
+pub(crate) fn parse_numeric(field: &[u8]) -> std::io::Result<u64> {
+    match u64::try_from(parse_numeric_signed(field)?) {
+        Ok(value) => Ok(value),
+        Err(_) => err!("negative numeric field"),
+    }
+}
+
+/// Parse a numeric field of a tar header, the field is either in octal, optionally padded with
+/// spaces and Nul, or in base-256 if the highest bit of the first byte is set. A base-256 field
+/// starting with 0xff is a negative number in two's complement, such as a timestamp before the
+/// epoch written by GNU tar
+pub(crate) fn parse_numeric_signed(field: &[u8]) -> std::io::Result<i64> {
+    if field[0] & 0x80 != 0 {
+        let mut value = if field[0] == 0xff {
+            -1
+        } else {
+            (field[0] & 0x7f) as i64
+        };
+        for byte in &field[1..] {
+            // the top byte has to be pure sign extension before shifting it out
+            if value >> 55 != 0 && value >> 55 != -1 {
+                return err!("numeric field overflow");
+            }
+            value = (value << 8) | *byte as i64;
+        }
+        Ok(value)
+    } else {
+        let mut value = 0i64;
+        let digits = field
+            .iter()
+            .skip_while(|c| **c == b' ')
+            .take_while(|c| **c != b' ' && **c != 0);
+        for byte in digits {
+            if !(b'0'..=b'7').contains(byte) {
+                return err!("Invalid octal digit");
+            }
+            if value >> 60 != 0 {
+                return err!("numeric field overflow");
+            }
+            value = (value << 3) | (byte - b'0') as i64;
+        }
+        Ok(value)
+    }
+}
+
+/// A record of a PAX extended header. The values are usually UTF-8 strings, except for records
+/// such as `SCHILY.xattr.*` which carry binary values
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Extension {
-    key: String,
-    value: String,
+    pub key: String,
+    pub value: Vec<u8>,
 }
 
 impl Extension {
     pub fn new(key: String, value: String) -> Extension {
+        Extension {
+            key,
+            value: value.into_bytes(),
+        }
+    }
+
+    pub fn new_binary(key: String, value: Vec<u8>) -> Extension {
         Extension { key, value }
     }
 
-    #[allow(dead_code)]
-    fn encoded(&self) -> String {
-        format!(
-            "{} {}={}",
-            self.key.len() + self.value.len() + 1,
-            self.key,
-            self.value
-        )
+    /// The value of the record if it is a UTF-8 string
+    pub fn value_str(&self) -> Option<&str> {
+        std::str::from_utf8(&self.value).ok()
+    }
+
+    /// Encode the record in the form of "{length} {key}={value}\n", where length is the length
+    /// of the whole record including the length field itself
+    pub fn encoded(&self) -> Vec<u8> {
+        let base = self.key.len() + self.value.len() + 3;
+        let mut length = base + base.to_string().len();
+        while length != base + length.to_string().len() {
+            length = base + length.to_string().len();
+        }
+        let mut encoded = format!("{length} {}=", self.key).into_bytes();
+        encoded.extend_from_slice(&self.value);
+        encoded.push(b'\n');
+        encoded
+    }
+
+    /// Encode the record in the form of "{length} {key}={value}" written by earlier versions of
+    /// this program, where length only counts the "{key}={value}" part. Earlier versions cannot
+    /// parse anything else in the whiteout extension
+    pub fn encoded_legacy(&self) -> Vec<u8> {
+        let mut encoded =
+            format!("{} {}=", self.key.len() + self.value.len() + 1, self.key).into_bytes();
+        encoded.extend_from_slice(&self.value);
+        encoded
+    }
+
+    /// Parse the records of an extended header. Besides the standard PAX format, records written
+    /// by earlier versions of this program, where the length only counts the "{key}={value}"
+    /// part and without the trailing newline, are also accepted
+    pub fn parse(buf: &[u8]) -> std::io::Result<Vec<Extension>> {
+        let mut extensions = vec![];
+        let mut rem = buf;
+
+        // the content is padded with Nul to the block boundary
+        while !rem.is_empty() && rem[0] != 0 {
+            let Some(space) = rem.iter().position(|c| *c == b' ') else {
+                return err!("malformed extended header record");
+            };
+            let Some(size) = std::str::from_utf8(&rem[..space])
+                .ok()
+                .and_then(|s| s.parse::<usize>().ok())
+            else {
+                return err!("malformed extended header record length");
+            };
+
+            let (record, next) = if size > space + 1 && size <= rem.len() && rem[size - 1] == b'\n'
+            {
+                (&rem[space + 1..size - 1], &rem[size..])
+            } else if space + 1 + size <= rem.len() {
+                (&rem[space + 1..space + 1 + size], &rem[space + 1 + size..])
+            } else {
+                return err!("extended header record exceed the header");
+            };
+
+            let Some(eq) = record.iter().position(|c| *c == b'=') else {
+                return err!("malformed extended header record");
+            };
+
+            let key = str_from_nul_bytes_buf(&record[..eq])?;
+            extensions.push(Extension::new_binary(
+                key.to_string(),
+                record[eq + 1..].to_vec(),
+            ));
+            rem = next;
+        }
+
+        Ok(extensions)
     }
 
     pub fn read_from_stream<R: Read>(
         reader: &mut R,
         length: usize,
     ) -> std::io::Result<Vec<Extension>> {
-        let blocks = (length + 511) / 512;
-        let rem = (blocks * 512) - length;
-        let mut v = vec![0u8; length];
-        let mut sink = [0u8; 512];
+        let blocks = length.div_ceil(512);
+        let mut v = vec![0u8; blocks * 512];
         reader.read_exact(&mut v)?;
-        reader.read_exact(&mut sink[0..rem])?;
-        let mut string = std::str::from_utf8(&v).unwrap();
+        Extension::parse(&v[..length])
+    }
+}
 
-        let mut extensions = vec![];
-        while !string.is_empty() {
-            let (sizestr, rem) = string.split_once(' ').unwrap();
-            let size = sizestr.parse::<usize>().unwrap();
-            let (pairbuf, rem) = rem.split_at(size);
-            let (key, value) = pairbuf.split_once('=').unwrap();
-            extensions.push(Extension::new(key.to_string(), value.to_string()));
+/// Parse a PAX timestamp in the form of "{seconds}[.{fraction}]" into seconds and nanoseconds
+fn parse_pax_time(value: &str) -> std::io::Result<(i64, u32)> {
+    let (secs, frac) = value.split_once('.').unwrap_or((value, ""));
+    let Ok(secs) = secs.parse::<i64>() else {
+        return err!("malformed timestamp");
+    };
+    let mut nsec = 0u32;
+    for i in 0..9 {
+        let digit = match frac.as_bytes().get(i) {
+            None => 0,
+            Some(c) if c.is_ascii_digit() => (c - b'0') as u32,
+            Some(_) => return err!("malformed timestamp"),
+        };
+        nsec = nsec * 10 + digit;
+    }
+    Ok((secs, nsec))
+}
 
-            string = rem;
+/// An entry of the archive, with the PAX extended header records apply to it taken into account
+#[derive(Debug, Clone)]
+pub struct TarEntry {
+    pub header: RawTarHeader,
+    /// The raw blocks of the extended headers and GNU long name / long link entries precede
+    /// this entry
+    pub extension_blocks: Vec<u8>,
+    /// The extended header records apply to this entry, global records come first. GNU long name
+    /// and long link are represented as `path` and `linkpath` records, taking precedence over
+    /// the global records but not the per-file ones
+    pub extensions: Vec<Extension>,
+    pub path: std::path::PathBuf,
+    pub link_path: Option<std::path::PathBuf>,
+    pub size: u64,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub user_name: String,
+    pub group_name: String,
+    pub mtime: i64,
+    pub mtime_nsec: u32,
+    /// The layout of the entry if it is a sparse file, in which case `size` is the number of
+    /// bytes stored in the blocks following the header, including the sparse map
+    pub sparse: Option<SparseMap>,
+}
+
+impl TarEntry {
+    pub fn new(
+        header: RawTarHeader,
+        extensions: Vec<Extension>,
+        extension_blocks: Vec<u8>,
+    ) -> std::io::Result<TarEntry> {
+        let find = |key: &str| {
+            extensions
+                .iter()
+                .rev()
+                .find(|ext| ext.key == key)
+                .and_then(|ext| ext.value_str())
+        };
+
+        // the path of a PAX sparse entry is a placeholder, the real one is in GNU.sparse.name
+        let path = match find("GNU.sparse.name").or_else(|| find("path")) {
+            Some(path) => std::path::PathBuf::from(path),
+            None => header.file_path()?,
+        };
+
+        let link_path = match find("linkpath") {
+            Some(path) => Some(std::path::PathBuf::from(path)),
+            None => header.link_path()?,
+        };
+
+        // the size is needed to walk the archive, a malformed one is fatal
+        let size = match find("size") {
+            None => header.content_length()? as u64,
+            Some(value) => match value.parse::<u64>() {
+                Ok(size) => size,
+                Err(_) => return err!("malformed extended header size"),
+            },
+        };
+
+        // the other attributes are informational and were not interpreted by earlier versions,
+        // fall back to the ustar header, and to a default if the header is malformed as well
+        macro_rules! lenient {
+            ($key:expr, $parse:expr, $header:expr, $default:expr) => {
+                match find($key).map($parse) {
+                    Some(Ok(value)) => value,
+                    parsed => {
+                        if parsed.is_some() {
+                            log::warn!("ignoring malformed extended header record {}", $key);
+                        }
+                        $header.unwrap_or_else(|_| {
+                            log::warn!("ignoring malformed header field {}", $key);
+                            $default
+                        })
+                    }
+                }
+            };
         }
 
-        Ok(extensions)
+        let uid = lenient!("uid", |v| v.parse::<u32>().ok().ok_or(()), header.uid(), 0);
+        let gid = lenient!("gid", |v| v.parse::<u32>().ok().ok_or(()), header.gid(), 0);
+        let (mtime, mtime_nsec) = lenient!(
+            "mtime",
+            |v| parse_pax_time(v).map_err(|_| ()),
+            header.lastmod().map(|mtime| (mtime, 0)),
+            (0, 0)
+        );
+        let user_name = lenient!(
+            "uname",
+            |v| Ok::<_, ()>(v.to_string()),
+            header.user_name(),
+            String::new()
+        );
+        let group_name = lenient!(
+            "gname",
+            |v| Ok::<_, ()>(v.to_string()),
+            header.group_name(),
+            String::new()
+        );
+
+        Ok(TarEntry {
+            mode: header.mode()?,
+            header,
+            extension_blocks,
+            extensions,
+            path,
+            link_path,
+            size,
+            uid,
+            gid,
+            user_name,
+            group_name,
+            mtime,
+            mtime_nsec,
+            sparse: None,
+        })
+    }
+
+    /// The extended attributes, ACL and file flags recorded for this entry
+    pub fn extended_metadata(&self) -> ExtendedMetadata {
+        ExtendedMetadata::from_extensions(&self.extensions)
     }
 }
 
-#[allow(dead_code)]
-#[derive(Debug)]
+/// Summary of a layer archive after it has been walked through
+#[derive(Debug, Default, Clone)]
 pub struct Summary {
+    /// sha256 digest of the uncompressed tar stream
+    pub diff_id: [u8; 32],
+    /// sha256 digest of the archive as stored, same as `diff_id` if the archive is not compressed
+    pub archive_digest: [u8; 32],
+    /// paths of the non-whiteout entries
     pub files: Vec<String>,
+    /// paths to whiteout from the parent layers, opaque directories are in the form of `{dir}/*`
     pub whiteouts: Vec<String>,
 }
 
+/// Type of an entry in `EntryRecord`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryType {
+    Regular,
+    HardLink,
+    Symlink,
+    CharDevice,
+    BlockDevice,
+    Directory,
+    Fifo,
+    /// A path to remove from the parent layers
+    Whiteout,
+    /// A directory to clear the content from the parent layers
+    Opaque,
+    Other,
+}
+
+impl EntryType {
+    /// The entry type of a tar header type flag
+    pub fn from_type_flag(flag: u8) -> EntryType {
+        match flag {
+            b'0' | b'\0' | b'7' | b'S' => EntryType::Regular,
+            b'1' => EntryType::HardLink,
+            b'2' => EntryType::Symlink,
+            b'3' => EntryType::CharDevice,
+            b'4' => EntryType::BlockDevice,
+            b'5' => EntryType::Directory,
+            b'6' => EntryType::Fifo,
+            _ => EntryType::Other,
+        }
+    }
+
+    /// The name of the type, same as the serialized form
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EntryType::Regular => "regular",
+            EntryType::HardLink => "hardlink",
+            EntryType::Symlink => "symlink",
+            EntryType::CharDevice => "chardevice",
+            EntryType::BlockDevice => "blockdevice",
+            EntryType::Directory => "directory",
+            EntryType::Fifo => "fifo",
+            EntryType::Whiteout => "whiteout",
+            EntryType::Opaque => "opaque",
+            EntryType::Other => "other",
+        }
+    }
+}
+
+/// A machine readable description of an entry, or a whiteout, of an archive. The attributes are
+/// absent on whiteouts
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EntryRecord {
+    pub path: String,
+    #[serde(rename = "type")]
+    pub entry_type: EntryType,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uid: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gid: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<i64>,
+    /// Target of symbolic links and hard links
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub link_path: Option<String>,
+    pub whiteout: bool,
+    pub opaque: bool,
+}
+
+impl EntryRecord {
+    pub fn from_entry(entry: &TarEntry) -> EntryRecord {
+        EntryRecord {
+            path: entry.path.to_string_lossy().to_string(),
+            entry_type: EntryType::from_type_flag(entry.header.entry_type()),
+            mode: Some(entry.mode),
+            uid: Some(entry.uid),
+            gid: Some(entry.gid),
+            size: Some(match &entry.sparse {
+                Some(sparse) => sparse.real_size,
+                None => entry.size,
+            }),
+            mtime: Some(entry.mtime),
+            link_path: entry
+                .link_path
+                .as_ref()
+                .map(|path| path.to_string_lossy().to_string()),
+            whiteout: false,
+            opaque: false,
+        }
+    }
+
+    /// A record of a path to remove from the parent layers
+    pub fn whiteout(path: String) -> EntryRecord {
+        EntryRecord {
+            path,
+            entry_type: EntryType::Whiteout,
+            mode: None,
+            uid: None,
+            gid: None,
+            size: None,
+            mtime: None,
+            link_path: None,
+            whiteout: true,
+            opaque: false,
+        }
+    }
+
+    /// A record of a directory whose content from the parent layers is removed
+    pub fn opaque(path: String) -> EntryRecord {
+        EntryRecord {
+            entry_type: EntryType::Opaque,
+            opaque: true,
+            ..EntryRecord::whiteout(path)
+        }
+    }
+}
+
+/// Parameters to create an archive that depends only on the content of the files
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Reproducible {
+    /// Modification times later than this are clamped to it, entries synthesized by this program
+    /// such as the whiteouts are also stamped with it
+    pub source_date_epoch: u64,
+}
+
+/// A header template for the entries synthesized by this program
+fn synthesized_header(reproducible: Option<&Reproducible>) -> RawTarHeader {
+    let mut header = RawTarHeader::empty_ustar();
+    header.set_mode(0o644);
+    match reproducible {
+        None => {
+            header.set_uid_gid();
+            header.set_lastmod(unsafe { libc::time(std::ptr::null_mut()) } as u64);
+        }
+        Some(reproducible) => {
+            header.set_owner(0, 0, b"", b"");
+            header.set_lastmod(reproducible.source_date_epoch);
+        }
+    }
+    header
+}
+
 pub fn remove_path(p: std::path::PathBuf) -> std::io::Result<()> {
     /*
     eprintln!("enter remove path");
@@ -318,46 +826,196 @@ pub fn remove_path(p: std::path::PathBuf) -> std::io::Result<()> {
     }
 }
 
-pub fn write_oci_whiteouts<W: Write>(path: String, output: &mut W) -> std::io::Result<()> {
+pub fn write_oci_whiteouts<W: Write>(
+    path: String,
+    reproducible: Option<&Reproducible>,
+    output: &mut W,
+) -> std::io::Result<()> {
     let path = std::path::PathBuf::from(path);
-    let parent = path.parent().map(|p| p.to_string_lossy().to_string());
     let mut file_name = path.file_name().unwrap().to_string_lossy().to_string();
     file_name.insert_str(0, ".wh.");
+    let whiteout = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => {
+            format!("{}/{file_name}", parent.to_string_lossy())
+        }
+        _ => file_name,
+    };
+
+    let mut header = synthesized_header(reproducible);
+    header.tpe = *b"0";
+
+    write_entry_header(output, header, &whiteout, 0)?;
+    Ok(())
+}
+
+/// Write the header of an entry at `path` with `size` bytes of content. If the path or size
+/// cannot be represented by the ustar header, a PAX extended header carrying them is written
+/// before the entry. Returns the number of blocks written
+pub fn write_entry_header<W: Write>(
+    writer: &mut W,
+    mut header: RawTarHeader,
+    path: &str,
+    size: u64,
+) -> std::io::Result<usize> {
+    let mut extensions = Vec::new();
+
+    if !header.try_set_path(path) {
+        extensions.push(Extension::new("path".to_string(), path.to_string()));
+        // put the truncated file name in the ustar header for implementations without PAX support
+        let file_name = path.rsplit('/').next().unwrap_or(path);
+        let mut end = file_name.len().min(100);
+        while !file_name.is_char_boundary(end) {
+            end -= 1;
+        }
+        header.try_set_path(&file_name[..end]);
+    }
+
+    if size > MAX_OCTAL_SIZE {
+        extensions.push(Extension::new("size".to_string(), size.to_string()));
+    }
+    header.set_size(size);
+
+    let mut written = 0;
+    if !extensions.is_empty() {
+        let mut pax_header = header.clone();
+        pax_header.tpe = *b"x";
+        written += write_pax_header(writer, pax_header, "PaxHeader", &extensions)?;
+    }
+
+    header.set_checksum();
+    writer.write_all(&unsafe { std::mem::transmute::<RawTarHeader, [u8; 512]>(header) })?;
+    Ok(written + 1)
+}
+
+/// Write a PAX extended header containing `extensions`, with `header` as the template of the
+/// header block. Returns the number of blocks written
+/// Write the records applying to `entry` as a single per-file PAX header, such that the entry can
+/// be copied to another archive without the global headers and GNU long entries preceding it
+pub fn write_entry_extensions<W: Write>(
+    writer: &mut W,
+    entry: &TarEntry,
+) -> std::io::Result<usize> {
+    // only the last record of each key takes effect
+    let mut extensions: Vec<Extension> = Vec::new();
+    for extension in entry.extensions.iter().rev() {
+        if !extensions.iter().any(|ext| ext.key == extension.key) {
+            extensions.push(extension.clone());
+        }
+    }
+    extensions.reverse();
+
+    if extensions.is_empty() {
+        return Ok(0);
+    }
+
+    let file_name = entry
+        .path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let mut end = file_name.len().min(80);
+    while !file_name.is_char_boundary(end) {
+        end -= 1;
+    }
 
     let mut header = RawTarHeader::empty_ustar();
     header.set_mode(0o644);
-    header.set_uid_gid();
-    header.set_size(0);
-    header.set_lastmod(unsafe { libc::time(std::ptr::null_mut()) } as u64);
-    header.set_path(parent, file_name);
-    header.tpe = *b"0";
+    header.set_owner(0, 0, b"", b"");
+    header.set_lastmod(entry.mtime.max(0) as u64);
+    header.try_set_path(&file_name[..end]);
+    header.tpe = *b"x";
+    write_pax_header(writer, header, "PaxHeader", &extensions)
+}
+
+fn write_pax_header<W: Write>(
+    writer: &mut W,
+    header: RawTarHeader,
+    prefix: &str,
+    extensions: &[Extension],
+) -> std::io::Result<usize> {
+    let content = extensions
+        .iter()
+        .flat_map(|extension| extension.encoded())
+        .collect::<Vec<u8>>();
+    write_pax_content(writer, header, prefix, &content)
+}
+
+/// Write an extended header with already encoded records as `content`
+fn write_pax_content<W: Write>(
+    writer: &mut W,
+    mut header: RawTarHeader,
+    prefix: &str,
+    content: &[u8],
+) -> std::io::Result<usize> {
+    let name = str_from_nul_bytes_buf(&header.name)?.to_string();
+    header.prefix.fill(0);
+    header.set_path(Some(prefix.to_string()), name);
+    header.set_size(content.len() as u64);
     header.set_checksum();
 
-    output.write_all(&unsafe { std::mem::transmute::<RawTarHeader, [u8; 512]>(header) })?;
-    Ok(())
+    writer.write_all(&unsafe { std::mem::transmute::<RawTarHeader, [u8; 512]>(header) })?;
+
+    let mut bytes = content;
+    let mut buf = [0u8; 512];
+
+    // We have already written a block of header
+    let mut written = 1;
+
+    while !bytes.is_empty() {
+        let length = usize::min(bytes.len(), 512);
+        buf[..length].copy_from_slice(&bytes[..length]);
+        buf[length..].fill(0);
+        writer.write_all(&buf)?;
+        bytes = &bytes[length..];
+        written += 1;
+    }
+
+    Ok(written)
 }
 
+/// Write the whiteouts of the layer followed by the tar stream from `tar` to `output`, returns
+/// the diff_id of the layer. If `reproducible` is set, the headers from `tar` are normalized with
+/// `RawTarHeader::normalize`, and the records of the PAX headers depending on the host or the
+/// time of creation are removed. If `metadata_root` is set, the extended attributes, ACL and file
+/// flags of the entries, resolved relative to it, are recorded in their PAX headers
 pub fn tap_create_tar<R: Read, W: Write>(
     without_oci: bool,
     without_ext: bool,
+    reproducible: Option<&Reproducible>,
+    metadata_root: Option<&Path>,
     whiteouts: &[String],
     tar: &mut R,
     mut output: W,
 ) -> std::io::Result<[u8; 32]> {
-    let whiteout_ext = WhiteoutExtension::new(whiteouts);
+    let mut whiteouts = whiteouts.to_vec();
+    if reproducible.is_some() {
+        whiteouts.sort();
+        whiteouts.dedup();
+    }
+    let whiteout_ext = WhiteoutExtension::new(&whiteouts);
     let content_str = serde_json::to_string(&whiteout_ext)?;
     let mut output = DigestWriter::<W>::new(&mut output);
 
     let mut buf = [0u8; 512 * 20];
 
     if !without_oci {
-        for whiteout in whiteouts {
-            write_oci_whiteouts(whiteout.to_string(), &mut output)?;
+        for whiteout in whiteouts.iter() {
+            write_oci_whiteouts(whiteout.to_string(), reproducible, &mut output)?;
         }
     }
 
     if !without_ext {
-        write_extended_header(&mut output, "whiteouts", &content_str)?;
+        write_extended_header(&mut output, "whiteouts", &content_str, reproducible)?;
+    }
+
+    if reproducible.is_some() || metadata_root.is_some() {
+        let mut handle = RewriteTar {
+            writer: &mut output,
+            reproducible: reproducible.copied(),
+            metadata_root: metadata_root.map(|root| root.to_path_buf()),
+        };
+        tap_foreach_entry(tar, &mut handle)?;
+        return Ok(output.consume());
     }
 
     loop {
@@ -372,89 +1030,359 @@ pub fn tap_create_tar<R: Read, W: Write>(
     Ok(output.consume())
 }
 
-trait TarEntryHandle {
-    fn on_whiteout_extension(&mut self, extension: WhiteoutExtension) -> std::io::Result<()>;
-    fn on_oci_whiteout_entry(&mut self, path: std::path::PathBuf) -> std::io::Result<()>;
-    fn on_oci_whiteout_opq(&mut self, path: std::path::PathBuf) -> std::io::Result<()>;
-    fn on_empty_records(&mut self) -> std::io::Result<()>;
-    fn on_normal_entry(&mut self, buf: &[u8; 512], header: &RawTarHeader) -> std::io::Result<()>;
+/// Rewrite the PAX and GNU extension headers preceding an entry. `additional` records are merged
+/// into the per-file PAX header, replacing the records of the same keys, a per-file PAX header is
+/// created if there is none. If `reproducible` is set, the headers are normalized
+fn rewrite_extension_blocks(
+    mut blocks: &[u8],
+    entry_path: &Path,
+    reproducible: Option<&Reproducible>,
+    mut additional: Vec<Extension>,
+) -> std::io::Result<Vec<u8>> {
+    let mut rewritten = Vec::with_capacity(blocks.len());
+
+    // some implementations embed the process id in the name of the extension header
+    let file_name = entry_path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let mut end = file_name.len().min(80);
+    while !file_name.is_char_boundary(end) {
+        end -= 1;
+    }
+    let pax_name = &file_name[..end];
+
+    while blocks.len() >= 512 {
+        let mut header_block = [0u8; 512];
+        header_block.copy_from_slice(&blocks[..512]);
+        let mut header: RawTarHeader = unsafe { std::mem::transmute(header_block) };
+        let length = header.content_length()? as usize;
+        let end = 512 + length.div_ceil(512) * 512;
+        if blocks.len() < end {
+            return err!("truncated extension header");
+        }
+
+        if header.is_extension() {
+            let mut extensions = Vec::new();
+            for extension in Extension::parse(&blocks[512..512 + length])? {
+                if header.entry_type() == b'x'
+                    && additional.iter().any(|ext| ext.key == extension.key)
+                {
+                    continue;
+                }
+                match reproducible {
+                    None => extensions.push(extension),
+                    Some(reproducible) => {
+                        if let Some(extension) = normalize_extension(extension, reproducible)? {
+                            extensions.push(extension);
+                        }
+                    }
+                }
+            }
+            if header.entry_type() == b'x' {
+                extensions.append(&mut additional);
+            }
+
+            header.name.fill(0);
+            header.prefix.fill(0);
+            header.try_set_path(pax_name);
+            if let Some(reproducible) = reproducible {
+                header.normalize(reproducible)?;
+            }
+            write_pax_header(&mut rewritten, header, "PaxHeader", &extensions)?;
+        } else {
+            if let Some(reproducible) = reproducible {
+                header.normalize(reproducible)?;
+            }
+            rewritten.extend_from_slice(&unsafe {
+                std::mem::transmute::<RawTarHeader, [u8; 512]>(header)
+            });
+            rewritten.extend_from_slice(&blocks[512..end]);
+        }
+
+        blocks = &blocks[end..];
+    }
+
+    if !additional.is_empty() {
+        let mut header = synthesized_header(reproducible);
+        header.try_set_path(pax_name);
+        header.tpe = *b"x";
+        write_pax_header(&mut rewritten, header, "PaxHeader", &additional)?;
+    }
+
+    Ok(rewritten)
+}
+
+/// Normalize a PAX record for a reproducible archive, returns `None` if the record should be
+/// removed
+fn normalize_extension(
+    extension: Extension,
+    reproducible: &Reproducible,
+) -> std::io::Result<Option<Extension>> {
+    match extension.key.as_str() {
+        "atime"
+        | "ctime"
+        | "uname"
+        | "gname"
+        | "LIBARCHIVE.creationtime"
+        | "SCHILY.dev"
+        | "SCHILY.ino"
+        | "SCHILY.nlink" => Ok(None),
+        "mtime" => {
+            let Some(value) = extension.value_str() else {
+                return err!("malformed extended header value");
+            };
+            let (secs, nsec) = parse_pax_time(value)?;
+            let epoch = reproducible.source_date_epoch as i64;
+            if secs > epoch || (secs == epoch && nsec > 0) {
+                Ok(Some(Extension::new("mtime".to_string(), epoch.to_string())))
+            } else {
+                Ok(Some(extension))
+            }
+        }
+        _ => Ok(Some(extension)),
+    }
+}
+
+/// Copy a tar stream from tar(1) while recording the extended metadata of the entries and
+/// normalizing the headers for a reproducible archive
+struct RewriteTar<'a, W> {
+    writer: &'a mut W,
+    reproducible: Option<Reproducible>,
+    metadata_root: Option<PathBuf>,
+}
+
+impl<'a, W: Write> RewriteTar<'a, W> {
+    fn extended_metadata(&self, path: &Path) -> Vec<Extension> {
+        let Some(root) = &self.metadata_root else {
+            return Vec::new();
+        };
+        // tar(1) strips the leading '/' of absolute paths, the metadata is only ever read from
+        // under the root
+        let relative = crate::util::normalize_entry_path(path);
+        if relative
+            .components()
+            .any(|component| component == std::path::Component::ParentDir)
+        {
+            log::warn!("not reading extended metadata of {path:?} outside of the root");
+            return Vec::new();
+        }
+        let real_path = root.join(relative);
+        if real_path.symlink_metadata().is_err() {
+            log::debug!("skipping extended metadata of {path:?}, not found under the root");
+            return Vec::new();
+        }
+        match ExtendedMetadata::read(real_path) {
+            Ok(metadata) => metadata.to_extensions(),
+            Err(error) => {
+                log::warn!("cannot read extended metadata of {path:?}: {error}");
+                Vec::new()
+            }
+        }
+    }
+}
+
+impl<'a, W: Write> TarEntryHandle for RewriteTar<'a, W> {
+    fn on_oci_whiteout_entry(&mut self, path: PathBuf) -> std::io::Result<()> {
+        write_oci_whiteouts(
+            path.to_string_lossy().to_string(),
+            self.reproducible.as_ref(),
+            self.writer,
+        )
+    }
+
+    fn on_oci_whiteout_opq(&mut self, path: PathBuf) -> std::io::Result<()> {
+        write_oci_whiteouts(
+            path.join(".wh..opq").to_string_lossy().to_string(),
+            self.reproducible.as_ref(),
+            self.writer,
+        )
+    }
+
+    fn on_empty_records(&mut self) -> std::io::Result<()> {
+        self.writer.write_all(&EMPTY_TAR_HEADER)
+    }
+
+    fn on_normal_entry(&mut self, buf: &[u8; 512], entry: &TarEntry) -> std::io::Result<()> {
+        let additional = self.extended_metadata(&entry.path);
+        let Some(reproducible) = self.reproducible else {
+            if additional.is_empty() {
+                self.writer.write_all(&entry.extension_blocks)?;
+            } else {
+                let extension_blocks = rewrite_extension_blocks(
+                    &entry.extension_blocks,
+                    &entry.path,
+                    None,
+                    additional,
+                )?;
+                self.writer.write_all(&extension_blocks)?;
+            }
+            return self.writer.write_all(buf);
+        };
+
+        let extension_blocks = rewrite_extension_blocks(
+            &entry.extension_blocks,
+            &entry.path,
+            Some(&reproducible),
+            additional,
+        )?;
+        self.writer.write_all(&extension_blocks)?;
+        let mut header = entry.header.clone();
+        header.normalize(&reproducible)?;
+        self.writer
+            .write_all(&unsafe { std::mem::transmute::<RawTarHeader, [u8; 512]>(header) })
+    }
+
+    fn on_normal_entry_block(&mut self, buf: &[u8; 512], _entry: &TarEntry) -> std::io::Result<()> {
+        self.writer.write_all(buf)
+    }
+
+    fn on_tailing_record(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        self.writer.write_all(buf)
+    }
+}
+
+/// Visitor of the entries of a tar stream, see [`tap_foreach_entry`]. All methods default to no-op
+/// so implementations only need to handle the events they are interested in
+pub trait TarEntryHandle {
+    /// Called on this program's custom whiteout extension
+    fn on_whiteout_extension(&mut self, _extension: WhiteoutExtension) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    /// Called on an OCI whiteout (`.wh.{name}`) entry, with the path to the file to remove
+    fn on_oci_whiteout_entry(&mut self, _path: PathBuf) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    /// Called on an OCI opaque whiteout (`.wh..wh..opq`) entry, with the path of the directory
+    fn on_oci_whiteout_opq(&mut self, _path: PathBuf) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn on_empty_records(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    /// Called on the header block of a regular entry, followed by the content blocks of the
+    /// entry via `on_normal_entry_block`. The extended headers precede the entry are available
+    /// in `entry.extension_blocks`
+    fn on_normal_entry(&mut self, _buf: &[u8; 512], _entry: &TarEntry) -> std::io::Result<()> {
+        Ok(())
+    }
+
     fn on_normal_entry_block(
         &mut self,
-        buf: &[u8; 512],
-        header: &RawTarHeader,
-    ) -> std::io::Result<()>;
-    fn on_tailing_record(&mut self, buf: &[u8]) -> std::io::Result<()>;
+        _buf: &[u8; 512],
+        _entry: &TarEntry,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    /// Called on the bytes after the end-of-archive records
+    fn on_tailing_record(&mut self, _buf: &[u8]) -> std::io::Result<()> {
+        Ok(())
+    }
 }
 
-struct ListTar {
+impl TarEntryHandle for () {}
+
+/// Record the entries into a `Summary` while forwarding the events to the inner handle
+struct Summarize<'a, H> {
+    handle: &'a mut H,
     summary: Summary,
 }
 
-impl TarEntryHandle for ListTar {
+impl<'a, H: TarEntryHandle> TarEntryHandle for Summarize<'a, H> {
     fn on_whiteout_extension(&mut self, extension: WhiteoutExtension) -> std::io::Result<()> {
-        for whiteout in extension.whiteouts {
-            self.summary.whiteouts.push(whiteout);
+        for whiteout in extension.whiteouts.iter() {
+            self.summary.whiteouts.push(whiteout.to_string());
         }
-        Ok(())
+        self.handle.on_whiteout_extension(extension)
     }
 
-    fn on_oci_whiteout_entry(&mut self, path: std::path::PathBuf) -> std::io::Result<()> {
+    fn on_oci_whiteout_entry(&mut self, path: PathBuf) -> std::io::Result<()> {
         self.summary
             .whiteouts
             .push(path.to_string_lossy().to_string());
-        Ok(())
+        self.handle.on_oci_whiteout_entry(path)
     }
 
-    fn on_oci_whiteout_opq(&mut self, path: std::path::PathBuf) -> std::io::Result<()> {
+    fn on_oci_whiteout_opq(&mut self, path: PathBuf) -> std::io::Result<()> {
         self.summary
             .whiteouts
             .push(format!("{}/*", path.to_string_lossy()));
-        Ok(())
+        self.handle.on_oci_whiteout_opq(path)
     }
 
     fn on_empty_records(&mut self) -> std::io::Result<()> {
-        Ok(())
+        self.handle.on_empty_records()
     }
 
-    fn on_normal_entry(&mut self, _buf: &[u8; 512], header: &RawTarHeader) -> std::io::Result<()> {
+    fn on_normal_entry(&mut self, buf: &[u8; 512], entry: &TarEntry) -> std::io::Result<()> {
         self.summary
             .files
-            .push(header.file_path()?.to_string_lossy().to_string());
-        Ok(())
+            .push(entry.path.to_string_lossy().to_string());
+        self.handle.on_normal_entry(buf, entry)
     }
 
-    fn on_normal_entry_block(
-        &mut self,
-        _buf: &[u8; 512],
-        _header: &RawTarHeader,
-    ) -> std::io::Result<()> {
-        Ok(())
+    fn on_normal_entry_block(&mut self, buf: &[u8; 512], entry: &TarEntry) -> std::io::Result<()> {
+        self.handle.on_normal_entry_block(buf, entry)
     }
 
-    fn on_tailing_record(&mut self, _buf: &[u8]) -> std::io::Result<()> {
+    fn on_tailing_record(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        self.handle.on_tailing_record(buf)
+    }
+}
+
+/// Pass an `EntryRecord` of each entry and whiteout to a callback
+pub struct RecordEntries<F> {
+    pub on_record: F,
+}
+
+impl<F: FnMut(EntryRecord) -> std::io::Result<()>> TarEntryHandle for RecordEntries<F> {
+    fn on_whiteout_extension(&mut self, extension: WhiteoutExtension) -> std::io::Result<()> {
+        for whiteout in extension.whiteouts {
+            (self.on_record)(EntryRecord::whiteout(whiteout))?;
+        }
         Ok(())
     }
+
+    fn on_oci_whiteout_entry(&mut self, path: PathBuf) -> std::io::Result<()> {
+        (self.on_record)(EntryRecord::whiteout(path.to_string_lossy().to_string()))
+    }
+
+    fn on_oci_whiteout_opq(&mut self, path: PathBuf) -> std::io::Result<()> {
+        (self.on_record)(EntryRecord::opaque(path.to_string_lossy().to_string()))
+    }
+
+    fn on_normal_entry(&mut self, _buf: &[u8; 512], entry: &TarEntry) -> std::io::Result<()> {
+        (self.on_record)(EntryRecord::from_entry(entry))
+    }
 }
 
 struct ExtractTar<'a, W> {
     writer: &'a mut W,
+    /// the directory the archive is extracting to, whiteouts are resolved relative to it
+    root: PathBuf,
+    owner_map: &'a OwnerMap,
 }
 
 impl<'a, W: Write> TarEntryHandle for ExtractTar<'a, W> {
     fn on_whiteout_extension(&mut self, extension: WhiteoutExtension) -> std::io::Result<()> {
         for whiteout in extension.whiteouts {
-            _ = remove_path(std::path::PathBuf::from(whiteout));
+            _ = remove_path(self.root.join(whiteout));
         }
         Ok(())
     }
 
-    fn on_oci_whiteout_entry(&mut self, path: std::path::PathBuf) -> std::io::Result<()> {
-        _ = remove_path(path);
+    fn on_oci_whiteout_entry(&mut self, path: PathBuf) -> std::io::Result<()> {
+        _ = remove_path(self.root.join(path));
         Ok(())
     }
 
-    fn on_oci_whiteout_opq(&mut self, path: std::path::PathBuf) -> std::io::Result<()> {
-        if let Ok(dirents) = std::fs::read_dir(path) {
+    fn on_oci_whiteout_opq(&mut self, path: PathBuf) -> std::io::Result<()> {
+        if let Ok(dirents) = std::fs::read_dir(self.root.join(path)) {
             for dirent in dirents {
                 let path = dirent?.path();
                 _ = remove_path(path);
@@ -468,15 +1396,19 @@ impl<'a, W: Write> TarEntryHandle for ExtractTar<'a, W> {
         self.writer.write_all(&EMPTY_TAR_HEADER)
     }
 
-    fn on_normal_entry(&mut self, buf: &[u8; 512], _header: &RawTarHeader) -> std::io::Result<()> {
-        self.writer.write_all(buf)
+    fn on_normal_entry(&mut self, buf: &[u8; 512], entry: &TarEntry) -> std::io::Result<()> {
+        if self.owner_map.is_identity() {
+            self.writer.write_all(&entry.extension_blocks)?;
+            return self.writer.write_all(buf);
+        }
+        // the ownership may come from the header or the PAX records, rewrite both
+        let mapped = self.owner_map.map_entry(entry)?;
+        write_entry_extensions(self.writer, &mapped)?;
+        self.writer
+            .write_all(&unsafe { std::mem::transmute::<RawTarHeader, [u8; 512]>(mapped.header) })
     }
 
-    fn on_normal_entry_block(
-        &mut self,
-        buf: &[u8; 512],
-        _header: &RawTarHeader,
-    ) -> std::io::Result<()> {
+    fn on_normal_entry_block(&mut self, buf: &[u8; 512], _entry: &TarEntry) -> std::io::Result<()> {
         self.writer.write_all(buf)
     }
 
@@ -485,7 +1417,8 @@ impl<'a, W: Write> TarEntryHandle for ExtractTar<'a, W> {
     }
 }
 
-fn tap_foreach_entry<R: Read, H: TarEntryHandle>(
+/// Walk through the entries of an uncompressed tar stream and notify `handle` on each of them
+pub fn tap_foreach_entry<R: Read, H: TarEntryHandle>(
     reader: &mut R,
     handle: &mut H,
 ) -> std::io::Result<()> {
@@ -493,6 +1426,17 @@ fn tap_foreach_entry<R: Read, H: TarEntryHandle>(
     let mut empty_records = 0;
     let mut buf = [0u8; 512];
 
+    // records of the PAX global headers, apply to all the following entries
+    let mut global_extensions: Vec<Extension> = Vec::new();
+    // records of the PAX per-file headers, apply to the next entry only
+    let mut extensions: Vec<Extension> = Vec::new();
+    // GNU long name and long link of the next entry, as `path` and `linkpath` records
+    let mut gnu_extensions: Vec<Extension> = Vec::new();
+    // raw blocks of the extended headers not yet passed downstream, global headers are kept
+    // separately as they should not be dropped along with a whiteout entry
+    let mut global_blocks: Vec<u8> = Vec::new();
+    let mut extension_blocks: Vec<u8> = Vec::new();
+
     let mut reader: Box<dyn Read> = Box::new(reader);
 
     loop {
@@ -507,17 +1451,79 @@ fn tap_foreach_entry<R: Read, H: TarEntryHandle>(
         if header.is_valid_tar_header() {
             empty_records = 0;
             let content_length = header.content_length()?;
-            let blocks = ((content_length + 511) / 512) as usize;
 
             if header.is_whiteout_extension() {
                 log::debug!("entry is whiteout extension");
                 let extensions = Extension::read_from_stream(&mut reader, content_length as usize)?;
                 for extension in extensions.iter() {
-                    let ext: WhiteoutExtension = serde_json::from_str(&extension.value).unwrap();
+                    let Ok(ext) = serde_json::from_slice::<WhiteoutExtension>(&extension.value)
+                    else {
+                        return err!("malformed whiteout extension");
+                    };
                     handle.on_whiteout_extension(ext)?;
                 }
+            } else if header.is_extension() {
+                log::debug!("entry is pax extended header");
+                let blocks = content_length.div_ceil(512) as usize;
+                let mut content = vec![0u8; blocks * 512];
+                reader.read_exact(&mut content)?;
+                let records = Extension::parse(&content[..content_length as usize])?;
+                let raw_blocks = if header.entry_type() == b'g' {
+                    global_extensions.extend(records);
+                    &mut global_blocks
+                } else {
+                    extensions.extend(records);
+                    &mut extension_blocks
+                };
+                raw_blocks.extend_from_slice(&buf);
+                raw_blocks.extend_from_slice(&content);
+            } else if header.is_gnu_long_entry() {
+                log::debug!("entry is gnu long name / long link");
+                let blocks = content_length.div_ceil(512) as usize;
+                let mut content = vec![0u8; blocks * 512];
+                reader.read_exact(&mut content)?;
+                let value = content[..content_length as usize]
+                    .split(|c| *c == 0)
+                    .next()
+                    .unwrap_or_default();
+                let Ok(value) = std::str::from_utf8(value) else {
+                    return err!("gnu long name is not utf8 string");
+                };
+                let key = if header.entry_type() == b'L' {
+                    "path"
+                } else {
+                    "linkpath"
+                };
+                gnu_extensions.push(Extension::new(key.to_string(), value.to_string()));
+                extension_blocks.extend_from_slice(&buf);
+                extension_blocks.extend_from_slice(&content);
             } else {
-                let path = header.file_path()?;
+                let mut applied = global_extensions.clone();
+                applied.append(&mut gnu_extensions);
+                applied.append(&mut extensions);
+                let raw_blocks = std::mem::take(&mut extension_blocks);
+                let mut entry = TarEntry::new(header, applied, Vec::new())?;
+                // the sparse map may follow the header, the blocks read to find it are passed
+                // downstream along with the rest of the content
+                let mut sparse_blocks = Vec::new();
+                entry.sparse =
+                    read_sparse_map(&buf, &entry.extensions, &mut reader, &mut sparse_blocks)?;
+                if entry.header.entry_type() == b'S' {
+                    // the size of old GNU sparse entries does not count the extended headers
+                    let Some(size) = entry.size.checked_add(sparse_blocks.len() as u64) else {
+                        return err!("malformed sparse map");
+                    };
+                    entry.size = size;
+                }
+                let Some(blocks) = entry
+                    .size
+                    .div_ceil(512)
+                    .checked_sub(sparse_blocks.len() as u64 / 512)
+                else {
+                    return err!("malformed sparse map");
+                };
+
+                let path = &entry.path;
                 let filename = path
                     .file_name()
                     .and_then(|name| name.to_str())
@@ -525,17 +1531,13 @@ fn tap_foreach_entry<R: Read, H: TarEntryHandle>(
 
                 if let Some(name) = filename {
                     if name == ".wh..opq" {
-                        let mut parent = match path.parent() {
+                        let parent = match path.parent() {
                             None => std::path::PathBuf::from("."),
                             Some(parent) if parent.to_string_lossy() == "" => {
                                 std::path::PathBuf::from(".")
                             }
                             Some(parent) => parent.to_path_buf(),
                         };
-                        //let mut parent = path.parent().unwrap().to_path_buf();
-                        if parent.to_string_lossy() == "" {
-                            parent = std::path::PathBuf::from(".")
-                        }
                         handle.on_oci_whiteout_opq(parent)?;
                     } else {
                         let to_delete = match path.parent() {
@@ -544,13 +1546,21 @@ fn tap_foreach_entry<R: Read, H: TarEntryHandle>(
                         };
                         handle.on_oci_whiteout_entry(to_delete)?;
                     }
+                    // whiteout entries are not passed downstream, discard the content if any
+                    for _ in 0..blocks {
+                        reader.read_exact(&mut buf)?;
+                    }
                 } else {
-                    handle.on_normal_entry(&buf, &header)?;
-                    // it doesn't matter if the entry is a pax entension, if we don't
-                    // know how to handle it we pass it downstream to tar
+                    entry.extension_blocks = std::mem::take(&mut global_blocks);
+                    entry.extension_blocks.extend(raw_blocks);
+                    handle.on_normal_entry(&buf, &entry)?;
+                    for block in sparse_blocks.chunks_exact(512) {
+                        buf.copy_from_slice(block);
+                        handle.on_normal_entry_block(&buf, &entry)?;
+                    }
                     for _ in 0..blocks {
                         reader.read_exact(&mut buf)?;
-                        handle.on_normal_entry_block(&buf, &header)?;
+                        handle.on_normal_entry_block(&buf, &entry)?;
                     }
                 }
             }
@@ -567,6 +1577,15 @@ fn tap_foreach_entry<R: Read, H: TarEntryHandle>(
                 }
                 break;
             }
+        } else if header.ustar[..5] == *b"ustar" {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "header checksum mismatch at entry {entries_count}: expected {:?}, got {:?}",
+                    String::from_utf8_lossy(&header.checksum()),
+                    String::from_utf8_lossy(&{ header.cksum })
+                ),
+            ));
         } else {
             return err!("unknown format");
         }
@@ -574,67 +1593,68 @@ fn tap_foreach_entry<R: Read, H: TarEntryHandle>(
     Ok(())
 }
 
-// read from a tar and pass to the stdin of a real tar process
-pub fn tap_extract_tar<R: Read, W: Write>(reader: R, writer: W) -> std::io::Result<[u8; 32]> {
+/// Walk through the entries of an uncompressed tar stream with `handle`, and summarize the
+/// entries seen
+pub fn summarize_entries<R: Read, H: TarEntryHandle>(
+    reader: R,
+    handle: &mut H,
+) -> std::io::Result<Summary> {
     let mut reader = DigestReader::<R>::new(reader);
+    let mut summarize = Summarize {
+        handle,
+        summary: Summary::default(),
+    };
+    tap_foreach_entry(&mut reader, &mut summarize)?;
+    let mut summary = summarize.summary;
+    // layers created by this program carry each whiteout both as an OCI whiteout entry and in
+    // the custom extension
+    let mut seen = std::collections::HashSet::new();
+    summary
+        .whiteouts
+        .retain(|whiteout| seen.insert(whiteout.clone()));
+    summary.diff_id = reader.consume();
+    summary.archive_digest = summary.diff_id;
+    Ok(summary)
+}
+
+// read from a tar and pass to the stdin of a real tar process extracting at `root`
+pub fn tap_extract_tar<R: Read, W: Write>(
+    reader: R,
+    writer: W,
+    root: impl AsRef<Path>,
+    owner_map: &OwnerMap,
+) -> std::io::Result<Summary> {
     let mut writer = DigestWriter::<W>::new(writer);
     let mut extractor = ExtractTar {
         writer: &mut writer,
+        root: root.as_ref().to_path_buf(),
+        owner_map,
     };
-    tap_foreach_entry(&mut reader, &mut extractor)?;
-    Ok(reader.consume())
+    summarize_entries(reader, &mut extractor)
 }
 
 pub fn list_tar<R: Read>(reader: &mut R) -> std::io::Result<Summary> {
-    let mut lister = ListTar {
-        summary: Summary {
-            files: vec![],
-            whiteouts: vec![],
-        },
-    };
-    tap_foreach_entry(reader, &mut lister)?;
-    Ok(lister.summary)
+    summarize_entries(reader, &mut ())
 }
 
 pub fn write_extended_header<W: Write>(
     writer: &mut W,
     key: &str,
     value: &str,
+    reproducible: Option<&Reproducible>,
 ) -> std::io::Result<usize> {
-    let value = format!("{} {key}={value}", key.len() + value.len() + 1);
-    let timestamp = unsafe { libc::time(std::ptr::null_mut()) };
-    let mut header = RawTarHeader::empty_ustar();
-    header.set_path(Some("PaxHeader".to_string()), "WhiteOuts".to_string());
-    header.set_mode(0o644);
-    header.set_uid_gid();
-    header.set_lastmod(timestamp.try_into().unwrap());
-    header.set_size(value.len());
+    let mut header = synthesized_header(reproducible);
+    header.set_path(None, "WhiteOuts".to_string());
     header.tpe = *b"g";
-    header.set_checksum();
-
-    writer.write_all(&unsafe { std::mem::transmute::<RawTarHeader, [u8; 512]>(header) })?;
-
-    let mut bytes = value.as_bytes();
-    let mut buf = [0u8; 512];
 
-    // We have already written a block of header
-    let mut written = 1;
-
-    while !bytes.is_empty() {
-        let length = usize::min(bytes.len(), 512);
-        buf[..length].copy_from_slice(&bytes[..length]);
-        buf[length..].fill(0);
-        writer.write_all(&buf)?;
-        bytes = &bytes[length..];
-        written += 1;
-    }
-
-    Ok(written)
+    // keep the encoding of earlier versions, which panic on standard PAX records here
+    let extension = Extension::new(key.to_string(), value.to_string());
+    write_pax_content(writer, header, "PaxHeader", &extension.encoded_legacy())
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{Extension, RawTarHeader};
+    use super::*;
 
     #[test]
     fn header_set_file_short_path() {
@@ -651,7 +1671,7 @@ mod tests {
         let mut header = RawTarHeader::empty_ustar();
         header.set_path(Some(prefix.to_string()), name.to_string());
         assert!(header.name.starts_with(b"file\0"));
-        assert!(header.prefix.starts_with(prefix.as_str().as_bytes()));
+        assert!(header.prefix.starts_with(prefix.as_bytes()));
     }
 
     #[test]
@@ -667,4 +1687,159 @@ mod tests {
             ]
         )
     }
+
+    #[test]
+    fn test_extension_encode() {
+        let extension = Extension::new("path".to_string(), "a".repeat(95));
+        let encoded = extension.encoded();
+        assert!(encoded.starts_with(format!("{} path=", encoded.len()).as_bytes()));
+        assert_eq!(Extension::parse(&encoded).unwrap(), vec![extension]);
+    }
+
+    #[test]
+    fn test_extension_decode_pax() {
+        let data = b"30 mtime=1350244992.023960108\n20 path=hello/world\n\0\0";
+        let exts = Extension::parse(data).unwrap();
+        assert_eq!(
+            exts,
+            vec![
+                Extension::new("mtime".to_string(), "1350244992.023960108".to_string()),
+                Extension::new("path".to_string(), "hello/world".to_string())
+            ]
+        );
+        assert_eq!(
+            parse_pax_time(exts[0].value_str().unwrap()).unwrap(),
+            (1350244992, 23960108)
+        );
+    }
+
+    #[test]
+    fn test_whiteout_extension_legacy_encoding() -> std::io::Result<()> {
+        let value = r#"{"whiteouts":["a/b","c"]}"#;
+        let mut archive = Vec::new();
+        write_extended_header(&mut archive, "whiteouts", value, None)?;
+
+        let header: RawTarHeader = unsafe {
+            std::mem::transmute::<[u8; 512], RawTarHeader>(archive[..512].try_into().unwrap())
+        };
+        assert!(header.is_whiteout_extension());
+        let length = header.content_length()? as usize;
+        let content = std::str::from_utf8(&archive[512..512 + length]).unwrap();
+        // the way earlier versions read the record
+        let (size, record) = content.split_once(' ').unwrap();
+        assert_eq!(size.parse::<usize>().unwrap(), record.len());
+        assert_eq!(record, format!("whiteouts={value}"));
+
+        let extensions = Extension::read_from_stream(&mut &archive[512..], length)?;
+        assert_eq!(
+            extensions,
+            vec![Extension::new("whiteouts".to_string(), value.to_string())]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_numeric_base256() {
+        let mut field = [0u8; 12];
+        field[0] = 0x80;
+        field[11] = 0x10;
+        assert_eq!(parse_numeric(&field).unwrap(), 16);
+        assert_eq!(parse_numeric_signed(&field).unwrap(), 16);
+
+        // -1 and -256 in two's complement
+        let field = [0xffu8; 12];
+        assert_eq!(parse_numeric_signed(&field).unwrap(), -1);
+        assert!(parse_numeric(&field).is_err());
+        let mut field = [0xffu8; 12];
+        field[11] = 0;
+        assert_eq!(parse_numeric_signed(&field).unwrap(), -256);
+
+        let mut field = [0xffu8; 12];
+        field[1] = 0;
+        assert!(parse_numeric_signed(&field).is_err());
+    }
+
+    #[test]
+    fn test_entry_lenient_attributes() -> std::io::Result<()> {
+        let mut header = file_header();
+        header.try_set_path("a");
+        header.uid = *b"garbage\0";
+        header.lastmod = [0xff; 12];
+        header.set_checksum();
+        let extensions = vec![
+            Extension::new("mtime".to_string(), "yesterday".to_string()),
+            Extension::new("gid".to_string(), "-1".to_string()),
+        ];
+        let entry = TarEntry::new(header.clone(), extensions, Vec::new())?;
+        assert_eq!(entry.uid, 0);
+        assert_eq!(entry.gid, header.gid()?);
+        assert_eq!((entry.mtime, entry.mtime_nsec), (-1, 0));
+
+        let extensions = vec![Extension::new("size".to_string(), "many".to_string())];
+        assert!(TarEntry::new(header, extensions, Vec::new()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn header_large_size() {
+        let mut header = RawTarHeader::empty_ustar();
+        header.set_size(1 << 40);
+        assert_eq!(header.content_length().unwrap(), 1 << 40);
+        header.set_size(1234);
+        assert_eq!(header.content_length().unwrap(), 1234);
+    }
+
+    fn file_header() -> RawTarHeader {
+        let mut header = RawTarHeader::empty_ustar();
+        header.set_mode(0o644);
+        header.set_uid_gid();
+        header.set_lastmod(0);
+        header.tpe = *b"0";
+        header
+    }
+
+    #[test]
+    fn test_list_pax_long_paths() -> std::io::Result<()> {
+        let long_dir = "d".repeat(160);
+        let long_file = format!("{long_dir}/{}", "f".repeat(120));
+        let mut archive = Vec::new();
+
+        write_entry_header(&mut archive, file_header(), &long_file, 5)?;
+        let mut content = [0u8; 512];
+        content[..5].copy_from_slice(b"hello");
+        archive.extend_from_slice(&content);
+        write_oci_whiteouts(
+            format!("{long_dir}/{}", "w".repeat(120)),
+            None,
+            &mut archive,
+        )?;
+        archive.extend_from_slice(&[0u8; 1024]);
+
+        let summary = list_tar(&mut archive.as_slice())?;
+        assert_eq!(summary.files, vec![long_file]);
+        assert_eq!(
+            summary.whiteouts,
+            vec![format!("{long_dir}/{}", "w".repeat(120))]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_list_pax_size() -> std::io::Result<()> {
+        let mut archive = Vec::new();
+        let mut pax_header = file_header();
+        pax_header.try_set_path("a");
+        pax_header.tpe = *b"x";
+        let size = Extension::new("size".to_string(), "600".to_string());
+        write_pax_header(&mut archive, pax_header, "PaxHeader", &[size])?;
+        // the ustar header carries a bogus size, the one in the extended header should be used
+        write_entry_header(&mut archive, file_header(), "a", 0)?;
+        archive.extend_from_slice(&[b'a'; 1024]);
+        write_entry_header(&mut archive, file_header(), "b", 0)?;
+        archive.extend_from_slice(&[0u8; 1024]);
+
+        let summary = list_tar(&mut archive.as_slice())?;
+        assert_eq!(summary.files, vec!["a".to_string(), "b".to_string()]);
+        Ok(())
+    }
 }
@@ -0,0 +1,112 @@
+// Copyright (c) 2023 Yan Ka, Chiu.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions
+// are met:
+// 1. Redistributions of source code must retain the above copyright
+//    notice, this list of conditions, and the following disclaimer,
+//    without modification, immediately at the beginning of the file.
+// 2. The name of the author may not be used to endorse or promote products
+//    derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE AUTHOR AND CONTRIBUTORS ``AS IS'' AND
+// ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE AUTHOR OR CONTRIBUTORS BE LIABLE FOR
+// ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+// OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+// HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+// LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
+// OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
+// SUCH DAMAGE.
+//! The tree built by applying a stack of layers in sequence, shared by the operations that need
+//! to resolve the whiteouts of the layers without extracting them
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// The entries of the tree by their normalized path, which is empty for the root directory
+pub(crate) struct LayerTree<T> {
+    entries: BTreeMap<PathBuf, T>,
+}
+
+impl<T> Default for LayerTree<T> {
+    fn default() -> LayerTree<T> {
+        LayerTree {
+            entries: BTreeMap::new(),
+        }
+    }
+}
+
+impl<T> LayerTree<T> {
+    /// Apply the whiteouts and opaque directories of a layer. They only apply to the layers
+    /// below, regardless of where they appear in the archive, so they are applied before any
+    /// entry of the same layer
+    pub fn apply_whiteouts(&mut self, whiteouts: &[PathBuf], opaques: &[PathBuf]) {
+        for path in whiteouts {
+            self.remove_under(path, true);
+        }
+        for path in opaques {
+            self.remove_under(path, false);
+        }
+    }
+
+    /// Apply an entry, replacing the entry of the same path. A non-directory entry also hides
+    /// everything under it
+    pub fn apply_entry(&mut self, path: PathBuf, is_dir: bool, value: T) {
+        if !is_dir {
+            self.remove_under(&path, false);
+        }
+        self.entries.insert(path, value);
+    }
+
+    pub fn get(&self, path: &Path) -> Option<&T> {
+        self.entries.get(path)
+    }
+
+    pub fn into_entries(self) -> BTreeMap<PathBuf, T> {
+        self.entries
+    }
+
+    /// Remove everything under `path`, and `path` itself if `inclusive` is set
+    fn remove_under(&mut self, path: &Path, inclusive: bool) {
+        let paths = self
+            .entries
+            .range(path.to_path_buf()..)
+            .map(|(key, _)| key)
+            .take_while(|key| key.starts_with(path))
+            .filter(|key| inclusive || key.as_path() != path)
+            .cloned()
+            .collect::<Vec<_>>();
+        for path in paths {
+            self.entries.remove(&path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layer_tree() {
+        let mut tree = LayerTree::default();
+        for (path, is_dir) in [("a", true), ("a/1", false), ("b", true), ("b/x", false)] {
+            tree.apply_entry(PathBuf::from(path), is_dir, path);
+        }
+        tree.apply_entry(PathBuf::from("c"), false, "c");
+
+        tree.apply_whiteouts(&[PathBuf::from("a")], &[PathBuf::from("b")]);
+        tree.apply_entry(PathBuf::from("a.txt"), false, "a.txt");
+        // a file replacing the directory hides everything under it
+        tree.apply_entry(PathBuf::from("b/x/y"), false, "b/x/y");
+        tree.apply_entry(PathBuf::from("b"), false, "b");
+
+        let entries = tree.into_entries();
+        assert_eq!(
+            entries.values().copied().collect::<Vec<_>>(),
+            vec!["a.txt", "b", "c"]
+        );
+    }
+}
@@ -49,12 +49,24 @@ pub fn hex(bytes: impl AsRef<[u8]>) -> String {
 }
 
 pub fn str_from_nul_bytes_buf(buf: &[u8]) -> Result<&str, std::io::Error> {
-    let buf = std::str::from_utf8(buf).map_err(|_| {
-        std::io::Error::new(std::io::ErrorKind::Other, "failed to encode utf8 string")
-    })?;
+    let buf = std::str::from_utf8(buf)
+        .map_err(|_| std::io::Error::other("failed to encode utf8 string"))?;
     Ok(buf.trim_end_matches('\0'))
 }
 
+/// Parse a sha256 digest in hex, with or without the "sha256:" prefix
+pub fn parse_sha256_digest(digest: &str) -> Option<[u8; 32]> {
+    let digest = digest.strip_prefix("sha256:").unwrap_or(digest);
+    if digest.len() != 64 || !digest.is_ascii() {
+        return None;
+    }
+    let mut bytes = [0u8; 32];
+    for (byte, pair) in bytes.iter_mut().zip(digest.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(bytes)
+}
+
 pub struct DigestReader<R: Read> {
     source: R,
     digest: Sha256,
@@ -169,11 +181,53 @@ impl<R: Read> Read for PrebufferedSource<R> {
     }
 }
 
+pub(crate) fn cstring(path: &std::path::Path) -> std::io::Result<std::ffi::CString> {
+    use std::os::unix::ffi::OsStrExt;
+    std::ffi::CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| std::io::Error::other("path contains nul byte"))
+}
+
+/// Normalize the path of an entry such that `./a/b/` and `a/b` are the same, the root directory
+/// is represented by an empty path
+pub(crate) fn normalize_entry_path(path: &std::path::Path) -> std::path::PathBuf {
+    use std::path::Component;
+    path.components()
+        .filter(|component| matches!(component, Component::Normal(_) | Component::ParentDir))
+        .collect()
+}
+
+/// Run `f` in a fresh directory `test-materials/stage-{ident}`, the directory is removed after
+/// `f` returns, fails or panics
+#[cfg(test)]
+pub(crate) fn with_stage_dir<R>(
+    ident: &str,
+    f: impl FnOnce(&std::path::Path) -> std::io::Result<R>,
+) -> std::io::Result<R> {
+    let dir = std::path::PathBuf::from(format!("test-materials/stage-{ident}"));
+    _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir)?;
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| f(&dir)));
+    _ = std::fs::remove_dir_all(&dir);
+    result.unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+}
+
 #[cfg(test)]
 mod tests {
 
     use super::*;
 
+    #[test]
+    fn test_parse_sha256_digest() {
+        let bytes: [u8; 32] = std::array::from_fn(|i| (i * 8) as u8);
+        assert_eq!(parse_sha256_digest(&hex(bytes)), Some(bytes));
+        assert_eq!(
+            parse_sha256_digest(&format!("sha256:{}", hex(bytes))),
+            Some(bytes)
+        );
+        assert_eq!(parse_sha256_digest("sha256:1234"), None);
+        assert_eq!(parse_sha256_digest(&"g".repeat(64)), None);
+    }
+
     #[test]
     fn test_str_conversion() {
         let buf = b"ustar\0\0\0\0\0";
@@ -24,9 +24,10 @@
 pub(crate) mod dataset;
 
 use ipcidr::IpCidr;
+use oci_util::image_reference::ImageReference;
 use pest::Parser;
 use pest_derive::Parser;
-use std::{ffi::OsString, str::FromStr};
+use std::{ffi::OsString, path::PathBuf, str::FromStr};
 use xc::models::network::{IpAssign, NetProto, PortNum, PortRedirection};
 
 #[derive(Parser)]
@@ -242,6 +243,58 @@ impl FromStr for PublishSpec {
     }
 }
 
+/// An artifact to attach to a pushed image, in the form of `path:media_type`
+#[derive(Debug, Clone)]
+pub(crate) struct AttachSpec {
+    pub(crate) path: PathBuf,
+    pub(crate) media_type: String,
+}
+
+impl FromStr for AttachSpec {
+    type Err = anyhow::Error;
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        // media types never contain ':', unlike paths
+        let Some((path, media_type)) = input.rsplit_once(':') else {
+            anyhow::bail!("expected <file>:<media type>");
+        };
+        if path.is_empty() || !media_type.contains('/') {
+            anyhow::bail!(
+                "expected <file>:<media type>, for example sbom.json:application/spdx+json"
+            );
+        }
+        Ok(AttachSpec {
+            path: PathBuf::from(path),
+            media_type: media_type.to_string(),
+        })
+    }
+}
+
+/// A repository in a registry, in the form of `[registry/]name`
+#[derive(Debug, Clone)]
+pub(crate) struct RepositorySpec {
+    pub(crate) hostname: Option<String>,
+    pub(crate) name: String,
+}
+
+impl FromStr for RepositorySpec {
+    type Err = anyhow::Error;
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        // reuse the image reference grammar to tell the registry apart from the name
+        let reference = format!("{input}:latest").parse::<ImageReference>()?;
+        let parsed = match &reference.hostname {
+            None => reference.name.clone(),
+            Some(hostname) => format!("{hostname}/{}", reference.name),
+        };
+        if parsed != input {
+            anyhow::bail!("expected [registry/]name without a tag or digest");
+        }
+        Ok(RepositorySpec {
+            hostname: reference.hostname,
+            name: reference.name,
+        })
+    }
+}
+
 const GB: usize = 1 << 30;
 const MB: usize = 1 << 20;
 const KB: usize = 1 << 10;
@@ -385,4 +438,27 @@ mod tests {
         assert_eq!(spec.0.proto, vec![NetProto::Udp, NetProto::Tcp]);
         Ok(())
     }
+
+    #[test]
+    fn test_parse_attach_spec() -> Result<()> {
+        let spec = "./out/sbom:v1.json:application/spdx+json".parse::<AttachSpec>()?;
+        assert_eq!(spec.path, PathBuf::from("./out/sbom:v1.json"));
+        assert_eq!(spec.media_type, "application/spdx+json");
+        assert!("sbom.json".parse::<AttachSpec>().is_err());
+        assert!(":application/json".parse::<AttachSpec>().is_err());
+        assert!("sbom.json:json".parse::<AttachSpec>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_repository_spec() -> Result<()> {
+        let spec = "registry.example.com:5000/ci/app".parse::<RepositorySpec>()?;
+        assert_eq!(spec.hostname.as_deref(), Some("registry.example.com:5000"));
+        assert_eq!(spec.name, "ci/app");
+        let spec = "library/alpine".parse::<RepositorySpec>()?;
+        assert_eq!(spec.hostname, None);
+        assert_eq!(spec.name, "library/alpine");
+        assert!("app:v1".parse::<RepositorySpec>().is_err());
+        Ok(())
+    }
 }
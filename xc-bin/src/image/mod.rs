@@ -29,10 +29,13 @@ use crate::image::patch::PatchActions;
 use anyhow::Context;
 use clap::Parser;
 use oci_util::image_reference::ImageReference;
-use std::os::fd::AsRawFd;
+use oci_util::layout::ImageLayout;
+use std::os::fd::{AsRawFd, OwnedFd};
 use std::os::unix::net::UnixStream;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
 use xc::models::jail_image::JailConfig;
+use xc::util::gen_id;
 use xcd::ipc::*;
 
 #[derive(Parser, Debug)]
@@ -71,6 +74,35 @@ pub(crate) enum ImageAction {
         action: PatchActions,
         image_reference: ImageReference,
     },
+    /// Save images as an OCI image layout
+    Save {
+        /// Path of the layout, written as a tar archive if it ends with .tar
+        #[arg(short = 'o')]
+        output: PathBuf,
+        #[arg(required = true)]
+        image_references: Vec<ImageReference>,
+    },
+    /// Load images from an OCI image layout or a docker save archive
+    Load {
+        /// Tag the image as this reference instead of the names in the layout
+        #[arg(short = 't', long = "tag")]
+        image_reference: Option<ImageReference>,
+        /// Path of the layout, either a directory or a tar archive
+        path: PathBuf,
+    },
+}
+
+/// Wait for `tar` to extract the layout saved by the daemon to `staging`, and merge it into the
+/// layout directory at `output`
+fn merge_saved_layout(tar: &mut Child, staging: &Path, output: &Path) -> anyhow::Result<()> {
+    let status = tar.wait().context("cannot wait for tar")?;
+    if !status.success() {
+        anyhow::bail!("tar exited with {status}");
+    }
+    let saved = ImageLayout::open(staging).context("cannot open saved layout")?;
+    ImageLayout::create(output)
+        .and_then(|layout| layout.merge(&saved))
+        .context("cannot write layout")
 }
 
 pub(crate) fn patch_image<F>(
@@ -236,6 +268,105 @@ pub(crate) fn use_image_action(
                 }
             }
         }
+        ImageAction::Save {
+            output,
+            image_references,
+        } => {
+            // the daemon writes the layout as a tar archive to a file we opened, layout
+            // directories are extracted from the archive and merged by ourselves
+            let staging = output.with_file_name(format!(".{}", gen_id()));
+            let (sink, extract) = if output.extension().is_some_and(|ext| ext == "tar") {
+                let file = std::fs::OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(&output)
+                    .context("cannot open output")?;
+                (OwnedFd::from(file), None)
+            } else {
+                std::fs::create_dir_all(&staging).context("cannot create staging directory")?;
+                let mut tar = Command::new("tar")
+                    .arg("-xf")
+                    .arg("-")
+                    .arg("-C")
+                    .arg(&staging)
+                    .stdin(Stdio::piped())
+                    .spawn()
+                    .context("cannot run tar")?;
+                let stdin = tar.stdin.take().expect("stdin of tar is piped");
+                (OwnedFd::from(stdin), Some(tar))
+            };
+            let request = SaveImageRequest {
+                image_references,
+                output: ipc::packet::codec::Fd(sink.as_raw_fd()),
+            };
+            let response = do_save_image(conn, request);
+            drop(sink);
+            if let Some(mut tar) = extract {
+                let merged = match &response {
+                    Ok(Ok(_)) => merge_saved_layout(&mut tar, &staging, &output),
+                    _ => tar.wait().map(|_| ()).map_err(anyhow::Error::from),
+                };
+                _ = std::fs::remove_dir_all(&staging);
+                merged?;
+            }
+            match response? {
+                Ok(response) => {
+                    for manifest in response.manifests.iter() {
+                        println!("{}", manifest.digest);
+                    }
+                }
+                Err(err) => eprintln!("cannot save image: {}", err.value),
+            }
+        }
+        ImageAction::Load {
+            image_reference,
+            path,
+        } => {
+            // the daemon only reads tar archives from a file we opened, directories are
+            // archived on the fly
+            let (source, archive) = if path.is_dir() {
+                let mut tar = Command::new("tar")
+                    .arg("-cf")
+                    .arg("-")
+                    .arg("-C")
+                    .arg(&path)
+                    .arg(".")
+                    .stdout(Stdio::piped())
+                    .spawn()
+                    .context("cannot run tar")?;
+                let stdout = tar.stdout.take().expect("stdout of tar is piped");
+                (OwnedFd::from(stdout), Some(tar))
+            } else {
+                let file = std::fs::OpenOptions::new()
+                    .read(true)
+                    .open(&path)
+                    .context("cannot open layout")?;
+                (OwnedFd::from(file), None)
+            };
+            let request = LoadImageRequest {
+                input: ipc::packet::codec::Fd(source.as_raw_fd()),
+                image_reference,
+            };
+            let response = do_load_image(conn, request);
+            // let tar exit on the broken pipe if the daemon stopped reading early
+            drop(source);
+            let archived = match archive {
+                Some(mut tar) => tar.wait().context("cannot wait for tar")?.success(),
+                None => true,
+            };
+            match response? {
+                Ok(response) => {
+                    for image_reference in response.image_references.iter() {
+                        println!("{image_reference}");
+                    }
+                }
+                Err(err) => eprintln!("cannot load image: {}", err.value),
+            }
+            if !archived {
+                Err(anyhow::anyhow!("cannot archive {path:?}"))?
+            }
+        }
         ImageAction::Remove { image_reference } => {
             _ = do_remove_image(conn, image_reference)?;
         }
@@ -29,16 +29,18 @@ mod image;
 mod jailfile;
 mod network;
 mod redirect;
+mod remote;
 mod run;
 mod volume;
 
 use crate::channel::{use_channel_action, ChannelAction};
 use crate::error::ActionError;
-use crate::format::{format_bandwidth, format_capacity};
+use crate::format::{format_bandwidth, format_capacity, AttachSpec};
 use crate::image::{use_image_action, ImageAction};
 use crate::jailfile::directives::volume::VolumeDirective;
 use crate::network::{use_network_action, NetworkAction};
 use crate::redirect::{use_rdr_action, RdrAction};
+use crate::remote::{use_remote_action, RemoteAction};
 use crate::run::{CreateArgs, DnsArgs, RunArg};
 use crate::volume::{use_volume_action, VolumeAction};
 
@@ -170,6 +172,17 @@ enum Action {
     Push {
         #[arg(long = "insecure", default_value_t)]
         insecure: bool,
+        /// Compression of the layers to push, one of plain, gzip and zstd. Layers stored in
+        /// another compression are converted before pushing
+        #[arg(long = "compression")]
+        compression: Option<String>,
+        /// Sign the pushed image with the signing key configured on the daemon
+        #[arg(long = "sign", action)]
+        sign: bool,
+        /// Attach a file to the pushed image as an artifact, in the form of
+        /// <file>:<media type>, for example sbom.json:application/spdx+json
+        #[arg(long = "attach")]
+        attach: Vec<AttachSpec>,
         /// The local image to push
         image_reference: ImageReference,
         /// Destination of the upload
@@ -177,6 +190,8 @@ enum Action {
     },
     #[command(subcommand)]
     Rdr(RdrAction),
+    #[command(subcommand)]
+    Remote(RemoteAction),
     Run {
         #[command(flatten)]
         create: CreateArgs,
@@ -491,13 +506,42 @@ fn main() -> Result<(), ActionError> {
 
         Action::Push {
             insecure,
+            compression,
+            sign,
+            attach,
             image_reference,
             new_image_reference,
         } => {
+            // the daemon uploads the attachments from the files we opened, which stay open
+            // until the request is sent
+            let files = attach
+                .iter()
+                .map(|spec| {
+                    std::fs::File::open(&spec.path)
+                        .map_err(|err| anyhow::anyhow!("cannot open {:?}: {err}", spec.path))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            let attachments = attach
+                .iter()
+                .zip(files.iter())
+                .map(|(spec, file)| PushAttachment {
+                    file: Fd(file.as_raw_fd()),
+                    title: spec
+                        .path
+                        .file_name()
+                        .unwrap_or(spec.path.as_os_str())
+                        .to_string_lossy()
+                        .to_string(),
+                    media_type: spec.media_type.clone(),
+                })
+                .collect();
             let req = PushImageRequest {
                 image_reference: image_reference.clone(),
                 remote_reference: new_image_reference.clone(),
                 insecure,
+                layer_compression: compression,
+                sign,
+                attachments,
             };
             match do_push_image(&mut conn, req)? {
                 Ok(_) => {
@@ -587,6 +631,9 @@ fn main() -> Result<(), ActionError> {
         Action::Rdr(rdr) => {
             _ = use_rdr_action(&mut conn, rdr);
         }
+        Action::Remote(action) => {
+            use_remote_action(&mut conn, action)?;
+        }
         Action::Create { create, publish } => {
             let publish = publish.publish.clone();
 
@@ -0,0 +1,130 @@
+// Copyright (c) 2023 Yan Ka, Chiu.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions
+// are met:
+// 1. Redistributions of source code must retain the above copyright
+//    notice, this list of conditions, and the following disclaimer,
+//    without modification, immediately at the beginning of the file.
+// 2. The name of the author may not be used to endorse or promote products
+//    derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE AUTHOR AND CONTRIBUTORS ``AS IS'' AND
+// ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE AUTHOR OR CONTRIBUTORS BE LIABLE FOR
+// ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+// OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+// HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+// LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
+// OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
+// SUCH DAMAGE.
+use crate::format::RepositorySpec;
+
+use clap::Subcommand;
+use oci_util::image_reference::ImageReference;
+use std::os::unix::net::UnixStream;
+use xcd::ipc::*;
+
+/// Manage images in remote registries without pulling them
+#[derive(Subcommand, Debug)]
+pub(crate) enum RemoteAction {
+    /// List the tags of a repository
+    Tags {
+        /// The server uses http instead of https
+        #[arg(long = "insecure", action)]
+        insecure: bool,
+        /// The repository, in the format of {registry}/{repo}, if registry is missing, assume
+        /// the default registry
+        repository: RepositorySpec,
+    },
+    /// Show the manifest and config of a remote image for the current architecture
+    Inspect {
+        #[arg(long = "insecure", action)]
+        insecure: bool,
+        image_reference: ImageReference,
+    },
+    /// Delete a tag, or the manifest of a digest, from the registry. Deleting a manifest also
+    /// deletes every tag pointing to it
+    Delete {
+        #[arg(long = "insecure", action)]
+        insecure: bool,
+        /// If the registry cannot delete the tag alone, delete its manifest along with all the
+        /// other tags of the manifest
+        #[arg(long = "all-tags", action)]
+        all_tags: bool,
+        image_reference: ImageReference,
+    },
+}
+
+pub(crate) fn use_remote_action(
+    conn: &mut UnixStream,
+    action: RemoteAction,
+) -> Result<(), crate::ActionError> {
+    match action {
+        RemoteAction::Tags {
+            insecure,
+            repository,
+        } => {
+            let request = ListRemoteTagsRequest {
+                hostname: repository.hostname,
+                name: repository.name,
+                insecure,
+            };
+            match do_list_remote_tags(conn, request)? {
+                Ok(response) => {
+                    for tag in response.tags.iter() {
+                        println!("{tag}");
+                    }
+                }
+                Err(err) => eprintln!("cannot list tags: {}", err.value),
+            }
+        }
+        RemoteAction::Inspect {
+            insecure,
+            image_reference,
+        } => {
+            let request = RemoteImageRequest {
+                image_reference,
+                insecure,
+            };
+            match do_inspect_remote_image(conn, request)? {
+                Ok(image) => println!("{}", serde_json::to_string_pretty(&image).unwrap()),
+                Err(err) => eprintln!("cannot inspect image: {}", err.value),
+            }
+        }
+        RemoteAction::Delete {
+            insecure,
+            all_tags,
+            image_reference,
+        } => {
+            let request = DeleteRemoteImageRequest {
+                image_reference,
+                insecure,
+                all_tags,
+            };
+            match do_delete_remote_image(conn, request)? {
+                Ok(deleted) if deleted.manifest_deleted => {
+                    println!("{}", deleted.digest);
+                    for tag in deleted.tags.iter() {
+                        println!("    removed tag: {tag}");
+                    }
+                }
+                Ok(deleted) => {
+                    for tag in deleted.tags.iter() {
+                        println!("removed tag: {tag} ({})", deleted.digest);
+                    }
+                }
+                Err(err) => {
+                    eprintln!("cannot delete image: {}", err.value);
+                    if let RemoteImageError::TagDeletionUnsupported(..) = err.value {
+                        eprintln!("use --all-tags to delete the manifest along with these tags");
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
@@ -57,6 +57,23 @@ fn default_devfs_offset() -> u16 {
     1000
 }
 
+/// A source of registries and their credentials
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RegistryProviderConfig {
+    /// Registries managed by xc, in the same format as `registries`
+    Json(PathBuf),
+    /// A docker `config.json`, including credentials kept by `docker-credential-*` helpers
+    Docker(PathBuf),
+    /// Registries managed by xc with credentials encrypted by the key at `key_file`, which is
+    /// generated if missing. Plaintext credentials in `path` are encrypted on first load
+    Encrypted { path: PathBuf, key_file: PathBuf },
+}
+
+fn default_max_concurrent_downloads() -> usize {
+    3
+}
+
 #[derive(Parser, Debug, Clone, PartialEq, Eq)]
 pub struct XcConfigArg {
     /// Network interfaces should "xc" consider external
@@ -119,6 +136,18 @@ pub struct XcConfigArg {
     #[arg(long = "warn-only", action)]
     pub warn_only: Option<bool>,
 
+    /// maximum number of layers to download at the same time when pulling images
+    #[arg(long = "max-concurrent-downloads")]
+    pub max_concurrent_downloads: Option<usize>,
+
+    /// file with the signature policy of pulled images
+    #[arg(long = "signature-policy")]
+    pub signature_policy: Option<PathBuf>,
+
+    /// PEM encoded PKCS#8 private key to sign pushed images with
+    #[arg(long = "signing-key")]
+    pub signing_key: Option<PathBuf>,
+
     #[arg(default_value = "/usr/local/etc/xc.conf")]
     pub config_dir: PathBuf,
 }
@@ -168,6 +197,34 @@ pub struct XcConfig {
 
     #[serde(default)]
     pub warn_only: bool,
+
+    /// Providers to look up registries from, in order, for example
+    ///
+    /// ```yaml
+    /// registry_providers:
+    ///   - json: /var/db/xc.registries.json
+    ///   - encrypted:
+    ///       path: /var/db/xc.registries.json
+    ///       key_file: /var/db/xc.registries.key
+    ///   - docker: /root/.docker/config.json
+    /// ```
+    ///
+    /// If empty, only the registries at `registries` are used
+    #[serde(default)]
+    pub registry_providers: Vec<RegistryProviderConfig>,
+
+    /// Maximum number of layers to download at the same time when pulling images
+    #[serde(default = "default_max_concurrent_downloads")]
+    pub max_concurrent_downloads: usize,
+
+    /// Signature policy of pulled images, deciding which repositories require images to be
+    /// signed and by which keys. See `crate::image::verify` for the format. If not set, images
+    /// are pulled without verification
+    pub signature_policy: Option<PathBuf>,
+
+    /// PEM encoded PKCS#8 ed25519 or ECDSA P-256 private key used to sign images pushed with
+    /// `xc push --sign`, for example generated by `openssl genpkey -algorithm ed25519`
+    pub signing_key: Option<PathBuf>,
 }
 
 impl XcConfig {
@@ -246,6 +303,7 @@ impl XcConfig {
             registries,
             inventory,
             warn_only,
+            max_concurrent_downloads,
         );
         if let Some(force_devfs_ruleset) = arg.force_devfs_ruleset {
             self.force_devfs_ruleset = Some(force_devfs_ruleset);
@@ -254,5 +312,11 @@ impl XcConfig {
         } else if let Some(default_volume_directory) = arg.default_volume_directory {
             self.default_volume_directory = Some(default_volume_directory);
         }
+        if let Some(signature_policy) = arg.signature_policy {
+            self.signature_policy = Some(signature_policy);
+        }
+        if let Some(signing_key) = arg.signing_key {
+            self.signing_key = Some(signing_key);
+        }
     }
 }
@@ -31,7 +31,7 @@ use crate::image::ImageManager;
 use crate::instantiate::{CheckedInstantiateRequest, InstantiateBlueprint};
 use crate::ipc::InstantiateRequest;
 use crate::port::PortForwardTable;
-use crate::registry::JsonRegistryProvider;
+use crate::registry::registries_provider_from_config;
 use crate::resources::volume::{Volume, VolumeDriverKind};
 use crate::resources::Resources;
 use crate::site::Site;
@@ -104,14 +104,16 @@ impl ServerContext {
             .expect("cannot create tables");
 
         let is = Arc::new(Mutex::new(image_store));
-        let provider = JsonRegistryProvider::from_path(&config.registries).unwrap();
+        let provider = registries_provider_from_config(&config).unwrap();
         let resources = Arc::new(RwLock::new(Resources::new(db, &config)));
 
         let image_manager = ImageManager::new(
             is,
             &config.image_dataset,
             &config.layers_dir,
-            Arc::new(Mutex::new(Box::new(provider))),
+            Arc::new(Mutex::new(provider)),
+            config.max_concurrent_downloads,
+            config.signature_policy.clone(),
         );
 
         ServerContext {
@@ -691,18 +693,89 @@ impl ServerContext {
         reference: ImageReference,
         remote_reference: ImageReference,
         insecure: bool,
+        layer_compression: Option<String>,
+        sign: bool,
+        attachments: Vec<crate::image::push::Attachment>,
     ) -> Result<(), crate::image::push::PushImageError> {
+        let signing_key = if sign {
+            Some(
+                self.config
+                    .signing_key
+                    .clone()
+                    .ok_or(crate::image::push::PushImageError::NoSigningKey)?,
+            )
+        } else {
+            None
+        };
         _ = crate::image::push::push_image(
             self.image_manager.clone(),
             &self.config.layers_dir,
             reference,
             remote_reference,
             insecure,
+            layer_compression,
+            signing_key,
+            attachments,
         )
         .await?;
         Ok(())
     }
 
+    pub(crate) async fn list_remote_tags(
+        &self,
+        hostname: Option<String>,
+        name: String,
+        insecure: bool,
+    ) -> Result<Vec<String>, crate::image::remote::RemoteImageError> {
+        crate::image::remote::list_tags(self.image_manager.clone(), hostname, name, insecure).await
+    }
+
+    pub(crate) async fn inspect_remote_image(
+        &self,
+        reference: ImageReference,
+        insecure: bool,
+    ) -> Result<crate::image::remote::RemoteImage, crate::image::remote::RemoteImageError> {
+        crate::image::remote::inspect(self.image_manager.clone(), reference, insecure).await
+    }
+
+    pub(crate) async fn delete_remote_image(
+        &self,
+        reference: ImageReference,
+        insecure: bool,
+        all_tags: bool,
+    ) -> Result<crate::image::remote::DeletedManifest, crate::image::remote::RemoteImageError> {
+        crate::image::remote::delete(self.image_manager.clone(), reference, insecure, all_tags)
+            .await
+    }
+
+    pub(crate) async fn save_image(
+        &self,
+        references: Vec<ImageReference>,
+        output: std::fs::File,
+    ) -> Result<Vec<oci_util::models::Descriptor>, crate::image::layout::ImageLayoutError> {
+        crate::image::layout::save_image(
+            self.image_manager.clone(),
+            &self.config.layers_dir,
+            references,
+            output,
+        )
+        .await
+    }
+
+    pub(crate) async fn load_image(
+        &self,
+        input: std::fs::File,
+        reference: Option<ImageReference>,
+    ) -> Result<Vec<ImageReference>, crate::image::layout::ImageLayoutError> {
+        crate::image::layout::load_image(
+            self.image_manager.clone(),
+            &self.config.layers_dir,
+            input,
+            reference,
+        )
+        .await
+    }
+
     pub(crate) async fn pull_image(
         &mut self,
         reference: ImageReference,
@@ -0,0 +1,250 @@
+// Copyright (c) 2023 Yan Ka, Chiu.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions
+// are met:
+// 1. Redistributions of source code must retain the above copyright
+//    notice, this list of conditions, and the following disclaimer,
+//    without modification, immediately at the beginning of the file.
+// 2. The name of the author may not be used to endorse or promote products
+//    derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE AUTHOR AND CONTRIBUTORS ``AS IS'' AND
+// ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE AUTHOR OR CONTRIBUTORS BE LIABLE FOR
+// ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+// OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+// HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+// LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
+// OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
+// SUCH DAMAGE.
+
+//! Import of the archives created by `docker save`, which have a `manifest.json` listing the
+//! config, the layer tarballs and the tags of each image
+
+use super::layer_media_type;
+use super::layout::{ImageLayoutError, LoadImage};
+use super::DiffMap;
+
+use oci_util::digest::{sha256_once, OciDigest};
+use oci_util::image_reference::ImageReference;
+use oci_util::models::Descriptor;
+use ocitar::compression::CompressionType;
+use ocitar::layer::verify_layer;
+use ocitar::util::{hex, parse_sha256_digest};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+use tracing::warn;
+use xc::models::jail_image::JailConfig;
+use xc::util::{gen_id, CompressionFormat, CompressionFormatExt};
+
+const DOCKER_ARCHIVE_MANIFEST: &str = "manifest.json";
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct ArchiveManifest {
+    config: String,
+    #[serde(default)]
+    repo_tags: Option<Vec<String>>,
+    layers: Vec<String>,
+}
+
+pub(super) fn is_docker_archive(root: &Path) -> bool {
+    root.join(DOCKER_ARCHIVE_MANIFEST).is_file()
+}
+
+/// Resolve `path` referenced by the archive manifest to a regular file under `root`, refusing
+/// anything that escapes `root`, as the archive may come from untrusted sources
+fn archive_file(root: &Path, path: &str) -> Result<PathBuf, ImageLayoutError> {
+    let invalid = || ImageLayoutError::InvalidLayout(format!("invalid path in archive: {path}"));
+    let mut resolved = root.to_path_buf();
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(name) => resolved.push(name),
+            Component::CurDir => continue,
+            _ => return Err(invalid()),
+        }
+        if std::fs::symlink_metadata(&resolved)?
+            .file_type()
+            .is_symlink()
+        {
+            return Err(invalid());
+        }
+    }
+    if !std::fs::symlink_metadata(&resolved)?.is_file() {
+        return Err(invalid());
+    }
+    Ok(resolved)
+}
+
+/// Resolve the images in the docker archive at `root`, and copy their layers to `layers_dir`
+/// after verifying them. The layers are moved instead if `consume` is set.
+pub(super) fn read_docker_archive(
+    root: &Path,
+    layers_dir: &Path,
+    consume: bool,
+) -> Result<Vec<LoadImage>, ImageLayoutError> {
+    let invalid = |error: serde_json::Error| ImageLayoutError::InvalidLayout(error.to_string());
+    let manifests: Vec<ArchiveManifest> = serde_json::from_slice(&std::fs::read(archive_file(
+        root,
+        DOCKER_ARCHIVE_MANIFEST,
+    )?)?)
+    .map_err(invalid)?;
+
+    // images in the same archive often share layers
+    let mut imported: HashMap<String, Descriptor> = HashMap::new();
+    let mut images = Vec::new();
+
+    for manifest in manifests.into_iter() {
+        let config = std::fs::read(archive_file(root, &manifest.config)?)?;
+        let id = sha256_once(&config);
+        let config: serde_json::Value = serde_json::from_slice(&config).map_err(invalid)?;
+        let jail_image = JailConfig::from_json(config).ok_or_else(|| {
+            ImageLayoutError::InvalidLayout(format!("cannot convert config of image {id}"))
+        })?;
+
+        let diff_ids = jail_image.layers();
+        if diff_ids.len() != manifest.layers.len() {
+            return Err(ImageLayoutError::InvalidLayout(format!(
+                "image {id} has {} layers but its config has {} diff_ids",
+                manifest.layers.len(),
+                diff_ids.len()
+            )));
+        }
+
+        let mut diff_maps = Vec::new();
+        for (layer, diff_id) in manifest.layers.iter().zip(diff_ids.into_iter()) {
+            let descriptor = match imported.get(layer) {
+                Some(descriptor) => descriptor.clone(),
+                None => {
+                    let source = archive_file(root, layer)?;
+                    let descriptor = import_layer(&source, layers_dir, &diff_id, consume)?;
+                    imported.insert(layer.to_string(), descriptor.clone());
+                    descriptor
+                }
+            };
+            diff_maps.push(DiffMap {
+                diff_id,
+                descriptor,
+            });
+        }
+
+        let mut names = Vec::new();
+        for tag in manifest.repo_tags.unwrap_or_default() {
+            match tag.parse::<ImageReference>() {
+                Ok(reference) => names.push(reference),
+                Err(_) => warn!("ignoring invalid tag {tag} of image {id}"),
+            }
+        }
+
+        images.push(LoadImage {
+            id,
+            names,
+            jail_image,
+            diff_maps,
+        });
+    }
+
+    Ok(images)
+}
+
+/// Copy the layer tarball at `source` to `layers_dir` after verifying its diff_id, the tarball
+/// is named by its digest as it is not recorded in the archive
+fn import_layer(
+    source: &Path,
+    layers_dir: &Path,
+    diff_id: &OciDigest,
+    consume: bool,
+) -> Result<Descriptor, ImageLayoutError> {
+    let in_progress = layers_dir.join(format!("{}.progress", gen_id()));
+    if consume {
+        std::fs::rename(source, &in_progress)?;
+    } else {
+        std::fs::copy(source, &in_progress)?;
+    }
+
+    let verified = std::fs::File::open(&in_progress).and_then(|archive| {
+        let format = archive.compression_format()?;
+        let summary = verify_layer(
+            archive,
+            CompressionType::Auto,
+            parse_sha256_digest(diff_id.as_str()),
+            None,
+        )?;
+        Ok((format, summary))
+    });
+
+    let (format, summary) = match verified {
+        Ok(verified) => verified,
+        Err(error) => {
+            _ = std::fs::remove_file(&in_progress);
+            return Err(ImageLayoutError::InvalidLayout(format!(
+                "layer {diff_id} failed verification: {error}"
+            )));
+        }
+    };
+
+    let algorithm = match format {
+        CompressionFormat::Gzip => "gzip",
+        CompressionFormat::Zstd => "zstd",
+        CompressionFormat::Other => "plain",
+    };
+    let digest = OciDigest::new_unchecked(&format!("sha256:{}", hex(summary.archive_digest)));
+    let size = std::fs::metadata(&in_progress)?.len() as usize;
+
+    let target = layers_dir.join(digest.as_str());
+    if target.exists() {
+        _ = std::fs::remove_file(&in_progress);
+    } else {
+        std::fs::rename(&in_progress, &target)?;
+    }
+
+    Ok(Descriptor {
+        media_type: layer_media_type(algorithm).unwrap().to_string(),
+        size,
+        digest,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_archive_manifest_and_paths() {
+        let root = std::env::temp_dir().join(format!("xcd-docker-archive-{}", std::process::id()));
+        _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("abc")).unwrap();
+        std::fs::write(root.join("abc/layer.tar"), b"").unwrap();
+        std::os::unix::fs::symlink("/etc", root.join("etc")).unwrap();
+        std::fs::write(
+            root.join(DOCKER_ARCHIVE_MANIFEST),
+            r#"[{"Config":"0123.json","RepoTags":["busybox:latest"],"Layers":["abc/layer.tar"]}]"#,
+        )
+        .unwrap();
+
+        assert!(is_docker_archive(&root));
+        let manifests: Vec<ArchiveManifest> =
+            serde_json::from_slice(&std::fs::read(root.join(DOCKER_ARCHIVE_MANIFEST)).unwrap())
+                .unwrap();
+        assert_eq!(manifests[0].config, "0123.json");
+        assert_eq!(
+            manifests[0].repo_tags,
+            Some(vec!["busybox:latest".to_string()])
+        );
+
+        assert_eq!(
+            archive_file(&root, "./abc/layer.tar").unwrap(),
+            root.join("abc/layer.tar")
+        );
+        for path in ["", "abc", "../abc/layer.tar", "/etc/passwd", "etc/passwd"] {
+            assert!(archive_file(&root, path).is_err(), "{path}");
+        }
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,520 @@
+// Copyright (c) 2023 Yan Ka, Chiu.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions
+// are met:
+// 1. Redistributions of source code must retain the above copyright
+//    notice, this list of conditions, and the following disclaimer,
+//    without modification, immediately at the beginning of the file.
+// 2. The name of the author may not be used to endorse or promote products
+//    derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE AUTHOR AND CONTRIBUTORS ``AS IS'' AND
+// ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE AUTHOR OR CONTRIBUTORS BE LIABLE FOR
+// ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+// OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+// HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+// LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
+// OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
+// SUCH DAMAGE.
+
+//! Save images to and load images from OCI image layouts, such that images can be moved between
+//! hosts without a registry. The daemon never opens the paths given by clients, layouts are
+//! exchanged as tar streams over file descriptors opened by the client instead. Archives created
+//! by `docker save` can be loaded as well, see `docker_archive`.
+
+use super::docker_archive::{is_docker_archive, read_docker_archive};
+use super::{layer_format, layer_media_type, wait_for_task, DiffMap, ImageManager};
+
+use oci_util::digest::OciDigest;
+use oci_util::image_reference::{ImageReference, ImageTag};
+use oci_util::layout::{
+    ImageIndex, ImageLayout, IndexEntry, ANNOTATION_IMAGE_NAME, ANNOTATION_REF_NAME,
+};
+use oci_util::models::{
+    Descriptor, ImageManifest, Platform, DOCKER_MANIFEST, DOCKER_MANIFESTS, OCI_IMAGE_INDEX,
+    OCI_MANIFEST,
+};
+use ocitar::compression::CompressionType;
+use ocitar::layer::verify_layer;
+use ocitar::util::parse_sha256_digest;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+use xc::image_store::{DiffIdMap, ImageStoreError};
+use xc::models::jail_image::{JailConfig, JailImage};
+use xc::util::{gen_id, get_current_arch};
+
+#[derive(Error, Debug, Serialize, Deserialize)]
+pub enum ImageLayoutError {
+    #[error("no such local reference: {0}")]
+    NoSuchLocalReference(String),
+    #[error("no archive of layer {0} available")]
+    LayerNotFound(OciDigest),
+    #[error("no image for this platform in the layout")]
+    NoImage,
+    #[error("cannot determine the reference to load image {0} as")]
+    NoReference(OciDigest),
+    #[error("invalid image layout: {0}")]
+    InvalidLayout(String),
+    #[error("io error: {0}")]
+    Io(String),
+    #[error("image store error: {0}")]
+    ImageStore(String),
+    #[error("failed to stage root: {0}")]
+    StageRoot(String),
+}
+
+impl From<std::io::Error> for ImageLayoutError {
+    fn from(error: std::io::Error) -> ImageLayoutError {
+        ImageLayoutError::Io(error.to_string())
+    }
+}
+
+impl From<ImageStoreError> for ImageLayoutError {
+    fn from(error: ImageStoreError) -> ImageLayoutError {
+        ImageLayoutError::ImageStore(error.to_string())
+    }
+}
+
+/// Run tar(1) with `stdin` and `stdout` connected to the given files, such as the streams
+/// received from the client
+fn run_tar(args: &[&OsStr], stdin: Stdio, stdout: Stdio) -> Result<(), ImageLayoutError> {
+    let output = Command::new("tar")
+        .args(args)
+        .stdin(stdin)
+        .stdout(stdout)
+        .output()?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(ImageLayoutError::Io(format!(
+            "tar exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )))
+    }
+}
+
+/// An image to write to the layout
+struct SaveImage {
+    reference: ImageReference,
+    jail_image: JailImage,
+    layers: Vec<DiffIdMap>,
+}
+
+/// Save the images `references` as an OCI image layout, using the layer archives in
+/// `layers_dir`, and write the layout as a tar archive to `output`
+pub async fn save_image(
+    this: Arc<RwLock<ImageManager>>,
+    layers_dir: impl AsRef<Path>,
+    references: Vec<ImageReference>,
+    output: File,
+) -> Result<Vec<Descriptor>, ImageLayoutError> {
+    let layers_dir = layers_dir.as_ref().to_path_buf();
+    let mut images = Vec::new();
+    {
+        let this = this.read().await;
+        for reference in references {
+            let record = this
+                .query_manifest(&reference)
+                .await
+                .map_err(|_| ImageLayoutError::NoSuchLocalReference(reference.to_string()))?;
+            let mut layers = Vec::new();
+            for diff_id in record.manifest.layers().iter() {
+                let archive = this
+                    .query_archives(diff_id)
+                    .await?
+                    .into_iter()
+                    .find(|map| {
+                        layer_media_type(&map.algorithm).is_some()
+                            && layers_dir.join(map.archive_digest.as_str()).exists()
+                    })
+                    .ok_or_else(|| ImageLayoutError::LayerNotFound(diff_id.clone()))?;
+                layers.push(archive);
+            }
+            images.push(SaveImage {
+                reference,
+                jail_image: record.manifest,
+                layers,
+            });
+        }
+    }
+
+    tokio::task::spawn_blocking(move || {
+        let staging = layers_dir.join(gen_id());
+        let result = write_layout(&staging, &layers_dir, &images).and_then(|descriptors| {
+            run_tar(
+                &[
+                    OsStr::new("-cf"),
+                    OsStr::new("-"),
+                    OsStr::new("-C"),
+                    staging.as_os_str(),
+                    OsStr::new("."),
+                ],
+                Stdio::null(),
+                Stdio::from(output),
+            )?;
+            Ok(descriptors)
+        });
+        _ = std::fs::remove_dir_all(&staging);
+        result
+    })
+    .await
+    .map_err(|error| ImageLayoutError::Io(error.to_string()))?
+}
+
+fn write_layout(
+    root: &Path,
+    layers_dir: &Path,
+    images: &[SaveImage],
+) -> Result<Vec<Descriptor>, ImageLayoutError> {
+    let layout = ImageLayout::create(root)?;
+    let mut descriptors = Vec::new();
+
+    for image in images.iter() {
+        let mut layers = Vec::new();
+        for map in image.layers.iter() {
+            let source = layers_dir.join(map.archive_digest.as_str());
+            let size = std::fs::metadata(&source)?.len() as usize;
+            layout.add_blob_file(&map.archive_digest, &source)?;
+            layers.push(Descriptor {
+                media_type: layer_media_type(&map.algorithm).unwrap().to_string(),
+                size,
+                digest: map.archive_digest.clone(),
+            });
+        }
+
+        let config = serde_json::to_vec(&image.jail_image)
+            .map_err(|error| ImageLayoutError::Io(error.to_string()))?;
+        let config = Descriptor {
+            media_type: "application/vnd.oci.image.config.v1+json".to_string(),
+            size: config.len(),
+            digest: layout.add_blob(&config)?,
+        };
+
+        let manifest = ImageManifest {
+            schema_version: 2,
+            media_type: OCI_MANIFEST.to_string(),
+            config,
+            layers,
+        };
+        let manifest = serde_json::to_vec(&manifest)
+            .map_err(|error| ImageLayoutError::Io(error.to_string()))?;
+        let descriptor = Descriptor {
+            media_type: OCI_MANIFEST.to_string(),
+            size: manifest.len(),
+            digest: layout.add_blob(&manifest)?,
+        };
+
+        let mut annotations = HashMap::new();
+        annotations.insert(
+            ANNOTATION_IMAGE_NAME.to_string(),
+            image.reference.to_string(),
+        );
+        if let ImageTag::Tag(tag) = &image.reference.tag {
+            annotations.insert(ANNOTATION_REF_NAME.to_string(), tag.to_string());
+        }
+        layout.add_index_entry(IndexEntry {
+            media_type: descriptor.media_type.clone(),
+            size: descriptor.size,
+            digest: descriptor.digest.clone(),
+            platform: Some(Platform {
+                os: image.jail_image.os().to_string(),
+                architecture: image.jail_image.architecture().to_string(),
+                os_version: None,
+                os_features: Vec::new(),
+                variant: None,
+                features: Vec::new(),
+            }),
+            artifact_type: None,
+            annotations,
+        })?;
+        info!("saved {} as {}", image.reference, descriptor.digest);
+        descriptors.push(descriptor);
+    }
+
+    Ok(descriptors)
+}
+
+/// An image read from the layout, with its layers copied to `layers_dir`
+pub(super) struct LoadImage {
+    /// The manifest digest of the image, or the config digest for docker archives
+    pub(super) id: OciDigest,
+    pub(super) names: Vec<ImageReference>,
+    pub(super) jail_image: JailImage,
+    pub(super) diff_maps: Vec<DiffMap>,
+}
+
+fn read_images(
+    root: &Path,
+    layers_dir: &Path,
+    consume: bool,
+) -> Result<Vec<LoadImage>, ImageLayoutError> {
+    if is_docker_archive(root) {
+        read_docker_archive(root, layers_dir, consume)
+    } else {
+        read_layout(root, layers_dir, consume)
+    }
+}
+
+/// Load the images in the OCI image layout or docker archive read as a tar archive from `input`
+/// into the image store. The images are tagged with the names recorded in the layout, or as
+/// `reference` if given, which requires the layout to contain exactly one image for this
+/// platform.
+pub async fn load_image(
+    this: Arc<RwLock<ImageManager>>,
+    layers_dir: impl AsRef<Path>,
+    input: File,
+    reference: Option<ImageReference>,
+) -> Result<Vec<ImageReference>, ImageLayoutError> {
+    let layers_dir = layers_dir.as_ref().to_path_buf();
+
+    let images = tokio::task::spawn_blocking(move || {
+        // extract next to the layers such that the blobs can be moved in place
+        let staging = layers_dir.join(gen_id());
+        std::fs::create_dir_all(&staging)?;
+        let result = run_tar(
+            &[
+                OsStr::new("-xf"),
+                OsStr::new("-"),
+                OsStr::new("-C"),
+                staging.as_os_str(),
+            ],
+            Stdio::from(input),
+            Stdio::piped(),
+        )
+        .and_then(|_| read_images(&staging, &layers_dir, true));
+        _ = std::fs::remove_dir_all(&staging);
+        result
+    })
+    .await
+    .map_err(|error| ImageLayoutError::Io(error.to_string()))??;
+
+    if images.is_empty() {
+        return Err(ImageLayoutError::NoImage);
+    }
+
+    if reference.is_some() && images.len() > 1 {
+        return Err(ImageLayoutError::InvalidLayout(format!(
+            "layout contains {} images, cannot load them as a single reference",
+            images.len()
+        )));
+    }
+
+    let mut loaded = Vec::new();
+
+    for image in images.into_iter() {
+        let names = match &reference {
+            Some(reference) => vec![reference.clone()],
+            None if image.names.is_empty() => return Err(ImageLayoutError::NoReference(image.id)),
+            None => image.names,
+        };
+
+        for map in image.diff_maps.iter() {
+            this.read()
+                .await
+                .map_diff_id(
+                    &map.diff_id,
+                    &map.descriptor.digest,
+                    layer_format(&map.descriptor.media_type),
+                    None,
+                )
+                .await?;
+        }
+
+        if let Some(chain_id) = image.jail_image.chain_id() {
+            let stage_root = this
+                .write()
+                .await
+                .stage_root(None, &chain_id, &image.diff_maps);
+            wait_for_task(stage_root)
+                .await
+                .map_err(ImageLayoutError::StageRoot)?;
+        }
+
+        let this = this.read().await;
+        for name in names.into_iter() {
+            this.register_and_tag_manifest(&name, &image.jail_image)
+                .await?;
+            info!("loaded {name} from {}", image.id);
+            loaded.push(name);
+        }
+    }
+
+    Ok(loaded)
+}
+
+/// Resolve the images in the layout at `root` for this platform, and copy their layers to
+/// `layers_dir` after verifying them. The blobs are moved instead if `consume` is set.
+fn read_layout(
+    root: &Path,
+    layers_dir: &Path,
+    consume: bool,
+) -> Result<Vec<LoadImage>, ImageLayoutError> {
+    let invalid = |error: std::io::Error| ImageLayoutError::InvalidLayout(error.to_string());
+    let layout = ImageLayout::open(root).map_err(invalid)?;
+    let index = layout.index().map_err(invalid)?;
+    let mut images = Vec::new();
+
+    for entry in index.manifests.iter() {
+        let Some(manifest_digest) = resolve_manifest(&layout, entry)? else {
+            warn!(
+                "skipping {} as there is no image for this platform",
+                entry.digest
+            );
+            continue;
+        };
+        let names = entry
+            .image_name()
+            .and_then(|name| name.parse::<ImageReference>().ok())
+            .into_iter()
+            .collect::<Vec<_>>();
+
+        let manifest: ImageManifest =
+            serde_json::from_slice(&layout.read_blob(&manifest_digest).map_err(invalid)?)
+                .map_err(|error| ImageLayoutError::InvalidLayout(error.to_string()))?;
+        let config: serde_json::Value =
+            serde_json::from_slice(&layout.read_blob(&manifest.config.digest).map_err(invalid)?)
+                .map_err(|error| ImageLayoutError::InvalidLayout(error.to_string()))?;
+        let jail_image = JailConfig::from_json(config).ok_or_else(|| {
+            ImageLayoutError::InvalidLayout(format!(
+                "cannot convert config of manifest {manifest_digest}"
+            ))
+        })?;
+
+        let diff_ids = jail_image.layers();
+        if diff_ids.len() != manifest.layers.len() {
+            return Err(ImageLayoutError::InvalidLayout(format!(
+                "manifest {manifest_digest} has {} layers but its config has {} diff_ids",
+                manifest.layers.len(),
+                diff_ids.len()
+            )));
+        }
+
+        let mut diff_maps = Vec::new();
+        for (descriptor, diff_id) in manifest.layers.iter().zip(diff_ids.into_iter()) {
+            import_layer(&layout, layers_dir, descriptor, &diff_id, consume)?;
+            diff_maps.push(DiffMap {
+                diff_id,
+                descriptor: descriptor.clone(),
+            });
+        }
+
+        images.push(LoadImage {
+            id: manifest_digest,
+            names,
+            jail_image,
+            diff_maps,
+        });
+    }
+
+    Ok(images)
+}
+
+/// The digest of the image manifest of `entry` for this platform, picking from the image index
+/// if `entry` is one
+fn resolve_manifest(
+    layout: &ImageLayout,
+    entry: &IndexEntry,
+) -> Result<Option<OciDigest>, ImageLayoutError> {
+    let media_type = entry.media_type.as_str();
+    if media_type == OCI_MANIFEST || media_type == DOCKER_MANIFEST {
+        return Ok(Some(entry.digest.clone()));
+    }
+    if media_type != OCI_IMAGE_INDEX && media_type != DOCKER_MANIFESTS {
+        return Ok(None);
+    }
+    let content = layout
+        .read_blob(&entry.digest)
+        .map_err(|error| ImageLayoutError::InvalidLayout(error.to_string()))?;
+    let index: ImageIndex = serde_json::from_slice(&content)
+        .map_err(|error| ImageLayoutError::InvalidLayout(error.to_string()))?;
+    Ok(index
+        .manifests
+        .iter()
+        .find(|desc| {
+            desc.platform
+                .as_ref()
+                .is_some_and(|platform| platform.architecture == get_current_arch())
+        })
+        .map(|desc| desc.digest.clone()))
+}
+
+/// Copy the layer `descriptor` to `layers_dir` unless it is there already, verifying both the
+/// digest of the archive and its diff_id
+fn import_layer(
+    layout: &ImageLayout,
+    layers_dir: &Path,
+    descriptor: &Descriptor,
+    diff_id: &OciDigest,
+    consume: bool,
+) -> Result<(), ImageLayoutError> {
+    let expected_diff_id = parse_sha256_digest(diff_id.as_str());
+    let verify = |path: &Path, expected_digest: Option<[u8; 32]>| {
+        std::fs::File::open(path)
+            .and_then(|archive| {
+                verify_layer(
+                    archive,
+                    CompressionType::Auto,
+                    expected_diff_id,
+                    expected_digest,
+                )
+            })
+            .map_err(|error| {
+                ImageLayoutError::InvalidLayout(format!(
+                    "layer {} failed verification: {error}",
+                    descriptor.digest
+                ))
+            })
+    };
+
+    // the archive is known, but the layout may still claim a wrong diff_id for it
+    let target = layers_dir.join(descriptor.digest.as_str());
+    if target.exists() {
+        verify(&target, None)?;
+        return Ok(());
+    }
+
+    let source = layout.blob_path(&descriptor.digest)?;
+    if !std::fs::symlink_metadata(&source)?.is_file() {
+        return Err(ImageLayoutError::InvalidLayout(format!(
+            "blob {} is not a regular file",
+            descriptor.digest
+        )));
+    }
+    let expected_digest = parse_sha256_digest(descriptor.digest.as_str());
+    if expected_digest.is_none() {
+        layout
+            .verify_blob(&descriptor.digest)
+            .map_err(|error| ImageLayoutError::InvalidLayout(error.to_string()))?;
+    }
+
+    let in_progress = layers_dir.join(format!("{}.progress", descriptor.digest));
+    _ = std::fs::remove_file(&in_progress);
+    // never link to a layout that may be modified by others after the verification
+    if consume {
+        std::fs::rename(&source, &in_progress)?;
+    } else {
+        std::fs::copy(&source, &in_progress)?;
+    }
+
+    if let Err(error) = verify(&in_progress, expected_digest) {
+        _ = std::fs::remove_file(&in_progress);
+        return Err(error);
+    }
+
+    std::fs::rename(&in_progress, &target)?;
+    Ok(())
+}
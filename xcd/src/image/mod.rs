@@ -21,8 +21,12 @@
 // LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
 // OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
 // SUCH DAMAGE.
+pub mod docker_archive;
+pub mod layout;
 pub mod pull;
 pub mod push;
+pub mod remote;
+pub mod verify;
 
 use self::pull::*;
 use self::push::*;
@@ -30,20 +34,21 @@ use crate::registry::*;
 use crate::task::*;
 
 use freebsd::fs::zfs::{ZfsError, ZfsHandle};
-use oci_util::digest::{DigestAlgorithm, Hasher, OciDigest};
+use oci_util::digest::{DigestAlgorithm, OciDigest};
 use oci_util::distribution::client::*;
+use oci_util::distribution::token::TokenCache;
 use oci_util::image_reference::ImageReference;
 use oci_util::layer::ChainId;
 use oci_util::models::Descriptor;
-use std::io::Write;
+use ocitar::compression::CompressionType;
+use ocitar::layer::{extract_layer_confined, verify_layer};
+use ocitar::util::parse_sha256_digest;
 use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
-use std::str::FromStr;
 use std::sync::Arc;
 use thiserror::Error;
 use tokio::sync::watch::Receiver;
-use tokio::sync::Mutex;
-use tracing::{debug, info};
+use tokio::sync::{Mutex, Semaphore};
+use tracing::{debug, info, warn};
 use xc::image_store::sqlite::SqliteImageStore;
 use xc::image_store::{DiffIdMap, ImageRecord, ImageStore, ImageStoreError};
 use xc::models::jail_image::JailImage;
@@ -62,6 +67,12 @@ struct SharedContext {
     registries: Arc<tokio::sync::Mutex<Box<dyn RegistriesProvider + Sync + Send>>>,
     image_dataset: PathBuf,
     layers_dir: PathBuf,
+    /// Bounds the number of layers downloading at the same time
+    download_slots: Arc<Semaphore>,
+    /// Bearer tokens shared by all pulls and pushes
+    token_cache: TokenCache,
+    /// Signature policy of pulled images
+    signature_policy: Option<PathBuf>,
 }
 
 impl SharedContext {
@@ -70,14 +81,32 @@ impl SharedContext {
         image_dataset: impl AsRef<Path>,
         layers_dir: impl AsRef<Path>,
         registries: Arc<Mutex<Box<dyn RegistriesProvider + Send + Sync>>>,
+        max_concurrent_downloads: usize,
+        signature_policy: Option<PathBuf>,
     ) -> SharedContext {
         SharedContext {
             image_store,
             image_dataset: image_dataset.as_ref().to_path_buf(),
             layers_dir: layers_dir.as_ref().to_path_buf(),
             registries,
+            download_slots: Arc::new(Semaphore::new(max_concurrent_downloads.max(1))),
+            token_cache: TokenCache::new(),
+            signature_policy,
         }
     }
+
+    /// Look up the registries on a blocking thread. Providers may block, for example reading
+    /// files or waiting for credential helpers, which must not stall the runtime
+    async fn with_registries<T, F>(&self, f: F) -> T
+    where
+        T: Send + 'static,
+        F: FnOnce(&dyn RegistriesProvider) -> T + Send + 'static,
+    {
+        let registries = self.registries.clone();
+        tokio::task::spawn_blocking(move || f(&**registries.blocking_lock()))
+            .await
+            .expect("registry lookup panicked")
+    }
 }
 
 pub struct ImageManager {
@@ -94,8 +123,17 @@ impl ImageManager {
         image_dataset: impl AsRef<Path>,
         layers_dir: impl AsRef<Path>,
         registries: Arc<Mutex<Box<dyn RegistriesProvider + Sync + Send>>>,
+        max_concurrent_downloads: usize,
+        signature_policy: Option<PathBuf>,
     ) -> ImageManager {
-        let shared_context = SharedContext::new(image_store, image_dataset, layers_dir, registries);
+        let shared_context = SharedContext::new(
+            image_store,
+            image_dataset,
+            layers_dir,
+            registries,
+            max_concurrent_downloads,
+            signature_policy,
+        );
         ImageManager {
             layers: NotificationStore::new(shared_context.clone()),
             rootfs: NotificationStore::new(shared_context.clone()),
@@ -233,29 +271,27 @@ impl ImageManager {
                 let state = if completed {
                     ImportImageState::Done
                 } else if status.config.is_some() {
+                    // report every layer being pulled, such that the progress of the layers
+                    // downloading in parallel can be followed until all of them are done
                     let mut dls = Vec::new();
+                    let mut downloading = false;
                     for desc in status.manifest.clone().unwrap().layers.iter() {
                         if let Some(c) = self.layers.get(&desc.digest) {
                             let t = c.borrow().is_completed();
                             let v = c.borrow().last_state.clone();
-                            if !t {
-                                dls.push(DownloadLayerStatus {
-                                    digest: desc.digest.clone(),
-                                    downloaded: v.written,
-                                    total: Some(v.total),
-                                })
-                            }
+                            downloading |= !t;
+                            dls.push(DownloadLayerStatus {
+                                digest: desc.digest.clone(),
+                                downloaded: if t { v.total } else { v.written },
+                                total: (t || v.started).then_some(v.total),
+                            })
                         }
                     }
-                    if dls.is_empty() {
-                        if completed {
-                            ImportImageState::Done
-                        } else {
-                            ImportImageState::ExtractLayers
-                        }
-                    } else {
+                    if downloading {
                         status.layers = Some(dls);
                         ImportImageState::DownloadLayers
+                    } else {
+                        ImportImageState::ExtractLayers
                     }
                 } else if status.manifest.is_some() {
                     ImportImageState::DownloadConfig
@@ -269,121 +305,158 @@ impl ImageManager {
         }
     }
 
+    /// Download the layer `descriptor` into `layers_dir` in the background. The number of layers
+    /// downloading at the same time is bounded by `max_concurrent_downloads`, layers waiting for
+    /// a slot are reported as not started yet.
     fn get_layer(
         &mut self,
         mut session: Session,
         descriptor: Descriptor,
+        diff_id: OciDigest,
     ) -> Receiver<Task<OciDigest, PullLayerStatus>> {
         let digest = descriptor.digest.clone();
         if let Some(rx) = self.layers.get(&descriptor.digest) {
-            rx
-        } else {
-            let (mut emitter, rx) = self.layers.register(&descriptor.digest);
-            let context = self.context.clone();
+            if !(*rx.borrow()).has_failed() {
+                return rx;
+            }
+        }
+
+        let (mut emitter, rx) = self.layers.register(&descriptor.digest);
+        let context = self.context.clone();
+
+        if !emitter.is_completed() {
+            _ = emitter.use_try(|state| {
+                state.total = descriptor.size;
+                Ok(())
+            });
+
+            tokio::spawn(async move {
+                let mut emitter = emitter;
+                let target_path = {
+                    let mut parent = context.layers_dir.clone();
+                    parent.push(format!("{digest}"));
+                    parent
+                };
+
+                // an interrupted download leaves its partial content here, which is resumed on
+                // the next attempt
+                let in_progress_path = {
+                    let mut parent = context.layers_dir.clone();
+                    parent.push(format!("{digest}.progress"));
+                    parent
+                };
+
+                let _permit = match context.download_slots.clone().acquire_owned().await {
+                    Ok(permit) => permit,
+                    Err(_) => {
+                        emitter.set_faulted("download slots closed");
+                        return;
+                    }
+                };
 
-            if !emitter.is_completed() {
                 _ = emitter.use_try(|state| {
-                    state.total = descriptor.size;
+                    state.started = true;
                     Ok(())
                 });
 
-                tokio::spawn(async move {
-                    let mut emitter = emitter;
-                    let target_path = {
-                        let mut parent = context.layers_dir.clone();
-                        parent.push(format!("{digest}"));
-                        parent
-                    };
-
-                    let in_progress_path = {
-                        let mut parent = context.layers_dir.clone();
-                        parent.push(format!("{digest}.progress"));
-                        parent
-                    };
-
-                    macro_rules! use_state {
-                        ($f:expr) => {{
-                            let res = emitter.use_try($f);
-                            if res.is_err() {
-                                return;
+                // the digest of the archive is verified by the session while streaming
+                let result = {
+                    let (tx, mut progress) = tokio::sync::watch::channel(DownloadStat::default());
+                    let download =
+                        session.download_blob_resumable(Some(tx), &digest, &in_progress_path);
+                    tokio::pin!(download);
+                    loop {
+                        tokio::select! {
+                            result = &mut download => break result,
+                            Ok(()) = progress.changed() => {
+                                let written = progress.borrow().downloaded.unwrap_or(0);
+                                _ = emitter.use_try(|state| {
+                                    state.written = written;
+                                    Ok(())
+                                });
                             }
-                            res.unwrap()
-                        }};
+                        }
                     }
+                };
 
-                    if let Ok(mut response) = session.fetch_blob(&digest).await {
-                        let in_progress_path_string =
-                            in_progress_path.to_string_lossy().to_string();
-                        let mut hasher = Hasher::new(digest.algorithm());
+                if let Err(err) = result {
+                    emitter.set_faulted(&format!("failed to download layer {digest}: {err}"));
+                    return;
+                }
 
-                        let (cat, format) = if descriptor.media_type.ends_with("gzip") {
-                            ("gzcat", "gzip")
-                        } else if descriptor.media_type.ends_with("zstd") {
-                            ("zstdcat", "zstd")
-                        } else {
-                            ("cat", "plain")
-                        };
-
-                        let shell_script =
-                            format!("tee {in_progress_path_string} | {cat} - | sha256 -q");
-
-                        let mut helper = Command::new("sh")
-                            .arg("-c")
-                            .arg(shell_script)
-                            .stdout(Stdio::piped())
-                            .stdin(Stdio::piped())
-                            .spawn()
-                            .expect("cannot spawn sh helper");
-
-                        let stdin = helper.stdin.as_mut().unwrap();
-
-                        while let Ok(Some(chunk)) = response.chunk().await {
-                            hasher.update(&chunk);
-                            stdin.write_all(&chunk).unwrap();
-                            //                            file.write_all(&chunk).unwrap();
-                            use_state!(|state| {
-                                state.written += chunk.len();
-                                Ok(())
-                            });
-                        }
+                _ = emitter.use_try(|state| {
+                    state.written = state.total;
+                    Ok(())
+                });
 
-                        let output = helper.wait_with_output().unwrap();
-
-                        let diff_id = {
-                            let string = std::str::from_utf8(&output.stdout).unwrap().trim();
-                            format!("sha256:{string}")
-                        };
-
-                        info!("get_layer: dffid={diff_id}");
-                        let digest = hasher.finalize();
-                        let diff_id = OciDigest::from_str(&diff_id).unwrap();
-
-                        context
-                            .image_store
-                            .lock()
-                            .await
-                            .map_diff_id(
-                                &diff_id,
-                                &digest,
-                                format,
-                                Some(session.repository().to_string()),
-                            )
-                            .unwrap();
-
-                        use_state!(|_| std::fs::rename(&in_progress_path, &target_path)
-                            .map_err(|_| "failed to mv file".to_string()));
-                        emitter.set_completed()
+                let expected_diff_id = parse_sha256_digest(diff_id.as_str());
+                let file_path = in_progress_path.clone();
+                let verified = tokio::task::spawn_blocking(move || {
+                    let archive = std::fs::File::open(file_path)?;
+                    verify_layer(archive, CompressionType::Auto, expected_diff_id, None)
+                })
+                .await;
+
+                match verified {
+                    Ok(Ok(_)) => info!("get_layer: digest={digest} diff_id={diff_id}"),
+                    Ok(Err(err)) => {
+                        _ = std::fs::remove_file(&in_progress_path);
+                        emitter.set_faulted(&format!("layer {digest} failed verification: {err}"));
+                        return;
                     }
-                });
-            }
+                    Err(err) => {
+                        emitter.set_faulted(&format!("cannot verify layer {digest}: {err}"));
+                        return;
+                    }
+                }
 
-            rx
+                // only record the layer once its archive is in place
+                if let Err(err) = std::fs::rename(&in_progress_path, &target_path) {
+                    emitter.set_faulted(&format!(
+                        "cannot move layer {digest} to {target_path:?}: {err}"
+                    ));
+                    return;
+                }
+
+                let mapped = context.image_store.lock().await.map_diff_id(
+                    &diff_id,
+                    &digest,
+                    layer_format(&descriptor.media_type),
+                    Some(session.repository().to_string()),
+                );
+
+                if let Err(err) = mapped {
+                    emitter.set_faulted(&format!("cannot record layer {digest}: {err:?}"));
+                    return;
+                }
+
+                emitter.set_completed()
+            });
+        }
+
+        rx
+    }
+
+    /// The task of a layer that is expected to be in `layers_dir` already, which faults if the
+    /// layer is not there
+    fn local_layer(&mut self, digest: &OciDigest) -> Receiver<Task<OciDigest, PullLayerStatus>> {
+        if let Some(rx) = self.layers.get(digest) {
+            if !(*rx.borrow()).has_failed() {
+                return rx;
+            }
+        }
+        let (mut emitter, rx) = self.layers.register(digest);
+        if !emitter.is_completed() {
+            emitter.set_faulted(&format!("layer {digest} is not available locally"));
         }
+        rx
     }
 
+    /// Layers available in `layers_dir` but not yet registered are not downloaded again, other
+    /// missing layers are downloaded with `session`, and fail to stage if there is no session.
     fn stage_root(
         &mut self,
-        session: &Session,
+        session: Option<&Session>,
         chain_id: &ChainId,
         diff_maps: &[DiffMap], //        descriptors: &[Descriptor],
     ) -> Receiver<Task<ChainId, StageRootStatus>> {
@@ -402,33 +475,28 @@ impl ImageManager {
                 }
 
                 let recipe = RootFsRecipe::resolve(&existing, diff_maps);
-
-                let mut layers = Vec::with_capacity(recipe.digests.len());
-
-                for digest in recipe.digests.iter() {
-                    let layer = self.get_layer(session.clone(), digest.clone());
-                    if !(*layer.borrow()).is_completed() {
-                        layers.push(layer);
-                    }
-                }
+                let diff_ids = &recipe.diff_ids[recipe.diff_ids.len() - recipe.digests.len()..];
+
+                // start downloading all the layers, the extraction takes them one by one in
+                // order as soon as each of them is available
+                let layers = recipe
+                    .digests
+                    .iter()
+                    .zip(diff_ids.iter())
+                    .map(|(descriptor, diff_id)| match session {
+                        Some(session) => {
+                            self.get_layer(session.clone(), descriptor.clone(), diff_id.clone())
+                        }
+                        None => self.local_layer(&descriptor.digest),
+                    })
+                    .collect::<Vec<_>>();
 
                 let context = self.context.clone();
 
                 tokio::spawn(async move {
                     let mut emitter = emitter;
-                    let n2 = layers
-                        .iter()
-                        .map(|r| r.borrow().notify.clone())
-                        .collect::<Vec<_>>();
-
-                    let notifies = n2.iter().map(|r| r.notified());
-                    futures::future::join_all(notifies).await;
-
-                    let faults = layers.iter().filter_map(|s| (*s.borrow()).fault());
-                    if let Some(reason) = faults.reduce(|a, b| format!("{a}\n{b}")) {
-                        emitter.set_faulted(&reason);
-                    } else if let Err(reason) = recipe
-                        .stage_layers_assume_existed(&context.image_dataset, &context.layers_dir)
+                    if let Err(reason) = recipe
+                        .stage_layers(&context.image_dataset, &context.layers_dir, layers)
                         .await
                     {
                         emitter.set_faulted(&format!("{reason:?}"));
@@ -442,6 +510,50 @@ impl ImageManager {
     }
 }
 
+/// Wait until a task, such as the download of a layer, is either completed or faulted
+async fn wait_for_task<K: std::hash::Hash + Eq + Clone, V>(
+    mut task: Receiver<Task<K, V>>,
+) -> Result<(), String> {
+    loop {
+        {
+            let task = task.borrow_and_update();
+            if task.is_completed() {
+                return Ok(());
+            } else if let Some(reason) = task.fault() {
+                return Err(reason);
+            }
+        }
+        if task.changed().await.is_err() {
+            return if task.borrow().is_completed() {
+                Ok(())
+            } else {
+                Err("task exited unexpectedly".to_string())
+            };
+        }
+    }
+}
+
+/// The compression algorithm of a layer as recorded in the image store, from its media type
+fn layer_format(media_type: &str) -> &'static str {
+    if media_type.ends_with("gzip") {
+        "gzip"
+    } else if media_type.ends_with("zstd") {
+        "zstd"
+    } else {
+        "plain"
+    }
+}
+
+/// The OCI media type of a layer compressed with `algorithm` ("plain", "gzip" or "zstd")
+fn layer_media_type(algorithm: &str) -> Option<&'static str> {
+    match algorithm {
+        "gzip" => Some("application/vnd.oci.image.layer.v1.tar+gzip"),
+        "zstd" => Some("application/vnd.oci.image.layer.v1.tar+zstd"),
+        "plain" => Some("application/vnd.oci.image.layer.v1.tar"),
+        _ => None,
+    }
+}
+
 /// The source dataset to be cloned from following by the extraction of layers to create the
 /// desired rootfs
 #[derive(Clone, Debug)]
@@ -528,14 +640,18 @@ impl RootFsRecipe {
         }
     }
 
+    /// Create the dataset of the chain and extract the layers in order, each layer is extracted
+    /// as soon as its download completed.
     ///
     /// # Arguments
     /// * `dataset`: The ZFS dataset for images
     /// * `layers_dir`: The directory that contains all the layer diff files
-    pub async fn stage_layers_assume_existed(
+    /// * `layers`: The download tasks of the layers, in the same order as `digests`
+    pub async fn stage_layers(
         &self,
         dataset: impl AsRef<Path>,
         layers_dir: impl AsRef<Path>,
+        layers: Vec<Receiver<Task<OciDigest, PullLayerStatus>>>,
     ) -> Result<(), StageLayerError> {
         let handle = ZfsHandle::default();
         let dataset = dataset.as_ref().to_path_buf();
@@ -547,71 +663,110 @@ impl RootFsRecipe {
             target
         };
 
-        if let Some(id) = &self.source {
-            debug!(id = id.as_str(), "cloning from ancestor dataset");
-            let mut source_dataset = dataset;
-            source_dataset.push(id.as_str());
-            if !handle.exists(&source_dataset) {
-                return Err(StageLayerError::SourceDatasetNotFound(id.clone()));
+        let source_dataset = match &self.source {
+            Some(id) => {
+                debug!(id = id.as_str(), "cloning from ancestor dataset");
+                let mut source_dataset = dataset;
+                source_dataset.push(id.as_str());
+                if !handle.exists(&source_dataset) {
+                    return Err(StageLayerError::SourceDatasetNotFound(id.clone()));
+                }
+                // TODO: rollback the dataset first
+                handle.snapshot2(&source_dataset, "xc2")?;
+                handle.clone2(&source_dataset, "xc2", &target_dataset)?;
+                handle.promote(&target_dataset)?;
+                Some(source_dataset)
+            }
+            None => {
+                debug!("creating new dataset as no ancestors found");
+                handle.create2(&target_dataset, false, false)?;
+                None
+            }
+        };
+
+        let result = self
+            .extract_layers(&handle, &target_dataset, layers_dir.as_ref(), layers)
+            .await;
+
+        if result.is_err() {
+            // the dataset of a chain is taken as staged once it exists, never leave a partial
+            // one behind
+            if let Some(source_dataset) = source_dataset {
+                if let Err(error) = handle.promote(&source_dataset) {
+                    warn!(
+                        dataset = source_dataset.to_str(),
+                        error = error.to_string(),
+                        "cannot promote ancestor dataset back"
+                    );
+                }
+            }
+            if let Err(error) = handle.destroy(&target_dataset, true, false, false) {
+                warn!(
+                    dataset = target_dataset.to_str(),
+                    error = error.to_string(),
+                    "cannot destroy partially staged dataset"
+                );
             }
-            // TODO: rollback the dataset first
-            handle.snapshot2(&source_dataset, "xc2")?;
-            handle.clone2(&source_dataset, "xc2", &target_dataset)?;
-            handle.promote(&target_dataset)?;
-        } else {
-            debug!("creating new dataset as no ancestors found");
-            handle.create2(&target_dataset, false, false)?;
         }
 
-        let layers = self
+        result
+    }
+
+    /// Extract the layers in order to the freshly created `target_dataset`, and snapshot it once
+    /// all of them are extracted
+    async fn extract_layers(
+        &self,
+        handle: &ZfsHandle,
+        target_dataset: &Path,
+        layers_dir: &Path,
+        layers: Vec<Receiver<Task<OciDigest, PullLayerStatus>>>,
+    ) -> Result<(), StageLayerError> {
+        let layers_list = self
             .diff_ids
             .iter()
             .fold(String::new(), |a, b| format!("{a},{b}"));
         //        let diff_ids = self.diff_ids.iter().reduce(|a, b| format!("{a},{b}")).unwrap_or_else(String::new);
 
         // at this point, our datase should exist
-        handle.set_prop(&target_dataset, "xc:chain_id", self.chain_id.as_str())?;
+        handle.set_prop(target_dataset, "xc:chain_id", self.chain_id.as_str())?;
 
-        handle.set_prop(&target_dataset, "xc:layers", &layers)?;
+        handle.set_prop(target_dataset, "xc:layers", &layers_list)?;
 
         let root = handle
-            .mount_point(&target_dataset)?
+            .mount_point(target_dataset)?
             .ok_or(StageLayerError::NoMountPoint)?;
         debug!(
             root = root.to_string_lossy().to_string(),
             "begin to extract layers"
         );
 
-        for digest in self.digests.iter() {
-            let mut file = layers_dir.as_ref().to_path_buf();
-            file.push(digest.digest.as_str());
+        for (digest, layer) in self.digests.iter().zip(layers) {
+            wait_for_task(layer).await.map_err(|reason| {
+                StageLayerError::LayerUnavailable(digest.digest.clone(), reason)
+            })?;
+            let file = layers_dir.join(digest.digest.as_str());
             let file_path = file.to_string_lossy().to_string();
             debug!(file_path, "extracting");
-            match tokio::process::Command::new("ocitar")
-                .arg("-xf")
-                .arg(&file)
-                .arg("-C")
-                .arg(&root)
-                .status()
-                .await
-            {
-                Ok(status) => {
-                    if !status.success() {
-                        debug!(
-                            file_path,
-                            root = root.to_str(),
-                            exit_code = status.code(),
-                            "failed to extract file to root: ocitar exit with non-zero exit code"
-                        )
-                    }
+            // layers come from untrusted sources, extract them with every entry confined in root
+            let extract_root = root.clone();
+            let result = tokio::task::spawn_blocking(move || {
+                let archive = std::fs::File::open(&file)?;
+                extract_layer_confined(archive, CompressionType::Auto, extract_root)
+            })
+            .await;
+            match result {
+                Ok(Ok(_)) => (),
+                Ok(Err(error)) => {
+                    return Err(StageLayerError::Extract(
+                        digest.digest.clone(),
+                        error.to_string(),
+                    ));
                 }
                 Err(error) => {
-                    debug!(
-                        file_path,
-                        root = root.to_str(),
-                        error = error.to_string(),
-                        "failed to extract file to root"
-                    );
+                    return Err(StageLayerError::Extract(
+                        digest.digest.clone(),
+                        format!("extract task failed: {error}"),
+                    ));
                 }
             }
             debug!(file_path, "finished");
@@ -629,6 +784,10 @@ pub enum StageLayerError {
     ZfsError(ZfsError),
     #[error("dataset has no mountpoint")]
     NoMountPoint,
+    #[error("layer {0} is not available: {1}")]
+    LayerUnavailable(OciDigest, String),
+    #[error("cannot extract layer {0}: {1}")]
+    Extract(OciDigest, String),
 }
 
 impl From<ZfsError> for StageLayerError {
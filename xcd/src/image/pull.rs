@@ -22,6 +22,7 @@
 // OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
 // SUCH DAMAGE.
 
+use super::verify::{verify_manifest, VerifyError};
 use super::DiffMap;
 use super::ImageManager;
 use super::SharedContext;
@@ -38,6 +39,7 @@ use std::sync::Arc;
 use thiserror::Error;
 use tokio::sync::watch::Receiver;
 use tokio::sync::RwLock;
+use tracing::info;
 use xc::image_store::ImageStore;
 use xc::models::jail_image::JailConfig;
 use xc::util::get_current_arch;
@@ -51,6 +53,7 @@ impl FromId<SharedContext, OciDigest> for PullLayerStatus {
                 PullLayerStatus {
                     written: 0,
                     total: 0,
+                    started: true,
                 },
                 TaskStatus::Completed,
             )
@@ -59,6 +62,7 @@ impl FromId<SharedContext, OciDigest> for PullLayerStatus {
                 PullLayerStatus {
                     written: 0,
                     total: 0,
+                    started: false,
                 },
                 TaskStatus::InProgress,
             )
@@ -95,6 +99,8 @@ impl FromId<SharedContext, String> for PullImageStatus {
 pub struct PullLayerStatus {
     pub(super) written: usize,
     pub(super) total: usize,
+    /// If the download has started, false while waiting for a download slot
+    pub(super) started: bool,
 }
 
 #[derive(Clone, Default, Debug)]
@@ -119,6 +125,8 @@ pub enum PullImageError {
     NoConfig,
     #[error("cannot convert config")]
     ConfigConvertFail,
+    #[error("{0}")]
+    Verification(VerifyError),
 }
 
 pub async fn pull_image(
@@ -130,20 +138,34 @@ pub async fn pull_image(
     let image = reference.name.clone();
     let tag = reference.tag.clone();
 
-    let (hostname, registry) = {
-        let this = this.clone();
-        let this = this.read().await;
-        let reg = this.context.registries.lock().await;
-        match &reference.hostname {
-            None => reg
-                .default_registry()
-                .map(|registry| (reg.default_name().unwrap(), registry))
-                .ok_or_else(|| PullImageError::RegistryNotFound)?,
-            Some(name) => reg
-                .get_registry_by_name(name)
-                .map(|registry| (name.to_string(), registry))
-                .unwrap_or_else(|| (name.to_string(), Registry::new(name.to_string(), None))),
-        }
+    let (hostname, registry, mirrors) = {
+        let context = { this.read().await.context.clone() };
+        let name = reference.hostname.clone();
+        let (hostname, registry, mirrors) = context
+            .with_registries(move |reg| {
+                let (hostname, registry) = match name {
+                    None => reg
+                        .default_registry()
+                        .map(|registry| (reg.default_name().unwrap(), registry))?,
+                    Some(name) => reg
+                        .get_registry_by_name(&name)
+                        .map(|registry| (name.to_string(), registry))
+                        .unwrap_or_else(|| (name.to_string(), Registry::new(name, None))),
+                };
+                let mirrors = reg.get_mirrors(&hostname);
+                Some((hostname, registry, mirrors))
+            })
+            .await
+            .ok_or(PullImageError::RegistryNotFound)?;
+        let mirrors = mirrors
+            .into_iter()
+            .map(|mirror| mirror.with_token_cache(context.token_cache.clone()))
+            .collect::<Vec<_>>();
+        (
+            hostname,
+            registry.with_token_cache(context.token_cache.clone()),
+            mirrors,
+        )
     };
 
     let maybe_task = { this.clone().write().await.images.get(&id) };
@@ -157,9 +179,8 @@ pub async fn pull_image(
     let (mut emitter, rx) = { this.clone().write().await.images.register(&id) };
 
     if !emitter.is_completed() {
-        let mut session = registry.new_session(image.to_string());
-        let manifest = session
-            .query_manifest_traced(tag.as_str(), |list| {
+        let (mut session, maybe_manifest) =
+            query_manifest_traced_with_mirrors(&mirrors, &registry, &image, tag.as_str(), |list| {
                 list.manifests
                     .iter()
                     .find(|desc| desc.platform.architecture == get_current_arch())
@@ -169,17 +190,53 @@ pub async fn pull_image(
             .map_err(|err| {
                 emitter.set_faulted(&format!("failed request manifest: {err:?}"));
                 PullImageError::ClientError(err)
-            })
-            .and_then(|maybe_manifest| {
-                emitter.set_faulted("cannot find usable manifest");
-                maybe_manifest.ok_or(PullImageError::NoManifest)
             })?;
+        let (digest, manifest) = maybe_manifest.ok_or_else(|| {
+            emitter.set_faulted("cannot find usable manifest");
+            PullImageError::NoManifest
+        })?;
+
+        let from_mirror = session.registry().base_url != registry.base_url;
+        if from_mirror {
+            info!(
+                "resolved {id} from mirror {} as {digest}",
+                session.registry().base_url
+            );
+        }
+
+        // verify the manifest before anything it refers to is fetched. The policy is keyed by
+        // the canonical registry name, mirrors do not necessarily carry the signatures so look
+        // them up from the registry itself if the mirror has none.
+        let signature_policy = { this.read().await.context.signature_policy.clone() };
+        let mut verified = verify_manifest(
+            signature_policy.as_deref(),
+            &hostname,
+            &mut session,
+            &digest,
+        )
+        .await;
+        if from_mirror && matches!(verified, Err(VerifyError::Unsigned(..))) {
+            let mut upstream = registry.new_session(image.to_string());
+            verified = verify_manifest(
+                signature_policy.as_deref(),
+                &hostname,
+                &mut upstream,
+                &digest,
+            )
+            .await;
+        }
+        verified.map_err(|err| {
+            emitter.set_faulted(&format!("{err}"));
+            PullImageError::Verification(err)
+        })?;
 
         _ = emitter.use_try(|state| {
             state.manifest = Some(manifest.clone());
             Ok(())
         });
 
+        // the session of a mirror fetches the config and layers the mirror fails to serve from
+        // the registry itself
         let config_descriptor = manifest.config.clone();
 
         let config: serde_json::Value = session
@@ -223,7 +280,7 @@ pub async fn pull_image(
                     this.clone()
                         .write()
                         .await
-                        .stage_root(&session, &chain_id, &diff_maps)
+                        .stage_root(Some(&session), &chain_id, &diff_maps)
                 };
                 let notify = {
                     let x = wait_stage_root.borrow();
@@ -247,6 +304,8 @@ pub async fn pull_image(
                         let arc_image_store = &this.write().await.context.image_store;
                         let image_store = arc_image_store.lock().await;
 
+                        // always tagged under the canonical reference, regardless of the
+                        // mirror the image is pulled from
                         _ = image_store
                             .register_and_tag_manifest(&reference, &jail_image)
                             .and_then(|digest| {
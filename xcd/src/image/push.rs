@@ -28,15 +28,23 @@ use crate::task::*;
 
 use oci_util::digest::OciDigest;
 use oci_util::distribution::client::*;
+use oci_util::distribution::signature::{push_signature, PrivateKey};
 use oci_util::image_reference::ImageReference;
 use oci_util::models::Descriptor;
 use oci_util::models::Platform;
+use ocitar::compression::{CompressionType, ZstdOptions};
+use ocitar::layer::recompress_layer;
+use ocitar::util::{hex, parse_sha256_digest};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::sync::Arc;
 use thiserror::Error;
 use tokio::sync::watch::Receiver;
 use tokio::sync::RwLock;
 use tracing::{debug, error, info};
+use xc::image_store::DiffIdMap;
 
 #[derive(Error, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub enum PushImageError {
@@ -44,6 +52,23 @@ pub enum PushImageError {
     NoSuchLocalReference,
     #[error("requested registry not found")]
     RegistryNotFound,
+    #[error("signing requested but no signing key is configured")]
+    NoSigningKey,
+    #[error("cannot load signing key: {0}")]
+    InvalidSigningKey(String),
+    #[error("cannot attach {0}: {1}")]
+    InvalidAttachment(String, String),
+}
+
+/// A file to attach to the pushed image as an artifact, such as a SBOM
+#[derive(Debug)]
+pub struct Attachment {
+    /// The file as opened by the client, such that the daemon never opens paths on its behalf
+    pub file: std::fs::File,
+    /// The file name, recorded as the title of the artifact
+    pub title: String,
+    /// The media type of the file, also used as the artifact type
+    pub media_type: String,
 }
 impl FromId<SharedContext, String> for PushImageStatus {
     fn from_id(_context: SharedContext, _k: &String) -> (Self, TaskStatus) {
@@ -109,6 +134,77 @@ impl PushImageStatus {
     }
 }
 
+/// Create the `algorithm` ("plain", "gzip" or "zstd") archive of a layer from an existing archive
+/// of it, verifying the diff_id, and register the new archive to the image store
+async fn generate_archive(
+    this: &Arc<RwLock<ImageManager>>,
+    layers_dir: &Path,
+    source: &DiffIdMap,
+    algorithm: &str,
+) -> Result<DiffIdMap, anyhow::Error> {
+    let compression = match algorithm {
+        "plain" => CompressionType::None,
+        "gzip" => CompressionType::Gzip,
+        "zstd" => CompressionType::Zstd,
+        _ => anyhow::bail!("unsupported compression algorithm: {algorithm}"),
+    };
+    let Some(diff_id) = parse_sha256_digest(source.diff_id.as_str()) else {
+        anyhow::bail!("unsupported diff_id: {}", source.diff_id);
+    };
+
+    let source_path = layers_dir.join(source.archive_digest.as_str());
+    let temp_path = layers_dir.join(xc::util::gen_id());
+
+    let result = tokio::task::spawn_blocking({
+        let temp_path = temp_path.clone();
+        move || {
+            let input = std::fs::File::open(source_path)?;
+            let output = std::fs::File::create(temp_path)?;
+            let workers = std::thread::available_parallelism()
+                .map(|n| n.get() as u32)
+                .unwrap_or(1);
+            let zstd = ZstdOptions {
+                workers,
+                ..ZstdOptions::default()
+            };
+            recompress_layer(
+                input,
+                CompressionType::Auto,
+                compression,
+                &zstd,
+                Some(diff_id),
+                output,
+            )
+        }
+    })
+    .await?;
+
+    let recompressed = match result {
+        Ok(recompressed) => recompressed,
+        Err(error) => {
+            _ = std::fs::remove_file(&temp_path);
+            return Err(error.into());
+        }
+    };
+
+    let archive_digest =
+        OciDigest::from_str(&format!("sha256:{}", hex(recompressed.archive_digest)))?;
+    std::fs::rename(&temp_path, layers_dir.join(archive_digest.as_str()))?;
+
+    this.read()
+        .await
+        .map_diff_id(&source.diff_id, &archive_digest, algorithm, None)
+        .await?;
+
+    Ok(DiffIdMap {
+        diff_id: source.diff_id.clone(),
+        archive_digest,
+        algorithm: algorithm.to_string(),
+        origin: None,
+    })
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn push_image_impl(
     this: Arc<RwLock<ImageManager>>,
     registry: Registry,
@@ -116,40 +212,55 @@ async fn push_image_impl(
     name: String,
     tag: String,
     layers_dir: std::path::PathBuf,
+    layer_compression: Option<String>,
+    signing_key: Option<PrivateKey>,
+    attachments: Vec<Attachment>,
     emitter: &mut TaskHandle<String, PushImageStatus>,
 ) -> Result<(), anyhow::Error> {
     let mut session = registry.new_session(name.to_string());
     let layers = record.manifest.layers().clone();
     let mut selections = Vec::new();
 
-    #[allow(unreachable_code)]
-    'layer_loop: for layer in layers.iter() {
+    for layer in layers.iter() {
         let maps = {
             let this = this.clone();
             let this = this.read().await;
             this.query_archives(layer).await?
         };
 
-        for map in maps.iter() {
-            let layer_file_path = {
-                let mut path = layers_dir.to_path_buf();
-                path.push(map.archive_digest.as_str());
-                path
-            };
-            if layer_file_path.exists() {
-                selections.push(map.clone());
-                continue 'layer_loop;
+        let available = maps
+            .iter()
+            .filter(|map| layers_dir.join(map.archive_digest.as_str()).exists())
+            .collect::<Vec<_>>();
+
+        let preferred = layer_compression
+            .as_deref()
+            .and_then(|algorithm| available.iter().find(|map| map.algorithm == algorithm));
+
+        let selection = match (preferred, available.first(), layer_compression.as_deref()) {
+            (Some(map), _, _) => (*map).clone(),
+            (None, Some(source), Some(algorithm)) => {
+                info!(
+                    "generating {algorithm} archive for {layer} from {}",
+                    source.archive_digest
+                );
+                generate_archive(&this, &layers_dir, source, algorithm)
+                    .await
+                    .map_err(|error| {
+                        emitter.set_faulted(&format!(
+                            "cannot generate {algorithm} archive for {layer}: {error}"
+                        ));
+                        error
+                    })?
             }
-        }
-
-        // XXX: we actually can recover by generating those layers, just not in this version
-        {
-            tracing::error!("cannot find archive layer for {layer}");
-            emitter.set_faulted(&format!("cannot find archive layer for {layer}"));
-            anyhow::bail!("cannot find archive layer for {layer}");
-        }
-
-        break;
+            (None, Some(source), None) => (*source).clone(),
+            (None, None, _) => {
+                tracing::error!("cannot find archive layer for {layer}");
+                emitter.set_faulted(&format!("cannot find archive layer for {layer}"));
+                anyhow::bail!("cannot find archive layer for {layer}");
+            }
+        };
+        selections.push(selection);
     }
 
     _ = emitter.use_try(|state| {
@@ -157,18 +268,9 @@ async fn push_image_impl(
         Ok(())
     });
 
-    if layers.len() != selections.len() {
-        todo!()
-    }
-
     let mut uploads = Vec::new();
     for map in selections.iter() {
-        let content_type = match map.algorithm.as_str() {
-            "gzip" => "application/vnd.oci.image.layer.v1.tar+gzip",
-            "zstd" => "application/vnd.oci.image.layer.v1.tar+zstd",
-            "plain" => "application/vnd.oci.image.layer.v1.tar",
-            _ => unreachable!(),
-        };
+        let content_type = super::layer_media_type(&map.algorithm).unwrap();
 
         let mut path = layers_dir.to_path_buf();
         path.push(map.archive_digest.as_str());
@@ -275,6 +377,30 @@ async fn push_image_impl(
     };
     let descriptor = session.register_manifest(&arch_tag, &manifest).await?;
 
+    // attach the signature and artifacts before the image is tagged, so the image is never
+    // visible without them
+    if let Some(key) = signing_key.as_ref() {
+        let signature = push_signature(&mut session, &descriptor, key).await?;
+        info!("signed {} with {}", descriptor.digest, signature.digest);
+    }
+
+    for attachment in attachments.iter() {
+        let blob = session
+            .upload_content(None, attachment.media_type.clone(), &attachment.file)
+            .await?;
+        let annotations = HashMap::from([(
+            "org.opencontainers.image.title".to_string(),
+            attachment.title.clone(),
+        )]);
+        let artifact = session
+            .attach_artifact(&descriptor, &attachment.media_type, vec![blob], annotations)
+            .await?;
+        info!(
+            "attached {} to {} as {}",
+            attachment.title, descriptor.digest, artifact.digest
+        );
+    }
+
     session
         .merge_manifest_list(&descriptor, &platform, &tag)
         .await?;
@@ -288,41 +414,80 @@ async fn push_image_impl(
     Ok::<(), anyhow::Error>(())
 }
 
+/// Push the local image `reference` to `remote_reference`. If `signing_key` is given, the pushed
+/// manifest is signed by the key at the path, and each of `attachments` is attached to the
+/// manifest as an artifact
+#[allow(clippy::too_many_arguments)]
 pub async fn push_image(
     this: Arc<RwLock<ImageManager>>,
     layers_dir: impl AsRef<std::path::Path>,
     reference: ImageReference,
     remote_reference: ImageReference,
     insecure: bool,
+    layer_compression: Option<String>,
+    signing_key: Option<PathBuf>,
+    attachments: Vec<Attachment>,
 ) -> Result<Receiver<Task<String, PushImageStatus>>, PushImageError> {
     let id = format!("{reference}->{remote_reference}");
     info!(id, "push image");
     let name = remote_reference.name;
     let tag = remote_reference.tag.to_string();
 
+    let signing_key = match signing_key {
+        None => None,
+        Some(path) => {
+            let pem = std::fs::read_to_string(&path)
+                .map_err(|err| PushImageError::InvalidSigningKey(format!("{path:?}: {err}")))?;
+            let key = PrivateKey::from_pem(&pem)
+                .map_err(|err| PushImageError::InvalidSigningKey(format!("{path:?}: {err}")))?;
+            Some(key)
+        }
+    };
+
+    for attachment in attachments.iter() {
+        match attachment.file.metadata() {
+            Ok(metadata) if metadata.is_file() => {}
+            Ok(_) => {
+                return Err(PushImageError::InvalidAttachment(
+                    attachment.title.clone(),
+                    "not a regular file".to_string(),
+                ))
+            }
+            Err(err) => {
+                return Err(PushImageError::InvalidAttachment(
+                    attachment.title.clone(),
+                    err.to_string(),
+                ))
+            }
+        }
+    }
+
     let (registry, record) = {
         let this = this.clone();
         let this = this.read().await;
-        let reg = this.context.registries.lock().await;
 
         let record = this
             .query_manifest(&reference)
             .await
             .map_err(|_| PushImageError::NoSuchLocalReference)?;
 
-        let registry = match remote_reference.hostname {
-            None => reg
-                .default_registry()
-                .ok_or(PushImageError::RegistryNotFound)?,
-            Some(hostname) => reg.get_registry_by_name(&hostname).unwrap_or_else(|| {
-                let base_url = if insecure {
-                    format!("http://{hostname}")
-                } else {
-                    format!("https://{hostname}")
-                };
-                Registry::new(base_url, None)
-            }),
-        };
+        let hostname = remote_reference.hostname.clone();
+        let registry = this
+            .context
+            .with_registries(move |reg| match hostname {
+                None => reg.default_registry(),
+                Some(hostname) => Some(reg.get_registry_by_name(&hostname).unwrap_or_else(|| {
+                    let base_url = if insecure {
+                        format!("http://{hostname}")
+                    } else {
+                        format!("https://{hostname}")
+                    };
+                    Registry::new(base_url, None)
+                })),
+            })
+            .await
+            .ok_or(PushImageError::RegistryNotFound)?
+            .with_token_cache(this.context.token_cache.clone());
 
         (registry, record)
     };
@@ -347,6 +512,9 @@ pub async fn push_image(
             name.to_string(),
             tag.to_string(),
             layers_dir,
+            layer_compression,
+            signing_key,
+            attachments,
             &mut emitter,
         )
         .await
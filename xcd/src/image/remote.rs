@@ -0,0 +1,239 @@
+// Copyright (c) 2023 Yan Ka, Chiu.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions
+// are met:
+// 1. Redistributions of source code must retain the above copyright
+//    notice, this list of conditions, and the following disclaimer,
+//    without modification, immediately at the beginning of the file.
+// 2. The name of the author may not be used to endorse or promote products
+//    derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE AUTHOR AND CONTRIBUTORS ``AS IS'' AND
+// ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE AUTHOR OR CONTRIBUTORS BE LIABLE FOR
+// ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+// OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+// HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+// LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
+// OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
+// SUCH DAMAGE.
+
+//! Operations on images in remote registries that do not touch the local image store, such as
+//! listing the tags of a repository or deleting a manifest.
+
+use super::ImageManager;
+
+use oci_util::digest::OciDigest;
+use oci_util::distribution::client::*;
+use oci_util::image_reference::{ImageReference, ImageTag};
+use oci_util::models::{ImageManifest, ImageManifestList, ManifestVariant};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::sync::RwLock;
+use tracing::info;
+use xc::util::get_current_arch;
+
+#[derive(Error, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum RemoteImageError {
+    #[error("requested registry not found")]
+    RegistryNotFound,
+    #[error("no such repository: {0}")]
+    NoSuchRepository(String),
+    #[error("no such manifest: {0}")]
+    NoSuchManifest(String),
+    #[error("{0} is not an image")]
+    NotAnImage(String),
+    #[error("request error: {0}")]
+    ClientError(String),
+    #[error("the registry cannot delete tag {0} alone, deleting its manifest would also remove the tags: {}", .1.join(", "))]
+    TagDeletionUnsupported(String, Vec<String>),
+}
+
+impl From<ClientError> for RemoteImageError {
+    fn from(err: ClientError) -> RemoteImageError {
+        RemoteImageError::ClientError(format!("{err:?}"))
+    }
+}
+
+/// The manifest and config of a remote image
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RemoteImage {
+    /// Digest of the manifest the reference resolves to
+    pub digest: OciDigest,
+    /// The manifest index the reference resolves to, if it is a multi-platform image
+    pub index: Option<ImageManifestList>,
+    /// Digest of the image manifest for the current architecture
+    pub manifest_digest: OciDigest,
+    pub manifest: ImageManifest,
+    pub config: serde_json::Value,
+}
+
+/// Create a session to the repository `name` on the registry `hostname`, or the default
+/// registry if `hostname` is `None`. Unknown registries are accessed anonymously.
+async fn new_session(
+    this: &Arc<RwLock<ImageManager>>,
+    hostname: Option<&str>,
+    name: &str,
+    insecure: bool,
+) -> Result<Session, RemoteImageError> {
+    let context = { this.read().await.context.clone() };
+    let hostname = hostname.map(|hostname| hostname.to_string());
+    let registry = context
+        .with_registries(move |reg| match hostname {
+            None => reg.default_registry(),
+            Some(hostname) => Some(reg.get_registry_by_name(&hostname).unwrap_or_else(|| {
+                let scheme = if insecure { "http" } else { "https" };
+                Registry::new(format!("{scheme}://{hostname}"), None)
+            })),
+        })
+        .await
+        .ok_or(RemoteImageError::RegistryNotFound)?
+        .with_token_cache(context.token_cache.clone());
+    Ok(registry.new_session(name.to_string()))
+}
+
+pub async fn list_tags(
+    this: Arc<RwLock<ImageManager>>,
+    hostname: Option<String>,
+    name: String,
+    insecure: bool,
+) -> Result<Vec<String>, RemoteImageError> {
+    let mut session = new_session(&this, hostname.as_deref(), &name, insecure).await?;
+    session
+        .list_tags(None)
+        .await?
+        .ok_or(RemoteImageError::NoSuchRepository(name))
+}
+
+pub async fn inspect(
+    this: Arc<RwLock<ImageManager>>,
+    reference: ImageReference,
+    insecure: bool,
+) -> Result<RemoteImage, RemoteImageError> {
+    let mut session = new_session(
+        &this,
+        reference.hostname.as_deref(),
+        &reference.name,
+        insecure,
+    )
+    .await?;
+    let (digest, manifest) = session
+        .query_manifest_with_digest(reference.tag.as_str())
+        .await?
+        .ok_or_else(|| RemoteImageError::NoSuchManifest(reference.to_string()))?;
+
+    let (index, manifest_digest, manifest) = match manifest {
+        ManifestVariant::Manifest(manifest) => (None, digest.clone(), manifest),
+        ManifestVariant::Artifact(_) => {
+            return Err(RemoteImageError::NotAnImage(reference.to_string()))
+        }
+        ManifestVariant::List(list) => {
+            let arch = get_current_arch();
+            let desc = list
+                .manifests
+                .iter()
+                .find(|desc| desc.platform.architecture == arch)
+                .ok_or_else(|| {
+                    RemoteImageError::NoSuchManifest(format!("{reference} for {arch}"))
+                })?;
+            let (manifest_digest, manifest) = session
+                .query_manifest_with_digest(desc.digest.as_str())
+                .await?
+                .ok_or_else(|| RemoteImageError::NoSuchManifest(desc.digest.to_string()))?;
+            match manifest {
+                ManifestVariant::Manifest(manifest) => (Some(list), manifest_digest, manifest),
+                _ => return Err(RemoteImageError::NotAnImage(desc.digest.to_string())),
+            }
+        }
+    };
+
+    let config = session
+        .fetch_blob_as(&manifest.config.digest)
+        .await?
+        .ok_or_else(|| RemoteImageError::NoSuchManifest(manifest.config.digest.to_string()))?;
+
+    Ok(RemoteImage {
+        digest,
+        index,
+        manifest_digest,
+        manifest,
+        config,
+    })
+}
+
+/// The manifest deleted from a registry, or untagged if the manifest is left in place
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DeletedManifest {
+    pub digest: OciDigest,
+    /// Whether the manifest itself is deleted, instead of only the requested tag
+    pub manifest_deleted: bool,
+    /// The tags removed from the repository
+    pub tags: Vec<String>,
+}
+
+/// Delete `reference` from the registry. A tag is deleted alone if the registry allows it,
+/// otherwise the manifest of the tag is deleted by digest only if `all_tags` is set, as this
+/// removes every other tag of the manifest as well.
+pub async fn delete(
+    this: Arc<RwLock<ImageManager>>,
+    reference: ImageReference,
+    insecure: bool,
+    all_tags: bool,
+) -> Result<DeletedManifest, RemoteImageError> {
+    let mut session = new_session(
+        &this,
+        reference.hostname.as_deref(),
+        &reference.name,
+        insecure,
+    )
+    .await?;
+    let digest = match &reference.tag {
+        ImageTag::Digest(digest) => digest.clone(),
+        ImageTag::Tag(tag) => {
+            let digest = session
+                .query_manifest_with_digest(tag)
+                .await?
+                .map(|(digest, _)| digest)
+                .ok_or_else(|| RemoteImageError::NoSuchManifest(reference.to_string()))?;
+            match session.delete_tag(tag).await? {
+                TagDeletion::Deleted => {
+                    info!("deleted tag {reference} of {digest} from remote registry");
+                    return Ok(DeletedManifest {
+                        digest,
+                        manifest_deleted: false,
+                        tags: vec![tag.to_string()],
+                    });
+                }
+                TagDeletion::NotFound => {
+                    return Err(RemoteImageError::NoSuchManifest(reference.to_string()))
+                }
+                TagDeletion::Unsupported if !all_tags => {
+                    let tags = session.list_tags_of(&digest).await?;
+                    return Err(RemoteImageError::TagDeletionUnsupported(
+                        reference.to_string(),
+                        tags,
+                    ));
+                }
+                TagDeletion::Unsupported => digest,
+            }
+        }
+    };
+    let tags = session.list_tags_of(&digest).await?;
+    if !session.delete_manifest(digest.as_str()).await? {
+        return Err(RemoteImageError::NoSuchManifest(reference.to_string()));
+    }
+    info!(
+        "deleted {digest} ({reference}) from remote registry along with tags {}",
+        tags.join(", ")
+    );
+    Ok(DeletedManifest {
+        digest,
+        manifest_deleted: true,
+        tags,
+    })
+}
@@ -0,0 +1,220 @@
+// Copyright (c) 2023 Yan Ka, Chiu.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions
+// are met:
+// 1. Redistributions of source code must retain the above copyright
+//    notice, this list of conditions, and the following disclaimer,
+//    without modification, immediately at the beginning of the file.
+// 2. The name of the author may not be used to endorse or promote products
+//    derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE AUTHOR AND CONTRIBUTORS ``AS IS'' AND
+// ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE AUTHOR OR CONTRIBUTORS BE LIABLE FOR
+// ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+// OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+// HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+// LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
+// OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
+// SUCH DAMAGE.
+
+//! Signature policy of pulled images, for example
+//!
+//! ```yaml
+//! # applies to repositories not listed below
+//! default:
+//!   policy: allow_unsigned
+//! repositories:
+//!   registry.example.com/team/*:
+//!     policy: require_signature
+//!     keys:
+//!       - /usr/local/etc/xc/keys/team.pub
+//!   registry.example.com/team/sandbox:
+//!     policy: allow_unsigned
+//! ```
+//!
+//! Repositories are named `<registry>/<repository>`, where registry is the name of the registry
+//! the image is pulled from. A pattern ending with `/*` matches every repository under it, the
+//! most specific pattern applies. Keys are PEM encoded ed25519 or ECDSA P-256 public keys.
+
+use oci_util::digest::OciDigest;
+use oci_util::distribution::client::{ClientError, Session};
+use oci_util::distribution::signature::{find_verified_signature, PublicKey};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+use tracing::{debug, info};
+
+#[derive(Error, Debug)]
+pub enum VerifyError {
+    #[error("cannot load signature policy {0:?}: {1}")]
+    Policy(PathBuf, String),
+    #[error("cannot load public key {0:?}: {1}")]
+    PublicKey(PathBuf, String),
+    #[error("signature required for {0} but no keys are trusted")]
+    NoTrustedKeys(String),
+    #[error("no valid signature for {0}@{1}")]
+    Unsigned(String, OciDigest),
+    #[error("failed to look up signatures: {0:?}")]
+    ClientError(Box<ClientError>),
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SignatureRequirement {
+    #[default]
+    AllowUnsigned,
+    RequireSignature,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq, Eq)]
+pub struct PolicyRule {
+    pub policy: SignatureRequirement,
+    /// Public keys trusted to sign the images
+    #[serde(default)]
+    pub keys: Vec<PathBuf>,
+}
+
+impl PolicyRule {
+    fn public_keys(&self) -> Result<Vec<PublicKey>, VerifyError> {
+        self.keys
+            .iter()
+            .map(|path| {
+                let pem = std::fs::read_to_string(path)
+                    .map_err(|err| VerifyError::PublicKey(path.clone(), err.to_string()))?;
+                PublicKey::from_pem(&pem)
+                    .map_err(|err| VerifyError::PublicKey(path.clone(), err.to_string()))
+            })
+            .collect()
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq, Eq)]
+pub struct SignaturePolicy {
+    #[serde(default)]
+    pub default: PolicyRule,
+    #[serde(default)]
+    pub repositories: HashMap<String, PolicyRule>,
+}
+
+impl SignaturePolicy {
+    pub fn from_path(path: impl AsRef<Path>) -> Result<SignaturePolicy, VerifyError> {
+        let path = path.as_ref();
+        let file = std::fs::File::open(path)
+            .map_err(|err| VerifyError::Policy(path.to_path_buf(), err.to_string()))?;
+        serde_yaml::from_reader(file)
+            .map_err(|err| VerifyError::Policy(path.to_path_buf(), err.to_string()))
+    }
+
+    /// The rule applying to `repository`, in the form of `<registry>/<repository>`
+    pub fn rule_for(&self, repository: &str) -> &PolicyRule {
+        if let Some(rule) = self.repositories.get(repository) {
+            return rule;
+        }
+        self.repositories
+            .iter()
+            .filter_map(|(pattern, rule)| {
+                let prefix = pattern.strip_suffix('*')?;
+                (prefix.ends_with('/') && repository.starts_with(prefix))
+                    .then_some((prefix.len(), rule))
+            })
+            .max_by_key(|(len, _)| *len)
+            .map(|(_, rule)| rule)
+            .unwrap_or(&self.default)
+    }
+}
+
+/// Check the manifest `digest` pulled from `<registry>/<repository of session>` against the
+/// signature policy at `policy_path`. Every image is allowed if there is no policy.
+///
+/// The policy is read on every call, so changes to it apply without restarting the daemon.
+pub async fn verify_manifest(
+    policy_path: Option<&Path>,
+    registry: &str,
+    session: &mut Session,
+    digest: &OciDigest,
+) -> Result<(), VerifyError> {
+    let Some(policy_path) = policy_path else {
+        return Ok(());
+    };
+    let repository = format!("{registry}/{}", session.repository());
+    let policy = SignaturePolicy::from_path(policy_path)?;
+    let rule = policy.rule_for(&repository);
+    if rule.policy == SignatureRequirement::AllowUnsigned {
+        debug!("{repository} allows unsigned images");
+        return Ok(());
+    }
+
+    let keys = rule.public_keys()?;
+    if keys.is_empty() {
+        return Err(VerifyError::NoTrustedKeys(repository));
+    }
+    match find_verified_signature(session, digest, &keys).await {
+        Ok(Some(signature)) => {
+            info!("{repository}@{digest} verified by signature {signature}");
+            Ok(())
+        }
+        Ok(None) => Err(VerifyError::Unsigned(repository, digest.clone())),
+        Err(err) => Err(VerifyError::ClientError(Box::new(err))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_policy_rule_for() {
+        let policy: SignaturePolicy = serde_yaml::from_str(
+            r#"
+default:
+  policy: require_signature
+  keys: [/keys/default.pub]
+repositories:
+  example.com/*:
+    policy: require_signature
+    keys: [/keys/example.pub]
+  example.com/team/*:
+    policy: allow_unsigned
+  example.com/team/release:
+    policy: require_signature
+    keys: [/keys/release.pub]
+"#,
+        )
+        .unwrap();
+
+        let keys = |repository: &str| {
+            let rule = policy.rule_for(repository);
+            (rule.policy, rule.keys.clone())
+        };
+        let key = |path: &str| vec![PathBuf::from(path)];
+        use SignatureRequirement::*;
+
+        assert_eq!(
+            keys("index.docker.io/library/alpine"),
+            (RequireSignature, key("/keys/default.pub"))
+        );
+        assert_eq!(
+            keys("example.com/app"),
+            (RequireSignature, key("/keys/example.pub"))
+        );
+        assert_eq!(keys("example.com/team/app"), (AllowUnsigned, Vec::new()));
+        assert_eq!(
+            keys("example.com/team/release"),
+            (RequireSignature, key("/keys/release.pub"))
+        );
+        // patterns match whole path components only
+        assert_eq!(
+            keys("example.community/app"),
+            (RequireSignature, key("/keys/default.pub"))
+        );
+
+        let policy: SignaturePolicy = serde_yaml::from_str("repositories: {}").unwrap();
+        assert_eq!(policy.rule_for("example.com/app").policy, AllowUnsigned);
+    }
+}
@@ -24,6 +24,7 @@
 
 use crate::auth::Credential;
 use crate::context::ServerContext;
+use crate::image::layout::ImageLayoutError;
 use crate::image::pull::PullImageError;
 use crate::image::push::{PushImageError, PushImageStatusDesc};
 use crate::resources::network::Network;
@@ -38,6 +39,8 @@ use ipc_macro::{ipc_method, FromPacket};
 use oci_util::digest::OciDigest;
 use oci_util::distribution::client::{BasicAuth, Registry};
 use oci_util::image_reference::{ImageReference, ImageTag};
+use ocitar::compression::CompressionType;
+use ocitar::util::hex;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::ffi::OsString;
@@ -181,6 +184,10 @@ async fn pull_image(
             error!("pull image result in error response: {response:?}");
             ipc_err(EINVAL, &format!("client error: {response:?}"))
         }
+        Err(PullImageError::Verification(err)) => {
+            error!("pull image rejected: {err}");
+            ipc_err(EPERM, &err.to_string())
+        }
         Ok(_) => Ok(PullImageResponse { existed: false }),
     }
 }
@@ -903,21 +910,17 @@ async fn fd_import(
         }
     };
 
-    let ocitar_output = std::process::Command::new("ocitar")
-        .arg("-xf-")
-        .arg("--print-input-digest")
-        .arg("-C")
-        .arg(mountpoint)
-        .stdin(file)
-        .output()
-        .unwrap();
+    let summary = match ocitar::layer::extract_layer(file, CompressionType::Auto, &mountpoint) {
+        Ok(summary) => summary,
+        Err(error) => {
+            error!("cannot extract archive to {mountpoint:?}: {error}");
+            return ipc_err(EIO, &format!("cannot extract archive: {error}"));
+        }
+    };
 
-    let output_lines = std::str::from_utf8(&ocitar_output.stdout)
-        .unwrap()
-        .lines()
-        .collect::<Vec<_>>();
-    let diff_id = OciDigest::new_unchecked(output_lines[0].trim());
-    let archive_digest = OciDigest::new_unchecked(output_lines[1].trim());
+    let diff_id = OciDigest::new_unchecked(&format!("sha256:{}", hex(summary.diff_id)));
+    let archive_digest =
+        OciDigest::new_unchecked(&format!("sha256:{}", hex(summary.archive_digest)));
 
     info!(diff_id=diff_id.as_str(), "diff_id");
     info!(archive_digest=archive_digest.as_str(), "archive_digest");
@@ -1234,11 +1237,29 @@ async fn run_main(
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// A file to attach to the pushed image as an artifact, such as a SBOM
+#[derive(FromPacket)]
+pub struct PushAttachment {
+    /// The file opened for read by the client
+    pub file: Fd,
+    /// The file name, recorded as the title of the artifact
+    pub title: String,
+    /// The media type of the file, also used as the artifact type
+    pub media_type: String,
+}
+
+#[derive(FromPacket)]
 pub struct PushImageRequest {
     pub image_reference: ImageReference,
     pub remote_reference: ImageReference,
     pub insecure: bool,
+    /// Compression of the layers to push, one of "plain", "gzip" and "zstd". Layers without an
+    /// archive in this compression are converted from another archive of them
+    pub layer_compression: Option<String>,
+    /// Sign the pushed manifest with the signing key of the daemon
+    pub sign: bool,
+    /// Files to attach to the pushed manifest as artifacts
+    pub attachments: List<PushAttachment>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -1250,11 +1271,26 @@ async fn push_image(
     load_context: &mut ConnectionContext<Variables>,
     request: PushImageRequest,
 ) -> Result<PushImageResponse, ipc::proto::ErrResponse<PushImageError>> {
+    // the attachments are uploaded long after this request, hold on to the files the caller
+    // opened instead of opening them as root by then
+    let attachments = request
+        .attachments
+        .to_vec()
+        .into_iter()
+        .map(|attachment| crate::image::push::Attachment {
+            file: unsafe { std::fs::File::from_raw_fd(attachment.file.as_raw_fd()) },
+            title: attachment.title,
+            media_type: attachment.media_type,
+        })
+        .collect();
     let ctx = context.read().await;
     ctx.push_image(
         request.image_reference,
         request.remote_reference,
         request.insecure,
+        request.layer_compression,
+        request.sign,
+        attachments,
     )
     .await
     .map(|_| PushImageResponse {})
@@ -1264,6 +1300,143 @@ async fn push_image(
     })
 }
 
+#[derive(FromPacket, Debug)]
+pub struct SaveImageRequest {
+    pub image_references: Vec<ImageReference>,
+    /// File opened for write by the client, the OCI image layout is written to it as a tar
+    /// archive
+    pub output: Fd,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SaveImageResponse {
+    pub manifests: Vec<oci_util::models::Descriptor>,
+}
+
+#[ipc_method(method = "save_image")]
+async fn save_image(
+    context: Arc<RwLock<ServerContext>>,
+    local_context: &mut ConnectionContext<Variables>,
+    request: SaveImageRequest,
+) -> Result<SaveImageResponse, ipc::proto::ErrResponse<ImageLayoutError>> {
+    let output = unsafe { std::fs::File::from_raw_fd(request.output.as_raw_fd()) };
+    let ctx = context.read().await;
+    ctx.save_image(request.image_references, output)
+        .await
+        .map(|manifests| SaveImageResponse { manifests })
+        .map_err(|err| ipc::proto::ErrResponse {
+            value: err,
+            errno: 1,
+        })
+}
+
+#[derive(FromPacket, Debug)]
+pub struct LoadImageRequest {
+    /// File opened for read by the client, containing a tar archive of the OCI image layout or a
+    /// `docker save` archive to load
+    pub input: Fd,
+    /// Tag the image as this reference instead of the names recorded in the layout
+    pub image_reference: Option<ImageReference>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LoadImageResponse {
+    pub image_references: Vec<ImageReference>,
+}
+
+#[ipc_method(method = "load_image")]
+async fn load_image(
+    context: Arc<RwLock<ServerContext>>,
+    local_context: &mut ConnectionContext<Variables>,
+    request: LoadImageRequest,
+) -> Result<LoadImageResponse, ipc::proto::ErrResponse<ImageLayoutError>> {
+    let input = unsafe { std::fs::File::from_raw_fd(request.input.as_raw_fd()) };
+    let ctx = context.read().await;
+    ctx.load_image(input, request.image_reference)
+        .await
+        .map(|image_references| LoadImageResponse { image_references })
+        .map_err(|err| ipc::proto::ErrResponse {
+            value: err,
+            errno: 1,
+        })
+}
+
+pub use crate::image::remote::{DeletedManifest, RemoteImage, RemoteImageError};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ListRemoteTagsRequest {
+    /// The registry, or the default registry if `None`
+    pub hostname: Option<String>,
+    pub name: String,
+    pub insecure: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ListRemoteTagsResponse {
+    pub tags: Vec<String>,
+}
+
+#[ipc_method(method = "list_remote_tags")]
+async fn list_remote_tags(
+    context: Arc<RwLock<ServerContext>>,
+    local_context: &mut ConnectionContext<Variables>,
+    request: ListRemoteTagsRequest,
+) -> Result<ListRemoteTagsResponse, ipc::proto::ErrResponse<RemoteImageError>> {
+    let ctx = context.read().await;
+    ctx.list_remote_tags(request.hostname, request.name, request.insecure)
+        .await
+        .map(|tags| ListRemoteTagsResponse { tags })
+        .map_err(|err| ipc::proto::ErrResponse {
+            value: err,
+            errno: 1,
+        })
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RemoteImageRequest {
+    pub image_reference: ImageReference,
+    pub insecure: bool,
+}
+
+#[ipc_method(method = "inspect_remote_image")]
+async fn inspect_remote_image(
+    context: Arc<RwLock<ServerContext>>,
+    local_context: &mut ConnectionContext<Variables>,
+    request: RemoteImageRequest,
+) -> Result<RemoteImage, ipc::proto::ErrResponse<RemoteImageError>> {
+    let ctx = context.read().await;
+    ctx.inspect_remote_image(request.image_reference, request.insecure)
+        .await
+        .map_err(|err| ipc::proto::ErrResponse {
+            value: err,
+            errno: 1,
+        })
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DeleteRemoteImageRequest {
+    pub image_reference: ImageReference,
+    pub insecure: bool,
+    /// Delete the manifest of a tag by digest if the registry cannot delete the tag alone, which
+    /// removes every other tag of the manifest as well
+    pub all_tags: bool,
+}
+
+#[ipc_method(method = "delete_remote_image")]
+async fn delete_remote_image(
+    context: Arc<RwLock<ServerContext>>,
+    local_context: &mut ConnectionContext<Variables>,
+    request: DeleteRemoteImageRequest,
+) -> Result<DeletedManifest, ipc::proto::ErrResponse<RemoteImageError>> {
+    let ctx = context.read().await;
+    ctx.delete_remote_image(request.image_reference, request.insecure, request.all_tags)
+        .await
+        .map_err(|err| ipc::proto::ErrResponse {
+            value: err,
+            errno: 1,
+        })
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct CreateVolumeRequest {
     pub name: String,
@@ -1394,4 +1567,9 @@ pub(crate) async fn register_to_service(
     service.register(replace_meta).await;
     service.register(run_main).await;
     service.register(push_image).await;
+    service.register(save_image).await;
+    service.register(load_image).await;
+    service.register(list_remote_tags).await;
+    service.register(inspect_remote_image).await;
+    service.register(delete_remote_image).await;
 }
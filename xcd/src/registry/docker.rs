@@ -0,0 +1,417 @@
+// Copyright (c) 2023 Yan Ka, Chiu.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions
+// are met:
+// 1. Redistributions of source code must retain the above copyright
+//    notice, this list of conditions, and the following disclaimer,
+//    without modification, immediately at the beginning of the file.
+// 2. The name of the author may not be used to endorse or promote products
+//    derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE AUTHOR AND CONTRIBUTORS ``AS IS'' AND
+// ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE AUTHOR OR CONTRIBUTORS BE LIABLE FOR
+// ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+// OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+// HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+// LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
+// OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
+// SUCH DAMAGE.
+
+//! Registry credentials from Docker `config.json`, including credentials kept by
+//! `docker-credential-*` helpers.
+
+use super::RegistriesProvider;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use oci_util::distribution::client::{BasicAuth, Registry};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{debug, warn};
+
+const DOCKER_HUB: &str = "index.docker.io";
+
+/// How Docker refers to Docker Hub in `auths` and when talking to credential helpers
+const DOCKER_HUB_SERVER: &str = "https://index.docker.io/v1/";
+
+/// Credential helpers can block, for example waiting for a keychain to be unlocked
+const HELPER_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct DockerConfig {
+    #[serde(default)]
+    auths: HashMap<String, AuthEntry>,
+    #[serde(default)]
+    cred_helpers: HashMap<String, String>,
+    creds_store: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct AuthEntry {
+    /// base64 encoded `username:password`
+    auth: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+    identitytoken: Option<String>,
+}
+
+impl AuthEntry {
+    fn credential(&self) -> Option<BasicAuth> {
+        if let (Some(username), Some(password)) = (&self.username, &self.password) {
+            return Some(BasicAuth::new(username.to_string(), password.to_string()));
+        }
+        if let Some(auth) = &self.auth {
+            let decoded = STANDARD.decode(auth.trim()).ok()?;
+            let decoded = String::from_utf8(decoded).ok()?;
+            let (username, password) = decoded.split_once(':')?;
+            return Some(BasicAuth::new(username.to_string(), password.to_string()));
+        }
+        if self.identitytoken.is_some() {
+            debug!("identity tokens in docker config are not supported");
+        }
+        None
+    }
+}
+
+/// Response of `docker-credential-* get`
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct HelperCredential {
+    username: String,
+    secret: String,
+}
+
+/// Normalize a registry as written in Docker config, such as `https://index.docker.io/v1/` or
+/// `ghcr.io`, to its hostname
+fn normalize_registry(name: &str) -> String {
+    let name = name.trim();
+    let name = name
+        .strip_prefix("https://")
+        .or_else(|| name.strip_prefix("http://"))
+        .unwrap_or(name);
+    let host = name.split('/').next().unwrap_or(name).to_ascii_lowercase();
+    match host.as_str() {
+        "docker.io" | "registry-1.docker.io" => DOCKER_HUB.to_string(),
+        _ => host,
+    }
+}
+
+/// The inode, modification time and size of a file, which change when the file is rewritten
+type FileStamp = (u64, Option<SystemTime>, u64);
+
+/// Registries from a Docker `config.json`. The file is parsed again whenever it changes, so
+/// credentials added by `docker login` are picked up without restarting the daemon. This
+/// provider is read-only.
+///
+/// Lookups may run credential helpers and block for up to `HELPER_TIMEOUT`, they should not be
+/// made on the async runtime.
+pub struct DockerConfigProvider {
+    path: PathBuf,
+    /// Credential helpers are executed as `{helper_prefix}{name}`
+    helper_prefix: String,
+    /// The last parsed config and the stamp of the file it is parsed from
+    cache: Mutex<Option<(FileStamp, Arc<DockerConfig>)>>,
+}
+
+impl DockerConfigProvider {
+    pub fn from_path(path: impl AsRef<Path>) -> DockerConfigProvider {
+        DockerConfigProvider {
+            path: path.as_ref().to_path_buf(),
+            helper_prefix: "docker-credential-".to_string(),
+            cache: Mutex::new(None),
+        }
+    }
+
+    fn load(&self) -> Option<Arc<DockerConfig>> {
+        let metadata = match std::fs::metadata(&self.path) {
+            Ok(metadata) => metadata,
+            Err(err) => {
+                debug!("cannot read docker config at {:?}: {err}", self.path);
+                return None;
+            }
+        };
+        let stamp = (metadata.ino(), metadata.modified().ok(), metadata.len());
+
+        let mut cache = self.cache.lock().unwrap();
+        if let Some((cached, config)) = cache.as_ref() {
+            if *cached == stamp {
+                return Some(config.clone());
+            }
+        }
+
+        let content = match std::fs::read(&self.path) {
+            Ok(content) => content,
+            Err(err) => {
+                debug!("cannot read docker config at {:?}: {err}", self.path);
+                return None;
+            }
+        };
+        match serde_json::from_slice::<DockerConfig>(&content) {
+            Ok(config) => {
+                let config = Arc::new(config);
+                *cache = Some((stamp, config.clone()));
+                Some(config)
+            }
+            Err(err) => {
+                warn!("cannot parse docker config at {:?}: {err}", self.path);
+                None
+            }
+        }
+    }
+
+    /// Run `{helper} get` with `server` as input, following the protocol of docker credential
+    /// helpers
+    fn helper_get(&self, helper: &str, server: &str) -> Option<BasicAuth> {
+        let program = format!("{}{helper}", self.helper_prefix);
+        let mut child = match Command::new(&program)
+            .arg("get")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+        {
+            Ok(child) => child,
+            Err(err) => {
+                warn!("cannot execute credential helper {program}: {err}");
+                return None;
+            }
+        };
+
+        // closing stdin lets the helper know the input is complete
+        if let Some(mut stdin) = child.stdin.take() {
+            _ = stdin.write_all(server.as_bytes());
+        }
+
+        let deadline = Instant::now() + HELPER_TIMEOUT;
+        let status = loop {
+            match child.try_wait() {
+                Ok(Some(status)) => break status,
+                Ok(None) if Instant::now() < deadline => {
+                    std::thread::sleep(Duration::from_millis(20));
+                }
+                _ => {
+                    warn!("credential helper {program} did not respond in time");
+                    _ = child.kill();
+                    _ = child.wait();
+                    return None;
+                }
+            }
+        };
+
+        let mut stdout = Vec::new();
+        if let Some(mut out) = child.stdout.take() {
+            _ = out.read_to_end(&mut stdout);
+        }
+
+        if !status.success() {
+            // helpers exit with failure when they have no credentials for the server
+            debug!(
+                "credential helper {program} has no credential for {server}: {}",
+                String::from_utf8_lossy(&stdout).trim()
+            );
+            return None;
+        }
+
+        match serde_json::from_slice::<HelperCredential>(&stdout) {
+            Ok(credential) if credential.username == "<token>" => {
+                debug!("identity tokens from credential helpers are not supported");
+                None
+            }
+            Ok(credential) => Some(BasicAuth::new(credential.username, credential.secret)),
+            Err(err) => {
+                warn!("unexpected output from credential helper {program}: {err}");
+                None
+            }
+        }
+    }
+
+    /// Find the credential of `host` the same way docker does: a credential helper configured
+    /// for the registry takes precedence, then the default credential store, then `auths`
+    fn credential(&self, config: &DockerConfig, host: &str) -> Option<BasicAuth> {
+        let server = if host == DOCKER_HUB {
+            DOCKER_HUB_SERVER.to_string()
+        } else {
+            host.to_string()
+        };
+
+        if let Some((_, helper)) = config
+            .cred_helpers
+            .iter()
+            .find(|(registry, _)| normalize_registry(registry) == host)
+        {
+            return self.helper_get(helper, &server);
+        }
+
+        let entry = config
+            .auths
+            .iter()
+            .find(|(registry, _)| normalize_registry(registry) == host);
+
+        if let Some(store) = &config.creds_store {
+            // the store knows the registry by the key docker saved it with
+            let key = entry.map(|(key, _)| key.as_str()).unwrap_or(&server);
+            if let Some(credential) = self.helper_get(store, key) {
+                return Some(credential);
+            }
+        }
+
+        entry.and_then(|(_, entry)| entry.credential())
+    }
+}
+
+impl RegistriesProvider for DockerConfigProvider {
+    fn default_name(&self) -> Option<String> {
+        Some(DOCKER_HUB.to_string())
+    }
+
+    fn default_registry(&self) -> Option<Registry> {
+        self.get_registry_by_name(DOCKER_HUB)
+    }
+
+    /// Only registries with credentials are returned, such that the lookup falls through to
+    /// other providers when chained
+    fn get_registry_by_name(&self, name: &str) -> Option<Registry> {
+        let host = normalize_registry(name);
+        let config = self.load()?;
+        let credential = self.credential(&config, &host)?;
+        Some(Registry::new(format!("https://{host}"), Some(credential)))
+    }
+
+    fn insert_registry(&mut self, name: &str, _registry: &Registry) {
+        warn!("not saving registry {name}, docker config is read-only for xc");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("xcd-docker-{name}-{}", std::process::id()));
+        _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_normalize_registry() {
+        assert_eq!(
+            normalize_registry("https://index.docker.io/v1/"),
+            DOCKER_HUB
+        );
+        assert_eq!(normalize_registry("docker.io"), DOCKER_HUB);
+        assert_eq!(normalize_registry("https://GHCR.io"), "ghcr.io");
+        assert_eq!(normalize_registry("localhost:5000"), "localhost:5000");
+    }
+
+    #[test]
+    fn test_docker_config_auths_and_helpers() {
+        let dir = temp_dir("helpers");
+
+        // a helper that only knows about ghcr.io, and logs the server it was asked about
+        let helper = dir.join("docker-credential-test");
+        let log = dir.join("requests");
+        std::fs::write(
+            &helper,
+            format!(
+                r#"#!/bin/sh
+[ "$1" = get ] || exit 1
+read server
+echo "$server" >> {}
+if [ "$server" = ghcr.io ]; then
+  echo '{{"ServerURL":"ghcr.io","Username":"octocat","Secret":"ghp_secret"}}'
+else
+  echo "credentials not found in native keychain"
+  exit 1
+fi
+"#,
+                log.to_string_lossy()
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&helper, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let config = dir.join("config.json");
+        std::fs::write(
+            &config,
+            r#"{
+                "auths": {
+                    "https://index.docker.io/v1/": { "auth": "aHViLXVzZXI6aHViLXBhc3M=" },
+                    "registry.example.com": { "username": "alice", "password": "wonderland" }
+                },
+                "credHelpers": { "ghcr.io": "test" }
+            }"#,
+        )
+        .unwrap();
+
+        let mut provider = DockerConfigProvider::from_path(&config);
+        provider.helper_prefix = dir.join("docker-credential-").to_string_lossy().to_string();
+
+        let hub = provider.default_registry().unwrap();
+        assert_eq!(hub.base_url, "https://index.docker.io");
+        let auth = hub.basic_auth.unwrap();
+        assert_eq!(
+            (auth.username.as_str(), auth.password.as_str()),
+            ("hub-user", "hub-pass")
+        );
+
+        let auth = provider
+            .get_registry_by_name("registry.example.com")
+            .unwrap()
+            .basic_auth
+            .unwrap();
+        assert_eq!(
+            (auth.username.as_str(), auth.password.as_str()),
+            ("alice", "wonderland")
+        );
+
+        let auth = provider
+            .get_registry_by_name("ghcr.io")
+            .unwrap()
+            .basic_auth
+            .unwrap();
+        assert_eq!(
+            (auth.username.as_str(), auth.password.as_str()),
+            ("octocat", "ghp_secret")
+        );
+
+        assert!(provider.get_registry_by_name("quay.io").is_none());
+
+        // with a default credential store, registries in auths are looked up by their key
+        std::fs::write(
+            &config,
+            r#"{
+                "auths": { "https://index.docker.io/v1/": {} },
+                "credsStore": "test"
+            }"#,
+        )
+        .unwrap();
+        assert!(provider.default_registry().is_none());
+        let auth = provider
+            .get_registry_by_name("ghcr.io")
+            .unwrap()
+            .basic_auth
+            .unwrap();
+        assert_eq!(auth.username, "octocat");
+
+        let requests = std::fs::read_to_string(&log).unwrap();
+        assert_eq!(
+            requests.lines().collect::<Vec<_>>(),
+            vec!["ghcr.io", "https://index.docker.io/v1/", "ghcr.io"]
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
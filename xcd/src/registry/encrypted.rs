@@ -0,0 +1,458 @@
+// Copyright (c) 2023 Yan Ka, Chiu.
+// All rights reserved.
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions
+// are met:
+// 1. Redistributions of source code must retain the above copyright
+//    notice, this list of conditions, and the following disclaimer,
+//    without modification, immediately at the beginning of the file.
+// 2. The name of the author may not be used to endorse or promote products
+//    derived from this software without specific prior written permission.
+//
+// THIS SOFTWARE IS PROVIDED BY THE AUTHOR AND CONTRIBUTORS ``AS IS'' AND
+// ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+// ARE DISCLAIMED. IN NO EVENT SHALL THE AUTHOR OR CONTRIBUTORS BE LIABLE FOR
+// ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+// OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+// HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+// LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
+// OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
+// SUCH DAMAGE.
+
+//! Registries with their credentials encrypted at rest.
+//!
+//! The store has the same layout as the file managed by `JsonRegistryProvider`, except the
+//! `basic_auth` of each registry is replaced by a `credential` sealed with XChaCha20-Poly1305
+//! using a key only readable by root. The registry name and base url are authenticated along
+//! with the credential, so a credential cannot be redirected to another registry by editing
+//! the store. Plaintext `basic_auth` entries are encrypted the first time the store is loaded.
+//! Mirrors listed under a registry have their credentials sealed the same way, bound to the
+//! registry name and the base url of the mirror.
+
+use super::{Auth, RegistriesProvider};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use oci_util::distribution::client::{BasicAuth, Registry};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 24;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct SealedCredential {
+    /// base64 encoded
+    nonce: String,
+    /// base64 encoded, including the authentication tag
+    ciphertext: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+struct EncryptedRegistryScheme {
+    base_url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    credential: Option<SealedCredential>,
+    /// Plaintext credential written by `JsonRegistryProvider`, only read for migration
+    #[serde(default, skip_serializing)]
+    basic_auth: Option<Auth>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    mirrors: Vec<EncryptedRegistryScheme>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+struct EncryptedRegistriesScheme {
+    default: Option<String>,
+    registries: HashMap<String, EncryptedRegistryScheme>,
+}
+
+pub struct EncryptedRegistryProvider {
+    path: PathBuf,
+    cipher: XChaCha20Poly1305,
+    data: EncryptedRegistriesScheme,
+}
+
+/// Binds a credential to the registry it belongs to
+fn associated_data(name: &str, base_url: &str) -> Vec<u8> {
+    format!("{name}\0{base_url}").into_bytes()
+}
+
+/// Load the host key at `path`, or generate one readable only by the owner if it does not exist
+fn load_or_create_key(path: &Path) -> Result<XChaCha20Poly1305, Error> {
+    if path.exists() {
+        let mode = std::fs::metadata(path)?.permissions().mode();
+        if mode & 0o077 != 0 {
+            warn!("registry key file {path:?} is accessible by other users, consider chmod 600");
+        }
+        let key = std::fs::read(path)?;
+        if key.len() != KEY_LEN {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("registry key file {path:?} should contain exactly {KEY_LEN} bytes"),
+            ));
+        }
+        XChaCha20Poly1305::new_from_slice(&key)
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "invalid registry key"))
+    } else {
+        let key = XChaCha20Poly1305::generate_key(&mut OsRng);
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)?;
+        file.write_all(key.as_slice())?;
+        file.sync_all()?;
+        info!("generated registry key file at {path:?}");
+        Ok(XChaCha20Poly1305::new(&key))
+    }
+}
+
+impl EncryptedRegistryProvider {
+    /// Open the store at `path` with the host key at `key_path`, both are created if they do
+    /// not exist. Plaintext credentials in the store are encrypted and written back right away.
+    pub fn from_path(
+        path: impl AsRef<Path>,
+        key_path: impl AsRef<Path>,
+    ) -> Result<EncryptedRegistryProvider, Error> {
+        let path = path.as_ref().to_path_buf();
+        let cipher = load_or_create_key(key_path.as_ref())?;
+
+        let mut provider = if path.exists() {
+            let content = std::fs::read(&path)?;
+            let data: EncryptedRegistriesScheme = serde_json::from_slice(&content)
+                .map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+            EncryptedRegistryProvider { path, cipher, data }
+        } else {
+            let mut data = EncryptedRegistriesScheme {
+                default: Some("index.docker.io".to_string()),
+                ..EncryptedRegistriesScheme::default()
+            };
+            data.registries.insert(
+                "index.docker.io".to_string(),
+                EncryptedRegistryScheme {
+                    base_url: "https://index.docker.io".to_string(),
+                    ..EncryptedRegistryScheme::default()
+                },
+            );
+            let provider = EncryptedRegistryProvider { path, cipher, data };
+            provider.save()?;
+            provider
+        };
+
+        let mut migrated = 0;
+        for (name, registry) in provider.data.registries.iter_mut() {
+            let mut mirrors = std::mem::take(&mut registry.mirrors);
+            for registry in std::iter::once(&mut *registry).chain(mirrors.iter_mut()) {
+                if let Some(auth) = registry.basic_auth.take() {
+                    registry.credential =
+                        Some(seal(&provider.cipher, name, &registry.base_url, &auth)?);
+                    migrated += 1;
+                }
+            }
+            registry.mirrors = mirrors;
+        }
+        if migrated > 0 {
+            provider.save()?;
+            info!(
+                "encrypted {migrated} plaintext registry credentials in {:?}",
+                provider.path
+            );
+        }
+
+        Ok(provider)
+    }
+
+    /// Write the store to a temporary file only readable by the owner, then move it in place
+    fn save(&self) -> Result<(), Error> {
+        let json = serde_json::to_vec_pretty(&self.data)?;
+        let mut temp = self.path.clone().into_os_string();
+        temp.push(".tmp");
+        let temp = PathBuf::from(temp);
+        _ = std::fs::remove_file(&temp);
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&temp)?;
+        file.write_all(&json)?;
+        file.sync_all()?;
+        std::fs::rename(&temp, &self.path)?;
+        // the store may have been created world readable by an older version
+        std::fs::set_permissions(&self.path, std::fs::Permissions::from_mode(0o600))
+    }
+
+    fn to_reg(&self, name: &str) -> Option<Registry> {
+        let registry = self.data.registries.get(name)?;
+        Some(self.unseal(name, registry))
+    }
+
+    fn unseal(&self, name: &str, registry: &EncryptedRegistryScheme) -> Registry {
+        let basic_auth = registry.credential.as_ref().and_then(|sealed| {
+            let auth = open(&self.cipher, name, &registry.base_url, sealed);
+            if auth.is_none() {
+                warn!("cannot decrypt the credential of registry {name}, ignoring it");
+            }
+            auth.map(|auth| BasicAuth::new(auth.username, auth.password))
+        });
+        Registry::new(registry.base_url.clone(), basic_auth)
+    }
+}
+
+fn seal(
+    cipher: &XChaCha20Poly1305,
+    name: &str,
+    base_url: &str,
+    auth: &Auth,
+) -> Result<SealedCredential, Error> {
+    let plaintext = serde_json::to_vec(auth)?;
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let aad = associated_data(name, base_url);
+    let ciphertext = cipher
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: &plaintext,
+                aad: &aad,
+            },
+        )
+        .map_err(|_| Error::new(ErrorKind::Other, "cannot encrypt credential"))?;
+    Ok(SealedCredential {
+        nonce: STANDARD.encode(nonce.as_slice()),
+        ciphertext: STANDARD.encode(ciphertext),
+    })
+}
+
+fn open(
+    cipher: &XChaCha20Poly1305,
+    name: &str,
+    base_url: &str,
+    sealed: &SealedCredential,
+) -> Option<Auth> {
+    let nonce = STANDARD.decode(&sealed.nonce).ok()?;
+    if nonce.len() != NONCE_LEN {
+        return None;
+    }
+    let ciphertext = STANDARD.decode(&sealed.ciphertext).ok()?;
+    let aad = associated_data(name, base_url);
+    let plaintext = cipher
+        .decrypt(
+            XNonce::from_slice(&nonce),
+            Payload {
+                msg: &ciphertext,
+                aad: &aad,
+            },
+        )
+        .ok()?;
+    serde_json::from_slice(&plaintext).ok()
+}
+
+impl RegistriesProvider for EncryptedRegistryProvider {
+    fn default_name(&self) -> Option<String> {
+        self.data.default.clone()
+    }
+
+    fn default_registry(&self) -> Option<Registry> {
+        self.data
+            .default
+            .as_ref()
+            .and_then(|name| self.to_reg(name))
+    }
+
+    fn get_registry_by_name(&self, name: &str) -> Option<Registry> {
+        self.to_reg(name)
+    }
+
+    fn get_mirrors(&self, name: &str) -> Vec<Registry> {
+        self.data
+            .registries
+            .get(name)
+            .map(|registry| {
+                registry
+                    .mirrors
+                    .iter()
+                    .map(|mirror| self.unseal(name, mirror))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    fn insert_registry(&mut self, name: &str, registry: &Registry) {
+        let credential = match registry.basic_auth.as_ref() {
+            None => None,
+            Some(auth) => {
+                let auth = Auth {
+                    username: auth.username.to_string(),
+                    password: auth.password.to_string(),
+                };
+                match seal(&self.cipher, name, &registry.base_url, &auth) {
+                    Ok(sealed) => Some(sealed),
+                    Err(err) => {
+                        warn!("cannot save registry {name}: {err}");
+                        return;
+                    }
+                }
+            }
+        };
+        let mirrors = self
+            .data
+            .registries
+            .get(name)
+            .map(|registry| registry.mirrors.clone())
+            .unwrap_or_default();
+        self.data.registries.insert(
+            name.to_string(),
+            EncryptedRegistryScheme {
+                base_url: registry.base_url.clone(),
+                credential,
+                basic_auth: None,
+                mirrors,
+            },
+        );
+        if let Err(err) = self.save() {
+            warn!("cannot write registries to {:?}: {err}", self.path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("xcd-encrypted-{name}-{}", std::process::id()));
+        _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_migrate_plaintext_credentials() {
+        let dir = temp_dir("migrate");
+        let store = dir.join("registries.json");
+        let key = dir.join("registries.key");
+        std::fs::write(
+            &store,
+            r#"{
+                "default": "example",
+                "registries": {
+                    "example": {
+                        "base_url": "https://registry.example.com",
+                        "basic_auth": { "username": "alice", "password": "hunter2" },
+                        "mirrors": [
+                            {
+                                "base_url": "https://cache.example.com",
+                                "basic_auth": { "username": "carol", "password": "m1rr0r" }
+                            }
+                        ]
+                    },
+                    "anonymous": { "base_url": "https://anonymous.example.com" }
+                }
+            }"#,
+        )
+        .unwrap();
+
+        let provider = EncryptedRegistryProvider::from_path(&store, &key).unwrap();
+        let content = std::fs::read_to_string(&store).unwrap();
+        assert!(!content.contains("hunter2"));
+        assert!(!content.contains("alice"));
+        assert!(!content.contains("basic_auth"));
+        assert!(!content.contains("m1rr0r"));
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&key), 0o600);
+        assert_eq!(mode(&store), 0o600);
+
+        let auth = provider.default_registry().unwrap().basic_auth.unwrap();
+        assert_eq!(auth.username, "alice");
+        assert_eq!(auth.password, "hunter2");
+        assert!(provider
+            .get_registry_by_name("anonymous")
+            .unwrap()
+            .basic_auth
+            .is_none());
+
+        // loading again with the same key reads back the sealed credentials
+        let mut provider = EncryptedRegistryProvider::from_path(&store, &key).unwrap();
+        let auth = provider
+            .get_registry_by_name("example")
+            .unwrap()
+            .basic_auth
+            .unwrap();
+        assert_eq!(auth.password, "hunter2");
+        let mirrors = provider.get_mirrors("example");
+        assert_eq!(mirrors.len(), 1);
+        assert_eq!(mirrors[0].base_url, "https://cache.example.com");
+        assert_eq!(mirrors[0].basic_auth.as_ref().unwrap().password, "m1rr0r");
+
+        // logging in again keeps the mirrors
+        provider.insert_registry(
+            "example",
+            &Registry::new(
+                "https://registry.example.com".to_string(),
+                Some(BasicAuth::new("alice".to_string(), "hunter3".to_string())),
+            ),
+        );
+        assert_eq!(provider.get_mirrors("example").len(), 1);
+
+        provider.insert_registry(
+            "other",
+            &Registry::new(
+                "https://other.example.com".to_string(),
+                Some(BasicAuth::new("bob".to_string(), "s3cret".to_string())),
+            ),
+        );
+        assert!(!std::fs::read_to_string(&store).unwrap().contains("s3cret"));
+        let provider = EncryptedRegistryProvider::from_path(&store, &key).unwrap();
+        let auth = provider
+            .get_registry_by_name("other")
+            .unwrap()
+            .basic_auth
+            .unwrap();
+        assert_eq!(auth.password, "s3cret");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_credential_bound_to_registry() {
+        let dir = temp_dir("bound");
+        let store = dir.join("registries.json");
+        let key = dir.join("registries.key");
+        let mut provider = EncryptedRegistryProvider::from_path(&store, &key).unwrap();
+        provider.insert_registry(
+            "example",
+            &Registry::new(
+                "https://registry.example.com".to_string(),
+                Some(BasicAuth::new("alice".to_string(), "hunter2".to_string())),
+            ),
+        );
+
+        // pointing the registry somewhere else does not take the credential along
+        let content = std::fs::read_to_string(&store)
+            .unwrap()
+            .replace("https://registry.example.com", "https://evil.example.com");
+        std::fs::write(&store, content).unwrap();
+        let provider = EncryptedRegistryProvider::from_path(&store, &key).unwrap();
+        let registry = provider.get_registry_by_name("example").unwrap();
+        assert_eq!(registry.base_url, "https://evil.example.com");
+        assert!(registry.basic_auth.is_none());
+
+        // a different key cannot decrypt the credentials
+        std::fs::remove_file(&key).unwrap();
+        let provider = EncryptedRegistryProvider::from_path(&store, &key).unwrap();
+        assert!(provider
+            .get_registry_by_name("example")
+            .unwrap()
+            .basic_auth
+            .is_none());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
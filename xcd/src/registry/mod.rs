@@ -21,7 +21,10 @@
 // LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
 // OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
 // SUCH DAMAGE.
+pub mod docker;
+pub mod encrypted;
 
+use crate::config::{RegistryProviderConfig, XcConfig};
 use oci_util::distribution::client::{BasicAuth, Registry};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
@@ -34,23 +37,135 @@ pub trait RegistriesProvider {
     fn default_registry(&self) -> Option<Registry>;
     fn get_registry_by_name(&self, name: &str) -> Option<Registry>;
     fn insert_registry(&mut self, name: &str, registry: &Registry);
+    /// Mirrors of the registry `name`, in the order they should be tried before the registry
+    /// itself
+    fn get_mirrors(&self, _name: &str) -> Vec<Registry> {
+        Vec::new()
+    }
+}
+
+/// Create the registries provider described by the configuration, which defaults to the JSON
+/// provider at `registries`
+pub fn registries_provider_from_config(
+    config: &XcConfig,
+) -> Result<Box<dyn RegistriesProvider + Send + Sync>, std::io::Error> {
+    if config.registry_providers.is_empty() {
+        return Ok(Box::new(JsonRegistryProvider::from_path(
+            &config.registries,
+        )?));
+    }
+    let mut providers: Vec<Box<dyn RegistriesProvider + Send + Sync>> = Vec::new();
+    for provider in config.registry_providers.iter() {
+        match provider {
+            RegistryProviderConfig::Json(path) => {
+                providers.push(Box::new(JsonRegistryProvider::from_path(path)?))
+            }
+            RegistryProviderConfig::Docker(path) => {
+                providers.push(Box::new(docker::DockerConfigProvider::from_path(path)))
+            }
+            RegistryProviderConfig::Encrypted { path, key_file } => providers.push(Box::new(
+                encrypted::EncryptedRegistryProvider::from_path(path, key_file)?,
+            )),
+        }
+    }
+    Ok(Box::new(ChainedRegistryProvider::new(providers)))
+}
+
+/// Look up registries from multiple providers in order. The base url of a registry comes from
+/// the first provider that knows the registry, and the credential from the first provider that
+/// has one for it. Mirrors come from the first provider that lists any for the registry. New
+/// registries are inserted to the first provider.
+pub struct ChainedRegistryProvider {
+    providers: Vec<Box<dyn RegistriesProvider + Send + Sync>>,
+}
+
+impl ChainedRegistryProvider {
+    pub fn new(providers: Vec<Box<dyn RegistriesProvider + Send + Sync>>) -> Self {
+        ChainedRegistryProvider { providers }
+    }
+}
+
+impl RegistriesProvider for ChainedRegistryProvider {
+    fn default_name(&self) -> Option<String> {
+        self.providers.iter().find_map(|p| p.default_name())
+    }
+
+    fn default_registry(&self) -> Option<Registry> {
+        self.default_name()
+            .and_then(|name| self.get_registry_by_name(&name))
+    }
+
+    fn get_registry_by_name(&self, name: &str) -> Option<Registry> {
+        let mut found: Option<Registry> = None;
+        for provider in self.providers.iter() {
+            if let Some(registry) = provider.get_registry_by_name(name) {
+                match found.as_mut() {
+                    None => found = Some(registry),
+                    Some(found) => found.basic_auth = registry.basic_auth,
+                }
+            }
+            if found.as_ref().is_some_and(|r| r.basic_auth.is_some()) {
+                break;
+            }
+        }
+        found
+    }
+
+    fn insert_registry(&mut self, name: &str, registry: &Registry) {
+        if let Some(provider) = self.providers.first_mut() {
+            provider.insert_registry(name, registry);
+        }
+    }
+
+    fn get_mirrors(&self, name: &str) -> Vec<Registry> {
+        self.providers
+            .iter()
+            .map(|p| p.get_mirrors(name))
+            .find(|mirrors| !mirrors.is_empty())
+            .unwrap_or_default()
+    }
 }
 
+/// Registries stored in a JSON file, a registry may list pull-through mirrors to try first:
+///
+/// ```json
+/// {
+///   "default": "index.docker.io",
+///   "registries": {
+///     "index.docker.io": {
+///       "base_url": "https://index.docker.io",
+///       "mirrors": [{ "base_url": "https://mirror.example.com" }]
+///     }
+///   }
+/// }
+/// ```
 pub struct JsonRegistryProvider {
     path: std::path::PathBuf,
     data: RegistriesJsonScheme,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Clone)]
 struct Auth {
     username: String,
     password: String,
 }
 
+impl std::fmt::Debug for Auth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Auth")
+            .field("username", &self.username)
+            .field("password", &"<redacted>")
+            .finish()
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
 struct RegistryScheme {
     base_url: String,
     basic_auth: Option<Auth>,
+    /// Pull-through caches of this registry, tried in order before `base_url`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    mirrors: Vec<RegistryScheme>,
 }
 
 impl RegistryScheme {
@@ -58,6 +173,7 @@ impl RegistryScheme {
         RegistryScheme {
             base_url: base_url.to_string(),
             basic_auth: None,
+            mirrors: Vec::new(),
         }
     }
 
@@ -75,7 +191,7 @@ impl RegistryScheme {
 struct RegistriesJsonScheme {
     default: Option<String>,
     // this is probably a bad idea for us to store all the credentials in plain text in a single
-    // file
+    // file, see `encrypted::EncryptedRegistryProvider`
     registries: HashMap<String, RegistryScheme>,
 }
 
@@ -132,15 +248,29 @@ impl RegistriesProvider for JsonRegistryProvider {
         self.data.get_registry_by_name(name).map(|r| r.to_reg())
     }
 
+    fn get_mirrors(&self, name: &str) -> Vec<Registry> {
+        self.data
+            .get_registry_by_name(name)
+            .map(|r| r.mirrors.iter().map(|m| m.to_reg()).collect())
+            .unwrap_or_default()
+    }
+
     fn insert_registry(&mut self, name: &str, registry: &Registry) {
         let mut copy = self.data.clone();
         let basic_auth = registry.basic_auth.clone().map(|auth| Auth {
             username: auth.username.to_string(),
             password: auth.password,
         });
+        // mirrors are only configured by editing the file, keep them across logins
+        let mirrors = copy
+            .registries
+            .get(name)
+            .map(|r| r.mirrors.clone())
+            .unwrap_or_default();
         let reg = RegistryScheme {
             base_url: registry.base_url.clone(),
             basic_auth,
+            mirrors,
         };
         copy.registries.insert(name.to_string(), reg);
         self.data = copy;
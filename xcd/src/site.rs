@@ -31,6 +31,9 @@ use ipc::packet::Packet;
 use ipc::proto::{Request, Response};
 use ipc::transport::PacketTransport;
 use oci_util::digest::OciDigest;
+use ocitar::compression::{CompressionType, ZstdOptions};
+use ocitar::layer::{create_layer_from_zfs_diff, extract_layer, CreateOptions};
+use ocitar::util::hex;
 use std::collections::HashMap;
 use std::ffi::OsString;
 use std::fs::File;
@@ -38,7 +41,6 @@ use std::net::IpAddr;
 use std::os::fd::{FromRawFd, RawFd};
 use std::os::unix::net::UnixStream;
 use std::path::Path;
-use std::process::Stdio;
 use std::str::FromStr;
 use std::sync::Arc;
 use tokio::sync::watch::Receiver;
@@ -236,28 +238,28 @@ impl Site {
             bail!("no such end tag");
         }
 
-        let output = std::process::Command::new("ocitar")
-            .arg("-cf-")
-            .arg("--write-to-stderr")
-            .stdout(file)
-            .arg("--compression")
-            .arg("zstd")
-            .stderr(Stdio::piped())
-            .arg("--zfs-diff")
-            .arg(format!("{root_dataset}@{start_tag}"))
-            .arg(format!("{root_dataset}@{end_tag}"))
-            .output()
-            .context("cannot spawn ocitar")?;
-
-        let (diff_id, digest) = {
-            let mut results = std::str::from_utf8(&output.stderr).unwrap().trim().lines();
-            let diff_id = results.next().expect("unexpceted output");
-            let digest = results.next().expect("unexpected output");
-            (
-                OciDigest::from_str(diff_id).unwrap(),
-                OciDigest::from_str(digest).unwrap(),
-            )
+        let workers = std::thread::available_parallelism()
+            .map(|n| n.get() as u32)
+            .unwrap_or(1);
+        let options = CreateOptions {
+            compression: CompressionType::Zstd,
+            zstd: ZstdOptions {
+                workers,
+                ..ZstdOptions::default()
+            },
+            ..CreateOptions::default()
         };
+
+        let summary = create_layer_from_zfs_diff(
+            &options,
+            &format!("{root_dataset}@{start_tag}"),
+            &format!("{root_dataset}@{end_tag}"),
+            file,
+        )
+        .context("cannot create layer from zfs diff")?;
+
+        let diff_id = OciDigest::from_str(&format!("sha256:{}", hex(summary.diff_id)))?;
+        let digest = OciDigest::from_str(&format!("sha256:{}", hex(summary.archive_digest)))?;
         Ok((diff_id, digest))
     }
 
@@ -394,20 +396,9 @@ impl Site {
                 for (i, layer_fd) in blueprint.extra_layers.iter().enumerate() {
                     let file = unsafe { std::fs::File::from_raw_fd(*layer_fd) };
                     info!("extracting extra layer: {i}");
-                    let exit_status = std::process::Command::new("ocitar")
-                        .arg("-xf-")
-                        .arg("-C")
-                        .arg(&root)
-                        .stdin(file)
-                        .status()
-                        .with_context(|| format!("extracting extra layers at offset {i}"))?;
-                    if !exit_status.success() {
-                        error!(
-                            "ocitar exit with {exit_status} while extract the extra layers at {i}"
-                        );
-                        bail!(
-                            "ocitar exit with unsuccessful exit code {exit_status} at offset {i}"
-                        );
+                    if let Err(error) = extract_layer(file, CompressionType::Auto, &root) {
+                        error!("ocitar failed with {error} while extract the extra layers at {i}");
+                        bail!("failed to extract extra layers at offset {i}: {error}");
                     }
                 }
 